@@ -0,0 +1,162 @@
+//! Publish throughput of the sink
+//!
+//! Publishes go through a transport answering after a fixed latency, so the
+//! numbers measure the sink rather than Pub/Sub. The client still needs the
+//! Pub/Sub emulator to connect to:
+//!
+//! ```sh
+//! PUBSUB_EMULATOR_HOST=localhost:8681 cargo bench --bench publish
+//! ```
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use apalis_codec::json::JsonCodec;
+use apalis_core::backend::TaskSink;
+use apalis_pubsub::{transport::PubSubTransport, PubSubBackend, PubSubCompact, PubSubConfig};
+use futures::{future::BoxFuture, FutureExt};
+use google_cloud_gax::grpc::Status;
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use google_cloud_pubsub::{client::ClientConfig, subscription::ReceiveConfig};
+use tokio_util::sync::CancellationToken;
+
+/// Tasks published per run
+const TASKS: usize = 10_000;
+
+/// Latency of every publish
+const LATENCY: Duration = Duration::from_millis(5);
+
+/// Answers publishes after [`LATENCY`]
+#[derive(Default)]
+struct SlowTransport {
+    published: AtomicUsize,
+}
+
+impl PubSubTransport for SlowTransport {
+    fn publish<'a>(
+        &'a self,
+        _topic: &'a str,
+        _message: PubsubMessage,
+    ) -> BoxFuture<'a, Result<String, Status>> {
+        async move {
+            tokio::time::sleep(LATENCY).await;
+            let id = self.published.fetch_add(1, Ordering::Relaxed);
+            Ok(id.to_string())
+        }
+        .boxed()
+    }
+
+    fn receive(
+        self: Arc<Self>,
+        _subscription: String,
+        _config: ReceiveConfig,
+        _handler: apalis_pubsub::transport::MessageHandler,
+        cancel: CancellationToken,
+    ) -> BoxFuture<'static, Result<(), Status>> {
+        async move {
+            cancel.cancelled().await;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn acknowledge<'a>(
+        &'a self,
+        _subscription: &'a str,
+        _ack_ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        async { Ok(()) }.boxed()
+    }
+
+    fn modify_ack_deadline<'a>(
+        &'a self,
+        _subscription: &'a str,
+        _ack_ids: Vec<String>,
+        _seconds: i32,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        async { Ok(()) }.boxed()
+    }
+}
+
+type Backend = PubSubBackend<usize, JsonCodec<PubSubCompact>>;
+
+async fn backend(publish_concurrency: usize) -> Backend {
+    let config = PubSubConfig {
+        publish_concurrency,
+        ..Default::default()
+    };
+    PubSubBackend::new_with_config(
+        ClientConfig::default(),
+        "bench-topic".to_string(),
+        "bench-subscription".to_string(),
+        config,
+    )
+    .await
+    .expect("the Pub/Sub emulator should be running")
+    .with_transport(Arc::new(SlowTransport::default()))
+}
+
+/// Tasks per second published one at a time, each waiting for the last
+async fn one_by_one(concurrency: usize) -> f64 {
+    let mut backend = backend(concurrency).await;
+    let tasks = TASKS / 10;
+    let started = Instant::now();
+    for task in 0..tasks {
+        backend.push(task).await.unwrap();
+    }
+    tasks as f64 / started.elapsed().as_secs_f64()
+}
+
+/// Tasks per second published in one batch
+async fn batch(concurrency: usize) -> f64 {
+    let mut backend = backend(concurrency).await;
+    let started = Instant::now();
+    backend.push_bulk((0..TASKS).collect()).await.unwrap();
+    TASKS as f64 / started.elapsed().as_secs_f64()
+}
+
+/// Tasks per second published by several producers sharing a backend
+async fn producers(concurrency: usize, producers: usize) -> f64 {
+    let backend = backend(concurrency).await;
+    let per_producer = TASKS / producers;
+    let started = Instant::now();
+    let handles: Vec<_> = (0..producers)
+        .map(|_| {
+            let mut backend = backend.clone();
+            tokio::spawn(async move {
+                for task in 0..per_producer {
+                    backend.push(task).await.unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    (per_producer * producers) as f64 / started.elapsed().as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    if std::env::var("PUBSUB_EMULATOR_HOST").is_err() {
+        eprintln!("PUBSUB_EMULATOR_HOST isn't set, skipping the publish benchmarks");
+        return;
+    }
+
+    for concurrency in [1, 10, 100, 1000] {
+        println!("publish_concurrency = {concurrency}");
+        println!(
+            "  one by one:   {:>10.0} tasks/s",
+            one_by_one(concurrency).await
+        );
+        println!("  batch:        {:>10.0} tasks/s", batch(concurrency).await);
+        println!(
+            "  16 producers: {:>10.0} tasks/s",
+            producers(concurrency, 16).await
+        );
+    }
+}
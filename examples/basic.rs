@@ -35,6 +35,8 @@ async fn main() {
         max_message_size: 5 * 1024 * 1024, // 5MB
         max_outstanding_messages: Some(1000),
         max_outstanding_bytes: Some(100 * 1024 * 1024), // 100MB
+        concurrency_limit: Some(16),
+        ..Default::default()
     };
 
     let mut ps: PubSubBackend<TestMessage, JsonCodec<PubSubCompact>> =
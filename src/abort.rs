@@ -0,0 +1,88 @@
+//! Releasing messages when a worker is aborted
+//!
+//! Messages received but not yet taken by the worker are nacked when the
+//! backend shuts down, so other workers get them right away. A worker whose
+//! future is aborted, or which panics, never gets there: its messages wait out
+//! their ack deadline before Pub/Sub redelivers them.
+//!
+//! [`PubSubBackend::abort_guard`] returns an [`AbortGuard`] to hold next to the
+//! worker. Dropping it without calling [`AbortGuard::disarm`] stops receiving
+//! and nacks every message still in flight, see [`crate::inflight`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis::prelude::*;
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact};
+//! # async fn handle(job: u32) {}
+//! # async fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let guard = backend.abort_guard();
+//! let worker = WorkerBuilder::new("guarded")
+//!     .backend(backend)
+//!     .build(handle);
+//! // If this future is aborted or panics, the guard nacks the in-flight messages
+//! worker.run().await.unwrap();
+//! guard.disarm();
+//! # }
+//! ```
+use std::sync::Arc;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{inflight::InFlight, spawn::Spawner, PubSubBackend};
+
+/// Nacks in-flight messages when dropped, see the [module level documentation](self)
+#[must_use = "the guard nacks in-flight messages as soon as it's dropped"]
+pub struct AbortGuard {
+    in_flight: Arc<InFlight>,
+    cancel: CancellationToken,
+    spawner: Spawner,
+    armed: bool,
+}
+
+impl AbortGuard {
+    /// Lets the guard go without nacking anything, once the worker stopped
+    /// on its own
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for AbortGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        tracing::warn!("Worker aborted, releasing in-flight messages");
+        self.cancel.cancel();
+        match self.spawner.try_handle() {
+            Some(handle) => {
+                let in_flight = self.in_flight.clone();
+                handle.spawn(async move {
+                    if let Err(e) = in_flight.nack_all().await {
+                        tracing::warn!(error = ?e, "Failed to nack in-flight messages");
+                    }
+                });
+            }
+            None => tracing::warn!(
+                "No runtime to nack in-flight messages, they will be redelivered after their ack deadline"
+            ),
+        }
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Guard nacking the messages in flight when the worker is aborted
+    ///
+    /// Dropping the guard also shuts the backend down, as with
+    /// [`shutdown`](Self::shutdown).
+    pub fn abort_guard(&self) -> AbortGuard {
+        AbortGuard {
+            in_flight: self.in_flight.clone(),
+            cancel: self.cancel.clone(),
+            spawner: self.spawner.clone(),
+            armed: true,
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Bulk acknowledgement and exactly-once delivery
+//!
+//! Custom consumers that collect completions can acknowledge them with a single
+//! RPC instead of one per message, either directly with
+//! [`PubSubBackend::ack_many`] or by accumulating ack ids in an [`AckBatch`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis::prelude::*;
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{ack::AckBatch, utils::PubSubContext, PubSubBackend, PubSubCompact};
+//! async fn handle(job: u32, ctx: PubSubContext, batch: Data<AckBatch>) {
+//!     batch.push(ctx.ack_id);
+//! }
+//!
+//! # async fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let batch = backend.ack_batch();
+//! let worker = WorkerBuilder::new("batched")
+//!     .backend(backend)
+//!     .data(batch.clone())
+//!     .build(handle);
+//!
+//! // Periodically, or when enough ack ids have been collected:
+//! batch.flush().await.unwrap();
+//! # }
+//! ```
+//!
+//! # Exactly-once delivery
+//!
+//! Subscriptions with exactly-once delivery enabled tell whether each
+//! acknowledgement or deadline change succeeded, and only guarantee a message
+//! isn't redelivered once its acknowledgement did. With
+//! [`PubSubBackend::with_exactly_once`], or
+//! [`PubSubConfig::exactly_once`](crate::PubSubConfig::exactly_once), the
+//! backend checks these responses wherever it settles messages: on dispatch,
+//! after the task with deferred acknowledgement, and when nacking.
+//! Transient failures are retried with the
+//! [`backoff`](crate::PubSubConfig::backoff) delays, and acknowledgements
+//! sent after the message's deadline fail with
+//! [`PubSubError::AckExpired`], counted in
+//! [`PubSubStats::ack_expired`](crate::stats::PubSubStats::ack_expired).
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use google_cloud_gax::grpc::{Code, Status};
+
+#[cfg(feature = "consume")]
+use crate::transport::TransportMessage;
+use crate::{
+    backoff::BackoffStrategy, clock::Clock, transport::Subscriber, PubSubBackend, PubSubError,
+};
+
+/// Maximum number of ack ids sent in a single acknowledge request
+pub(crate) const MAX_ACK_IDS_PER_REQUEST: usize = 2500;
+
+/// Acknowledgement attempts made in exactly-once mode before giving up
+const EXACTLY_ONCE_ACK_ATTEMPTS: u32 = 5;
+
+/// How received messages are acknowledged
+#[derive(Debug, Clone)]
+pub(crate) struct AckMode {
+    /// Whether the subscription has exactly-once delivery enabled
+    pub(crate) exactly_once: bool,
+    /// Delays between retries of transient failures in exactly-once mode
+    pub(crate) backoff: Arc<dyn BackoffStrategy>,
+    /// Clock the retries wait on
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+/// Whether a request failure may succeed when retried
+pub(crate) fn is_transient(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::Internal | Code::ResourceExhausted
+    )
+}
+
+/// Whether a request failed because the ack deadline of its messages passed
+///
+/// Pub/Sub only reports this for subscriptions with exactly-once delivery,
+/// as an invalid ack id.
+pub(crate) fn is_expired(status: &Status) -> bool {
+    const INVALID_ACK_ID: &[u8] = b"INVALID_ACK_ID";
+    matches!(
+        status.code(),
+        Code::InvalidArgument | Code::FailedPrecondition
+    ) && (status
+        .details()
+        .windows(INVALID_ACK_ID.len())
+        .any(|window| window == INVALID_ACK_ID)
+        || status.message().to_ascii_lowercase().contains("expired"))
+}
+
+/// The error of a failed acknowledgement or deadline change
+pub(crate) fn ack_error(status: Status) -> PubSubError {
+    if is_expired(&status) {
+        PubSubError::AckExpired(status.to_string())
+    } else {
+        PubSubError::AckFailed(status.to_string())
+    }
+}
+
+/// Acknowledges a received message
+///
+/// With exactly-once delivery a successful response guarantees the message
+/// won't be redelivered, so transient failures are retried. Otherwise
+/// acknowledgements are best effort and sent once.
+#[cfg(feature = "consume")]
+pub(crate) async fn ack_message(
+    message: &TransportMessage,
+    mode: &AckMode,
+) -> Result<(), PubSubError> {
+    retry_ack(mode, || message.ack()).await
+}
+
+/// Acknowledges the message `ack_id` of `subscriber`, like [`ack_message`]
+pub(crate) async fn ack_by_id(
+    subscriber: &Subscriber,
+    ack_id: &str,
+    mode: &AckMode,
+) -> Result<(), PubSubError> {
+    retry_ack(mode, || subscriber.ack(vec![ack_id.to_string()])).await
+}
+
+/// Sets the ack deadline of a received message, 0 nacking it, retrying
+/// transient failures in exactly-once mode like [`ack_message`]
+#[cfg(feature = "consume")]
+pub(crate) async fn modify_message_deadline(
+    message: &TransportMessage,
+    seconds: i32,
+    mode: &AckMode,
+) -> Result<(), PubSubError> {
+    retry_ack(mode, || message.modify_ack_deadline(seconds)).await
+}
+
+/// Sets the ack deadline of the message `ack_id` of `subscriber`, like
+/// [`modify_message_deadline`]
+pub(crate) async fn modify_deadline(
+    subscriber: &Subscriber,
+    ack_id: &str,
+    seconds: i32,
+    mode: &AckMode,
+) -> Result<(), PubSubError> {
+    retry_ack(mode, || {
+        subscriber.modify_ack_deadline(vec![ack_id.to_string()], seconds)
+    })
+    .await
+}
+
+/// Sends an acknowledgement or deadline change with `ack`, retrying
+/// transient failures in exactly-once mode
+async fn retry_ack<F, Fut>(mode: &AckMode, mut ack: F) -> Result<(), PubSubError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), Status>>,
+{
+    let mut attempt = 1;
+    loop {
+        match ack().await {
+            Ok(()) => return Ok(()),
+            Err(status)
+                if mode.exactly_once
+                    && attempt < EXACTLY_ONCE_ACK_ATTEMPTS
+                    && is_transient(&status) =>
+            {
+                tracing::debug!(error = ?status, attempt, "Retrying acknowledgement");
+                mode.clock.sleep(mode.backoff.delay(attempt)).await;
+                attempt += 1;
+            }
+            Err(status) => return Err(ack_error(status)),
+        }
+    }
+}
+
+async fn ack_chunked(subscriber: &Subscriber, ack_ids: &[String]) -> Result<(), PubSubError> {
+    for chunk in ack_ids.chunks(MAX_ACK_IDS_PER_REQUEST) {
+        subscriber.ack(chunk.to_vec()).await.map_err(ack_error)?;
+    }
+    Ok(())
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Checks and retries acknowledgements as the subscription has
+    /// exactly-once delivery enabled, see
+    /// [exactly-once delivery](crate::ack#exactly-once-delivery)
+    pub fn with_exactly_once(mut self) -> Self {
+        self.config.exactly_once = true;
+        self
+    }
+
+    /// Acknowledges several messages of this backend's subscription at once
+    ///
+    /// Large lists are split into requests of at most 2500 ack ids.
+    pub async fn ack_many(&self, ack_ids: &[String]) -> Result<(), PubSubError> {
+        ack_chunked(&self.subscriber(), ack_ids).await
+    }
+
+    /// Creates an empty [`AckBatch`] for this backend's subscription
+    pub fn ack_batch(&self) -> AckBatch {
+        AckBatch {
+            subscriber: self.subscriber(),
+            pending: Arc::default(),
+        }
+    }
+}
+
+/// Accumulates ack ids to acknowledge them together
+///
+/// Clones share the same pending ack ids, so a batch can be handed to a worker
+/// as data while another task flushes it.
+#[derive(Clone)]
+pub struct AckBatch {
+    subscriber: Subscriber,
+    pending: Arc<Mutex<Vec<String>>>,
+}
+
+impl AckBatch {
+    /// Adds an ack id to the batch
+    pub fn push(&self, ack_id: impl Into<String>) {
+        self.pending.lock().unwrap().push(ack_id.into());
+    }
+
+    /// Number of ack ids waiting to be flushed
+    pub fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Whether there is nothing to flush
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Acknowledges every pending ack id
+    ///
+    /// On failure the ack ids are put back so the next flush retries them.
+    pub async fn flush(&self) -> Result<(), PubSubError> {
+        let ack_ids = std::mem::take(&mut *self.pending.lock().unwrap());
+        if ack_ids.is_empty() {
+            return Ok(());
+        }
+
+        let result = ack_chunked(&self.subscriber, &ack_ids).await;
+        if result.is_err() {
+            self.pending.lock().unwrap().extend(ack_ids);
+        }
+        result
+    }
+}
@@ -0,0 +1,193 @@
+//! Auditing the queue topology of a project
+//!
+//! [`list_topology`] lists the topics and subscriptions of the client's
+//! project with their key settings, and marks the ones used by apalis, so
+//! operators can audit queues from Rust tooling rather than the console.
+//!
+//! A subscription is marked as used by apalis when it carries the
+//! [`LABEL_MANAGED_BY`] label, which [`Provisioning`](crate::provision::Provisioning)
+//! sets on the resources it creates, or when its filter reads an attribute
+//! apalis writes, such as `attributes.job_type`. A topic is marked when one of
+//! its subscriptions is: the Pub/Sub client doesn't expose the labels or
+//! schema of topics.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact};
+//! # async fn example(
+//! #     backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>,
+//! # ) -> Result<(), apalis_pubsub::PubSubError> {
+//! let topology = backend.topology().await?;
+//! for subscription in topology.subscriptions.iter().filter(|s| s.apalis) {
+//!     println!(
+//!         "{} <- {}: ack deadline {:?}, dead letters to {:?}",
+//!         subscription.name,
+//!         subscription.topic,
+//!         subscription.ack_deadline,
+//!         subscription.dead_letter_topic,
+//!     );
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::{collections::HashMap, time::Duration};
+
+use futures::future::try_join_all;
+use google_cloud_pubsub::{client::Client, subscription::Subscription};
+
+use crate::{parts, PubSubBackend, PubSubError};
+
+/// Label marking the resources provisioned for apalis
+pub const LABEL_MANAGED_BY: &str = "managed-by";
+
+/// Value of [`LABEL_MANAGED_BY`] on resources provisioned for apalis
+pub const LABEL_MANAGED_BY_APALIS: &str = "apalis";
+
+/// A topic of the project
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicInfo {
+    /// Fully qualified name of the topic
+    pub name: String,
+    /// Whether one of its subscriptions is used by apalis
+    pub apalis: bool,
+    /// Fully qualified names of its subscriptions
+    pub subscriptions: Vec<String>,
+}
+
+/// A subscription of the project and its key settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionInfo {
+    /// Fully qualified name of the subscription
+    pub name: String,
+    /// Fully qualified name of its topic, `_deleted-topic_` once the topic is gone
+    pub topic: String,
+    /// Whether it's used by apalis
+    pub apalis: bool,
+    /// Labels of the subscription
+    pub labels: HashMap<String, String>,
+    /// Ack deadline of delivered messages
+    pub ack_deadline: Duration,
+    /// How long unacknowledged messages are kept
+    pub message_retention: Option<Duration>,
+    /// Whether acknowledged messages are kept too
+    pub retain_acked_messages: bool,
+    /// Whether exactly-once delivery is enabled
+    pub exactly_once: bool,
+    /// Whether messages sharing an ordering key are delivered in order
+    pub message_ordering: bool,
+    /// Where messages go after too many delivery attempts
+    pub dead_letter_topic: Option<String>,
+    /// Delivery attempts before a message is dead-lettered
+    pub max_delivery_attempts: Option<i32>,
+    /// Filter on message attributes, empty when every message is delivered
+    pub filter: String,
+    /// Whether messages are pushed to an endpoint rather than pulled
+    pub push: bool,
+    /// Whether it was detached from its topic
+    pub detached: bool,
+}
+
+/// Topics and subscriptions of a project
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Topology {
+    /// Topics, sorted by name
+    pub topics: Vec<TopicInfo>,
+    /// Subscriptions, sorted by name
+    pub subscriptions: Vec<SubscriptionInfo>,
+}
+
+impl Topology {
+    /// Topics used by apalis
+    pub fn apalis_topics(&self) -> impl Iterator<Item = &TopicInfo> {
+        self.topics.iter().filter(|topic| topic.apalis)
+    }
+
+    /// Subscriptions used by apalis
+    pub fn apalis_subscriptions(&self) -> impl Iterator<Item = &SubscriptionInfo> {
+        self.subscriptions
+            .iter()
+            .filter(|subscription| subscription.apalis)
+    }
+}
+
+/// Whether a subscription with `labels` and `filter` is used by apalis
+fn is_apalis(labels: &HashMap<String, String>, filter: &str) -> bool {
+    labels.get(LABEL_MANAGED_BY).map(String::as_str) == Some(LABEL_MANAGED_BY_APALIS)
+        || parts::RESERVED_ATTRIBUTES
+            .iter()
+            .any(|attribute| filter.contains(&format!("attributes.{attribute}")))
+}
+
+/// Reads the settings of `subscription`
+async fn describe(subscription: Subscription) -> Result<SubscriptionInfo, PubSubError> {
+    let (topic, config) = subscription
+        .config(None)
+        .await
+        .map_err(|e| PubSubError::Client(e.to_string()))?;
+    Ok(SubscriptionInfo {
+        name: subscription.fully_qualified_name().to_string(),
+        topic,
+        apalis: is_apalis(&config.labels, &config.filter),
+        ack_deadline: Duration::from_secs(config.ack_deadline_seconds.max(0) as u64),
+        message_retention: config.message_retention_duration,
+        retain_acked_messages: config.retain_acked_messages,
+        exactly_once: config.enable_exactly_once_delivery,
+        message_ordering: config.enable_message_ordering,
+        dead_letter_topic: config
+            .dead_letter_policy
+            .as_ref()
+            .map(|policy| policy.dead_letter_topic.clone()),
+        max_delivery_attempts: config
+            .dead_letter_policy
+            .as_ref()
+            .map(|policy| policy.max_delivery_attempts),
+        filter: config.filter,
+        push: config
+            .push_config
+            .is_some_and(|push| !push.push_endpoint.is_empty()),
+        detached: config.detached,
+        labels: config.labels,
+    })
+}
+
+/// Lists the topics and subscriptions of the project of `client`, see the
+/// [module level documentation](self)
+pub async fn list_topology(client: &Client) -> Result<Topology, PubSubError> {
+    let (topics, subscriptions) =
+        futures::future::try_join(client.get_topics(None), client.get_subscriptions(None))
+            .await
+            .map_err(|e| PubSubError::Client(e.to_string()))?;
+    let mut subscriptions = try_join_all(subscriptions.into_iter().map(describe)).await?;
+    subscriptions.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut topics: Vec<_> = topics
+        .into_iter()
+        .map(|name| {
+            let subscribed = subscriptions
+                .iter()
+                .filter(|subscription| subscription.topic == name);
+            TopicInfo {
+                apalis: subscribed.clone().any(|subscription| subscription.apalis),
+                subscriptions: subscribed
+                    .map(|subscription| subscription.name.clone())
+                    .collect(),
+                name,
+            }
+        })
+        .collect();
+    topics.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(Topology {
+        topics,
+        subscriptions,
+    })
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Lists the topics and subscriptions of the backend's project
+    pub async fn topology(&self) -> Result<Topology, PubSubError> {
+        list_topology(&self.client).await
+    }
+}
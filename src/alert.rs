@@ -0,0 +1,223 @@
+//! Failure records published to an alert topic
+//!
+//! With [`PubSubBackend::with_alert_topic`], every task that fails for good
+//! publishes a [`FailureRecord`], so paging and alerting pipelines can
+//! subscribe to failures directly instead of scraping logs. A task fails for
+//! good when its handler fails and it isn't republished for another attempt,
+//! see [`crate::retry`].
+//!
+//! The task id, job type and attempt are carried in attributes and the error
+//! in the body, as UTF-8.
+use std::{
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+#[cfg(feature = "consume")]
+use google_cloud_pubsub::topic::Topic;
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+use crate::{
+    backoff::BackoffStrategy, clock::Clock, jobs::JobTypes, sink::publish_with_retry,
+    transport::PubSubTransport, PubSubBackend, PubSubError, PubSubTask, PubSubTaskId,
+    PUBSUB_ATTRIBUTE_TASK_ID,
+};
+
+/// Attribute holding the job type of a failure
+const ALERT_ATTRIBUTE_JOB_TYPE: &str = "job_type";
+
+/// Attribute holding the attempts made at the failed task
+const ALERT_ATTRIBUTE_ATTEMPT: &str = "attempt";
+
+/// A task that failed for good
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailureRecord {
+    /// Id of the task, if it had one
+    pub task_id: Option<PubSubTaskId>,
+    /// Job type of the task, by default the Rust type name of its arguments,
+    /// see [`crate::jobs`]
+    pub job_type: String,
+    /// Error returned by the last attempt
+    pub error: String,
+    /// Attempts made at the task
+    pub attempt: usize,
+}
+
+impl FailureRecord {
+    /// Encodes the record as an alert message
+    pub fn to_message(&self) -> PubsubMessage {
+        let mut message = PubsubMessage {
+            data: self.error.clone().into_bytes(),
+            ..Default::default()
+        };
+        let attributes = &mut message.attributes;
+        if let Some(task_id) = self.task_id {
+            attributes.insert(PUBSUB_ATTRIBUTE_TASK_ID.to_owned(), task_id.to_string());
+        }
+        attributes.insert(ALERT_ATTRIBUTE_JOB_TYPE.to_owned(), self.job_type.clone());
+        attributes.insert(ALERT_ATTRIBUTE_ATTEMPT.to_owned(), self.attempt.to_string());
+        message
+    }
+
+    /// Decodes an alert message
+    pub fn from_message(message: &PubsubMessage) -> Result<Self, PubSubError> {
+        let attribute = |name: &str| {
+            message
+                .attributes
+                .get(name)
+                .ok_or_else(|| PubSubError::Codec(format!("Failure record has no {name}")))
+        };
+
+        let task_id = match message.attributes.get(PUBSUB_ATTRIBUTE_TASK_ID) {
+            Some(task_id) => Some(
+                Uuid::from_str(task_id)
+                    .map_err(|e| PubSubError::Codec(format!("Invalid task id {task_id}: {e}")))?,
+            ),
+            None => None,
+        };
+        let attempt = attribute(ALERT_ATTRIBUTE_ATTEMPT)?;
+        Ok(Self {
+            task_id,
+            job_type: attribute(ALERT_ATTRIBUTE_JOB_TYPE)?.clone(),
+            error: String::from_utf8_lossy(&message.data).into_owned(),
+            attempt: attempt.parse().map_err(|e| {
+                PubSubError::Codec(format!("Invalid failure record attempt {attempt}: {e}"))
+            })?,
+        })
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Publishes a [`FailureRecord`] to the topic `topic_name` for every task
+    /// that fails for good
+    pub fn with_alert_topic(mut self, topic_name: &str) -> Self {
+        self.alert_topic = Some(self.client.topic(topic_name));
+        self
+    }
+}
+
+/// Middleware layer publishing failure records, see the [module level documentation](self)
+#[derive(Clone)]
+pub struct AlertLayer {
+    transport: Arc<dyn PubSubTransport>,
+    topic: String,
+    /// Attempts after which a failed task isn't retried, if it's retried at all
+    max_attempts: Option<usize>,
+    backoff: Arc<dyn BackoffStrategy>,
+    clock: Arc<dyn Clock>,
+    job_types: Arc<JobTypes>,
+}
+
+impl AlertLayer {
+    /// Creates a layer publishing to `topic` through `transport`
+    #[cfg(feature = "consume")]
+    pub(crate) fn new(
+        transport: Arc<dyn PubSubTransport>,
+        topic: &Topic,
+        max_attempts: Option<usize>,
+        backoff: Arc<dyn BackoffStrategy>,
+        clock: Arc<dyn Clock>,
+        job_types: Arc<JobTypes>,
+    ) -> Self {
+        Self {
+            transport,
+            topic: topic.fully_qualified_name().to_string(),
+            max_attempts,
+            backoff,
+            clock,
+            job_types,
+        }
+    }
+}
+
+impl<S> Layer<S> for AlertLayer {
+    type Service = AlertService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AlertService {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service publishing failure records for tasks its inner service fails for good
+#[derive(Clone)]
+pub struct AlertService<S> {
+    inner: S,
+    layer: AlertLayer,
+}
+
+impl<S, M> Service<PubSubTask<M>> for AlertService<S>
+where
+    S: Service<PubSubTask<M>>,
+    S::Future: Send + 'static,
+    S::Response: Send + 'static,
+    S::Error: std::fmt::Display + Send + 'static,
+    M: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = std::pin::Pin<
+        Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: PubSubTask<M>) -> Self::Future {
+        let task_id = req.parts.task_id.map(|id| *id.inner());
+        let attempt = req.parts.attempt.clone();
+        let overrides = self.layer.job_types.get::<M>(&req.parts.ctx);
+        // Without republished retries, tasks fail for good on their first failure
+        let max_attempts = self.layer.max_attempts.map(|max| {
+            overrides
+                .and_then(|overrides| overrides.max_attempts)
+                .unwrap_or(max)
+        });
+        let job_type = req
+            .parts
+            .ctx
+            .job_type()
+            .unwrap_or(std::any::type_name::<M>())
+            .to_string();
+        let layer = self.layer.clone();
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let res = future.await;
+            let Err(error) = &res else {
+                return res;
+            };
+
+            let attempts = attempt.current();
+            if max_attempts.is_some_and(|max| attempts < max) {
+                // Republished for another attempt
+                return res;
+            }
+
+            let record = FailureRecord {
+                task_id,
+                job_type,
+                error: error.to_string(),
+                attempt: attempts,
+            };
+            match publish_with_retry(
+                layer.transport.as_ref(),
+                &layer.topic,
+                record.to_message(),
+                layer.backoff.as_ref(),
+                layer.clock.as_ref(),
+            )
+            .await
+            {
+                Ok(_) => tracing::debug!(?task_id, "Published failure record"),
+                Err(e) => tracing::warn!(error = ?e, "Failed to publish failure record"),
+            }
+            res
+        })
+    }
+}
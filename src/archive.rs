@@ -0,0 +1,280 @@
+//! Archiving messages outside of Pub/Sub
+//!
+//! Pub/Sub keeps messages for a few days at most. With
+//! [`PubSubBackend::with_archive`], the backend also writes a copy of every
+//! message it receives, publishes, or both, to an [`ArchiveStore`], giving
+//! teams an archive to replay or inspect long after Pub/Sub has dropped the
+//! messages.
+//!
+//! Messages are archived whole, payload and attributes, exactly as they went
+//! through Pub/Sub. They are batched in the background and written as files of
+//! length-delimited [`PubsubMessage`] protobufs, which [`read_archive`] reads
+//! back. A batch is written once it reaches [`Archive::max_messages`] or
+//! [`Archive::max_bytes`], or [`Archive::max_delay`] after its first message.
+//!
+//! Archiving never holds up tasks: when the store falls behind, messages are
+//! dropped from the archive and a warning is logged.
+//!
+//! With the `gcs-archive` feature, [`GcsArchiveStore`] writes the files to a
+//! Cloud Storage bucket.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # #[cfg(feature = "gcs-archive")]
+//! # async fn example(
+//! #     backend: apalis_pubsub::PubSubBackend<u32, apalis_codec::json::JsonCodec<apalis_pubsub::PubSubCompact>>,
+//! # ) -> Result<(), Box<dyn std::error::Error>> {
+//! use apalis_pubsub::archive::{Archive, GcsArchiveStore};
+//! use google_cloud_storage::client::{Client, ClientConfig};
+//! use std::sync::Arc;
+//!
+//! let storage = Client::new(ClientConfig::default().with_auth().await?);
+//! let store = GcsArchiveStore::new(storage, "my-archive-bucket");
+//! let backend = backend.with_archive(Archive::new(Arc::new(store)).with_prefix("jobs/"));
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use apalis_core::timer::sleep;
+use futures::future::{select, Either};
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use prost::Message;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{spawn::Spawner, PubSubBackend, PubSubError};
+
+/// Messages waiting to be batched before new ones are dropped
+const ARCHIVE_QUEUE_SIZE: usize = 10_000;
+
+/// Where archive files are written
+pub trait ArchiveStore: Send + Sync {
+    /// Writes the file `name` with `contents`
+    fn write(
+        &self,
+        name: String,
+        contents: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PubSubError>> + Send + '_>>;
+}
+
+/// What to archive, where, and how to batch it
+#[derive(Clone)]
+pub struct Archive {
+    store: Arc<dyn ArchiveStore>,
+    /// Prepended to the name of every file (default: none)
+    pub prefix: String,
+    /// Archive messages the backend receives (default: true)
+    pub received: bool,
+    /// Archive messages the backend publishes (default: false)
+    pub published: bool,
+    /// Most messages in a file (default: 1000)
+    pub max_messages: usize,
+    /// Most bytes in a file (default: 8MB)
+    pub max_bytes: usize,
+    /// Longest a message waits before its file is written (default: 60s)
+    pub max_delay: Duration,
+}
+
+impl Archive {
+    /// Archives received messages to `store` with default batching
+    pub fn new(store: Arc<dyn ArchiveStore>) -> Self {
+        Self {
+            store,
+            prefix: String::new(),
+            received: true,
+            published: false,
+            max_messages: 1000,
+            max_bytes: 8 * 1024 * 1024,
+            max_delay: Duration::from_secs(60),
+        }
+    }
+
+    /// Prepends `prefix` to the name of every file
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Whether to archive received messages
+    pub fn archive_received(mut self, received: bool) -> Self {
+        self.received = received;
+        self
+    }
+
+    /// Whether to archive published messages
+    pub fn archive_published(mut self, published: bool) -> Self {
+        self.published = published;
+        self
+    }
+}
+
+/// Handle queueing messages for archival
+#[derive(Clone)]
+pub(crate) struct Archiver {
+    #[cfg(any(feature = "publish", feature = "consume"))]
+    tx: mpsc::Sender<PubsubMessage>,
+    #[cfg(feature = "consume")]
+    received: bool,
+    #[cfg(feature = "publish")]
+    published: bool,
+}
+
+impl Archiver {
+    /// Starts writing batches described by `archive` in the background
+    fn start(archive: Archive, spawner: &Spawner) -> Self {
+        #[cfg_attr(
+            not(any(feature = "publish", feature = "consume")),
+            allow(unused_variables)
+        )]
+        let (tx, rx) = mpsc::channel(ARCHIVE_QUEUE_SIZE);
+        let archiver = Self {
+            #[cfg(any(feature = "publish", feature = "consume"))]
+            tx,
+            #[cfg(feature = "consume")]
+            received: archive.received,
+            #[cfg(feature = "publish")]
+            published: archive.published,
+        };
+        spawner.spawn(run(archive, rx));
+        archiver
+    }
+
+    /// Archives `message` if received messages are archived
+    #[cfg(feature = "consume")]
+    pub(crate) fn received(&self, message: &PubsubMessage) {
+        if self.received {
+            self.queue(message);
+        }
+    }
+
+    /// Archives `message` if published messages are archived
+    #[cfg(feature = "publish")]
+    pub(crate) fn published(&self, message: &PubsubMessage) {
+        if self.published {
+            self.queue(message);
+        }
+    }
+
+    #[cfg(any(feature = "publish", feature = "consume"))]
+    fn queue(&self, message: &PubsubMessage) {
+        if self.tx.try_send(message.clone()).is_err() {
+            tracing::warn!(
+                message_id = message.message_id,
+                "Archive is falling behind, message not archived"
+            );
+        }
+    }
+}
+
+/// Batches queued messages into files until every [`Archiver`] is dropped
+async fn run(archive: Archive, mut rx: mpsc::Receiver<PubsubMessage>) {
+    let mut batch = Vec::new();
+    let mut count = 0;
+    while let Some(message) = rx.recv().await {
+        message
+            .encode_length_delimited(&mut batch)
+            .expect("Vec has unbounded capacity");
+        count += 1;
+
+        // Fill the batch until it's full or due
+        let mut deadline = std::pin::pin!(sleep(archive.max_delay));
+        while count < archive.max_messages && batch.len() < archive.max_bytes {
+            match select(std::pin::pin!(rx.recv()), deadline.as_mut()).await {
+                Either::Left((Some(message), _)) => {
+                    message
+                        .encode_length_delimited(&mut batch)
+                        .expect("Vec has unbounded capacity");
+                    count += 1;
+                }
+                Either::Left((None, _)) | Either::Right(_) => break,
+            }
+        }
+
+        write_batch(&archive, std::mem::take(&mut batch), count).await;
+        count = 0;
+    }
+}
+
+/// Writes one file of `count` messages
+async fn write_batch(archive: &Archive, contents: Vec<u8>, count: usize) {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let name = format!("{}{millis}-{}.pb", archive.prefix, Uuid::new_v4());
+    match archive.store.write(name.clone(), contents).await {
+        Ok(()) => tracing::debug!(name, count, "Archived messages"),
+        Err(e) => tracing::error!(error = ?e, name, count, "Failed to archive messages"),
+    }
+}
+
+/// Reads the messages of an archive file
+pub fn read_archive(mut contents: &[u8]) -> Result<Vec<PubsubMessage>, PubSubError> {
+    let mut messages = Vec::new();
+    while !contents.is_empty() {
+        let message = PubsubMessage::decode_length_delimited(&mut contents)
+            .map_err(|e| PubSubError::Codec(format!("Invalid archive file: {e}")))?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Archives messages as described by `archive`
+    ///
+    /// Must be called from within a Tokio runtime, unless one was set with
+    /// [`with_runtime_handle`](Self::with_runtime_handle), which writes the
+    /// archive files in the background.
+    pub fn with_archive(mut self, archive: Archive) -> Self {
+        self.archiver = Some(Archiver::start(archive, &self.spawner));
+        self
+    }
+}
+
+/// Writes archive files to a Cloud Storage bucket
+#[cfg(feature = "gcs-archive")]
+pub struct GcsArchiveStore {
+    client: google_cloud_storage::client::Client,
+    bucket: String,
+}
+
+#[cfg(feature = "gcs-archive")]
+impl GcsArchiveStore {
+    /// Writes files to `bucket` with `client`
+    pub fn new(client: google_cloud_storage::client::Client, bucket: impl Into<String>) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+        }
+    }
+}
+
+#[cfg(feature = "gcs-archive")]
+impl ArchiveStore for GcsArchiveStore {
+    fn write(
+        &self,
+        name: String,
+        contents: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PubSubError>> + Send + '_>> {
+        use google_cloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
+
+        Box::pin(async move {
+            let request = UploadObjectRequest {
+                bucket: self.bucket.clone(),
+                ..Default::default()
+            };
+            self.client
+                .upload_object(&request, contents, &UploadType::Simple(Media::new(name)))
+                .await
+                .map_err(|e| PubSubError::Client(e.to_string()))?;
+            Ok(())
+        })
+    }
+}
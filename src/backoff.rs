@@ -0,0 +1,102 @@
+//! Delays between retries
+//!
+//! Everything the backend retries waits according to the same
+//! [`PubSubConfig::backoff`](crate::PubSubConfig::backoff) strategy:
+//!
+//! - acknowledgements in exactly-once mode that fail transiently,
+//! - publishes that fail transiently,
+//! - tasks republished by [`crate::retry`], which are scheduled to run after
+//!   the delay.
+//!
+//! [`Exponential`], [`Fixed`] and [`DecorrelatedJitter`] cover the usual
+//! needs. Other strategies implement [`BackoffStrategy`].
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Debug,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// How long to wait before each retry
+pub trait BackoffStrategy: Debug + Send + Sync {
+    /// Delay before retry number `attempt`, starting at 1
+    fn delay(&self, attempt: u32) -> Duration;
+}
+
+/// The same delay before every retry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fixed(pub Duration);
+
+impl BackoffStrategy for Fixed {
+    fn delay(&self, _attempt: u32) -> Duration {
+        self.0
+    }
+}
+
+/// Delays growing by a constant factor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exponential {
+    /// Delay before the first retry (default: 100ms)
+    pub initial: Duration,
+    /// Factor applied to the delay after every retry (default: 2)
+    pub multiplier: f64,
+    /// Longest delay (default: 60s)
+    pub max: Duration,
+}
+
+impl Default for Exponential {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            multiplier: 2.0,
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl BackoffStrategy for Exponential {
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        Duration::try_from_secs_f64(self.initial.as_secs_f64() * factor)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+/// Random delays that spread out retries of many clients failing together
+///
+/// The delay before a retry is drawn between `base` and three times the
+/// longest delay of the previous retry, capped at `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecorrelatedJitter {
+    /// Shortest delay (default: 100ms)
+    pub base: Duration,
+    /// Longest delay (default: 60s)
+    pub max: Duration,
+}
+
+impl Default for DecorrelatedJitter {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl BackoffStrategy for DecorrelatedJitter {
+    fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = Exponential {
+            initial: self.base,
+            multiplier: 3.0,
+            max: self.max,
+        }
+        .delay(attempt);
+        self.base + ceiling.saturating_sub(self.base).mul_f64(random_unit())
+    }
+}
+
+/// A random number in `[0, 1)`, good enough to spread retries
+fn random_unit() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
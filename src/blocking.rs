@@ -0,0 +1,103 @@
+//! Publishing tasks from synchronous code
+//!
+//! CLI tools and codebases without an async runtime can enqueue tasks with a
+//! [`BlockingPubSubBackend`]. It owns a small Tokio runtime, connects the
+//! wrapped [`PubSubBackend`] on it, and drives every publish to completion
+//! before returning.
+//!
+//! Don't use it from async code: creating, using or dropping it inside a
+//! runtime panics. Workers keep using [`PubSubBackend`] directly.
+//!
+//! Requires the `blocking` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{blocking::BlockingPubSubBackend, PubSubBackend, PubSubCompact};
+//! # use google_cloud_pubsub::client::ClientConfig;
+//! # fn example() -> Result<(), apalis_pubsub::PubSubError> {
+//! let mut backend = BlockingPubSubBackend::connect(|| async {
+//!     let config = ClientConfig::default().with_auth().await.unwrap();
+//!     PubSubBackend::<u32, JsonCodec<PubSubCompact>>::new_from_config(
+//!         config,
+//!         "my-topic".to_string(),
+//!         "my-subscription".to_string(),
+//!     )
+//!     .await
+//! })?;
+//! backend.push(42)?;
+//! backend.push_batch(vec![1, 2, 3])?;
+//! # Ok(())
+//! # }
+//! ```
+use std::future::Future;
+
+use apalis_core::backend::{codec::Codec, TaskSink};
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{PubSubBackend, PubSubCompact, PubSubError, PublishedTask};
+
+/// A [`PubSubBackend`] publishing from synchronous code, see the
+/// [module level documentation](self)
+pub struct BlockingPubSubBackend<M, C> {
+    backend: PubSubBackend<M, C>,
+    runtime: Runtime,
+}
+
+impl<M, C> std::fmt::Debug for BlockingPubSubBackend<M, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockingPubSubBackend")
+            .field("topic", &self.backend.topic_name())
+            .finish()
+    }
+}
+
+impl<M, C> BlockingPubSubBackend<M, C> {
+    /// Creates the backend with `connect`, on a runtime owned by the
+    /// blocking backend
+    ///
+    /// The backend's client must be created by `connect`, so its connections
+    /// run on that runtime.
+    pub fn connect<F, Fut>(connect: F) -> Result<Self, PubSubError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<PubSubBackend<M, C>, PubSubError>>,
+    {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("apalis-pubsub-blocking")
+            .enable_all()
+            .build()
+            .map_err(|e| PubSubError::Io(e.to_string()))?;
+        let backend = runtime.block_on(connect())?;
+        Ok(Self { backend, runtime })
+    }
+
+    /// The wrapped backend
+    pub fn backend(&self) -> &PubSubBackend<M, C> {
+        &self.backend
+    }
+}
+
+impl<M, C> BlockingPubSubBackend<M, C>
+where
+    M: Unpin,
+    C: Codec<M, Compact = PubSubCompact> + Unpin,
+    C::Error: std::error::Error,
+    PubSubBackend<M, C>: TaskSink<M, Error = PubSubError>,
+{
+    /// Publishes a task with `args`, returning its message and task ids once
+    /// Pub/Sub accepted it
+    pub fn push(&mut self, args: M) -> Result<PublishedTask, PubSubError> {
+        self.runtime.block_on(self.backend.push(args))
+    }
+
+    /// Publishes a task for each of `args` in one batch, returning once
+    /// Pub/Sub accepted them all
+    pub fn push_batch(&mut self, args: Vec<M>) -> Result<(), PubSubError> {
+        self.runtime
+            .block_on(self.backend.push_bulk(args))
+            .map_err(Into::into)
+    }
+}
@@ -0,0 +1,320 @@
+//! Stopping consumption when messages stop decoding
+//!
+//! Messages that fail to decode are dropped as poison, one by one. When a bad
+//! producer deploy starts publishing a payload workers can't read, that drops
+//! the whole backlog of a job type before anyone notices. With
+//! [`PubSubBackend::with_decode_breaker`], workers track the share of messages
+//! of each job type failing to decode over a window, and when it reaches
+//! [`DecodeBreaker::failure_rate`] the breaker of the job type trips:
+//!
+//! - an error is logged and the optional alert callback is invoked,
+//! - with [`BreakerAction::Pause`], the job type is
+//!   [paused](crate::jobs#pausing-job-types): its messages stay in the
+//!   subscription until it's resumed with
+//!   [`PubSubBackend::resume_job_type`],
+//! - with [`BreakerAction::Quarantine`], its messages are republished
+//!   undecoded to a quarantine topic and acknowledged, until the breaker is
+//!   reset with [`PubSubBackend::reset_decode_breaker`].
+//!
+//! Decode failures are counted per job type in
+//! [`PubSubStats::decode_failures`](crate::stats::PubSubStats::decode_failures)
+//! whether or not a breaker is set.
+//!
+//! Requires the `consume` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{breaker::{BreakerAction, DecodeBreaker}, PubSubBackend, PubSubCompact};
+//! # use std::sync::Arc;
+//! # fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let backend = backend.with_decode_breaker(
+//!     DecodeBreaker::new(BreakerAction::Quarantine("jobs-quarantine".to_string()))
+//!         .with_failure_rate(0.2)
+//!         .with_alert(Arc::new(|trip| {
+//!             eprintln!("{} stopped decoding: {}/{}", trip.job_type, trip.failures, trip.messages);
+//!         })),
+//! );
+//! # }
+//! ```
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+
+use crate::{
+    ack::{ack_message, modify_message_deadline, AckMode},
+    jobs::PausedJobTypes,
+    sink::publish_with_retry,
+    transport::{PubSubTransport, TransportMessage},
+    PubSubBackend,
+};
+
+/// What a tripped breaker does with the messages of its job type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BreakerAction {
+    /// Pause the job type, leaving its messages in the subscription
+    Pause,
+    /// Republish its messages to the topic with this name and acknowledge them
+    Quarantine(String),
+}
+
+/// A breaker that tripped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakerTrip {
+    /// Job type whose messages stopped decoding
+    pub job_type: String,
+    /// Messages of the job type that failed to decode in the window
+    pub failures: u64,
+    /// Messages of the job type received in the window
+    pub messages: u64,
+}
+
+/// Called when a breaker trips
+pub type BreakerAlert = Arc<dyn Fn(&BreakerTrip) + Send + Sync>;
+
+/// When the breakers of a backend trip, see the
+/// [module level documentation](self)
+#[derive(Clone)]
+pub struct DecodeBreaker {
+    /// Share of messages failing to decode that trips the breaker of their
+    /// job type (default: 0.5)
+    pub failure_rate: f64,
+    /// Messages of a job type received in a window before the breaker may
+    /// trip (default: 20)
+    pub min_messages: u64,
+    /// Span over which messages are counted (default: 60s)
+    pub window: Duration,
+    /// What a tripped breaker does
+    pub action: BreakerAction,
+    alert: Option<BreakerAlert>,
+}
+
+impl std::fmt::Debug for DecodeBreaker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecodeBreaker")
+            .field("failure_rate", &self.failure_rate)
+            .field("min_messages", &self.min_messages)
+            .field("window", &self.window)
+            .field("action", &self.action)
+            .finish()
+    }
+}
+
+impl DecodeBreaker {
+    /// Breakers doing `action` when they trip
+    pub fn new(action: BreakerAction) -> Self {
+        Self {
+            failure_rate: 0.5,
+            min_messages: 20,
+            window: Duration::from_secs(60),
+            action,
+            alert: None,
+        }
+    }
+
+    /// Trips once a share `failure_rate` of messages fails to decode
+    pub fn with_failure_rate(mut self, failure_rate: f64) -> Self {
+        self.failure_rate = failure_rate;
+        self
+    }
+
+    /// Waits for `min_messages` messages of a job type before tripping
+    pub fn with_min_messages(mut self, min_messages: u64) -> Self {
+        self.min_messages = min_messages;
+        self
+    }
+
+    /// Counts messages over `window`
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Calls `alert` when a breaker trips
+    pub fn with_alert(mut self, alert: BreakerAlert) -> Self {
+        self.alert = Some(alert);
+        self
+    }
+}
+
+/// Messages of a job type counted since `started`
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    messages: u64,
+    failures: u64,
+}
+
+/// The breakers of a backend, shared by its clones
+#[derive(Debug)]
+pub(crate) struct Breakers {
+    config: DecodeBreaker,
+    /// Fully qualified name of the quarantine topic, if any
+    quarantine: Option<String>,
+    windows: Mutex<HashMap<String, Window>>,
+    /// Job types whose messages are quarantined
+    quarantined: RwLock<HashSet<String>>,
+}
+
+impl Breakers {
+    /// Counts a message of `job_type` received at `now`, tripping its breaker
+    /// if too many failed to decode
+    pub(crate) fn record(
+        &self,
+        job_type: &str,
+        failed: bool,
+        now: Instant,
+        paused: &PausedJobTypes,
+    ) {
+        let trip = {
+            let mut windows = self.windows.lock().expect("breaker lock poisoned");
+            let window = windows
+                .entry(job_type.to_owned())
+                .or_insert_with(|| Window {
+                    started: now,
+                    messages: 0,
+                    failures: 0,
+                });
+            if now.duration_since(window.started) >= self.config.window {
+                *window = Window {
+                    started: now,
+                    messages: 0,
+                    failures: 0,
+                };
+            }
+            window.messages += 1;
+            window.failures += u64::from(failed);
+            let rate = window.failures as f64 / window.messages as f64;
+            if !failed
+                || window.messages < self.config.min_messages
+                || rate < self.config.failure_rate
+            {
+                return;
+            }
+            let trip = BreakerTrip {
+                job_type: job_type.to_owned(),
+                failures: window.failures,
+                messages: window.messages,
+            };
+            windows.remove(job_type);
+            trip
+        };
+
+        tracing::error!(
+            job_type,
+            failures = trip.failures,
+            messages = trip.messages,
+            action = ?self.config.action,
+            "Messages stopped decoding, tripping the breaker"
+        );
+        match &self.config.action {
+            BreakerAction::Pause => {
+                paused.pause(job_type.to_owned());
+            }
+            BreakerAction::Quarantine(_) => {
+                self.quarantined
+                    .write()
+                    .expect("breaker lock poisoned")
+                    .insert(job_type.to_owned());
+            }
+        }
+        if let Some(alert) = &self.config.alert {
+            alert(&trip);
+        }
+    }
+
+    /// Whether the messages of `job_type` are quarantined
+    pub(crate) fn is_quarantined(&self, job_type: &str) -> bool {
+        let quarantined = self.quarantined.read().expect("breaker lock poisoned");
+        !quarantined.is_empty() && quarantined.contains(job_type)
+    }
+
+    /// Republishes `message` to the quarantine topic and acknowledges it,
+    /// nacking it if it couldn't be republished
+    pub(crate) async fn quarantine(
+        &self,
+        transport: &dyn PubSubTransport,
+        message: &TransportMessage,
+        mode: &AckMode,
+    ) {
+        let Some(topic) = &self.quarantine else {
+            return;
+        };
+        let copy = PubsubMessage {
+            data: message.message.data.clone(),
+            attributes: message.message.attributes.clone(),
+            ..Default::default()
+        };
+        let published = publish_with_retry(
+            transport,
+            topic,
+            copy,
+            mode.backoff.as_ref(),
+            mode.clock.as_ref(),
+        )
+        .await;
+        let settled = match published {
+            Ok(_) => ack_message(message, mode).await,
+            Err(e) => {
+                tracing::error!(error = ?e, "Failed to quarantine message");
+                modify_message_deadline(message, 0, mode).await
+            }
+        };
+        if let Err(e) = settled {
+            tracing::error!(error = ?e, "Failed to settle quarantined message");
+        }
+    }
+
+    /// Resets the breaker of `job_type`, returning whether it had tripped
+    fn reset(&self, job_type: &str) -> bool {
+        self.windows
+            .lock()
+            .expect("breaker lock poisoned")
+            .remove(job_type);
+        self.quarantined
+            .write()
+            .expect("breaker lock poisoned")
+            .remove(job_type)
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Trips a breaker for job types whose messages stop decoding, see
+    /// [`breaker`](crate::breaker)
+    pub fn with_decode_breaker(mut self, breaker: DecodeBreaker) -> Self {
+        let quarantine = match &breaker.action {
+            BreakerAction::Quarantine(topic) => {
+                Some(self.client.topic(topic).fully_qualified_name().to_string())
+            }
+            BreakerAction::Pause => None,
+        };
+        self.breakers = Some(Arc::new(Breakers {
+            config: breaker,
+            quarantine,
+            windows: Mutex::default(),
+            quarantined: RwLock::default(),
+        }));
+        self
+    }
+
+    /// Consumes the messages of `job_type` again after its breaker tripped
+    ///
+    /// This stops quarantining its messages, or resumes it if the breaker
+    /// paused it.
+    pub fn reset_decode_breaker(&self, job_type: &str) {
+        let Some(breakers) = &self.breakers else {
+            return;
+        };
+        let quarantined = breakers.reset(job_type);
+        if breakers.config.action == BreakerAction::Pause {
+            self.resume_job_type(job_type);
+        } else if quarantined {
+            tracing::info!(job_type, "Breaker reset");
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Memory budget for buffered payloads
+//!
+//! Workers handling large payloads can run out of memory when many of them
+//! pile up locally, either received and waiting for the worker or pushed and
+//! waiting to be published. [`PubSubConfig::max_buffered_bytes`](crate::PubSubConfig::max_buffered_bytes)
+//! caps the payload bytes held in both places together:
+//!
+//! - Receiving waits for room in the budget before queueing a message for the
+//!   worker, so Pub/Sub's flow control stops delivering more. Messages waiting
+//!   for room get it in the order they arrived, so a large payload isn't
+//!   overtaken by smaller ones forever.
+//! - The sink waits for its pending publishes before accepting more tasks.
+//!
+//! [`PubSubBackend::is_backpressured`] reports whether the budget is used up.
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "consume")]
+use std::{pin::pin, sync::Arc};
+
+#[cfg(feature = "consume")]
+use tokio::sync::Mutex;
+#[cfg(any(feature = "publish", feature = "consume"))]
+use tokio::sync::Notify;
+
+use crate::PubSubBackend;
+
+/// Payload bytes buffered by a backend, up to a limit
+#[derive(Debug)]
+pub struct BufferBudget {
+    limit: usize,
+    used: AtomicUsize,
+    #[cfg(any(feature = "publish", feature = "consume"))]
+    released: Notify,
+    /// Held by the next reservation to go through, queueing the others
+    #[cfg(feature = "consume")]
+    turn: Mutex<()>,
+}
+
+impl BufferBudget {
+    /// Creates a budget of `limit` bytes
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+            #[cfg(any(feature = "publish", feature = "consume"))]
+            released: Notify::new(),
+            #[cfg(feature = "consume")]
+            turn: Mutex::new(()),
+        }
+    }
+
+    /// Bytes currently buffered
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    /// Whether the budget is used up
+    pub fn is_exhausted(&self) -> bool {
+        self.used() >= self.limit
+    }
+
+    /// Reserves `bytes` if they fit, or if nothing else is buffered so a
+    /// payload larger than the whole budget can't block forever
+    #[cfg(feature = "consume")]
+    fn try_reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used == 0 || used + bytes <= self.limit).then_some(used + bytes)
+            })
+            .is_ok()
+    }
+
+    /// Waits until `bytes` fit in the budget, then reserves them until the
+    /// permit is dropped
+    ///
+    /// Callers get their reservation in the order they asked for it. A
+    /// payload larger than the whole budget is reserved once nothing else is
+    /// buffered.
+    #[cfg(feature = "consume")]
+    pub async fn reserve(self: &Arc<Self>, bytes: usize) -> BudgetPermit {
+        // The lock is fair, so later callers wait behind this one
+        let _turn = self.turn.lock().await;
+        loop {
+            let mut released = pin!(self.released.notified());
+            released.as_mut().enable();
+            if self.try_reserve(bytes) {
+                return BudgetPermit {
+                    budget: self.clone(),
+                    bytes,
+                };
+            }
+            tracing::debug!(bytes, used = self.used(), "Waiting for buffer budget");
+            released.await;
+        }
+    }
+
+    /// Counts `bytes` against the budget without waiting
+    #[cfg(feature = "publish")]
+    pub(crate) fn add(&self, bytes: usize) {
+        self.used.fetch_add(bytes, Ordering::AcqRel);
+    }
+
+    /// Returns `bytes` to the budget
+    #[cfg(any(feature = "publish", feature = "consume"))]
+    pub(crate) fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+        self.released.notify_waiters();
+    }
+}
+
+/// Bytes reserved for a buffered message, returned to the budget on drop
+#[cfg(feature = "consume")]
+#[derive(Debug)]
+pub struct BudgetPermit {
+    budget: Arc<BufferBudget>,
+    bytes: usize,
+}
+
+#[cfg(feature = "consume")]
+impl Drop for BudgetPermit {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Payload bytes buffered by the backend, if a budget is configured
+    pub fn buffered_bytes(&self) -> Option<usize> {
+        self.budget.as_ref().map(|budget| budget.used())
+    }
+
+    /// Whether receiving and pushing are held back by the buffer budget
+    pub fn is_backpressured(&self) -> bool {
+        self.budget
+            .as_ref()
+            .is_some_and(|budget| budget.is_exhausted())
+    }
+}
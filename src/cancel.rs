@@ -0,0 +1,89 @@
+//! Cancelling tasks across workers
+//!
+//! Publishing [`ControlCommand::Cancel`](crate::control::ControlCommand::Cancel)
+//! to the control topic cancels a task wherever it is:
+//!
+//! - A worker running the task cancels the token in its [`PubSubContext`]
+//!   (see [`PubSubContext::cancelled`]). Handlers decide how to stop.
+//! - Copies of the task buffered by a worker, or delivered again later, are
+//!   acknowledged and dropped without running.
+//!
+//! Only workers listening on the control topic take part, see
+//! [`PubSubBackend::with_control_subscription`](crate::PubSubBackend::with_control_subscription).
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_pubsub::utils::PubSubContext;
+//! # use apalis_core::error::BoxDynError;
+//! async fn long_job(job: u32, ctx: PubSubContext) -> Result<(), BoxDynError> {
+//!     tokio::select! {
+//!         _ = ctx.cancelled() => Err("cancelled".into()),
+//!         _ = tokio::time::sleep(std::time::Duration::from_secs(job.into())) => Ok(()),
+//!     }
+//! }
+//! ```
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{utils::PubSubContext, PubSubTaskId};
+
+/// How long a cancellation keeps dropping redelivered copies of its task
+pub const CANCELLATION_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Tasks running on this worker and tasks cancelled recently
+#[derive(Debug, Default)]
+pub struct Cancellations {
+    running: Mutex<HashMap<PubSubTaskId, CancellationToken>>,
+    cancelled: Mutex<HashMap<PubSubTaskId, Instant>>,
+}
+
+impl Cancellations {
+    /// Cancels the task `task_id`, running or not
+    pub fn cancel(&self, task_id: PubSubTaskId) {
+        let now = Instant::now();
+        {
+            let mut cancelled = self.cancelled.lock().expect("cancellations lock poisoned");
+            cancelled.retain(|_, at| now.duration_since(*at) < CANCELLATION_TTL);
+            cancelled.insert(task_id, now);
+        }
+        let running = self.running.lock().expect("cancellations lock poisoned");
+        if let Some(token) = running.get(&task_id) {
+            tracing::info!(%task_id, "Cancelling running task");
+            token.cancel();
+        }
+    }
+
+    /// Whether the task `task_id` was cancelled recently
+    pub fn is_cancelled(&self, task_id: &PubSubTaskId) -> bool {
+        let cancelled = self.cancelled.lock().expect("cancellations lock poisoned");
+        cancelled
+            .get(task_id)
+            .is_some_and(|at| at.elapsed() < CANCELLATION_TTL)
+    }
+
+    /// Tracks a task the worker starts running
+    pub(crate) fn start(&self, task_id: PubSubTaskId, ctx: &PubSubContext) {
+        self.running
+            .lock()
+            .expect("cancellations lock poisoned")
+            .insert(task_id, ctx.cancellation().clone());
+        // The cancellation may have arrived while the task was being dispatched
+        if self.is_cancelled(&task_id) {
+            ctx.cancellation().cancel();
+        }
+    }
+
+    /// Stops tracking a task once it finished
+    pub(crate) fn finish(&self, task_id: &PubSubTaskId) {
+        self.running
+            .lock()
+            .expect("cancellations lock poisoned")
+            .remove(task_id);
+    }
+}
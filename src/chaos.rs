@@ -0,0 +1,305 @@
+//! Injecting Pub/Sub failures to test resilience
+//!
+//! Pub/Sub fails in ways that are rare in development: publishes time out,
+//! acknowledgements arrive after the deadline, messages are delivered twice,
+//! payloads get mangled by a misbehaving producer. [`FaultyTransport`] wraps
+//! the transport of a backend and injects these failures at configurable
+//! rates, so applications can check locally, for example against the
+//! emulator, that their handlers are idempotent and their retries work:
+//!
+//! - [publish failures](FaultInjection::with_publish_failures) fail publishes
+//!   with a transient `UNAVAILABLE` status,
+//! - [ack delays](FaultInjection::with_ack_delays) hold acknowledgements and
+//!   deadline changes back before sending them,
+//! - [duplicates](FaultInjection::with_duplicates) hand received messages to
+//!   the backend twice,
+//! - [corruption](FaultInjection::with_corruption) flips a byte of received
+//!   payloads.
+//!
+//! Each rate is the probability, between 0 and 1, that an operation is
+//! affected. Faults are logged at warn level as they're injected.
+//!
+//! Requires the `chaos` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{chaos::FaultInjection, PubSubBackend, PubSubCompact};
+//! # use std::time::Duration;
+//! # fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let backend = backend.with_fault_injection(
+//!     FaultInjection::default()
+//!         .with_publish_failures(0.1)
+//!         .with_ack_delays(0.05, Duration::from_secs(15))
+//!         .with_duplicates(0.02)
+//!         .with_seed(42),
+//! );
+//! # }
+//! ```
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use apalis_core::timer::sleep;
+use futures::{future::BoxFuture, FutureExt};
+use google_cloud_gax::grpc::Status;
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use google_cloud_pubsub::subscription::ReceiveConfig;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    transport::{MessageHandler, PubSubTransport, SubscriptionState, TransportMessage},
+    PubSubBackend,
+};
+
+/// Appended to the ack id of duplicates, so the backend tells them apart from
+/// the original delivery
+const DUPLICATE_SUFFIX: &str = "#duplicate";
+
+/// Ack ids of the original deliveries of `ack_ids`
+fn original_ack_ids(ack_ids: Vec<String>) -> Vec<String> {
+    let mut originals: Vec<String> = ack_ids
+        .into_iter()
+        .map(|ack_id| match ack_id.strip_suffix(DUPLICATE_SUFFIX) {
+            Some(original) => original.to_string(),
+            None => ack_id,
+        })
+        .collect();
+    originals.sort();
+    originals.dedup();
+    originals
+}
+
+/// Which faults are injected and how often, see the
+/// [module level documentation](self)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultInjection {
+    /// Share of publishes that fail (default: 0)
+    pub publish_failure_rate: f64,
+    /// Share of acknowledgements and deadline changes that are delayed
+    /// (default: 0)
+    pub ack_delay_rate: f64,
+    /// How long delayed acknowledgements are held back
+    pub ack_delay: Duration,
+    /// Share of received messages delivered twice (default: 0)
+    pub duplicate_rate: f64,
+    /// Share of received payloads corrupted (default: 0)
+    pub corruption_rate: f64,
+    /// Seed of the fault sequence (default: random)
+    ///
+    /// Runs with the same seed and the same operations inject the same faults.
+    pub seed: Option<u64>,
+}
+
+impl FaultInjection {
+    /// Fails a share `rate` of publishes
+    pub fn with_publish_failures(mut self, rate: f64) -> Self {
+        self.publish_failure_rate = rate;
+        self
+    }
+
+    /// Holds a share `rate` of acknowledgements back for `delay`
+    pub fn with_ack_delays(mut self, rate: f64, delay: Duration) -> Self {
+        self.ack_delay_rate = rate;
+        self.ack_delay = delay;
+        self
+    }
+
+    /// Delivers a share `rate` of received messages twice
+    pub fn with_duplicates(mut self, rate: f64) -> Self {
+        self.duplicate_rate = rate;
+        self
+    }
+
+    /// Corrupts a share `rate` of received payloads
+    pub fn with_corruption(mut self, rate: f64) -> Self {
+        self.corruption_rate = rate;
+        self
+    }
+
+    /// Draws faults from the sequence seeded with `seed`
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+}
+
+/// Source of the faults, a xorshift generator
+#[derive(Debug)]
+struct Dice(Mutex<u64>);
+
+impl Dice {
+    fn new(seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+        });
+        // The generator never leaves 0
+        Self(Mutex::new(seed.max(1)))
+    }
+
+    fn next(&self) -> u64 {
+        let mut state = self.0.lock().unwrap();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// Whether an event happening at `rate` happens this time
+    fn roll(&self, rate: f64) -> bool {
+        rate > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+}
+
+/// Transport injecting faults into another one, see the
+/// [module level documentation](self)
+pub struct FaultyTransport {
+    inner: Arc<dyn PubSubTransport>,
+    faults: FaultInjection,
+    dice: Dice,
+}
+
+impl FaultyTransport {
+    /// Injects `faults` into the operations of `inner`
+    pub fn new(inner: Arc<dyn PubSubTransport>, faults: FaultInjection) -> Self {
+        Self {
+            inner,
+            dice: Dice::new(faults.seed),
+            faults,
+        }
+    }
+
+    /// Waits before an acknowledgement or deadline change, when it's delayed
+    async fn maybe_delay(&self, ack_ids: usize) {
+        if self.dice.roll(self.faults.ack_delay_rate) {
+            tracing::warn!(
+                ack_ids,
+                delay = ?self.faults.ack_delay,
+                "Injected fault: delaying acknowledgement"
+            );
+            sleep(self.faults.ack_delay).await;
+        }
+    }
+}
+
+impl PubSubTransport for FaultyTransport {
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        message: PubsubMessage,
+    ) -> BoxFuture<'a, Result<String, Status>> {
+        if self.dice.roll(self.faults.publish_failure_rate) {
+            tracing::warn!(topic, "Injected fault: failing publish");
+            return async { Err(Status::unavailable("Injected publish failure")) }.boxed();
+        }
+        self.inner.publish(topic, message)
+    }
+
+    fn receive(
+        self: Arc<Self>,
+        subscription: String,
+        config: ReceiveConfig,
+        handler: MessageHandler,
+        cancel: CancellationToken,
+    ) -> BoxFuture<'static, Result<(), Status>> {
+        let this = self.clone();
+        let name = subscription.clone();
+        let handler: MessageHandler = Arc::new(move |received: TransportMessage| {
+            let this = this.clone();
+            let handler = handler.clone();
+            let name = name.clone();
+            async move {
+                let transport: Arc<dyn PubSubTransport> = this.clone();
+                let ack_id = received.ack_id().to_string();
+                let delivery_attempt = received.delivery_attempt();
+                let mut message = received.message;
+                if this.dice.roll(this.faults.corruption_rate) {
+                    tracing::warn!(
+                        message_id = message.message_id,
+                        "Injected fault: corrupting payload"
+                    );
+                    match message.data.len() {
+                        0 => message.data.push(0xff),
+                        len => message.data[this.dice.next() as usize % len] ^= 0xff,
+                    }
+                }
+                let duplicate = this.dice.roll(this.faults.duplicate_rate).then(|| {
+                    tracing::warn!(
+                        message_id = message.message_id,
+                        "Injected fault: delivering message twice"
+                    );
+                    message.clone()
+                });
+
+                // Acknowledgements go through the faulty transport too
+                let deliver = |ack_id, message| {
+                    TransportMessage::new(
+                        transport.clone(),
+                        name.clone(),
+                        ack_id,
+                        message,
+                        delivery_attempt,
+                    )
+                };
+                if let Some(duplicate) = duplicate {
+                    handler(deliver(format!("{ack_id}{DUPLICATE_SUFFIX}"), duplicate)).await;
+                }
+                handler(deliver(ack_id, message)).await;
+            }
+            .boxed()
+        });
+        self.inner
+            .clone()
+            .receive(subscription, config, handler, cancel)
+    }
+
+    fn acknowledge<'a>(
+        &'a self,
+        subscription: &'a str,
+        ack_ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        async move {
+            self.maybe_delay(ack_ids.len()).await;
+            self.inner
+                .acknowledge(subscription, original_ack_ids(ack_ids))
+                .await
+        }
+        .boxed()
+    }
+
+    fn modify_ack_deadline<'a>(
+        &'a self,
+        subscription: &'a str,
+        ack_ids: Vec<String>,
+        seconds: i32,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        async move {
+            self.maybe_delay(ack_ids.len()).await;
+            self.inner
+                .modify_ack_deadline(subscription, original_ack_ids(ack_ids), seconds)
+                .await
+        }
+        .boxed()
+    }
+
+    fn subscription_state<'a>(
+        &'a self,
+        subscription: &'a str,
+    ) -> BoxFuture<'a, Result<SubscriptionState, Status>> {
+        self.inner.subscription_state(subscription)
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Injects `faults` into the messages the backend publishes, receives and
+    /// acknowledges
+    pub fn with_fault_injection(self, faults: FaultInjection) -> Self {
+        let transport = FaultyTransport::new(self.transport(), faults);
+        self.with_transport(Arc::new(transport))
+    }
+}
@@ -0,0 +1,160 @@
+//! Checkpointing long-running tasks
+//!
+//! A task that runs for minutes or hours shouldn't start over when it's
+//! delivered again. Handlers record their progress with
+//! [`PubSubContext::checkpoint`] and, when the task comes back, read it with
+//! [`PubSubContext::last_checkpoint`] to resume from there.
+//!
+//! Checkpoints reach the next attempt in two ways:
+//!
+//! - They're written to the `checkpoint` attribute of any message the task is
+//!   published in again, such as by [`RepublishRetry`](crate::retry::RepublishRetry).
+//! - With a [`CheckpointStore`], set with
+//!   [`PubSubBackend::with_checkpoint_store`], they're also saved by task id,
+//!   so they survive redeliveries by Pub/Sub itself, after a nack, an expired
+//!   lease or a crash. Stored checkpoints are cleared once the task succeeds.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_pubsub::{utils::PubSubContext, PubSubError};
+//! async fn import(rows: u32, ctx: PubSubContext) -> Result<(), PubSubError> {
+//!     let start = ctx
+//!         .last_checkpoint()
+//!         .and_then(|row| row.parse().ok())
+//!         .unwrap_or(0);
+//!     for row in start..rows {
+//!         // Import the row...
+//!         if row % 1000 == 0 {
+//!             ctx.checkpoint(row.to_string()).await?;
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+
+use crate::{PubSubBackend, PubSubError, PubSubTaskId};
+
+/// Storage for the checkpoints of running tasks
+pub trait CheckpointStore: Send + Sync {
+    /// Inserts or replaces the checkpoint of a task
+    fn save(
+        &self,
+        task_id: &PubSubTaskId,
+        checkpoint: &str,
+    ) -> BoxFuture<'_, Result<(), PubSubError>>;
+
+    /// Fetches the checkpoint of a task, if one was saved
+    fn load(&self, task_id: &PubSubTaskId) -> BoxFuture<'_, Result<Option<String>, PubSubError>>;
+
+    /// Removes the checkpoint of a task
+    fn clear(&self, task_id: &PubSubTaskId) -> BoxFuture<'_, Result<(), PubSubError>>;
+}
+
+/// A [`CheckpointStore`] that keeps checkpoints in process memory
+#[derive(Debug, Default)]
+pub struct InMemoryCheckpointStore {
+    checkpoints: Mutex<HashMap<PubSubTaskId, String>>,
+}
+
+impl CheckpointStore for InMemoryCheckpointStore {
+    fn save(
+        &self,
+        task_id: &PubSubTaskId,
+        checkpoint: &str,
+    ) -> BoxFuture<'_, Result<(), PubSubError>> {
+        self.checkpoints
+            .lock()
+            .unwrap()
+            .insert(*task_id, checkpoint.to_string());
+        async { Ok(()) }.boxed()
+    }
+
+    fn load(&self, task_id: &PubSubTaskId) -> BoxFuture<'_, Result<Option<String>, PubSubError>> {
+        let checkpoint = self.checkpoints.lock().unwrap().get(task_id).cloned();
+        async move { Ok(checkpoint) }.boxed()
+    }
+
+    fn clear(&self, task_id: &PubSubTaskId) -> BoxFuture<'_, Result<(), PubSubError>> {
+        self.checkpoints.lock().unwrap().remove(task_id);
+        async { Ok(()) }.boxed()
+    }
+}
+
+/// Progress of a task, shared by the clones of its context
+#[derive(Clone, Default)]
+pub(crate) struct Checkpoint {
+    latest: Arc<Mutex<Option<String>>>,
+    /// Where the checkpoint is saved, and under which task id
+    store: Option<(Arc<dyn CheckpointStore>, PubSubTaskId)>,
+}
+
+impl fmt::Debug for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Checkpoint")
+            .field("latest", &self.latest())
+            .field("stored", &self.store.is_some())
+            .finish()
+    }
+}
+
+impl Checkpoint {
+    /// Resumes from `latest`
+    pub(crate) fn restored(latest: Option<String>) -> Self {
+        Self {
+            latest: Arc::new(Mutex::new(latest)),
+            store: None,
+        }
+    }
+
+    /// Resumes from the checkpoint `store` holds for `task_id`, falling back
+    /// to this one, and saves later checkpoints there
+    #[cfg(feature = "consume")]
+    pub(crate) async fn load(self, store: Arc<dyn CheckpointStore>, task_id: PubSubTaskId) -> Self {
+        match store.load(&task_id).await {
+            Ok(Some(stored)) => *self.latest.lock().unwrap() = Some(stored),
+            Ok(None) => {}
+            Err(e) => tracing::warn!(error = ?e, %task_id, "Failed to load checkpoint"),
+        }
+        Self {
+            latest: self.latest,
+            store: Some((store, task_id)),
+        }
+    }
+
+    pub(crate) fn latest(&self) -> Option<String> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    pub(crate) async fn save(&self, checkpoint: String) -> Result<(), PubSubError> {
+        if let Some((store, task_id)) = &self.store {
+            store.save(task_id, &checkpoint).await?;
+        }
+        *self.latest.lock().unwrap() = Some(checkpoint);
+        Ok(())
+    }
+
+    /// Removes the stored checkpoint of a task that's done
+    pub(crate) async fn clear(&self) {
+        if let Some((store, task_id)) = &self.store {
+            if let Err(e) = store.clear(task_id).await {
+                tracing::warn!(error = ?e, %task_id, "Failed to clear checkpoint");
+            }
+        }
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Saves the checkpoints of tasks to `store`, so they survive redeliveries
+    pub fn with_checkpoint_store(mut self, store: Arc<dyn CheckpointStore>) -> Self {
+        self.checkpoint_store = Some(store);
+        self
+    }
+}
@@ -0,0 +1,172 @@
+//! Time as seen by the backend
+//!
+//! The backend reads the time and waits through a [`Clock`], set with
+//! [`PubSubConfig::clock`](crate::PubSubConfig::clock). It's used by:
+//!
+//! - the retries of publishes and acknowledgements, which wait for the
+//!   [`PubSubConfig::backoff`](crate::PubSubConfig::backoff) delays,
+//! - [lease extension](crate::lease), which runs every half extension,
+//! - [scheduled holds](crate::schedule), which wait until `run_at`,
+//! - the [`max_age`](crate::PubSubConfig::max_age) and `run_before` checks.
+//!
+//! [`SystemClock`], the default, is the real time. Tests can use a
+//! [`ManualClock`] instead, which only moves when it's
+//! [advanced](ManualClock::advance), so they check delays and retries without
+//! sleeping through them.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_pubsub::{clock::ManualClock, PubSubConfig};
+//! # use std::{sync::Arc, time::Duration};
+//! # async fn example() {
+//! let clock = ManualClock::new();
+//! let config = PubSubConfig {
+//!     clock: Arc::new(clock.clone()),
+//!     ..Default::default()
+//! };
+//! // ... start a worker with `config`, then skip a lease extension interval
+//! clock.advance(Duration::from_secs(30));
+//! # }
+//! ```
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use apalis_core::timer::sleep;
+use futures::{future::BoxFuture, FutureExt};
+use tokio::sync::oneshot;
+
+/// Source of the time, see the [module level documentation](self)
+pub trait Clock: Debug + Send + Sync {
+    /// The current wall-clock time
+    fn now(&self) -> SystemTime;
+
+    /// The current monotonic time
+    fn instant(&self) -> Instant;
+
+    /// Completes once `duration` has passed
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The real time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        sleep(duration).boxed()
+    }
+}
+
+/// A clock that only moves when it's advanced, for tests
+///
+/// It starts at the real time it's created at. Clones share their time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    state: Arc<Mutex<ManualState>>,
+}
+
+#[derive(Debug)]
+struct ManualState {
+    started_at: SystemTime,
+    started_instant: Instant,
+    elapsed: Duration,
+    /// Pending sleeps, by when they're due
+    sleepers: Vec<(Duration, oneshot::Sender<()>)>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    /// A clock stopped at the current time
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ManualState {
+                started_at: SystemTime::now(),
+                started_instant: Instant::now(),
+                elapsed: Duration::ZERO,
+                sleepers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Moves the clock forward by `duration`, completing the sleeps that are
+    /// then due
+    pub fn advance(&self, duration: Duration) {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            state.elapsed += duration;
+            let elapsed = state.elapsed;
+            let (due, pending) = std::mem::take(&mut state.sleepers)
+                .into_iter()
+                .filter(|(_, waker)| !waker.is_closed())
+                .partition::<Vec<_>, _>(|(at, _)| *at <= elapsed);
+            state.sleepers = pending;
+            due
+        };
+        for (_, waker) in due {
+            let _ = waker.send(());
+        }
+    }
+
+    /// How far the clock was advanced since it was created
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+
+    /// Number of sleeps waiting for the clock to advance
+    ///
+    /// Tests can wait for this to grow to know that the code under test
+    /// reached a delay.
+    pub fn pending_sleeps(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .sleepers
+            .iter()
+            .filter(|(_, waker)| !waker.is_closed())
+            .count()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        let state = self.state.lock().unwrap();
+        state.started_at + state.elapsed
+    }
+
+    fn instant(&self) -> Instant {
+        let state = self.state.lock().unwrap();
+        state.started_instant + state.elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        if duration.is_zero() {
+            return futures::future::ready(()).boxed();
+        }
+        let (waker, sleeping) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            let due = state.elapsed + duration;
+            state.sleepers.push((due, waker));
+        }
+        async move {
+            let _ = sleeping.await;
+        }
+        .boxed()
+    }
+}
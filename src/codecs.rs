@@ -0,0 +1,165 @@
+//! Choosing the codec of each message at runtime
+//!
+//! While the producers of a topic migrate from one encoding to another, the
+//! topic carries both. Producers name the codec of their messages with
+//! [`PubSubBackend::with_codec_name`], which sets the `codec` attribute, and
+//! workers register a decoder for every name they may see with
+//! [`PubSubBackend::with_codec`]:
+//!
+//! - messages without the attribute, or naming the backend's own codec, are
+//!   decoded with the backend's codec,
+//! - messages naming a registered codec are decoded with it,
+//! - messages naming any other codec fail to decode.
+//!
+//! Registered codecs can also encode the tasks of a job type, see
+//! [`JobTypeConfig::codec`](crate::jobs::JobTypeConfig::codec), so job types
+//! sharing a backend each use their own encoding. Workers decode them all, as
+//! every message names its codec. [`PubSubBackend::with_job_type_codec`]
+//! registers a codec and assigns it to a job type at once.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact};
+//! # fn example<LegacyCodec>(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>)
+//! # where
+//! #     LegacyCodec: apalis_core::backend::codec::Codec<u32, Compact = PubSubCompact>,
+//! #     LegacyCodec::Error: std::error::Error + Send + Sync + 'static,
+//! # {
+//! // Publish JSON, and still read messages from producers using the old codec
+//! let backend = backend
+//!     .with_codec_name("json")
+//!     .with_codec::<LegacyCodec>("legacy");
+//! # }
+//! ```
+//!
+//! Encoding one job type differently from the others:
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact};
+//! # fn example<ProtobufCodec>(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>)
+//! # where
+//! #     ProtobufCodec: apalis_core::backend::codec::Codec<u32, Compact = PubSubCompact>,
+//! #     ProtobufCodec::Error: std::error::Error + Send + Sync + 'static,
+//! # {
+//! // Telemetry is published as protobuf, every other job type as JSON
+//! let backend = backend
+//!     .with_codec_name("json")
+//!     .with_job_type_codec::<ProtobufCodec>("telemetry", "protobuf");
+//! # }
+//! ```
+use std::{collections::HashMap, error::Error, sync::Arc};
+
+use apalis_core::{backend::codec::Codec, error::BoxDynError};
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+
+use crate::{PubSubBackend, PubSubCompact};
+
+/// Name of the attribute naming the codec of a message
+pub const PUBSUB_ATTRIBUTE_CODEC: &str = "codec";
+
+/// Decodes task arguments encoded by a registered codec
+pub(crate) type Decoder<M> = Arc<dyn Fn(&PubSubCompact) -> Result<M, BoxDynError> + Send + Sync>;
+
+/// Encodes task arguments with a registered codec
+pub(crate) type Encoder<M> = Arc<dyn Fn(&M) -> Result<PubSubCompact, BoxDynError> + Send + Sync>;
+
+/// The codecs a backend reads, besides its own
+pub(crate) struct Codecs<M> {
+    /// Name of the backend's own codec
+    pub(crate) name: Option<String>,
+    decoders: HashMap<String, Decoder<M>>,
+    encoders: HashMap<String, Encoder<M>>,
+}
+
+impl<M> Clone for Codecs<M> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            decoders: self.decoders.clone(),
+            encoders: self.encoders.clone(),
+        }
+    }
+}
+
+impl<M> Default for Codecs<M> {
+    fn default() -> Self {
+        Self {
+            name: None,
+            decoders: HashMap::new(),
+            encoders: HashMap::new(),
+        }
+    }
+}
+
+impl<M> Codecs<M> {
+    /// Decodes the task arguments of `message`, with `C` unless it names
+    /// another codec
+    pub(crate) fn decode<C>(&self, message: &PubsubMessage) -> Result<M, BoxDynError>
+    where
+        C: Codec<M, Compact = PubSubCompact>,
+        C::Error: Error + Send + Sync + 'static,
+    {
+        match message.attributes.get(PUBSUB_ATTRIBUTE_CODEC) {
+            Some(name) if Some(name) != self.name.as_ref() => match self.decoders.get(name) {
+                Some(decoder) => decoder(&message.data),
+                None => Err(format!("No codec registered for {name}").into()),
+            },
+            _ => Ok(C::decode(&message.data)?),
+        }
+    }
+
+    /// The encoder of the codec registered as `name`
+    #[cfg(feature = "publish")]
+    pub(crate) fn encoder(&self, name: &str) -> Option<&Encoder<M>> {
+        self.encoders.get(name)
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Decodes messages naming the codec `name` with `D`, and encodes with it
+    /// the job types publishing with `name`
+    pub fn with_codec<D>(mut self, name: impl Into<String>) -> Self
+    where
+        D: Codec<M, Compact = PubSubCompact>,
+        D::Error: Error + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let decoder: Decoder<M> = Arc::new(|data| Ok(D::decode(data)?));
+        let encoder: Encoder<M> = Arc::new(|args| Ok(D::encode(args)?));
+        self.codecs.decoders.insert(name.clone(), decoder);
+        self.codecs.encoders.insert(name, encoder);
+        self
+    }
+
+    /// Registers `D` as the codec `name`, like [`with_codec`](Self::with_codec),
+    /// and publishes the tasks of the job type `job_type` with it
+    ///
+    /// Overrides of the job type set before with
+    /// [`with_job_type`](Self::with_job_type) are kept, while setting them
+    /// afterwards replaces the codec too.
+    pub fn with_job_type_codec<D>(
+        self,
+        job_type: impl Into<String>,
+        name: impl Into<String>,
+    ) -> Self
+    where
+        D: Codec<M, Compact = PubSubCompact>,
+        D::Error: Error + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let mut backend = self.with_codec::<D>(name.clone());
+        Arc::make_mut(&mut backend.job_types)
+            .entry(job_type.into())
+            .codec = Some(name);
+        backend
+    }
+
+    /// Names the backend's codec `name` in the messages it publishes
+    pub fn with_codec_name(mut self, name: impl Into<String>) -> Self {
+        self.codecs.name = Some(name.into());
+        self
+    }
+}
@@ -0,0 +1,330 @@
+//! Building a checked [`PubSubConfig`]
+//!
+//! [`PubSubConfig`] can be written as a struct literal, but nothing then
+//! stops a zero buffer or a message size Pub/Sub would reject from reaching a
+//! worker. [`PubSubConfig::builder`] sets the same fields with chained
+//! setters, and [`PubSubConfigBuilder::build`] checks them with
+//! [`PubSubConfig::validate`], returning a [`ConfigError`] for the first
+//! value that makes no sense. Configurations written as literals can be
+//! checked with [`PubSubConfig::validate`] too.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_pubsub::{outcome::AckStrategy, PubSubConfig};
+//! # use std::time::Duration;
+//! # fn example() -> Result<(), apalis_pubsub::config::ConfigError> {
+//! let config = PubSubConfig::builder()
+//!     .with_buffer_size(200)
+//!     .with_concurrency_limit(8)
+//!     .with_ack_strategy(AckStrategy::OnSuccess)
+//!     .with_max_age(Duration::from_secs(3600))
+//!     .build()?;
+//! # let _ = config;
+//! # Ok(())
+//! # }
+//! ```
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    backoff::BackoffStrategy, clock::Clock, envelope::WireFormat, heartbeat::HealthCheck,
+    lease::LeasePolicy, outcome::AckStrategy, prefetch::AdaptivePrefetch,
+    provision::ACK_DEADLINE_RANGE, retry::RepublishRetry, sampling::PayloadSampling,
+    schedule::HoldScheduled, PubSubConfig,
+};
+
+/// Largest message Pub/Sub accepts, in bytes
+pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Why a [`PubSubConfig`] was rejected
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ConfigError {
+    #[error("buffer_size must be at least 1")]
+    ZeroBufferSize,
+
+    #[error("max_message_size must be between 1 byte and Pub/Sub's 10MB, got {0} bytes")]
+    InvalidMessageSize(usize),
+
+    #[error("max_outstanding_messages must be positive, got {0}")]
+    InvalidOutstandingMessages(i64),
+
+    #[error("max_outstanding_bytes must be positive, got {0}")]
+    InvalidOutstandingBytes(i64),
+
+    #[error("concurrency_limit must be at least 1")]
+    ZeroConcurrencyLimit,
+
+    #[error("max_attempts must be at least 1")]
+    ZeroMaxAttempts,
+
+    #[error("max_tasks_per_second must be positive, got {0}")]
+    InvalidTaskRate(f64),
+
+    #[error("ack_deadline must be between 10 and 600 seconds, got {0:?}")]
+    InvalidAckDeadline(Duration),
+
+    #[error("publish_concurrency must be at least 1")]
+    ZeroPublishConcurrency,
+
+    #[error("max_tasks_per_worker must be at least 1")]
+    ZeroMaxTasksPerWorker,
+
+    #[error("{0} must not be zero")]
+    ZeroInterval(&'static str),
+
+    #[error("republish_retry can't settle the messages of the manual ack strategy")]
+    RetryWithManualAck,
+}
+
+impl PubSubConfig {
+    /// A builder starting from the default configuration, see
+    /// [`config`](crate::config)
+    pub fn builder() -> PubSubConfigBuilder {
+        PubSubConfigBuilder::default()
+    }
+
+    /// Checks the configuration, returning the first value that makes no
+    /// sense
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.buffer_size == 0 {
+            return Err(ConfigError::ZeroBufferSize);
+        }
+        if !(1..=MAX_MESSAGE_SIZE).contains(&self.max_message_size) {
+            return Err(ConfigError::InvalidMessageSize(self.max_message_size));
+        }
+        if let Some(messages) = self
+            .max_outstanding_messages
+            .filter(|messages| *messages <= 0)
+        {
+            return Err(ConfigError::InvalidOutstandingMessages(messages));
+        }
+        if let Some(bytes) = self.max_outstanding_bytes.filter(|bytes| *bytes <= 0) {
+            return Err(ConfigError::InvalidOutstandingBytes(bytes));
+        }
+        if self.concurrency_limit == Some(0) {
+            return Err(ConfigError::ZeroConcurrencyLimit);
+        }
+        if self.max_attempts == Some(0) {
+            return Err(ConfigError::ZeroMaxAttempts);
+        }
+        if let Some(rate) = self
+            .max_tasks_per_second
+            .filter(|rate| !rate.is_finite() || *rate <= 0.0)
+        {
+            return Err(ConfigError::InvalidTaskRate(rate));
+        }
+        if let Some(deadline) = self
+            .ack_deadline
+            .filter(|deadline| !ACK_DEADLINE_RANGE.contains(&deadline.as_secs()))
+        {
+            return Err(ConfigError::InvalidAckDeadline(deadline));
+        }
+        if self.publish_concurrency == 0 {
+            return Err(ConfigError::ZeroPublishConcurrency);
+        }
+        if self.max_tasks_per_worker == Some(0) {
+            return Err(ConfigError::ZeroMaxTasksPerWorker);
+        }
+        if self.subscription_check_interval == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroInterval("subscription_check_interval"));
+        }
+        if self
+            .health_check
+            .as_ref()
+            .is_some_and(|check| check.interval.is_zero())
+        {
+            return Err(ConfigError::ZeroInterval("health_check.interval"));
+        }
+        if self.republish_retry.is_some() && self.ack_strategy == AckStrategy::Manual {
+            return Err(ConfigError::RetryWithManualAck);
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`PubSubConfig`], see the [module level documentation](self)
+///
+/// Each setter sets the [`PubSubConfig`] field of the same name, whose
+/// documentation describes it.
+#[derive(Debug, Clone, Default)]
+pub struct PubSubConfigBuilder {
+    config: PubSubConfig,
+}
+
+impl PubSubConfigBuilder {
+    /// Sets [`PubSubConfig::buffer_size`]
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.config.buffer_size = buffer_size;
+        self
+    }
+
+    /// Sets [`PubSubConfig::max_message_size`], at most [`MAX_MESSAGE_SIZE`]
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.config.max_message_size = max_message_size;
+        self
+    }
+
+    /// Sets [`PubSubConfig::max_outstanding_messages`]
+    pub fn with_max_outstanding_messages(mut self, messages: i64) -> Self {
+        self.config.max_outstanding_messages = Some(messages);
+        self
+    }
+
+    /// Sets [`PubSubConfig::max_outstanding_bytes`]
+    pub fn with_max_outstanding_bytes(mut self, bytes: i64) -> Self {
+        self.config.max_outstanding_bytes = Some(bytes);
+        self
+    }
+
+    /// Sets [`PubSubConfig::concurrency_limit`]
+    pub fn with_concurrency_limit(mut self, limit: usize) -> Self {
+        self.config.concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Sets [`PubSubConfig::load_shed`]
+    pub fn with_load_shed(mut self, load_shed: bool) -> Self {
+        self.config.load_shed = load_shed;
+        self
+    }
+
+    /// Sets [`PubSubConfig::exactly_once`]
+    pub fn with_exactly_once(mut self, exactly_once: bool) -> Self {
+        self.config.exactly_once = exactly_once;
+        self
+    }
+
+    /// Sets [`PubSubConfig::ack_strategy`]
+    pub fn with_ack_strategy(mut self, ack_strategy: AckStrategy) -> Self {
+        self.config.ack_strategy = ack_strategy;
+        self
+    }
+
+    /// Sets [`PubSubConfig::max_attempts`]
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.config.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Sets [`PubSubConfig::adaptive_prefetch`]
+    pub fn with_adaptive_prefetch(mut self, adaptive_prefetch: AdaptivePrefetch) -> Self {
+        self.config.adaptive_prefetch = Some(adaptive_prefetch);
+        self
+    }
+
+    /// Sets [`PubSubConfig::max_tasks_per_second`]
+    pub fn with_max_tasks_per_second(mut self, rate: f64) -> Self {
+        self.config.max_tasks_per_second = Some(rate);
+        self
+    }
+
+    /// Sets [`PubSubConfig::max_age`]
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.config.max_age = Some(max_age);
+        self
+    }
+
+    /// Sets [`PubSubConfig::slow_dispatch_threshold`], `None` disabling the
+    /// warnings
+    pub fn with_slow_dispatch_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.config.slow_dispatch_threshold = threshold;
+        self
+    }
+
+    /// Sets [`PubSubConfig::lease_extension`]
+    pub fn with_lease_extension(mut self, policy: LeasePolicy) -> Self {
+        self.config.lease_extension = Some(policy);
+        self
+    }
+
+    /// Sets [`PubSubConfig::ack_deadline`], between 10 and 600 seconds
+    pub fn with_ack_deadline(mut self, ack_deadline: Duration) -> Self {
+        self.config.ack_deadline = Some(ack_deadline);
+        self
+    }
+
+    /// Sets [`PubSubConfig::task_timeout`]
+    pub fn with_task_timeout(mut self, task_timeout: Duration) -> Self {
+        self.config.task_timeout = Some(task_timeout);
+        self
+    }
+
+    /// Sets [`PubSubConfig::max_buffered_bytes`]
+    pub fn with_max_buffered_bytes(mut self, bytes: usize) -> Self {
+        self.config.max_buffered_bytes = Some(bytes);
+        self
+    }
+
+    /// Sets [`PubSubConfig::payload_sampling`]
+    pub fn with_payload_sampling(mut self, sampling: PayloadSampling) -> Self {
+        self.config.payload_sampling = Some(sampling);
+        self
+    }
+
+    /// Sets [`PubSubConfig::publish_concurrency`]
+    pub fn with_publish_concurrency(mut self, concurrency: usize) -> Self {
+        self.config.publish_concurrency = concurrency;
+        self
+    }
+
+    /// Sets [`PubSubConfig::wire_format`]
+    pub fn with_wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.config.wire_format = wire_format;
+        self
+    }
+
+    /// Sets [`PubSubConfig::republish_retry`]
+    pub fn with_republish_retry(mut self, retry: RepublishRetry) -> Self {
+        self.config.republish_retry = Some(retry);
+        self
+    }
+
+    /// Sets [`PubSubConfig::backoff`]
+    pub fn with_backoff(mut self, backoff: Arc<dyn BackoffStrategy>) -> Self {
+        self.config.backoff = backoff;
+        self
+    }
+
+    /// Sets [`PubSubConfig::subscription_check_interval`], `None` disabling
+    /// the checks
+    pub fn with_subscription_check_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.subscription_check_interval = interval;
+        self
+    }
+
+    /// Sets [`PubSubConfig::health_check`], `None` disabling the probes
+    pub fn with_health_check(mut self, health_check: Option<HealthCheck>) -> Self {
+        self.config.health_check = health_check;
+        self
+    }
+
+    /// Sets [`PubSubConfig::hold_scheduled`]
+    pub fn with_hold_scheduled(mut self, hold: HoldScheduled) -> Self {
+        self.config.hold_scheduled = Some(hold);
+        self
+    }
+
+    /// Sets [`PubSubConfig::max_tasks_per_worker`]
+    pub fn with_max_tasks_per_worker(mut self, max_tasks: usize) -> Self {
+        self.config.max_tasks_per_worker = Some(max_tasks);
+        self
+    }
+
+    /// Sets [`PubSubConfig::dead_letter_topic`]
+    pub fn with_dead_letter_topic(mut self, topic: impl Into<String>) -> Self {
+        self.config.dead_letter_topic = Some(topic.into());
+        self
+    }
+
+    /// Sets [`PubSubConfig::clock`]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.config.clock = clock;
+        self
+    }
+
+    /// The configuration, once [validated](PubSubConfig::validate)
+    pub fn build(self) -> Result<PubSubConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
@@ -0,0 +1,127 @@
+//! Contract testing between producers and consumers of a topic
+//!
+//! The producer and the consumers of a topic are often owned by different
+//! teams, and nothing stops one side from changing its job type in a way the
+//! other can't read anymore. These helpers let both sides check compatibility
+//! in CI:
+//!
+//! - [`producer_message`] builds the message a backend publishes for a job,
+//!   exactly as the sink does.
+//! - [`consume`] reads a job back out of a message like a worker receiving it.
+//! - [`assert_compatible`] checks that jobs produced with one type and codec
+//!   decode with another. Call it both ways round to check the two sides can
+//!   upgrade in either order.
+//! - [`write_fixture`] and [`read_fixture`] store messages as files, so the
+//!   producer can commit fixtures the consumer's tests decode without sharing
+//!   any code.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use apalis_codec::json::JsonCodec;
+//! use apalis_pubsub::{contract, PubSubCompact};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Debug, Serialize, Deserialize)]
+//! struct SendEmailV1 { to: String }
+//!
+//! #[derive(Debug, Serialize, Deserialize)]
+//! struct SendEmailV2 { to: String, #[serde(default)] cc: Vec<String> }
+//!
+//! // Jobs queued by the old producer still decode in the new consumer
+//! contract::assert_compatible::<_, JsonCodec<PubSubCompact>, SendEmailV2, JsonCodec<PubSubCompact>>(
+//!     [SendEmailV1 { to: "ops@example.com".into() }],
+//! );
+//! ```
+use std::{fmt::Debug, path::Path};
+
+use apalis_core::{backend::codec::Codec, task::builder::TaskBuilder};
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use prost::Message;
+
+use crate::{
+    envelope::{self, WireFormat},
+    message_task_id,
+    sink::task_message,
+    PubSubCompact, PubSubError,
+};
+
+/// Builds the message a backend publishes for `args`
+pub fn producer_message<M, C>(args: &M) -> Result<PubsubMessage, PubSubError>
+where
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: Debug,
+{
+    let data = C::encode(args).map_err(|e| PubSubError::Codec(format!("{e:?}")))?;
+    Ok(task_message(
+        TaskBuilder::new(data).build(),
+        None,
+        WireFormat::default(),
+    ))
+}
+
+/// Reads the job out of `message` like a worker receiving it
+///
+/// Fails if the message has no valid task id or its payload doesn't decode.
+pub fn consume<M, C>(message: &PubsubMessage) -> Result<M, PubSubError>
+where
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: Debug,
+{
+    let mut message = message.clone();
+    envelope::open(&mut message)?;
+    if message_task_id(&message).is_none() {
+        return Err(PubSubError::Codec(
+            "Message has no valid task id".to_string(),
+        ));
+    }
+    C::decode(&message.data).map_err(|e| PubSubError::Codec(format!("{e:?}")))
+}
+
+/// Asserts that each of `jobs` comes back unchanged from its published message
+pub fn assert_round_trip<M, C>(jobs: impl IntoIterator<Item = M>)
+where
+    M: PartialEq + Debug,
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: Debug,
+{
+    for job in jobs {
+        let message = producer_message::<M, C>(&job)
+            .unwrap_or_else(|e| panic!("Producer can't publish {job:?}: {e}"));
+        let consumed = consume::<M, C>(&message)
+            .unwrap_or_else(|e| panic!("Consumer can't read {job:?}: {e}"));
+        assert_eq!(consumed, job, "Job changed on the way through Pub/Sub");
+    }
+}
+
+/// Asserts that each of `jobs`, published as `P` with codec `PC`, is read by
+/// a consumer of `Q` with codec `QC`, returning what the consumer read
+pub fn assert_compatible<P, PC, Q, QC>(jobs: impl IntoIterator<Item = P>) -> Vec<Q>
+where
+    P: Debug,
+    PC: Codec<P, Compact = PubSubCompact>,
+    PC::Error: Debug,
+    QC: Codec<Q, Compact = PubSubCompact>,
+    QC::Error: Debug,
+{
+    jobs.into_iter()
+        .map(|job| {
+            let message = producer_message::<P, PC>(&job)
+                .unwrap_or_else(|e| panic!("Producer can't publish {job:?}: {e}"));
+            consume::<Q, QC>(&message)
+                .unwrap_or_else(|e| panic!("Consumer can't read {job:?}: {e}"))
+        })
+        .collect()
+}
+
+/// Writes `message` to a fixture file at `path`
+pub fn write_fixture(path: impl AsRef<Path>, message: &PubsubMessage) -> std::io::Result<()> {
+    std::fs::write(path, message.encode_to_vec())
+}
+
+/// Reads a message from a fixture file written by [`write_fixture`]
+pub fn read_fixture(path: impl AsRef<Path>) -> std::io::Result<PubsubMessage> {
+    let bytes = std::fs::read(path)?;
+    PubsubMessage::decode(bytes.as_slice())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
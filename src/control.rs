@@ -0,0 +1,449 @@
+//! Control topic for managing running workers
+//!
+//! Operators publish small command messages to a control topic. Each worker
+//! that was given a control subscription with
+//! [`PubSubBackend::with_control_subscription`] applies them as they arrive:
+//!
+//! - [`ControlCommand::Pause`] stops the worker from taking new tasks
+//! - [`ControlCommand::Resume`] lets a paused worker take tasks again
+//! - [`ControlCommand::Drain`] stops receiving, like [`PubSubBackend::shutdown`]
+//! - [`ControlCommand::SetConcurrency`] changes the worker's concurrency limit
+//! - [`ControlCommand::Cancel`] cancels a task, see [`crate::cancel`]
+//! - [`ControlCommand::PauseJobType`] stops the worker from running the tasks
+//!   of a job type, see [`crate::jobs`]
+//! - [`ControlCommand::ResumeJobType`] lets the worker run them again
+//!
+//! Commands are meant for every worker, so each worker instance needs its own
+//! subscription to the control topic. Workers sharing a subscription would
+//! split the commands between them.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_pubsub::control::{publish_command, ControlCommand};
+//! # async fn example(
+//! #     client: google_cloud_pubsub::client::Client,
+//! # ) -> Result<(), apalis_pubsub::PubSubError> {
+//! let control = client.topic("worker-control");
+//! publish_command(&control, ControlCommand::SetConcurrency(4)).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use google_cloud_pubsub::topic::Topic;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::PollSemaphore;
+use tower::{Layer, Service};
+
+use crate::{
+    parts::PUBSUB_ATTRIBUTE_JOB_TYPE, PubSubBackend, PubSubError, PubSubTaskId,
+    PUBSUB_ATTRIBUTE_TASK_ID,
+};
+#[cfg(feature = "consume")]
+use {
+    crate::{cancel::Cancellations, jobs::PausedJobTypes},
+    apalis_core::worker::context::WorkerContext,
+    google_cloud_pubsub::subscription::Subscription,
+    tokio_util::sync::CancellationToken,
+};
+
+/// Attribute holding the command name of a control message
+const CONTROL_ATTRIBUTE_COMMAND: &str = "command";
+
+/// Attribute holding the limit of a `set_concurrency` control message
+const CONTROL_ATTRIBUTE_LIMIT: &str = "limit";
+
+/// A command sent to workers over the control topic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Stop taking new tasks, in-flight tasks keep running
+    Pause,
+    /// Take new tasks again after a [`ControlCommand::Pause`]
+    Resume,
+    /// Stop receiving messages and finish in-flight tasks
+    ///
+    /// Buffered messages are nacked for other workers to pick up.
+    Drain,
+    /// Run at most this many tasks concurrently
+    ///
+    /// The limit can be raised or lowered, but must be at least 1. Lowering it
+    /// doesn't interrupt running tasks, the worker just waits for enough of
+    /// them to finish.
+    SetConcurrency(usize),
+    /// Cancel the task with this id
+    Cancel(PubSubTaskId),
+    /// Stop running the tasks of this job type, nacking them instead
+    PauseJobType(String),
+    /// Run the tasks of this job type again after a
+    /// [`ControlCommand::PauseJobType`]
+    ResumeJobType(String),
+}
+
+impl ControlCommand {
+    /// Encodes the command as a control message
+    pub fn to_message(self) -> PubsubMessage {
+        let mut message = PubsubMessage::default();
+        let command = match self {
+            ControlCommand::Pause => "pause",
+            ControlCommand::Resume => "resume",
+            ControlCommand::Drain => "drain",
+            ControlCommand::SetConcurrency(limit) => {
+                message
+                    .attributes
+                    .insert(CONTROL_ATTRIBUTE_LIMIT.to_owned(), limit.to_string());
+                "set_concurrency"
+            }
+            ControlCommand::Cancel(task_id) => {
+                message
+                    .attributes
+                    .insert(PUBSUB_ATTRIBUTE_TASK_ID.to_owned(), task_id.to_string());
+                "cancel"
+            }
+            ControlCommand::PauseJobType(job_type) => {
+                message
+                    .attributes
+                    .insert(PUBSUB_ATTRIBUTE_JOB_TYPE.to_owned(), job_type);
+                "pause_job_type"
+            }
+            ControlCommand::ResumeJobType(job_type) => {
+                message
+                    .attributes
+                    .insert(PUBSUB_ATTRIBUTE_JOB_TYPE.to_owned(), job_type);
+                "resume_job_type"
+            }
+        };
+        message
+            .attributes
+            .insert(CONTROL_ATTRIBUTE_COMMAND.to_owned(), command.to_owned());
+        message
+    }
+
+    /// Decodes a control message
+    pub fn from_message(message: &PubsubMessage) -> Result<Self, PubSubError> {
+        let attribute = |name: &str| {
+            message
+                .attributes
+                .get(name)
+                .ok_or_else(|| PubSubError::Codec(format!("Control message has no {name}")))
+        };
+        match attribute(CONTROL_ATTRIBUTE_COMMAND)?.as_str() {
+            "pause" => Ok(ControlCommand::Pause),
+            "resume" => Ok(ControlCommand::Resume),
+            "drain" => Ok(ControlCommand::Drain),
+            "set_concurrency" => {
+                let limit = attribute(CONTROL_ATTRIBUTE_LIMIT)?;
+                match limit.parse() {
+                    Ok(limit) if limit > 0 => Ok(ControlCommand::SetConcurrency(limit)),
+                    _ => Err(PubSubError::Codec(format!(
+                        "Invalid concurrency limit: {limit}"
+                    ))),
+                }
+            }
+            "cancel" => {
+                let task_id = attribute(PUBSUB_ATTRIBUTE_TASK_ID)?;
+                task_id
+                    .parse()
+                    .map(ControlCommand::Cancel)
+                    .map_err(|e| PubSubError::Codec(format!("Invalid task id {task_id}: {e}")))
+            }
+            "pause_job_type" => Ok(ControlCommand::PauseJobType(
+                attribute(PUBSUB_ATTRIBUTE_JOB_TYPE)?.clone(),
+            )),
+            "resume_job_type" => Ok(ControlCommand::ResumeJobType(
+                attribute(PUBSUB_ATTRIBUTE_JOB_TYPE)?.clone(),
+            )),
+            other => Err(PubSubError::Codec(format!(
+                "Unknown control command: {other}"
+            ))),
+        }
+    }
+}
+
+/// Publishes `command` to the control topic `topic`
+///
+/// Returns the Pub/Sub message id of the command.
+pub async fn publish_command(
+    topic: &Topic,
+    command: ControlCommand,
+) -> Result<String, PubSubError> {
+    let publisher = topic.new_publisher(None);
+    let awaiter = publisher.publish(command.to_message()).await;
+    awaiter
+        .get()
+        .await
+        .map_err(|e| PubSubError::Client(e.to_string()))
+}
+
+/// Concurrency limit that can be changed while the worker runs
+///
+/// Without a limit tasks aren't limited at all. Once a limit is set it can be
+/// changed but not lifted again; tasks already running when the first limit
+/// is set don't count towards it.
+#[derive(Debug)]
+pub struct ConcurrencyControl {
+    semaphore: Arc<Semaphore>,
+    limit: Mutex<Option<usize>>,
+    /// Permits still to be removed after lowering the limit, taken back as
+    /// running tasks finish
+    debt: AtomicUsize,
+}
+
+impl ConcurrencyControl {
+    /// Creates a control starting at `limit`
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit.unwrap_or(0))),
+            limit: Mutex::new(limit),
+            debt: AtomicUsize::new(0),
+        }
+    }
+
+    /// The current concurrency limit
+    pub fn limit(&self) -> Option<usize> {
+        *self.limit.lock().expect("concurrency limit lock poisoned")
+    }
+
+    /// Changes the concurrency limit to `limit`
+    pub fn set_limit(&self, limit: usize) {
+        let mut current = self.limit.lock().expect("concurrency limit lock poisoned");
+        let previous = current.unwrap_or(0);
+        if limit > previous {
+            let added = limit - previous;
+            // Cancel out permits that were still to be removed first
+            let debt = self
+                .debt
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                    Some(debt - debt.min(added))
+                })
+                .unwrap_or_default();
+            self.semaphore.add_permits(added - debt.min(added));
+        } else if limit < previous {
+            let removed = previous - limit;
+            let forgotten = self.semaphore.forget_permits(removed);
+            self.debt.fetch_add(removed - forgotten, Ordering::SeqCst);
+        }
+        *current = Some(limit);
+    }
+
+    /// Waits for a permit, holding back the caller while the limit is reached
+    #[cfg(feature = "consume")]
+    pub(crate) async fn acquire(self: &Arc<Self>) -> ControlPermit {
+        let permit = match self.limit() {
+            Some(_) => self.semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        ControlPermit {
+            permit,
+            control: self.clone(),
+        }
+    }
+
+    /// Takes one permit owed after lowering the limit, if any
+    fn take_debt(&self) -> bool {
+        self.debt
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                debt.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+/// Permit of a task running under a [`ConcurrencyControl`]
+pub(crate) struct ControlPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    control: Arc<ConcurrencyControl>,
+}
+
+impl Drop for ControlPermit {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            if self.control.take_debt() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// Layer limiting concurrency through a shared [`ConcurrencyControl`]
+#[derive(Debug, Clone)]
+pub struct ConcurrencyControlLayer {
+    control: Arc<ConcurrencyControl>,
+}
+
+impl ConcurrencyControlLayer {
+    /// Creates a layer applying the limit of `control`
+    pub fn new(control: Arc<ConcurrencyControl>) -> Self {
+        Self { control }
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyControlLayer {
+    type Service = ConcurrencyControlService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConcurrencyControlService {
+            inner,
+            semaphore: PollSemaphore::new(self.control.semaphore.clone()),
+            control: self.control.clone(),
+            permit: None,
+        }
+    }
+}
+
+/// Service produced by [`ConcurrencyControlLayer`]
+pub struct ConcurrencyControlService<S> {
+    inner: S,
+    semaphore: PollSemaphore,
+    control: Arc<ConcurrencyControl>,
+    permit: Option<ControlPermit>,
+}
+
+impl<S: Clone> Clone for ConcurrencyControlService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            semaphore: self.semaphore.clone(),
+            control: self.control.clone(),
+            // Permits belong to the service that acquired them
+            permit: None,
+        }
+    }
+}
+
+impl<S, Req> Service<Req> for ConcurrencyControlService<S>
+where
+    S: Service<Req>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.permit.is_none() {
+            let permit = if self.control.limit().is_some() {
+                match self.semaphore.poll_acquire(cx) {
+                    Poll::Ready(permit) => permit,
+                    Poll::Pending => return Poll::Pending,
+                }
+            } else {
+                None
+            };
+            self.permit = Some(ControlPermit {
+                permit,
+                control: self.control.clone(),
+            });
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let permit = self
+            .permit
+            .take()
+            .expect("poll_ready must be called before call");
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let res = future.await;
+            drop(permit);
+            res
+        })
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Listens for [`ControlCommand`]s on the subscription `subscription_name`
+    ///
+    /// The subscription must be attached to the control topic and must not be
+    /// shared with other workers.
+    pub fn with_control_subscription(mut self, subscription_name: &str) -> Self {
+        self.control = Some(Arc::new(self.client.subscription(subscription_name)));
+        self
+    }
+
+    /// The concurrency limit of workers polling this backend
+    pub fn concurrency(&self) -> &Arc<ConcurrencyControl> {
+        &self.concurrency
+    }
+}
+
+/// Applies commands from the control subscription until `cancel` fires
+#[cfg(feature = "consume")]
+pub(crate) async fn run_control_loop(
+    subscription: Arc<Subscription>,
+    worker: WorkerContext,
+    concurrency: Arc<ConcurrencyControl>,
+    cancellations: Arc<Cancellations>,
+    paused_job_types: Arc<PausedJobTypes>,
+    cancel: CancellationToken,
+) {
+    let drain = cancel.clone();
+    let result = subscription
+        .receive(
+            move |message, _cancel| {
+                let worker = worker.clone();
+                let concurrency = concurrency.clone();
+                let cancellations = cancellations.clone();
+                let paused_job_types = paused_job_types.clone();
+                let drain = drain.clone();
+                async move {
+                    if let Err(e) = message.ack().await {
+                        tracing::warn!(error = ?e, "Failed to ack control message");
+                    }
+                    let command = match ControlCommand::from_message(&message.message) {
+                        Ok(command) => command,
+                        Err(e) => {
+                            tracing::warn!(error = %e, "Ignoring control message");
+                            return;
+                        }
+                    };
+                    tracing::info!(?command, worker = %worker.name(), "Received control command");
+                    let applied = match &command {
+                        ControlCommand::Pause => worker.pause(),
+                        ControlCommand::Resume => worker.resume(),
+                        ControlCommand::Drain => {
+                            drain.cancel();
+                            Ok(())
+                        }
+                        ControlCommand::SetConcurrency(limit) => {
+                            concurrency.set_limit(*limit);
+                            tracing::info!(limit, "Concurrency limit changed");
+                            Ok(())
+                        }
+                        ControlCommand::Cancel(task_id) => {
+                            cancellations.cancel(*task_id);
+                            Ok(())
+                        }
+                        ControlCommand::PauseJobType(job_type) => {
+                            paused_job_types.pause(job_type.clone());
+                            Ok(())
+                        }
+                        ControlCommand::ResumeJobType(job_type) => {
+                            paused_job_types.resume(job_type);
+                            Ok(())
+                        }
+                    };
+                    if let Err(e) = applied {
+                        tracing::warn!(error = %e, ?command, "Failed to apply control command");
+                    }
+                }
+            },
+            cancel.clone(),
+            None,
+        )
+        .await;
+
+    if let Err(e) = result {
+        tracing::error!(error = ?e, "Control subscription error");
+    }
+}
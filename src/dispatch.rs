@@ -0,0 +1,406 @@
+//! Hand-off of received messages to the worker
+//!
+//! The receive loop decodes messages and queues them on a channel together with
+//! their lease. Messages are only acknowledged when the worker takes them off
+//! the channel, so on shutdown everything still queued can be nacked and picked
+//! up by other workers right away instead of being lost.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use apalis_core::timer::sleep;
+use futures::{future::join_all, stream::BoxStream, StreamExt};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    ack::{ack_message, AckMode},
+    budget::BudgetPermit,
+    cancel::Cancellations,
+    control::ControlPermit,
+    inflight::InFlight,
+    jobs::{PausedJobTypes, PAUSED_REDELIVERY_DELAY},
+    lease::LeaseKeeper,
+    outcome::AckDecision,
+    pipeline,
+    reload::RuntimeConfig,
+    report::{report, ErrorReport, ErrorReporter, FailureKind},
+    spawn::Spawner,
+    stats::PubSubStats,
+    transport::TransportMessage,
+    PubSubError, PubSubTask,
+};
+
+/// A decoded message waiting to be dispatched to the worker
+pub(crate) struct Received<M> {
+    pub(crate) task: PubSubTask<M>,
+    pub(crate) message: TransportMessage,
+    /// Slot in the buffer, freed on dispatch
+    pub(crate) slot: ControlPermit,
+    /// Slot in the adaptive prefetch window, freed on dispatch
+    pub(crate) prefetch: Option<ControlPermit>,
+    /// Bytes of the payload held in the memory budget, freed on dispatch
+    pub(crate) budget: Option<BudgetPermit>,
+    /// When the message was received, to measure how long it waits for the worker
+    pub(crate) received_at: Instant,
+}
+
+/// Items sent from the receive loop to the [`Dispatcher`]
+pub(crate) type ReceivedItem<M> = Result<Received<M>, PubSubError>;
+
+/// Nacks messages so Pub/Sub redelivers them immediately
+pub(crate) async fn nack_all(messages: Vec<TransportMessage>) {
+    let count = messages.len();
+    let results = join_all(messages.iter().map(|message| message.nack())).await;
+    let failed = results.iter().filter(|result| result.is_err()).count();
+    if failed > 0 {
+        tracing::warn!(failed, "Failed to nack undispatched messages");
+    }
+    tracing::info!(count, "Nacked undispatched messages");
+}
+
+/// Feeds the worker from the receive channel, acknowledging dispatched messages
+pub(crate) struct Dispatcher<M> {
+    rx: mpsc::Receiver<ReceivedItem<M>>,
+    cancel: CancellationToken,
+    ack_mode: AckMode,
+    cancellations: Arc<Cancellations>,
+    stats: Arc<PubSubStats>,
+    runtime: watch::Receiver<RuntimeConfig>,
+    /// When the last task was handed to the worker, to pace dispatches
+    last_dispatch: Option<Instant>,
+    leases: Option<Arc<LeaseKeeper>>,
+    reporter: Option<Arc<dyn ErrorReporter>>,
+    in_flight: Option<Arc<InFlight>>,
+    /// Whether dispatched messages are acknowledged after their task instead
+    deferred_ack: bool,
+    /// Tasks to dispatch before ending the stream, and the receive loop to
+    /// stop then
+    max_tasks: Option<(usize, CancellationToken)>,
+    dispatched: usize,
+    spawner: Spawner,
+    paused: Arc<PausedJobTypes>,
+}
+
+impl<M: Send + 'static> Dispatcher<M> {
+    pub(crate) fn new(
+        rx: mpsc::Receiver<ReceivedItem<M>>,
+        cancel: CancellationToken,
+        ack_mode: AckMode,
+        cancellations: Arc<Cancellations>,
+        stats: Arc<PubSubStats>,
+        runtime: watch::Receiver<RuntimeConfig>,
+        leases: Option<Arc<LeaseKeeper>>,
+    ) -> Self {
+        Self {
+            rx,
+            cancel,
+            ack_mode,
+            cancellations,
+            stats,
+            runtime,
+            last_dispatch: None,
+            leases,
+            reporter: None,
+            in_flight: None,
+            deferred_ack: false,
+            max_tasks: None,
+            dispatched: 0,
+            spawner: Spawner::default(),
+            paused: Arc::default(),
+        }
+    }
+
+    /// Reports acknowledgements that fail to `reporter`
+    pub(crate) fn with_reporter(mut self, reporter: Option<Arc<dyn ErrorReporter>>) -> Self {
+        self.reporter = reporter;
+        self
+    }
+
+    /// Stops tracking messages in `in_flight` once they're dispatched
+    pub(crate) fn with_in_flight(mut self, in_flight: Arc<InFlight>) -> Self {
+        self.in_flight = Some(in_flight);
+        self
+    }
+
+    /// Leaves dispatched messages to be acknowledged once their task finished,
+    /// see [`outcome`](crate::outcome)
+    pub(crate) fn with_deferred_ack(mut self, deferred_ack: bool) -> Self {
+        self.deferred_ack = deferred_ack;
+        self
+    }
+
+    /// Acknowledges messages on the runtime of `spawner`
+    pub(crate) fn with_spawner(mut self, spawner: Spawner) -> Self {
+        self.spawner = spawner;
+        self
+    }
+
+    /// Ends the stream once `max_tasks` tasks were dispatched, cancelling
+    /// `receiving` to stop pulling
+    pub(crate) fn with_max_tasks(
+        mut self,
+        max_tasks: Option<usize>,
+        receiving: CancellationToken,
+    ) -> Self {
+        self.max_tasks = max_tasks.map(|max_tasks| (max_tasks, receiving));
+        self
+    }
+
+    /// Nacks the tasks of the job types in `paused` instead of dispatching
+    /// them, see [`jobs`](crate::jobs#pausing-job-types)
+    pub(crate) fn with_paused_job_types(mut self, paused: Arc<PausedJobTypes>) -> Self {
+        self.paused = paused;
+        self
+    }
+
+    /// Whether the worker took all the tasks it may take
+    fn exhausted(&self) -> bool {
+        self.max_tasks
+            .as_ref()
+            .is_some_and(|(max_tasks, _)| self.dispatched >= *max_tasks)
+    }
+
+    /// Stream of tasks for the worker, ending when the backend shuts down
+    pub(crate) fn into_stream(
+        self,
+    ) -> BoxStream<'static, Result<Option<PubSubTask<M>>, PubSubError>> {
+        futures::stream::unfold(self, |mut dispatcher| async move {
+            let item = dispatcher.next().await?;
+            Some((item, dispatcher))
+        })
+        .boxed()
+    }
+
+    async fn next(&mut self) -> Option<Result<Option<PubSubTask<M>>, PubSubError>> {
+        if self.exhausted() {
+            // Receiving stopped with the last task, give back what's buffered
+            let leftovers = self.take_leftovers();
+            tracing::info!(
+                dispatched = self.dispatched,
+                nacked = leftovers.len(),
+                "Worker took its maximum number of tasks, stopping"
+            );
+            if !leftovers.is_empty() {
+                nack_all(leftovers).await;
+            }
+            return None;
+        }
+        self.pace().await;
+        loop {
+            let Some(Some(item)) = self.cancel.run_until_cancelled(self.rx.recv()).await else {
+                // Shutting down, or the receive loop ended
+                let leftovers = self.take_leftovers();
+                if !leftovers.is_empty() {
+                    nack_all(leftovers).await;
+                }
+                return None;
+            };
+
+            let Received {
+                task,
+                message,
+                slot,
+                prefetch,
+                budget,
+                received_at,
+            } = match item {
+                Ok(received) => received,
+                Err(e) => return Some(Err(e)),
+            };
+            // The worker took the task, make room for the next prefetched one
+            drop(slot);
+            drop(prefetch);
+            drop(budget);
+            self.record_wait(&task, received_at.elapsed());
+
+            let cancelled = task
+                .parts
+                .task_id
+                .is_some_and(|id| self.cancellations.is_cancelled(id.inner()));
+            let expired = task.parts.ctx.is_expired(self.ack_mode.clock.now());
+            let paused = !cancelled && !expired && self.paused.is_paused::<M>(&task.parts.ctx);
+            // Tasks that run are acknowledged after they finish in deferred mode
+            let deferred = self.deferred_ack && !cancelled && !expired && !paused;
+            let in_flight = if deferred {
+                self.dispatch(message.ack_id())
+            } else {
+                self.release(message.ack_id())
+            };
+            if !in_flight {
+                // The message was nacked through the registry already
+                continue;
+            }
+            let leased = self.leases.as_ref().is_none_or(|leases| {
+                if deferred {
+                    leases.dispatch(message.ack_id())
+                } else {
+                    leases.release(message.ack_id())
+                }
+            });
+            if !leased {
+                // Pub/Sub has redelivered the message, maybe to another worker
+                if deferred {
+                    // Nothing will settle it, so it's no longer in flight here
+                    self.release(message.ack_id());
+                }
+                tracing::warn!(
+                    task_id = ?task.parts.task_id,
+                    "Lease ran out while buffered, dropping task"
+                );
+                continue;
+            }
+
+            if paused {
+                tracing::debug!(
+                    task_id = ?task.parts.task_id,
+                    job_type = task.parts.ctx.job_type(),
+                    "Job type paused, nacking task"
+                );
+                let ack_mode = self.ack_mode.clone();
+                self.spawner.spawn(async move {
+                    let decision = AckDecision::NackAfter(PAUSED_REDELIVERY_DELAY);
+                    pipeline::settle(&message, decision, &ack_mode, None).await;
+                });
+                continue;
+            }
+
+            // Ack message now that we've committed to processing it, or
+            // dropping it when it was cancelled or expired while buffered
+            if !deferred {
+                let ack_mode = self.ack_mode.clone();
+                let stats = self.stats.clone();
+                let reporter = self.reporter.clone();
+                let task_id = task.parts.task_id.map(|id| *id.inner());
+                self.spawner.spawn(async move {
+                    if let Err(ack_err) = ack_message(&message, &ack_mode).await {
+                        if matches!(ack_err, PubSubError::AckExpired(_)) {
+                            stats.record_ack_expired();
+                            tracing::warn!(
+                                error = ?ack_err,
+                                "Ack deadline expired while buffered, the message will be redelivered"
+                            );
+                        } else {
+                            tracing::error!(error = ?ack_err, "Failed to ack message");
+                        }
+                        report(reporter.as_ref(), || {
+                            ErrorReport::new(FailureKind::Ack, &ack_err)
+                                .with_task_id(task_id)
+                                .with_message_id(&message.message.message_id)
+                        });
+                    } else {
+                        stats.record_acked();
+                        tracing::debug!("Message acknowledged");
+                    }
+                });
+            }
+
+            if cancelled {
+                tracing::info!(task_id = ?task.parts.task_id, "Dropping cancelled task");
+                continue;
+            }
+            if expired {
+                tracing::info!(
+                    task_id = ?task.parts.task_id,
+                    run_before = task.parts.ctx.run_before(),
+                    "Dropping task past its deadline"
+                );
+                continue;
+            }
+            self.last_dispatch = Some(Instant::now());
+            self.dispatched += 1;
+            if self.exhausted() {
+                if let Some((_, receiving)) = &self.max_tasks {
+                    receiving.cancel();
+                }
+            }
+            return Some(Ok(Some(task)));
+        }
+    }
+}
+
+impl<M> Dispatcher<M> {
+    /// Records how long a task waited for the worker, warning when it's too long
+    fn record_wait(&self, task: &PubSubTask<M>, wait: Duration) {
+        let slow = self
+            .runtime
+            .borrow()
+            .slow_dispatch_threshold
+            .is_some_and(|threshold| wait > threshold);
+        self.stats.record_dispatched(wait, slow);
+        if slow {
+            tracing::warn!(
+                task_id = ?task.parts.task_id,
+                wait_ms = wait.as_millis() as u64,
+                buffered = self.stats.buffered(),
+                "Task waited long for the worker, the worker is slower than its intake"
+            );
+        }
+    }
+
+    /// Waits until the next task may be dispatched under the rate limit
+    async fn pace(&self) {
+        let rate = self.runtime.borrow().max_tasks_per_second;
+        let interval = rate
+            .filter(|&rate| rate > 0.0)
+            .and_then(|rate| Duration::try_from_secs_f64(1.0 / rate).ok());
+        let next = self
+            .last_dispatch
+            .zip(interval)
+            .map(|(last, interval)| last + interval);
+        if let Some(wait) = next.and_then(|next| next.checked_duration_since(Instant::now())) {
+            self.cancel.run_until_cancelled(sleep(wait)).await;
+        }
+    }
+
+    /// Stops tracking a message, returning whether it was still in flight
+    fn release(&self, ack_id: &str) -> bool {
+        self.in_flight
+            .as_ref()
+            .is_none_or(|in_flight| in_flight.untrack(ack_id))
+    }
+
+    /// Marks a message as dispatched, returning whether it's still in flight
+    fn dispatch(&self, ack_id: &str) -> bool {
+        self.in_flight
+            .as_ref()
+            .is_none_or(|in_flight| in_flight.dispatch(ack_id))
+    }
+
+    /// Closes the channel and collects the messages still queued on it
+    fn take_leftovers(&mut self) -> Vec<TransportMessage> {
+        self.rx.close();
+        let mut leftovers = Vec::new();
+        while let Ok(item) = self.rx.try_recv() {
+            if let Ok(received) = item {
+                if let Some(leases) = &self.leases {
+                    leases.release(received.message.ack_id());
+                }
+                if self.release(received.message.ack_id()) {
+                    leftovers.push(received.message);
+                }
+            }
+        }
+        leftovers
+    }
+}
+
+impl<M> Drop for Dispatcher<M> {
+    fn drop(&mut self) {
+        // The worker stopped polling without the backend shutting down
+        let leftovers = self.take_leftovers();
+        if leftovers.is_empty() {
+            return;
+        }
+        match self.spawner.try_handle() {
+            Some(handle) => {
+                handle.spawn(nack_all(leftovers));
+            }
+            None => tracing::warn!(
+                count = leftovers.len(),
+                "No runtime to nack undispatched messages, they will be redelivered after their ack deadline"
+            ),
+        }
+    }
+}
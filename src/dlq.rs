@@ -0,0 +1,334 @@
+//! Dead-lettering messages and monitoring dead-letter queues
+//!
+//! # Client-side dead-lettering
+//!
+//! Messages that can't be processed at all, because they fail to decode or
+//! to validate, are acknowledged and dropped so they aren't redelivered
+//! forever. With
+//! [`PubSubConfig::dead_letter_topic`](crate::PubSubConfig::dead_letter_topic)
+//! they're first republished, undecoded, to that topic, so they can be
+//! inspected and replayed. So are tasks failing their last attempt with
+//! [`RepublishRetry`](crate::retry::RepublishRetry), and messages settled
+//! with [`AckDecision::DeadLetter`](crate::outcome::AckDecision::DeadLetter),
+//! by an ack policy, an [`AckHandle`](crate::manual::AckHandle) or a
+//! [pipeline](crate::pipeline) stage. The copies carry two more attributes:
+//!
+//! - [`DEAD_LETTER_KIND_ATTRIBUTE`]: one of [`DEAD_LETTER_KIND_POISON`],
+//!   [`DEAD_LETTER_KIND_FAILED`] and [`DEAD_LETTER_KIND_REJECTED`],
+//! - [`DEAD_LETTER_REASON_ATTRIBUTE`]: the error, at most 1024 bytes of it.
+//!
+//! Messages that can't be republished are nacked instead of acknowledged, so
+//! they're tried again later.
+//!
+//! # Monitoring
+//!
+//! Messages Pub/Sub dead-letters are out of the workers' sight, so a poisoned
+//! workload can pile up in a dead-letter topic unnoticed. With
+//! [`PubSubBackend::with_dead_letter_monitor`], workers periodically measure
+//! the backlog of a subscription on the dead-letter topic:
+//!
+//! - The backlog is reported in [`Metrics`](apalis_core::backend::Metrics) and
+//!   [`PubSubStats::dead_letter_backlog`](crate::stats::PubSubStats::dead_letter_backlog).
+//! - When it reaches [`DeadLetterMonitor::threshold`], a warning is logged and
+//!   the optional alert callback is invoked. It's invoked again only after the
+//!   backlog has dropped below the threshold in between.
+//!
+//! Backlogs are measured with the backend's
+//! [`BacklogEstimator`](crate::stats::BacklogEstimator), so one must be set.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{dlq::DeadLetterMonitor, PubSubBackend, PubSubCompact};
+//! # use std::sync::Arc;
+//! # fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let backend = backend.with_dead_letter_monitor(
+//!     DeadLetterMonitor::new("jobs-dead-letter-sub")
+//!         .with_threshold(10)
+//!         .with_alert(Arc::new(|depth| {
+//!             eprintln!("{} has {} dead-lettered messages", depth.subscription, depth.backlog);
+//!         })),
+//! );
+//! # }
+//! ```
+use std::{sync::Arc, time::Duration};
+
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+
+use crate::{
+    backoff::BackoffStrategy, clock::Clock, sink::publish_with_retry, transport::PubSubTransport,
+    PubSubBackend, PubSubError,
+};
+#[cfg(feature = "consume")]
+use {
+    crate::{
+        ack::{ack_message, modify_message_deadline, AckMode},
+        stats::{BacklogEstimator, PubSubStats},
+        transport::TransportMessage,
+    },
+    apalis_core::timer::sleep,
+    google_cloud_pubsub::subscription::Subscription,
+    tokio_util::sync::CancellationToken,
+};
+
+/// Attribute of dead-lettered messages telling why they were, see
+/// [client-side dead-lettering](self#client-side-dead-lettering)
+pub const DEAD_LETTER_KIND_ATTRIBUTE: &str = "dead_letter_kind";
+
+/// Attribute of dead-lettered messages carrying the error that got them
+/// dead-lettered
+pub const DEAD_LETTER_REASON_ATTRIBUTE: &str = "dead_letter_reason";
+
+/// Kind of messages that couldn't be decoded or validated
+pub const DEAD_LETTER_KIND_POISON: &str = "poison";
+
+/// Kind of tasks that failed their last attempt
+pub const DEAD_LETTER_KIND_FAILED: &str = "failed";
+
+/// Kind of messages settled with
+/// [`AckDecision::DeadLetter`](crate::outcome::AckDecision::DeadLetter)
+pub const DEAD_LETTER_KIND_REJECTED: &str = "rejected";
+
+/// Longest attribute value Pub/Sub accepts, in bytes
+const MAX_ATTRIBUTE_VALUE_BYTES: usize = 1024;
+
+/// Republishes messages to the dead-letter topic
+#[derive(Clone)]
+pub(crate) struct DeadLetterer {
+    transport: Arc<dyn PubSubTransport>,
+    /// Fully qualified name of the dead-letter topic
+    topic: String,
+    backoff: Arc<dyn BackoffStrategy>,
+    clock: Arc<dyn Clock>,
+}
+
+impl DeadLetterer {
+    /// Publishes a copy of `message` to the dead-letter topic, with `kind`
+    /// and `reason` attributes
+    pub(crate) async fn publish(
+        &self,
+        message: &PubsubMessage,
+        kind: &str,
+        reason: &str,
+    ) -> Result<String, PubSubError> {
+        let mut reason = reason;
+        if reason.len() > MAX_ATTRIBUTE_VALUE_BYTES {
+            let mut end = MAX_ATTRIBUTE_VALUE_BYTES;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason = &reason[..end];
+        }
+        let mut copy = PubsubMessage {
+            data: message.data.clone(),
+            attributes: message.attributes.clone(),
+            ..Default::default()
+        };
+        copy.attributes
+            .insert(DEAD_LETTER_KIND_ATTRIBUTE.to_string(), kind.to_string());
+        copy.attributes
+            .insert(DEAD_LETTER_REASON_ATTRIBUTE.to_string(), reason.to_string());
+        let message_id = publish_with_retry(
+            self.transport.as_ref(),
+            &self.topic,
+            copy,
+            self.backoff.as_ref(),
+            self.clock.as_ref(),
+        )
+        .await
+        .map_err(|e| PubSubError::Client(e.to_string()))?;
+        tracing::debug!(message_id, kind, "Message dead-lettered");
+        Ok(message_id)
+    }
+}
+
+/// Drops a message that can't be processed because of `reason`,
+/// dead-lettering it first when there's a dead-letter topic
+#[cfg(feature = "consume")]
+pub(crate) async fn reject_poison(
+    dead_letters: Option<&DeadLetterer>,
+    message: &TransportMessage,
+    reason: &str,
+    mode: &AckMode,
+) {
+    reject(dead_letters, message, DEAD_LETTER_KIND_POISON, reason, mode).await;
+}
+
+/// Drops a message for good, dead-lettering it first as `kind` when there's a
+/// dead-letter topic
+///
+/// The message is nacked instead when it can't be dead-lettered.
+#[cfg(feature = "consume")]
+pub(crate) async fn reject(
+    dead_letters: Option<&DeadLetterer>,
+    message: &TransportMessage,
+    kind: &str,
+    reason: &str,
+    mode: &AckMode,
+) {
+    if let Some(dead_letters) = dead_letters {
+        if let Err(e) = dead_letters.publish(&message.message, kind, reason).await {
+            tracing::error!(error = ?e, kind, "Failed to dead-letter message, nacking it");
+            if let Err(e) = modify_message_deadline(message, 0, mode).await {
+                tracing::error!(error = ?e, kind, "Failed to nack message");
+            }
+            return;
+        }
+    }
+    // Ack rejected messages to prevent infinite redelivery
+    if let Err(e) = ack_message(message, mode).await {
+        tracing::error!(error = ?e, kind, "Failed to ack rejected message");
+    }
+}
+
+/// A dead-letter backlog that reached its threshold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetterDepth {
+    /// Name of the dead-letter subscription
+    pub subscription: String,
+    /// Messages waiting in it
+    pub backlog: u64,
+    /// Threshold the backlog reached
+    pub threshold: u64,
+}
+
+/// Called when a dead-letter backlog reaches its threshold
+pub type DeadLetterAlert = Arc<dyn Fn(&DeadLetterDepth) + Send + Sync>;
+
+/// A dead-letter subscription to watch, see the [module level documentation](self)
+#[derive(Clone)]
+pub struct DeadLetterMonitor {
+    /// Name of a subscription on the dead-letter topic
+    pub subscription: String,
+    /// Backlog that triggers an alert (default: 1)
+    pub threshold: u64,
+    /// How often the backlog is measured (default: 60s)
+    pub interval: Duration,
+    alert: Option<DeadLetterAlert>,
+}
+
+impl DeadLetterMonitor {
+    /// Watches the subscription `subscription`
+    pub fn new(subscription: impl Into<String>) -> Self {
+        Self {
+            subscription: subscription.into(),
+            threshold: 1,
+            interval: Duration::from_secs(60),
+            alert: None,
+        }
+    }
+
+    /// Alerts once the backlog reaches `threshold`
+    pub fn with_threshold(mut self, threshold: u64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Measures the backlog every `interval`
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Calls `alert` when the backlog reaches the threshold
+    pub fn with_alert(mut self, alert: DeadLetterAlert) -> Self {
+        self.alert = Some(alert);
+        self
+    }
+
+    /// Measures the backlog of `subscription` until `cancel` fires
+    #[cfg(feature = "consume")]
+    pub(crate) async fn run(
+        self,
+        subscription: Subscription,
+        estimator: Arc<dyn BacklogEstimator>,
+        stats: Arc<PubSubStats>,
+        cancel: CancellationToken,
+    ) {
+        let mut alerted = false;
+        loop {
+            let backlog = estimator
+                .undelivered_messages(subscription.fully_qualified_name())
+                .await;
+            match backlog {
+                Ok(backlog) => {
+                    stats.record_dead_letter_backlog(&self.subscription, backlog);
+                    let over = backlog >= self.threshold;
+                    if over && !alerted {
+                        tracing::warn!(
+                            subscription = self.subscription,
+                            backlog,
+                            threshold = self.threshold,
+                            "Dead-letter backlog reached its threshold"
+                        );
+                        if let Some(alert) = &self.alert {
+                            alert(&DeadLetterDepth {
+                                subscription: self.subscription.clone(),
+                                backlog,
+                                threshold: self.threshold,
+                            });
+                        }
+                    }
+                    alerted = over;
+                }
+                Err(e) => tracing::warn!(
+                    error = ?e,
+                    subscription = self.subscription,
+                    "Failed to measure dead-letter backlog"
+                ),
+            }
+
+            if cancel
+                .run_until_cancelled(sleep(self.interval))
+                .await
+                .is_none()
+            {
+                return;
+            }
+        }
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Watches the backlog of a dead-letter subscription while workers run
+    ///
+    /// Requires a [`BacklogEstimator`], see
+    /// [`with_backlog_estimator`](Self::with_backlog_estimator).
+    pub fn with_dead_letter_monitor(mut self, monitor: DeadLetterMonitor) -> Self {
+        self.dead_letter_monitors.push(monitor);
+        self
+    }
+
+    /// Republishes messages to the dead-letter topic, if any
+    pub(crate) fn dead_letterer(&self) -> Option<DeadLetterer> {
+        let topic = self.config.dead_letter_topic.as_ref()?;
+        Some(DeadLetterer {
+            transport: self.transport.clone(),
+            topic: self.client.topic(topic).fully_qualified_name().to_string(),
+            backoff: self.config.backoff.clone(),
+            clock: self.config.clock.clone(),
+        })
+    }
+
+    /// Starts the dead-letter monitors, as long as `cancel` isn't cancelled
+    #[cfg(feature = "consume")]
+    pub(crate) fn start_dead_letter_monitors(&self, cancel: &CancellationToken) {
+        if self.dead_letter_monitors.is_empty() {
+            return;
+        }
+        let Some(estimator) = &self.backlog_estimator else {
+            tracing::warn!("Dead-letter monitors need a backlog estimator, they won't run");
+            return;
+        };
+        for monitor in &self.dead_letter_monitors {
+            let subscription = self.client.subscription(&monitor.subscription);
+            self.spawner.spawn(monitor.clone().run(
+                subscription,
+                estimator.clone(),
+                self.stats.clone(),
+                cancel.clone(),
+            ));
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Self-describing task envelopes
+//!
+//! By default a message's body holds only the encoded task arguments, and the
+//! rest of the task travels in attributes, see [`crate::parts`]. With
+//! [`WireFormat::Envelope`] the whole task is encoded into the body instead,
+//! as a protobuf [`TaskEnvelope`]:
+//!
+//! ```protobuf
+//! message TaskEnvelope {
+//!   string task_id = 1;
+//!   uint64 attempt = 2;
+//!   uint64 run_at = 3;
+//!   int32 priority = 4;
+//!   map<string, string> meta = 5;
+//!   bytes args = 6;
+//!   uint64 run_before = 7;
+//!   string job_type = 8;
+//!   string checkpoint = 9;
+//!   string schema_version = 10;
+//!   string trace_context = 11;
+//!   uint64 not_before = 12;
+//! }
+//! ```
+//!
+//! Envelopes are self-describing, so they can be stored, forwarded or moved to
+//! other apalis backends without losing the task's metadata. They're marked
+//! with a `format=envelope` attribute, and the task id and job type are kept
+//! in the attributes too so subscription filters and logs still see them.
+//!
+//! Consumers recognise envelopes by their marker whatever format they publish
+//! in, so producers can switch formats without coordinating with workers.
+use std::collections::HashMap;
+
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use prost::Message;
+
+use crate::{
+    parts::{MessageAttributes, PUBSUB_ATTRIBUTE_JOB_TYPE},
+    PubSubCompact, PubSubError, PubSubTask, PUBSUB_ATTRIBUTE_TASK_ID,
+};
+
+/// Name of the attribute marking the wire format of a message
+pub(crate) const PUBSUB_ATTRIBUTE_FORMAT: &str = "format";
+
+/// Value of [`PUBSUB_ATTRIBUTE_FORMAT`] on envelopes
+const FORMAT_ENVELOPE: &str = "envelope";
+
+/// How tasks are laid out in published messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Arguments in the body, the rest of the task in attributes
+    #[default]
+    Attributes,
+    /// The whole task in the body as a [`TaskEnvelope`]
+    Envelope,
+}
+
+/// A whole task, as encoded in the body of an envelope message
+#[derive(Clone, PartialEq, Message)]
+pub struct TaskEnvelope {
+    /// Id of the task
+    #[prost(string, tag = "1")]
+    pub task_id: String,
+    /// Attempts made so far
+    #[prost(uint64, tag = "2")]
+    pub attempt: u64,
+    /// When the task should run, as a UNIX time
+    #[prost(uint64, tag = "3")]
+    pub run_at: u64,
+    /// Priority of the task
+    #[prost(int32, tag = "4")]
+    pub priority: i32,
+    /// Custom metadata of the task
+    #[prost(map = "string, string", tag = "5")]
+    pub meta: HashMap<String, String>,
+    /// Task arguments, encoded with the backend's codec
+    #[prost(bytes = "vec", tag = "6")]
+    pub args: Vec<u8>,
+    /// Deadline of the task as a UNIX time, 0 if it has none
+    #[prost(uint64, tag = "7")]
+    pub run_before: u64,
+    /// Job type of the task, empty if it has none
+    #[prost(string, tag = "8")]
+    pub job_type: String,
+    /// Last checkpoint of the task, empty if it has none
+    #[prost(string, tag = "9")]
+    pub checkpoint: String,
+    /// Version of the schema of the arguments, empty if unknown
+    #[prost(string, tag = "10")]
+    pub schema_version: String,
+    /// W3C `traceparent` of the task, empty if it has none
+    #[prost(string, tag = "11")]
+    pub trace_context: String,
+    /// When the task is scheduled for as a UNIX time, 0 if it isn't
+    #[prost(uint64, tag = "12")]
+    pub not_before: u64,
+}
+
+impl TaskEnvelope {
+    /// Packs `task`, which must already have a task id
+    pub(crate) fn new(task: PubSubTask<PubSubCompact>) -> Self {
+        Self {
+            task_id: task
+                .parts
+                .task_id
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+            attempt: task.parts.attempt.current() as u64,
+            run_at: task.parts.run_at,
+            priority: task.parts.ctx.priority(),
+            meta: task.parts.ctx.meta_entries().clone(),
+            run_before: task.parts.ctx.run_before().unwrap_or_default(),
+            not_before: task.parts.ctx.not_before().unwrap_or_default(),
+            job_type: task.parts.ctx.job_type().unwrap_or_default().to_owned(),
+            checkpoint: task.parts.ctx.last_checkpoint().unwrap_or_default(),
+            schema_version: task
+                .parts
+                .ctx
+                .schema_version()
+                .unwrap_or_default()
+                .to_owned(),
+            trace_context: task
+                .parts
+                .ctx
+                .trace_context()
+                .unwrap_or_default()
+                .to_owned(),
+            args: task.args,
+        }
+    }
+}
+
+/// Replaces the body and attributes of `message` by an envelope of them
+pub(crate) fn seal(message: &mut PubsubMessage, envelope: TaskEnvelope) {
+    message.data = envelope.encode_to_vec();
+    message.attributes = HashMap::from([
+        (
+            PUBSUB_ATTRIBUTE_FORMAT.to_owned(),
+            FORMAT_ENVELOPE.to_owned(),
+        ),
+        (PUBSUB_ATTRIBUTE_TASK_ID.to_owned(), envelope.task_id),
+    ]);
+    if !envelope.job_type.is_empty() {
+        message
+            .attributes
+            .insert(PUBSUB_ATTRIBUTE_JOB_TYPE.to_owned(), envelope.job_type);
+    }
+}
+
+/// Unpacks `message` in place if it's an envelope, so it reads like a
+/// message published with [`WireFormat::Attributes`]
+pub(crate) fn open(message: &mut PubsubMessage) -> Result<(), PubSubError> {
+    if message
+        .attributes
+        .get(PUBSUB_ATTRIBUTE_FORMAT)
+        .map(String::as_str)
+        != Some(FORMAT_ENVELOPE)
+    {
+        return Ok(());
+    }
+
+    let envelope = TaskEnvelope::decode(message.data.as_slice())
+        .map_err(|e| PubSubError::Codec(format!("Invalid task envelope: {e}")))?;
+    let non_empty = |value: String| (!value.is_empty()).then_some(value);
+    let attributes = MessageAttributes {
+        task_id: envelope
+            .task_id
+            .parse()
+            .inspect_err(|e| tracing::warn!(task_id = envelope.task_id, "Invalid task id: {e}"))
+            .ok(),
+        attempt: (envelope.attempt > 0).then_some(envelope.attempt as usize),
+        run_at: Some(envelope.run_at),
+        priority: (envelope.priority != 0).then_some(envelope.priority),
+        run_before: (envelope.run_before != 0).then_some(envelope.run_before),
+        not_before: (envelope.not_before != 0).then_some(envelope.not_before),
+        job_type: non_empty(envelope.job_type),
+        checkpoint: non_empty(envelope.checkpoint),
+        schema_version: non_empty(envelope.schema_version),
+        trace_context: non_empty(envelope.trace_context),
+        meta: envelope.meta,
+        ..Default::default()
+    };
+    message.attributes.remove(PUBSUB_ATTRIBUTE_FORMAT);
+    attributes.apply(message);
+    message.data = envelope.args;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! Per-message data for handlers
+//!
+//! Applications often derive data from every message before handling it: the
+//! tenant it belongs to, the user's locale, verified auth claims. Context hooks
+//! run when a message is consumed, read its attributes and payload, and attach
+//! whatever they derive to the task's [`PubSubContext`] as typed extensions.
+//! Handlers then read it back with [`PubSubContext::extension`].
+//!
+//! Hooks run in the order they were added, before the task reaches any
+//! middleware.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{utils::PubSubContext, PubSubBackend, PubSubCompact};
+//! #[derive(Clone)]
+//! struct Tenant(String);
+//!
+//! # fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let backend = backend.with_context_hook(|message, ctx| {
+//!     if let Some(tenant) = message.attributes.get("tenant") {
+//!         ctx.insert_extension(Tenant(tenant.clone()));
+//!     }
+//! });
+//! # }
+//!
+//! async fn handler(job: u32, ctx: PubSubContext) {
+//!     let tenant = ctx.extension::<Tenant>().map(|tenant| tenant.0.as_str());
+//!     // ...
+//! }
+//! ```
+use std::sync::Arc;
+
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+
+use crate::{utils::PubSubContext, PubSubBackend};
+
+/// Attaches data derived from a consumed message to its context
+pub type ContextHook = Arc<dyn Fn(&PubsubMessage, &mut PubSubContext) + Send + Sync>;
+
+/// Runs `hooks` in order on the context of `message`
+pub(crate) fn apply_hooks(hooks: &[ContextHook], message: &PubsubMessage, ctx: &mut PubSubContext) {
+    for hook in hooks {
+        hook(message, ctx);
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Runs `hook` on every consumed message, after the hooks added before it
+    pub fn with_context_hook(
+        mut self,
+        hook: impl Fn(&PubsubMessage, &mut PubSubContext) + Send + Sync + 'static,
+    ) -> Self {
+        self.context_hooks.push(Arc::new(hook));
+        self
+    }
+}
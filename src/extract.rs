@@ -0,0 +1,103 @@
+//! Handler arguments extracted from the task
+//!
+//! Handlers can take the whole [`PubSubContext`] as an argument, or only the
+//! parts they need:
+//!
+//! - [`MessageId`]: the Pub/Sub message id,
+//! - [`DeliveryAttempt`]: how many times Pub/Sub delivered the message, when
+//!   the subscription has a dead-letter policy,
+//! - [`JobType`]: the job type of the task, see [`crate::jobs`],
+//! - [`Extension`]: an extension attached by a context hook, see
+//!   [`crate::extensions`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_pubsub::extract::{DeliveryAttempt, Extension, MessageId};
+//! #[derive(Clone)]
+//! struct Tenant(String);
+//!
+//! async fn handler(
+//!     job: u32,
+//!     MessageId(message_id): MessageId,
+//!     DeliveryAttempt(attempt): DeliveryAttempt,
+//!     Extension(tenant): Extension<Tenant>,
+//! ) {
+//!     tracing::info!(message_id, ?attempt, tenant = tenant.0, "Handling job {job}");
+//! }
+//! ```
+use std::convert::Infallible;
+
+use apalis_core::task_fn::FromRequest;
+
+use crate::{utils::PubSubContext, PubSubTask};
+
+/// Why an argument couldn't be extracted from a task
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ExtractError {
+    #[error("Task has no message id")]
+    MissingMessageId,
+
+    #[error("No extension of type {0} was attached")]
+    MissingExtension(&'static str),
+}
+
+/// Id of the message the task was received in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageId(pub String);
+
+impl<M: Sync> FromRequest<PubSubTask<M>> for MessageId {
+    type Error = ExtractError;
+
+    async fn from_request(task: &PubSubTask<M>) -> Result<Self, Self::Error> {
+        task.parts
+            .ctx
+            .message_id()
+            .map(|id| Self(id.to_owned()))
+            .ok_or(ExtractError::MissingMessageId)
+    }
+}
+
+/// Times Pub/Sub delivered the message, if the subscription counts them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryAttempt(pub Option<usize>);
+
+impl<M: Sync> FromRequest<PubSubTask<M>> for DeliveryAttempt {
+    type Error = Infallible;
+
+    async fn from_request(task: &PubSubTask<M>) -> Result<Self, Self::Error> {
+        Ok(Self(task.parts.ctx.delivery_attempt()))
+    }
+}
+
+/// Job type of the task, by default the type name of its arguments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobType(pub String);
+
+impl<M: Sync> FromRequest<PubSubTask<M>> for JobType {
+    type Error = Infallible;
+
+    async fn from_request(task: &PubSubTask<M>) -> Result<Self, Self::Error> {
+        let job_type = task
+            .parts
+            .ctx
+            .job_type()
+            .unwrap_or_else(|| std::any::type_name::<M>());
+        Ok(Self(job_type.to_owned()))
+    }
+}
+
+/// Extension of type `T` attached to the task's [`PubSubContext`]
+#[derive(Debug, Clone)]
+pub struct Extension<T>(pub T);
+
+impl<M: Sync, T: Clone + Send + Sync + 'static> FromRequest<PubSubTask<M>> for Extension<T> {
+    type Error = ExtractError;
+
+    async fn from_request(task: &PubSubTask<M>) -> Result<Self, Self::Error> {
+        let ctx: &PubSubContext = &task.parts.ctx;
+        ctx.extension::<T>()
+            .map(|value| Self(value.clone()))
+            .ok_or(ExtractError::MissingExtension(std::any::type_name::<T>()))
+    }
+}
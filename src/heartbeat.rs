@@ -0,0 +1,263 @@
+//! Worker liveness: heartbeat records and subscription health checks
+//!
+//! # Heartbeat records
+//!
+//! With [`PubSubBackend::with_heartbeat_topic`], every worker polling the
+//! backend publishes a [`HeartbeatRecord`] at a fixed interval. Subscribing to
+//! the heartbeat topic shows which workers are alive, how busy they are and
+//! whether they are still making progress, without access to the workers
+//! themselves.
+//!
+//! Records are carried in message attributes, so they can be read from the
+//! Cloud Console or exported to BigQuery as is. Publishing them requires the
+//! `consume` feature.
+//!
+//! # Health checks
+//!
+//! The streaming pull reconnects on its own, so a worker whose connection to
+//! Pub/Sub is broken just stops receiving messages, which looks like an idle
+//! queue. With [`PubSubConfig::health_check`](crate::PubSubConfig::health_check)
+//! set, the heartbeat of every worker also probes its subscription every
+//! [`HealthCheck::interval`], through the backend's
+//! [transport](crate::transport). The beat fails, which stops the worker:
+//!
+//! - with [`PubSubError::SubscriptionGone`] as soon as the subscription
+//!   doesn't exist anymore or was detached from its topic,
+//! - with [`PubSubError::Subscription`] once [`HealthCheck::max_failures`]
+//!   probes in a row couldn't reach Pub/Sub.
+//!
+//! Probes failing for other reasons, such as a service account allowed to
+//! pull but not to read the subscription, are logged and don't count as
+//! failures.
+//!
+//! The probes replace the
+//! [subscription watch](crate::PubSubConfig::subscription_check_interval) of
+//! the worker, so the subscription isn't checked twice.
+//!
+//! Transports other than the Google Cloud one usually have no subscription to
+//! probe, and report it as healthy, see
+//! [`PubSubTransport::subscription_state`].
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+
+use crate::PubSubError;
+#[cfg(feature = "consume")]
+use {
+    crate::{
+        ack::is_transient, transport::PubSubTransport, watch::check_subscription, PubSubBackend,
+    },
+    apalis_core::{timer::sleep, worker::context::WorkerContext},
+    futures::{stream::BoxStream, StreamExt},
+    google_cloud_pubsub::topic::Topic,
+    std::sync::Arc,
+};
+
+/// Attribute holding the worker name of a heartbeat
+const HEARTBEAT_ATTRIBUTE_WORKER: &str = "worker";
+
+/// Attribute holding the queue of a heartbeat
+const HEARTBEAT_ATTRIBUTE_QUEUE: &str = "queue";
+
+/// Attribute holding the number of tasks in flight
+const HEARTBEAT_ATTRIBUTE_IN_FLIGHT: &str = "in_flight";
+
+/// Attribute holding when the worker started, in milliseconds since the Unix epoch
+const HEARTBEAT_ATTRIBUTE_STARTED_AT: &str = "started_at";
+
+/// Attribute holding the last ack, in milliseconds since the Unix epoch
+const HEARTBEAT_ATTRIBUTE_LAST_ACK: &str = "last_ack";
+
+/// Where and how often workers publish heartbeats
+#[cfg(feature = "consume")]
+#[derive(Clone)]
+pub(crate) struct HeartbeatConfig {
+    topic: Topic,
+    interval: Duration,
+}
+
+/// How workers probe their subscription, see the
+/// [module level documentation](self#health-checks)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// Time between probes (default: 30s)
+    pub interval: Duration,
+    /// Failed probes in a row after which the worker stops (default: 3)
+    pub max_failures: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            max_failures: 3,
+        }
+    }
+}
+
+/// Liveness record of a single worker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeartbeatRecord {
+    /// Name of the worker
+    pub worker: String,
+    /// Topic the worker processes tasks from
+    pub queue: String,
+    /// Tasks the worker was running when the heartbeat was sent
+    pub in_flight: usize,
+    /// When the worker started publishing heartbeats
+    pub started_at: SystemTime,
+    /// When the backend last acknowledged a message, if ever
+    pub last_ack: Option<SystemTime>,
+}
+
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl HeartbeatRecord {
+    /// Encodes the record as a heartbeat message
+    pub fn to_message(&self) -> PubsubMessage {
+        let mut message = PubsubMessage::default();
+        let attributes = &mut message.attributes;
+        attributes.insert(HEARTBEAT_ATTRIBUTE_WORKER.to_owned(), self.worker.clone());
+        attributes.insert(HEARTBEAT_ATTRIBUTE_QUEUE.to_owned(), self.queue.clone());
+        attributes.insert(
+            HEARTBEAT_ATTRIBUTE_IN_FLIGHT.to_owned(),
+            self.in_flight.to_string(),
+        );
+        attributes.insert(
+            HEARTBEAT_ATTRIBUTE_STARTED_AT.to_owned(),
+            to_millis(self.started_at).to_string(),
+        );
+        if let Some(last_ack) = self.last_ack {
+            attributes.insert(
+                HEARTBEAT_ATTRIBUTE_LAST_ACK.to_owned(),
+                to_millis(last_ack).to_string(),
+            );
+        }
+        message
+    }
+
+    /// Decodes a heartbeat message
+    pub fn from_message(message: &PubsubMessage) -> Result<Self, PubSubError> {
+        let attribute = |name: &str| {
+            message
+                .attributes
+                .get(name)
+                .ok_or_else(|| PubSubError::Codec(format!("Heartbeat has no {name}")))
+        };
+        let number = |name: &str, value: &String| {
+            value
+                .parse::<u64>()
+                .map_err(|e| PubSubError::Codec(format!("Invalid heartbeat {name}: {e}")))
+        };
+
+        let in_flight = attribute(HEARTBEAT_ATTRIBUTE_IN_FLIGHT)?;
+        let started_at = attribute(HEARTBEAT_ATTRIBUTE_STARTED_AT)?;
+        let last_ack = match message.attributes.get(HEARTBEAT_ATTRIBUTE_LAST_ACK) {
+            Some(millis) => Some(
+                UNIX_EPOCH + Duration::from_millis(number(HEARTBEAT_ATTRIBUTE_LAST_ACK, millis)?),
+            ),
+            None => None,
+        };
+        Ok(Self {
+            worker: attribute(HEARTBEAT_ATTRIBUTE_WORKER)?.clone(),
+            queue: attribute(HEARTBEAT_ATTRIBUTE_QUEUE)?.clone(),
+            in_flight: number(HEARTBEAT_ATTRIBUTE_IN_FLIGHT, in_flight)? as usize,
+            started_at: UNIX_EPOCH
+                + Duration::from_millis(number(HEARTBEAT_ATTRIBUTE_STARTED_AT, started_at)?),
+            last_ack,
+        })
+    }
+}
+
+#[cfg(feature = "consume")]
+impl<M, C> PubSubBackend<M, C> {
+    /// Publishes a [`HeartbeatRecord`] to the topic `topic_name` every `interval`
+    pub fn with_heartbeat_topic(mut self, topic_name: &str, interval: Duration) -> Self {
+        self.heartbeat = Some(HeartbeatConfig {
+            topic: self.client.topic(topic_name),
+            interval,
+        });
+        self
+    }
+}
+
+/// Publishes heartbeats for `worker` until the stream is dropped
+///
+/// Failed publishes are only logged, as errors in the beat stream stop the worker.
+#[cfg(feature = "consume")]
+pub(crate) fn heartbeats<M, C>(
+    backend: &PubSubBackend<M, C>,
+    config: &HeartbeatConfig,
+    worker: &WorkerContext,
+) -> BoxStream<'static, Result<(), PubSubError>> {
+    let publisher = config.topic.new_publisher(None);
+    let interval = config.interval;
+    let queue = backend.queue_name();
+    let stats = backend.stats.clone();
+    let worker = worker.clone();
+    let started_at = SystemTime::now();
+
+    futures::stream::unfold(true, move |first| {
+        let publisher = publisher.clone();
+        let queue = queue.clone();
+        let stats = stats.clone();
+        let worker = worker.clone();
+        async move {
+            if !first {
+                sleep(interval).await;
+            }
+            let record = HeartbeatRecord {
+                worker: worker.name().clone(),
+                queue,
+                in_flight: worker.task_count(),
+                started_at,
+                last_ack: stats.last_ack(),
+            };
+            match publisher.publish(record.to_message()).await.get().await {
+                Ok(_) => tracing::trace!(worker = record.worker, "Published heartbeat"),
+                Err(e) => tracing::warn!(error = ?e, "Failed to publish heartbeat"),
+            }
+            Some((Ok(()), false))
+        }
+    })
+    .boxed()
+}
+
+/// Probes `subscription` through `transport` as `check` says until the
+/// stream is dropped
+#[cfg(feature = "consume")]
+pub(crate) fn health_checks(
+    transport: Arc<dyn PubSubTransport>,
+    subscription: String,
+    check: &HealthCheck,
+) -> BoxStream<'static, Result<(), PubSubError>> {
+    let check = check.clone();
+    futures::stream::unfold(0, move |failures| {
+        let transport = transport.clone();
+        let subscription = subscription.clone();
+        let check = check.clone();
+        async move {
+            sleep(check.interval).await;
+            let error = match check_subscription(transport.as_ref(), &subscription).await {
+                Ok(None) => return Some((Ok(()), 0)),
+                Ok(Some(gone)) => gone,
+                Err(status) if !is_transient(&status) => {
+                    tracing::warn!(error = ?status, "Health check failed, not counting it");
+                    return Some((Ok(()), failures));
+                }
+                Err(status) if failures + 1 < check.max_failures => {
+                    tracing::debug!(error = ?status, failures = failures + 1, "Health check failed");
+                    return Some((Ok(()), failures + 1));
+                }
+                Err(status) => PubSubError::Subscription(status.to_string()),
+            };
+            tracing::error!(error = ?error, "Subscription is unhealthy");
+            Some((Err(error), 0))
+        }
+    })
+    .boxed()
+}
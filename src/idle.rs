@@ -0,0 +1,142 @@
+//! Detecting idle workers, for scale-to-zero deployments
+//!
+//! Serverless deployments save costs by stopping workers that have nothing to
+//! do and letting an autoscaler start them again when the backlog returns.
+//! With [`PubSubBackend::with_idle_detection`], the backend considers its
+//! workers idle once they haven't received a message for
+//! [`IdleDetection::timeout`]. It then logs it, calls the optional callback,
+//! and completes the futures returned by [`PubSubBackend::until_idle`].
+//!
+//! Receiving a message again ends the idle period, and the next one is
+//! detected the same way.
+//!
+//! Requires the `consume` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis::prelude::*;
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{idle::IdleDetection, PubSubBackend, PubSubCompact};
+//! # use std::time::Duration;
+//! # async fn handle(job: u32) {}
+//! # async fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let backend = backend.with_idle_detection(IdleDetection::new(Duration::from_secs(300)));
+//! let idle = backend.until_idle();
+//! let shutdown = backend.clone();
+//! let worker = WorkerBuilder::new("scale-to-zero")
+//!     .backend(backend)
+//!     .build(handle);
+//! tokio::spawn(async move {
+//!     idle.await;
+//!     shutdown.shutdown();
+//! });
+//! worker.run().await.unwrap();
+//! # }
+//! ```
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use apalis_core::timer::sleep;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::{stats::PubSubStats, PubSubBackend};
+
+/// Shortest interval between checks for idleness
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Called when the workers become idle, with how long they've been idle
+pub type IdleCallback = Arc<dyn Fn(Duration) + Send + Sync>;
+
+/// When workers are idle, see the [module level documentation](self)
+#[derive(Clone)]
+pub struct IdleDetection {
+    /// Time without receiving a message after which workers are idle
+    pub timeout: Duration,
+    callback: Option<IdleCallback>,
+}
+
+impl IdleDetection {
+    /// Considers workers idle after `timeout` without receiving a message
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            callback: None,
+        }
+    }
+
+    /// Calls `callback` whenever workers become idle
+    pub fn with_callback(mut self, callback: IdleCallback) -> Self {
+        self.callback = Some(callback);
+        self
+    }
+}
+
+/// Idle detection shared by clones of a backend
+#[derive(Clone)]
+pub(crate) struct IdleMonitor {
+    detection: IdleDetection,
+    idle: Arc<watch::Sender<bool>>,
+}
+
+impl IdleMonitor {
+    /// Tracks the messages counted in `stats` until `cancel` fires
+    pub(crate) async fn run(self, stats: Arc<PubSubStats>, cancel: CancellationToken) {
+        let timeout = self.detection.timeout;
+        let interval = (timeout / 4).max(MIN_CHECK_INTERVAL);
+        let mut received = stats.received();
+        let mut active_at = Instant::now();
+        while cancel.run_until_cancelled(sleep(interval)).await.is_some() {
+            let now_received = stats.received();
+            if now_received != received {
+                received = now_received;
+                active_at = Instant::now();
+                self.idle
+                    .send_if_modified(|idle| std::mem::replace(idle, false));
+                continue;
+            }
+
+            let idle_for = active_at.elapsed();
+            if idle_for >= timeout && !*self.idle.borrow() {
+                tracing::info!(?idle_for, "Workers are idle");
+                self.idle.send_replace(true);
+                if let Some(callback) = &self.detection.callback {
+                    callback(idle_for);
+                }
+            }
+        }
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Detects when workers are idle, as described by `detection`
+    pub fn with_idle_detection(mut self, detection: IdleDetection) -> Self {
+        self.idle = Some(IdleMonitor {
+            detection,
+            idle: Arc::new(watch::Sender::new(false)),
+        });
+        self
+    }
+
+    /// Completes once the workers are idle
+    ///
+    /// Completes right away if they're idle already, and never without
+    /// [idle detection](Self::with_idle_detection).
+    pub fn until_idle(&self) -> impl Future<Output = ()> + Send + 'static {
+        let idle = self.idle.as_ref().map(|monitor| monitor.idle.subscribe());
+        async move {
+            match idle {
+                Some(mut idle) => {
+                    // The sender lives as long as the backend, which may be dropped
+                    // while workers still run
+                    let _ = idle.wait_for(|idle| *idle).await;
+                }
+                None => std::future::pending().await,
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Registry of the messages in flight
+//!
+//! A message is in flight from the moment the backend receives it until it's
+//! acknowledged or nacked: while it waits in the local buffer, and while its
+//! task runs when acknowledgement is deferred, see [`crate::outcome`].
+//! [`PubSubBackend::in_flight`] returns the registry of these messages, which
+//! the backend itself uses to release them on abort, see [`crate::abort`].
+//!
+//! Operational tooling can list the messages in flight, extend all their
+//! deadlines, for example ahead of a slow dependency recovering, or nack them
+//! all so other workers pick them up.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact};
+//! # use std::time::Duration;
+//! # async fn example(backend: &PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let in_flight = backend.in_flight();
+//! for message in in_flight.messages() {
+//!     println!("{} in flight for {:?}", message.message_id, message.age);
+//! }
+//! in_flight.extend_all(Duration::from_secs(300)).await.unwrap();
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+
+use crate::{
+    ack::{ack_error, MAX_ACK_IDS_PER_REQUEST},
+    lease::MAX_ACK_DEADLINE,
+    transport::Subscriber,
+    PubSubBackend, PubSubError,
+};
+
+/// A message in flight
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InFlightMessage {
+    /// Ack id of the message
+    pub ack_id: String,
+    /// Id of the message
+    pub message_id: String,
+    /// Time since the message was received
+    pub age: Duration,
+    /// Whether the worker took the task, rather than it being buffered
+    pub dispatched: bool,
+}
+
+/// What the registry knows of a message in flight
+struct Entry {
+    message_id: String,
+    received_at: Instant,
+    dispatched: bool,
+    /// Copy of the message, kept to dead-letter it
+    message: Option<PubsubMessage>,
+}
+
+/// Messages received but not yet acknowledged or nacked, see the
+/// [module level documentation](self)
+pub struct InFlight {
+    subscriber: Subscriber,
+    messages: Mutex<HashMap<String, Entry>>,
+}
+
+impl InFlight {
+    pub(crate) fn new(subscriber: Subscriber) -> Self {
+        Self {
+            subscriber,
+            messages: Mutex::default(),
+        }
+    }
+
+    /// Starts tracking a message just received, keeping a copy of it when
+    /// `keep_message`
+    #[cfg(feature = "consume")]
+    pub(crate) fn track(&self, ack_id: &str, message: &PubsubMessage, keep_message: bool) {
+        self.messages.lock().unwrap().insert(
+            ack_id.to_string(),
+            Entry {
+                message_id: message.message_id.clone(),
+                received_at: Instant::now(),
+                dispatched: false,
+                message: keep_message.then(|| message.clone()),
+            },
+        );
+    }
+
+    /// Takes the copy of a message in flight, if one was kept
+    pub(crate) fn take_message(&self, ack_id: &str) -> Option<PubsubMessage> {
+        self.messages
+            .lock()
+            .unwrap()
+            .get_mut(ack_id)
+            .and_then(|entry| entry.message.take())
+    }
+
+    /// Stops tracking a message, returning whether it was still in flight
+    ///
+    /// Messages that aren't were nacked through the registry and must be left
+    /// alone.
+    pub(crate) fn untrack(&self, ack_id: &str) -> bool {
+        self.messages.lock().unwrap().remove(ack_id).is_some()
+    }
+
+    /// Marks a message as taken by the worker, returning whether it's still
+    /// in flight
+    #[cfg(feature = "consume")]
+    pub(crate) fn dispatch(&self, ack_id: &str) -> bool {
+        match self.messages.lock().unwrap().get_mut(ack_id) {
+            Some(entry) => {
+                entry.dispatched = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the message with `ack_id` is still in flight
+    #[cfg(feature = "consume")]
+    pub(crate) fn contains(&self, ack_id: &str) -> bool {
+        self.messages.lock().unwrap().contains_key(ack_id)
+    }
+
+    /// Number of messages in flight
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    /// Whether no message is in flight
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The messages in flight
+    pub fn messages(&self) -> Vec<InFlightMessage> {
+        let now = Instant::now();
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ack_id, entry)| InFlightMessage {
+                ack_id: ack_id.clone(),
+                message_id: entry.message_id.clone(),
+                age: now.duration_since(entry.received_at),
+                dispatched: entry.dispatched,
+            })
+            .collect()
+    }
+
+    /// Sets the ack deadline of every message in flight to `deadline`, at
+    /// most 10 minutes, returning how many were extended
+    pub async fn extend_all(&self, deadline: Duration) -> Result<usize, PubSubError> {
+        let ack_ids: Vec<_> = self.messages.lock().unwrap().keys().cloned().collect();
+        let seconds = deadline.min(MAX_ACK_DEADLINE).as_secs() as i32;
+        self.modify_deadlines(&ack_ids, seconds).await?;
+        tracing::debug!(
+            count = ack_ids.len(),
+            ?deadline,
+            "Extended in-flight messages"
+        );
+        Ok(ack_ids.len())
+    }
+
+    /// Nacks every message in flight so Pub/Sub redelivers them right away,
+    /// returning how many were nacked
+    ///
+    /// Tasks still running finish, but their messages are no longer
+    /// acknowledged.
+    pub async fn nack_all(&self) -> Result<usize, PubSubError> {
+        let ack_ids: Vec<_> = self
+            .messages
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(ack_id, _)| ack_id)
+            .collect();
+        self.modify_deadlines(&ack_ids, 0).await?;
+        if !ack_ids.is_empty() {
+            tracing::info!(count = ack_ids.len(), "Nacked in-flight messages");
+        }
+        Ok(ack_ids.len())
+    }
+
+    async fn modify_deadlines(&self, ack_ids: &[String], seconds: i32) -> Result<(), PubSubError> {
+        let requests = ack_ids
+            .chunks(MAX_ACK_IDS_PER_REQUEST)
+            .map(|chunk| self.subscriber.modify_ack_deadline(chunk.to_vec(), seconds));
+        join_all(requests)
+            .await
+            .into_iter()
+            .find_map(Result::err)
+            .map_or(Ok(()), |e| Err(ack_error(e)))
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Registry of the messages in flight, shared by clones of the backend
+    pub fn in_flight(&self) -> Arc<InFlight> {
+        self.in_flight.clone()
+    }
+}
@@ -0,0 +1,162 @@
+//! State shared by the clones of a backend
+//!
+//! The type is public so [`PubSubBackend`](crate::PubSubBackend) can deref to it, but this module
+//! isn't, so it can't be named outside the crate.
+use std::{marker::PhantomData, sync::Arc};
+
+use google_cloud_pubsub::{client::Client, subscription::Subscription, topic::Topic};
+
+use crate::{
+    archive::Archiver,
+    budget::BufferBudget,
+    cancel::Cancellations,
+    checkpoint,
+    codecs::Codecs,
+    control::ConcurrencyControl,
+    dlq,
+    extensions::ContextHook,
+    inflight::InFlight,
+    jobs::{JobTypes, PausedJobTypes},
+    outcome::AckPolicy,
+    pipeline, priority, provision, registry,
+    reload::ConfigHandle,
+    report::ErrorReporter,
+    results, shard, spawn,
+    stats::{self, PubSubStats},
+    transport::PubSubTransport,
+    validate, PubSubConfig,
+};
+#[cfg(feature = "consume")]
+use crate::{breaker, heartbeat, idle, shared};
+
+/// What the clones of a [`PubSubBackend`](crate::PubSubBackend) share
+///
+/// Builders change it copy-on-write, so configuring a clone leaves the
+/// others as they were.
+pub struct BackendInner<M, Codec> {
+    /// Client must be kept alive as topic/subscription hold references to it
+    pub(crate) client: Client,
+    /// Carries the messages published, received and acknowledged, see [`transport`](crate::transport)
+    pub(crate) transport: Arc<dyn PubSubTransport>,
+    /// Topic tasks are published to, none for consume-only backends
+    pub(crate) topic: Option<Topic>,
+    /// Arc-wrapped subscription for safe sharing across worker threads in poll()
+    pub(crate) subscription: std::sync::Arc<Subscription>,
+    /// Configuration for backend behavior
+    pub(crate) config: PubSubConfig,
+    /// Cancellation token for graceful shutdown
+    pub(crate) cancel: tokio_util::sync::CancellationToken,
+    /// Where task outcomes are looked up by [`WaitForCompletion`](apalis_core::backend::WaitForCompletion)
+    pub(crate) result_store: Option<std::sync::Arc<dyn results::ResultStore>>,
+    /// Counters shared with the workers polling this backend
+    pub(crate) stats: Arc<PubSubStats>,
+    /// Source of the subscription backlog reported by [`Metrics`](apalis_core::backend::Metrics)
+    pub(crate) backlog_estimator: Option<Arc<dyn stats::BacklogEstimator>>,
+    /// Subscription to the control topic, see [`control`](crate::control)
+    pub(crate) control: Option<Arc<Subscription>>,
+    /// Concurrency limit shared with the workers, adjustable over the control topic
+    pub(crate) concurrency: Arc<ConcurrencyControl>,
+    /// Tasks cancelled over the control topic
+    pub(crate) cancellations: Arc<Cancellations>,
+    /// Where workers publish their liveness, see [`heartbeat`](crate::heartbeat)
+    #[cfg(feature = "consume")]
+    pub(crate) heartbeat: Option<heartbeat::HeartbeatConfig>,
+    /// Workers reported by [`ListWorkers`](apalis_core::backend::ListWorkers)
+    pub(crate) registry: Option<Arc<registry::WorkerRegistry>>,
+    /// Limit on buffered payload bytes, see [`budget`](crate::budget)
+    pub(crate) budget: Option<Arc<BufferBudget>>,
+    /// Checks payloads before they're decoded, see [`validate`](crate::validate)
+    pub(crate) validator: Option<Arc<dyn validate::PayloadValidator>>,
+    /// Run on every consumed message, see [`extensions`](crate::extensions)
+    pub(crate) context_hooks: Vec<ContextHook>,
+    /// Where tasks that fail for good are reported, see [`alert`](crate::alert)
+    pub(crate) alert_topic: Option<Topic>,
+    /// Receives the failures of the backend, see [`report`](crate::report)
+    pub(crate) reporter: Option<Arc<dyn ErrorReporter>>,
+    /// Created when found missing, see [`PubSubBackend::with_auto_create`](crate::PubSubBackend::with_auto_create)
+    pub(crate) auto_create: Option<Arc<provision::Resources>>,
+    /// Codecs messages may name, see [`codecs`](crate::codecs)
+    pub(crate) codecs: Codecs<M>,
+    /// Tunables changed at runtime, see [`reload`](crate::reload)
+    pub(crate) runtime: ConfigHandle,
+    /// Copies messages to an archive, see [`archive`](crate::archive)
+    pub(crate) archiver: Option<Archiver>,
+    /// Dead-letter subscriptions watched by workers, see [`dlq`](crate::dlq)
+    pub(crate) dead_letter_monitors: Vec<dlq::DeadLetterMonitor>,
+    /// Messages received but not yet acknowledged, see [`abort`](crate::abort)
+    pub(crate) in_flight: Arc<InFlight>,
+    /// Decides how messages are acknowledged after their task, see [`outcome`](crate::outcome)
+    pub(crate) ack_policy: Option<Arc<dyn AckPolicy>>,
+    /// Settings overridden per job type, see [`jobs`](crate::jobs)
+    pub(crate) job_types: Arc<JobTypes>,
+    /// Job types whose tasks aren't run, shared with every clone, see [`jobs`](crate::jobs)
+    pub(crate) paused_job_types: Arc<PausedJobTypes>,
+    /// Detects idle workers, see [`idle`](crate::idle)
+    #[cfg(feature = "consume")]
+    pub(crate) idle: Option<idle::IdleMonitor>,
+    /// Where task checkpoints are saved, see [`checkpoint`](crate::checkpoint)
+    pub(crate) checkpoint_store: Option<Arc<dyn checkpoint::CheckpointStore>>,
+    /// Topics tasks are published to by priority, see [`priority`](crate::priority)
+    pub(crate) priority_tiers: priority::PriorityTiers,
+    /// Runs background tasks, see [`spawn`](crate::spawn)
+    pub(crate) spawner: spawn::Spawner,
+    /// Runs on tasks before they're dispatched, see [`pipeline`](crate::pipeline)
+    pub(crate) pipeline: pipeline::Pipeline<M>,
+    /// Shard topics tasks are spread across, see [`shard`](crate::shard)
+    pub(crate) sharding: Option<shard::Sharding<M>>,
+    /// Stops consuming job types whose messages stop decoding, see [`breaker`](crate::breaker)
+    #[cfg(feature = "consume")]
+    pub(crate) breakers: Option<Arc<breaker::Breakers>>,
+    /// Streaming pull shared by the workers, see [`shared`](crate::shared)
+    #[cfg(feature = "consume")]
+    pub(crate) shared_consumer: Option<Arc<shared::SharedConsumer<M>>>,
+    pub(crate) _phantom: PhantomData<(M, Codec)>,
+}
+
+impl<M, C> Clone for BackendInner<M, C> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            transport: self.transport.clone(),
+            topic: self.topic.clone(),
+            subscription: self.subscription.clone(),
+            config: self.config.clone(),
+            cancel: self.cancel.clone(),
+            result_store: self.result_store.clone(),
+            stats: self.stats.clone(),
+            backlog_estimator: self.backlog_estimator.clone(),
+            control: self.control.clone(),
+            concurrency: self.concurrency.clone(),
+            cancellations: self.cancellations.clone(),
+            #[cfg(feature = "consume")]
+            heartbeat: self.heartbeat.clone(),
+            registry: self.registry.clone(),
+            budget: self.budget.clone(),
+            validator: self.validator.clone(),
+            context_hooks: self.context_hooks.clone(),
+            alert_topic: self.alert_topic.clone(),
+            reporter: self.reporter.clone(),
+            auto_create: self.auto_create.clone(),
+            codecs: self.codecs.clone(),
+            runtime: self.runtime.clone(),
+            archiver: self.archiver.clone(),
+            dead_letter_monitors: self.dead_letter_monitors.clone(),
+            in_flight: self.in_flight.clone(),
+            ack_policy: self.ack_policy.clone(),
+            job_types: self.job_types.clone(),
+            paused_job_types: self.paused_job_types.clone(),
+            #[cfg(feature = "consume")]
+            idle: self.idle.clone(),
+            checkpoint_store: self.checkpoint_store.clone(),
+            priority_tiers: self.priority_tiers.clone(),
+            spawner: self.spawner.clone(),
+            pipeline: self.pipeline.clone(),
+            sharding: self.sharding.clone(),
+            #[cfg(feature = "consume")]
+            breakers: self.breakers.clone(),
+            #[cfg(feature = "consume")]
+            shared_consumer: self.shared_consumer.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
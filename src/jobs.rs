@@ -0,0 +1,227 @@
+//! Settings overridden per job type
+//!
+//! A backend serving many kinds of jobs, for example with an enum of task
+//! arguments, applies one [`PubSubConfig`](crate::PubSubConfig) to all of
+//! them. With [`PubSubBackend::with_job_type`], a [`JobTypeConfig`] overrides
+//! some settings for the tasks of one job type:
+//!
+//! - [`timeout`](JobTypeConfig::timeout): tasks running longer fail with
+//!   [`TaskTimedOut`],
+//! - [`max_attempts`](JobTypeConfig::max_attempts): attempts made by
+//!   [`RepublishRetry`](crate::retry::RepublishRetry) and before alerting,
+//! - [`topic`](JobTypeConfig::topic): topic the tasks are published to,
+//!   for example a priority topic with its own workers,
+//! - [`codec`](JobTypeConfig::codec): codec the tasks are published with,
+//!   registered with [`PubSubBackend::with_codec`].
+//!
+//! The job type of a task is [`PubSubContext::job_type`], carried in the
+//! `job_type` attribute, and defaults to the type name of the task arguments.
+//!
+//! # Pausing job types
+//!
+//! When one job type misbehaves, [`PubSubBackend::pause_job_type`] stops the
+//! workers of the backend from running its tasks while the others keep
+//! flowing. Tasks of a paused job type are nacked as they reach the worker,
+//! with a deadline of [`PAUSED_REDELIVERY_DELAY`], so Pub/Sub redelivers them
+//! once the job type has had time to be resumed with
+//! [`PubSubBackend::resume_job_type`]. Tasks already running finish.
+//!
+//! Pausing applies to every clone of the backend. Workers listening on a
+//! control topic can also be told to pause a job type, see
+//! [`ControlCommand::PauseJobType`](crate::control::ControlCommand::PauseJobType).
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_core::{backend::TaskSink, task::builder::TaskBuilder};
+//! # use apalis_pubsub::{jobs::JobTypeConfig, utils::PubSubContext, PubSubBackend, PubSubCompact};
+//! # use std::time::Duration;
+//! # async fn example(
+//! #     backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>,
+//! # ) -> Result<(), Box<dyn std::error::Error>> {
+//! let mut backend = backend.with_job_type(
+//!     "report",
+//!     JobTypeConfig::default()
+//!         .with_timeout(Duration::from_secs(600))
+//!         .with_topic("reports"),
+//! );
+//! let ctx = PubSubContext::default().with_job_type("report");
+//! backend.push_task(TaskBuilder::new(42).with_ctx(ctx).build()).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+    time::Duration,
+};
+
+use crate::{utils::PubSubContext, PubSubBackend};
+#[cfg(feature = "consume")]
+use {crate::parts::PUBSUB_ATTRIBUTE_JOB_TYPE, google_cloud_googleapis::pubsub::v1::PubsubMessage};
+
+/// How long Pub/Sub waits before redelivering the tasks of a paused job type
+pub const PAUSED_REDELIVERY_DELAY: Duration = Duration::from_secs(60);
+
+/// Job type of the task with `ctx`, whose arguments are of type `M`
+fn job_type_of<M>(ctx: &PubSubContext) -> &str {
+    ctx.job_type().unwrap_or_else(|| std::any::type_name::<M>())
+}
+
+/// Job type of `message`, whose task arguments are of type `M`, before it's
+/// decoded
+#[cfg(feature = "consume")]
+pub(crate) fn message_job_type<M>(message: &PubsubMessage) -> &str {
+    message
+        .attributes
+        .get(PUBSUB_ATTRIBUTE_JOB_TYPE)
+        .map_or_else(|| std::any::type_name::<M>(), String::as_str)
+}
+
+/// Error of a task that ran past the timeout of its job type
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Task timed out after {0:?}")]
+pub struct TaskTimedOut(pub Duration);
+
+/// Settings overriding the backend's for one job type
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobTypeConfig {
+    /// Longest a task may run before failing (default: unlimited)
+    pub timeout: Option<Duration>,
+    /// Most times a task is attempted, counting the first (default: the
+    /// backend's)
+    pub max_attempts: Option<usize>,
+    /// Topic tasks are published to (default: the backend's)
+    pub topic: Option<String>,
+    /// Name of the codec tasks are published with (default: the backend's)
+    pub codec: Option<String>,
+}
+
+impl JobTypeConfig {
+    /// Fails tasks running longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Attempts tasks at most `max_attempts` times
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Publishes tasks to the topic `topic`
+    pub fn with_topic(mut self, topic: impl Into<String>) -> Self {
+        self.topic = Some(topic.into());
+        self
+    }
+
+    /// Publishes tasks with the codec registered as `codec`
+    pub fn with_codec(mut self, codec: impl Into<String>) -> Self {
+        self.codec = Some(codec.into());
+        self
+    }
+}
+
+/// The overrides of a backend by job type
+#[derive(Debug, Clone, Default)]
+pub(crate) struct JobTypes {
+    overrides: HashMap<String, JobTypeConfig>,
+}
+
+impl JobTypes {
+    /// Overrides of the job type of the task with `ctx`, whose arguments are
+    /// of type `M`
+    pub(crate) fn get<M>(&self, ctx: &PubSubContext) -> Option<&JobTypeConfig> {
+        if self.overrides.is_empty() {
+            return None;
+        }
+        self.overrides.get(job_type_of::<M>(ctx))
+    }
+
+    /// Overrides of `job_type`, added empty if it had none
+    pub(crate) fn entry(&mut self, job_type: String) -> &mut JobTypeConfig {
+        self.overrides.entry(job_type).or_default()
+    }
+}
+
+/// The job types whose tasks workers don't run, see
+/// [pausing job types](self#pausing-job-types)
+#[derive(Debug, Default)]
+pub(crate) struct PausedJobTypes {
+    paused: RwLock<HashSet<String>>,
+}
+
+impl PausedJobTypes {
+    /// Stops running the tasks of `job_type`, returning whether it was running
+    pub(crate) fn pause(&self, job_type: String) -> bool {
+        self.paused
+            .write()
+            .expect("paused job types lock poisoned")
+            .insert(job_type)
+    }
+
+    /// Runs the tasks of `job_type` again, returning whether it was paused
+    pub(crate) fn resume(&self, job_type: &str) -> bool {
+        self.paused
+            .write()
+            .expect("paused job types lock poisoned")
+            .remove(job_type)
+    }
+
+    /// Whether the task with `ctx`, whose arguments are of type `M`, is of a
+    /// paused job type
+    #[cfg(feature = "consume")]
+    pub(crate) fn is_paused<M>(&self, ctx: &PubSubContext) -> bool {
+        let paused = self.paused.read().expect("paused job types lock poisoned");
+        !paused.is_empty() && paused.contains(job_type_of::<M>(ctx))
+    }
+
+    /// Whether the job type `job_type` is paused
+    #[cfg(feature = "consume")]
+    pub(crate) fn contains(&self, job_type: &str) -> bool {
+        let paused = self.paused.read().expect("paused job types lock poisoned");
+        !paused.is_empty() && paused.contains(job_type)
+    }
+
+    /// The paused job types, sorted
+    fn list(&self) -> Vec<String> {
+        let paused = self.paused.read().expect("paused job types lock poisoned");
+        let mut list: Vec<_> = paused.iter().cloned().collect();
+        list.sort();
+        list
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Stops the workers of the backend from running the tasks of the job
+    /// type `job_type`, see [pausing job types](crate::jobs#pausing-job-types)
+    pub fn pause_job_type(&self, job_type: impl Into<String>) {
+        let job_type = job_type.into();
+        if self.paused_job_types.pause(job_type.clone()) {
+            tracing::info!(job_type, "Job type paused");
+        }
+    }
+
+    /// Lets the workers of the backend run the tasks of the job type
+    /// `job_type` again
+    pub fn resume_job_type(&self, job_type: &str) {
+        if self.paused_job_types.resume(job_type) {
+            tracing::info!(job_type, "Job type resumed");
+        }
+    }
+
+    /// The job types currently paused, sorted
+    pub fn paused_job_types(&self) -> Vec<String> {
+        self.paused_job_types.list()
+    }
+
+    /// Overrides settings for the tasks of the job type `job_type`
+    pub fn with_job_type(mut self, job_type: impl Into<String>, config: JobTypeConfig) -> Self {
+        std::sync::Arc::make_mut(&mut self.job_types)
+            .overrides
+            .insert(job_type.into(), config);
+        self
+    }
+}
@@ -0,0 +1,186 @@
+//! Leader election for singleton producers
+//!
+//! Schedulers and cron-like producers usually run on every replica of a
+//! service, yet recurring tasks must only be published once. A
+//! [`LeaderElection`] lets the replicas compete for a named lease in a
+//! [`LeaseStore`]; only the holder of the lease is the leader.
+//!
+//! The leader renews its lease at a third of the lease duration. If it stops
+//! renewing, for example because it crashed, another replica takes over once
+//! the lease expires. A leader that can't reach the store stops considering
+//! itself leader when its lease runs out, so two replicas never both believe
+//! they lead as long as their clocks agree on the lease duration.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use std::{sync::Arc, time::Duration};
+//! # use apalis_pubsub::leader::{InMemoryLeaseStore, LeaderElection};
+//! # async fn example() {
+//! let election = Arc::new(LeaderElection::new(
+//!     Arc::new(InMemoryLeaseStore::default()),
+//!     "nightly-report-scheduler",
+//!     Duration::from_secs(30),
+//! ));
+//! let cancel = tokio_util::sync::CancellationToken::new();
+//! tokio::spawn(election.clone().run(cancel));
+//!
+//! // In the scheduling loop
+//! if election.is_leader() {
+//!     // publish the recurring task
+//! }
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use apalis_core::timer::sleep;
+use futures::{future::BoxFuture, FutureExt};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::PubSubError;
+
+/// Storage for named, expiring leases
+///
+/// Implementations must make [`LeaseStore::try_acquire`] atomic across every
+/// process competing for a lease, e.g. with a conditional write.
+pub trait LeaseStore: Send + Sync {
+    /// Acquires the lease `name` for `holder`, or renews it if `holder` already
+    /// holds it, for `ttl`
+    ///
+    /// Returns `false` if another holder has an unexpired lease.
+    fn try_acquire(
+        &self,
+        name: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> BoxFuture<'_, Result<bool, PubSubError>>;
+
+    /// Gives up the lease `name` if `holder` holds it
+    fn release(&self, name: &str, holder: &str) -> BoxFuture<'_, Result<(), PubSubError>>;
+}
+
+/// A [`LeaseStore`] that keeps leases in process memory
+///
+/// Only useful to elect a leader among tasks of a single process, or in tests.
+#[derive(Debug, Default)]
+pub struct InMemoryLeaseStore {
+    leases: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl LeaseStore for InMemoryLeaseStore {
+    fn try_acquire(
+        &self,
+        name: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> BoxFuture<'_, Result<bool, PubSubError>> {
+        let mut leases = self.leases.lock().unwrap();
+        let now = Instant::now();
+        let acquired = match leases.get(name) {
+            Some((current, expires)) => current == holder || *expires <= now,
+            None => true,
+        };
+        if acquired {
+            leases.insert(name.to_string(), (holder.to_string(), now + ttl));
+        }
+        futures::future::ready(Ok(acquired)).boxed()
+    }
+
+    fn release(&self, name: &str, holder: &str) -> BoxFuture<'_, Result<(), PubSubError>> {
+        let mut leases = self.leases.lock().unwrap();
+        if leases
+            .get(name)
+            .is_some_and(|(current, _)| current == holder)
+        {
+            leases.remove(name);
+        }
+        futures::future::ready(Ok(())).boxed()
+    }
+}
+
+/// Competes for a named lease to decide which replica leads
+pub struct LeaderElection {
+    store: Arc<dyn LeaseStore>,
+    name: String,
+    holder: String,
+    ttl: Duration,
+    /// Until when the lease is known to be held by this replica
+    leader_until: Mutex<Option<Instant>>,
+}
+
+impl LeaderElection {
+    /// Creates an election for the lease `name`, held for `ttl` at a time
+    ///
+    /// The replica identifies itself with a random id, see
+    /// [`LeaderElection::with_holder`] to choose one.
+    pub fn new(store: Arc<dyn LeaseStore>, name: &str, ttl: Duration) -> Self {
+        Self {
+            store,
+            name: name.to_string(),
+            holder: Uuid::new_v4().to_string(),
+            ttl,
+            leader_until: Mutex::new(None),
+        }
+    }
+
+    /// Identifies this replica as `holder`, e.g. its hostname
+    pub fn with_holder(mut self, holder: &str) -> Self {
+        self.holder = holder.to_string();
+        self
+    }
+
+    /// The id this replica holds the lease under
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Whether this replica currently holds the lease
+    pub fn is_leader(&self) -> bool {
+        self.leader_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Tries to acquire or renew the lease once, returning whether this replica leads
+    pub async fn try_lead(&self) -> Result<bool, PubSubError> {
+        let attempted_at = Instant::now();
+        let acquired = self
+            .store
+            .try_acquire(&self.name, &self.holder, self.ttl)
+            .await?;
+        let was_leader = self.is_leader();
+        *self.leader_until.lock().unwrap() = acquired.then_some(attempted_at + self.ttl);
+        match (was_leader, acquired) {
+            (false, true) => {
+                tracing::info!(lease = self.name, holder = self.holder, "Became leader")
+            }
+            (true, false) => {
+                tracing::warn!(lease = self.name, holder = self.holder, "Lost leadership")
+            }
+            _ => {}
+        }
+        Ok(acquired)
+    }
+
+    /// Competes for the lease until `cancel` fires, then releases it
+    pub async fn run(self: Arc<Self>, cancel: CancellationToken) {
+        let interval = self.ttl / 3;
+        while !cancel.is_cancelled() {
+            if let Err(e) = self.try_lead().await {
+                tracing::warn!(error = %e, lease = self.name, "Failed to acquire lease");
+            }
+            cancel.run_until_cancelled(sleep(interval)).await;
+        }
+
+        *self.leader_until.lock().unwrap() = None;
+        if let Err(e) = self.store.release(&self.name, &self.holder).await {
+            tracing::warn!(error = %e, lease = self.name, "Failed to release lease");
+        }
+    }
+}
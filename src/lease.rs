@@ -0,0 +1,214 @@
+//! Lease extension for buffered messages and running tasks
+//!
+//! A received message is leased to the worker for the subscription's stream
+//! ack deadline. Messages that wait in the local buffer longer than that are
+//! redelivered elsewhere while still queued here. With
+//! [`PubSubConfig::lease_extension`](crate::PubSubConfig::lease_extension) the
+//! backend keeps extending the leases of buffered messages until the worker
+//! takes them.
+//!
+//! A [`LeasePolicy`] bounds how far leases are extended. A worker that stops
+//! taking tasks, for example because its handlers are stuck, eventually loses
+//! the leases of its buffered messages so other workers can process them.
+//! Messages whose lease ran out are dropped instead of being dispatched.
+//!
+//! # Running tasks
+//!
+//! When acknowledgement is deferred until the task finished, see
+//! [`outcome`](crate::outcome), a task running longer than its ack deadline
+//! would be redelivered while it still runs. With
+//! [`LeasePolicy::extend_running`], the default, leases also keep being
+//! extended while the task runs, until its message is settled or
+//! [`max_total_extension`](LeasePolicy::max_total_extension) after it was
+//! received.
+use std::time::Duration;
+#[cfg(feature = "consume")]
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+#[cfg(feature = "consume")]
+use google_cloud_pubsub::subscriber::SubscriberConfig;
+#[cfg(feature = "consume")]
+use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "consume")]
+use crate::{
+    ack::MAX_ACK_IDS_PER_REQUEST, clock::Clock, inflight::InFlight, transport::Subscriber,
+};
+
+/// Longest ack deadline Pub/Sub accepts
+pub(crate) const MAX_ACK_DEADLINE: Duration = Duration::from_secs(600);
+
+/// Shortest ack deadline Pub/Sub accepts
+#[cfg(feature = "consume")]
+const MIN_ACK_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Bounds on lease extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeasePolicy {
+    /// How far a lease is extended at a time (default: 60s)
+    ///
+    /// Pub/Sub accepts between 10 seconds and 10 minutes.
+    pub max_extension: Duration,
+    /// How long after receiving a message its lease is extended at most
+    /// (default: 10 minutes)
+    pub max_total_extension: Duration,
+    /// Keep extending the leases of messages while their task runs, when
+    /// acknowledgement is deferred (default: true)
+    pub extend_running: bool,
+}
+
+impl Default for LeasePolicy {
+    fn default() -> Self {
+        Self {
+            max_extension: Duration::from_secs(60),
+            max_total_extension: Duration::from_secs(10 * 60),
+            extend_running: true,
+        }
+    }
+}
+
+#[cfg(feature = "consume")]
+impl LeasePolicy {
+    fn extension(&self) -> Duration {
+        self.max_extension.clamp(MIN_ACK_DEADLINE, MAX_ACK_DEADLINE)
+    }
+}
+
+/// Lease of a buffered message
+#[cfg(feature = "consume")]
+struct Lease {
+    received_at: Instant,
+    expires_at: Instant,
+    /// Whether the message's task is running, rather than it being buffered
+    running: bool,
+}
+
+/// Extends the leases of buffered messages, and of running tasks
+#[cfg(feature = "consume")]
+pub(crate) struct LeaseKeeper {
+    subscriber: Subscriber,
+    policy: LeasePolicy,
+    clock: Arc<dyn Clock>,
+    /// Tells when the messages of running tasks were settled
+    in_flight: Arc<InFlight>,
+    /// Leases of buffered messages by ack id
+    leases: Mutex<HashMap<String, Lease>>,
+}
+
+#[cfg(feature = "consume")]
+impl LeaseKeeper {
+    pub(crate) fn new(
+        subscriber: Subscriber,
+        policy: LeasePolicy,
+        clock: Arc<dyn Clock>,
+        in_flight: Arc<InFlight>,
+    ) -> Self {
+        Self {
+            subscriber,
+            policy,
+            clock,
+            in_flight,
+            leases: Mutex::default(),
+        }
+    }
+
+    /// Starts tracking the lease of a message just received
+    pub(crate) fn track(&self, ack_id: &str) {
+        let now = self.clock.instant();
+        let deadline =
+            Duration::from_secs(SubscriberConfig::default().stream_ack_deadline_seconds as u64);
+        self.leases.lock().unwrap().insert(
+            ack_id.to_string(),
+            Lease {
+                received_at: now,
+                expires_at: now + deadline,
+                running: false,
+            },
+        );
+    }
+
+    /// Stops tracking a message, returning whether its lease is still held
+    pub(crate) fn release(&self, ack_id: &str) -> bool {
+        self.leases
+            .lock()
+            .unwrap()
+            .remove(ack_id)
+            .is_none_or(|lease| self.clock.instant() < lease.expires_at)
+    }
+
+    /// Marks a message as taken by the worker for a task whose message is
+    /// settled after it runs, returning whether its lease is still held
+    ///
+    /// Its lease keeps being extended until it's settled if the policy says
+    /// so, and is released otherwise.
+    pub(crate) fn dispatch(&self, ack_id: &str) -> bool {
+        if !self.policy.extend_running {
+            return self.release(ack_id);
+        }
+        let now = self.clock.instant();
+        let mut leases = self.leases.lock().unwrap();
+        match leases.get_mut(ack_id) {
+            Some(lease) if now < lease.expires_at => {
+                lease.running = true;
+                true
+            }
+            Some(_) => {
+                leases.remove(ack_id);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Extends leases within the policy until `cancel` fires
+    pub(crate) async fn run(self: Arc<Self>, cancel: CancellationToken) {
+        let extension = self.policy.extension();
+        while cancel
+            .run_until_cancelled(self.clock.sleep(extension / 2))
+            .await
+            .is_some()
+        {
+            let now = self.clock.instant();
+            let ack_ids: Vec<_> = {
+                let mut leases = self.leases.lock().unwrap();
+                // Tasks that finished settled their message
+                leases.retain(|ack_id, lease| !lease.running || self.in_flight.contains(ack_id));
+                leases
+                    .iter()
+                    .filter(|(_, lease)| {
+                        now < lease.expires_at
+                            && now + extension
+                                <= lease.received_at + self.policy.max_total_extension
+                    })
+                    .map(|(ack_id, _)| ack_id.clone())
+                    .collect()
+            };
+            if ack_ids.is_empty() {
+                continue;
+            }
+
+            for chunk in ack_ids.chunks(MAX_ACK_IDS_PER_REQUEST) {
+                match self
+                    .subscriber
+                    .modify_ack_deadline(chunk.to_vec(), extension.as_secs() as i32)
+                    .await
+                {
+                    Ok(_) => {
+                        let mut leases = self.leases.lock().unwrap();
+                        for ack_id in chunk {
+                            if let Some(lease) = leases.get_mut(ack_id) {
+                                lease.expires_at = now + extension;
+                            }
+                        }
+                        tracing::debug!(count = chunk.len(), "Extended leases");
+                    }
+                    Err(e) => tracing::warn!(error = ?e, "Failed to extend leases"),
+                }
+            }
+        }
+    }
+}
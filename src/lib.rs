@@ -1,44 +1,228 @@
 use apalis_core::{
-    backend::{codec::Codec, queue::Queue, Backend, BackendExt, TaskStream},
+    backend::TaskSinkError,
+    error::BoxDynError,
     task::{builder::TaskBuilder, task_id::TaskId, Task},
-    worker::context::WorkerContext,
+    timer::sleep,
 };
-use futures::StreamExt;
+use futures::future::{self, select};
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
 use google_cloud_pubsub::{
     client::{Client, ClientConfig},
-    subscription::Subscription,
     topic::Topic,
 };
 use std::task::{Context, Poll};
-use std::{marker::PhantomData, str::FromStr};
-use tokio_stream::wrappers::ReceiverStream;
-use tower::Layer;
-use tower::Service;
+use std::{
+    marker::PhantomData,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tower::{
+    layer::util::{Identity, Stack},
+    load_shed::LoadShedLayer,
+    util::{option_layer, Either},
+    Layer, Service,
+};
 use uuid::Uuid;
+#[cfg(feature = "consume")]
+use {
+    apalis_core::{
+        backend::{codec::Codec, queue::Queue, Backend, BackendExt, TaskStream},
+        worker::context::WorkerContext,
+    },
+    futures::FutureExt,
+    google_cloud_gax::grpc::Status,
+    std::time::SystemTime,
+    tokio::sync::mpsc::error::SendError,
+};
 
+pub mod abort;
+pub mod ack;
+pub mod admin;
+pub mod alert;
+pub mod archive;
+pub mod backoff;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "consume")]
+pub mod breaker;
+pub mod budget;
+pub mod cancel;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod checkpoint;
+pub mod clock;
+pub mod codecs;
+pub mod config;
+pub mod contract;
+pub mod control;
+#[cfg(feature = "consume")]
+mod dispatch;
+pub mod dlq;
+pub mod envelope;
+pub mod extensions;
+pub mod extract;
+pub mod heartbeat;
+#[cfg(feature = "consume")]
+pub mod idle;
+pub mod inflight;
+mod inner;
+pub mod jobs;
+pub mod leader;
+pub mod lease;
+#[cfg(feature = "local")]
+pub mod local;
+pub mod manual;
+pub mod outcome;
+pub mod parts;
+pub mod peek;
+pub mod pipeline;
+pub mod prefetch;
+pub mod priority;
+#[cfg(feature = "publish")]
+pub mod producer;
+pub mod provision;
+#[cfg(feature = "publish")]
+mod publisher;
+#[cfg(feature = "push")]
+pub mod push;
+#[cfg(feature = "record")]
+pub mod record;
+pub mod registry;
+pub mod reload;
+pub mod report;
+pub mod respond;
+pub mod results;
+pub mod retry;
+pub mod saga;
+pub mod sampling;
+pub mod schedule;
+pub mod shard;
+#[cfg(feature = "consume")]
+pub mod shared;
 mod sink;
+pub mod snapshot;
+pub mod spawn;
+pub mod stats;
+pub mod streaming;
+pub mod transport;
 pub mod utils;
+pub mod validate;
+#[cfg(feature = "consume")]
+mod watch;
+pub mod workflow;
 use utils::PubSubContext;
 
 pub use google_cloud_pubsub;
 
-use crate::sink::PubSubSink;
+#[cfg(feature = "consume")]
+use crate::{
+    ack::AckMode,
+    alert::AlertLayer,
+    dispatch::{Dispatcher, Received},
+    lease::LeaseKeeper,
+    outcome::AckDecision,
+    provision::is_not_found,
+    report::{report, ErrorReport, FailureKind, ReportLayer},
+    retry::RepublishRetryLayer,
+    sampling::PayloadSampler,
+    transport::MessageHandler,
+};
+use crate::{
+    backoff::{BackoffStrategy, Exponential},
+    budget::BufferBudget,
+    cancel::Cancellations,
+    clock::{Clock, SystemClock},
+    codecs::Codecs,
+    control::{ConcurrencyControl, ConcurrencyControlLayer},
+    envelope::WireFormat,
+    extensions::{apply_hooks, ContextHook},
+    heartbeat::HealthCheck,
+    inflight::InFlight,
+    inner::BackendInner,
+    jobs::{JobTypes, TaskTimedOut},
+    lease::LeasePolicy,
+    outcome::{AckStrategy, Acker},
+    parts::MessageAttributes,
+    prefetch::AdaptivePrefetch,
+    reload::{ConfigHandle, RuntimeConfig},
+    retry::RepublishRetry,
+    sampling::PayloadSampling,
+    schedule::HoldScheduled,
+    sink::PubSubSink,
+    stats::PubSubStats,
+    transport::{GcpTransport, PubSubTransport, Subscriber, TransportMessage},
+};
 
 /// Middleware layer that acknowledges messages on successful completion
 #[derive(Clone)]
-pub struct PubSubLayer;
+pub struct PubSubLayer {
+    stats: Arc<PubSubStats>,
+    cancellations: Arc<Cancellations>,
+    acker: Option<Acker>,
+    failures_retried: bool,
+    job_types: Arc<JobTypes>,
+}
+
+impl PubSubLayer {
+    /// Creates a layer recording task outcomes in `stats` and tracking
+    /// running tasks in `cancellations`
+    pub fn new(stats: Arc<PubSubStats>, cancellations: Arc<Cancellations>) -> Self {
+        Self {
+            stats,
+            cancellations,
+            acker: None,
+            failures_retried: false,
+            job_types: Arc::default(),
+        }
+    }
+
+    /// Applies the timeouts of `job_types`, see [`jobs`]
+    #[cfg(feature = "consume")]
+    pub(crate) fn with_job_types(mut self, job_types: Arc<JobTypes>) -> Self {
+        self.job_types = job_types;
+        self
+    }
+
+    /// Settles messages with `acker` once their task finished, see [`outcome`]
+    #[cfg(feature = "consume")]
+    pub(crate) fn with_acker(mut self, acker: Option<Acker>) -> Self {
+        self.acker = acker;
+        self
+    }
+
+    /// Leaves the messages of failed tasks to the [`RepublishRetryLayer`]
+    /// around this one, see [`retry`]
+    #[cfg(feature = "consume")]
+    pub(crate) fn with_failures_retried(mut self, failures_retried: bool) -> Self {
+        self.failures_retried = failures_retried;
+        self
+    }
+}
 
 impl<S> Layer<S> for PubSubLayer {
     type Service = PubSubService<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        PubSubService { inner: service }
+        PubSubService {
+            inner: service,
+            stats: self.stats.clone(),
+            cancellations: self.cancellations.clone(),
+            acker: self.acker.clone(),
+            failures_retried: self.failures_retried,
+            job_types: self.job_types.clone(),
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct PubSubService<S> {
     inner: S,
+    stats: Arc<PubSubStats>,
+    cancellations: Arc<Cancellations>,
+    acker: Option<Acker>,
+    failures_retried: bool,
+    job_types: Arc<JobTypes>,
 }
 
 impl<S, M> Service<PubSubTask<M>> for PubSubService<S>
@@ -46,7 +230,7 @@ where
     S: Service<PubSubTask<M>>,
     S::Future: Send + 'static,
     S::Response: Send + 'static,
-    S::Error: Send + 'static,
+    S::Error: std::fmt::Display + From<BoxDynError> + Send + 'static,
     M: Send + 'static,
 {
     type Response = S::Response;
@@ -60,12 +244,69 @@ where
     }
 
     fn call(&mut self, req: PubSubTask<M>) -> Self::Future {
-        // We don't need to do anything special in our tower service,
-        // so just pass execution down the tree
-        Box::pin(self.inner.call(req))
+        let stats = self.stats.clone();
+        let cancellations = self.cancellations.clone();
+        let settle = self
+            .acker
+            .clone()
+            .map(|acker| (acker, req.parts.ctx.clone()));
+        let failures_retried = self.failures_retried;
+        let timeout = self
+            .job_types
+            .get::<M>(&req.parts.ctx)
+            .and_then(|overrides| overrides.timeout);
+        let task_id = req.parts.task_id.map(|id| *id.inner());
+        let checkpoint = req.parts.ctx.checkpoint_state().clone();
+        let started_at = Instant::now();
+        stats.record_started();
+        if let Some(task_id) = task_id {
+            cancellations.start(task_id, &req.parts.ctx);
+        }
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let res = match timeout {
+                Some(timeout) => {
+                    match select(std::pin::pin!(future), std::pin::pin!(sleep(timeout))).await {
+                        future::Either::Left((res, _)) => res,
+                        future::Either::Right(_) => {
+                            tracing::warn!(?task_id, ?timeout, "Task timed out");
+                            Err(BoxDynError::from(TaskTimedOut(timeout)).into())
+                        }
+                    }
+                }
+                None => future.await,
+            };
+            stats.record_latency(std::any::type_name::<M>(), started_at.elapsed());
+            if let Some(task_id) = task_id {
+                cancellations.finish(&task_id);
+            }
+            stats.record_finished(res.is_ok());
+            if res.is_ok() {
+                checkpoint.clear().await;
+            }
+            if let Some((acker, ctx)) = settle {
+                match &res {
+                    Ok(_) => acker.settle(&ctx, acker.decide(&ctx, Ok(()))).await,
+                    // The retry layer settles failures once it republished them
+                    Err(_) if failures_retried => {}
+                    Err(error) => {
+                        let decision = acker.decide_error(&ctx, error);
+                        acker.settle_error(&ctx, decision, &error.to_string()).await
+                    }
+                }
+            }
+            res
+        })
     }
 }
 
+/// Overload protection layers configured through [`PubSubConfig`]
+///
+/// Tasks first pass through the optional [`LoadShedLayer`], then through the
+/// [`ConcurrencyControlLayer`], which lets every task through until a limit is
+/// set. A disabled load shed layer is replaced by [`Identity`].
+pub type OverloadLayer = Stack<ConcurrencyControlLayer, Either<LoadShedLayer, Identity>>;
+
 /// Error type for PubSub backend operations
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum PubSubError {
@@ -75,8 +316,41 @@ pub enum PubSubError {
     #[error("Message acknowledgment failed: {0}")]
     AckFailed(String),
 
+    #[error("Ack deadline expired, the message will be redelivered: {0}")]
+    AckExpired(String),
+
     #[error("Subscription error: {0}")]
     Subscription(String),
+
+    #[error("Subscription is gone: {0}")]
+    SubscriptionGone(String),
+
+    #[error("Codec error: {0}")]
+    Codec(String),
+
+    #[error("State store error: {0}")]
+    Store(String),
+
+    #[error("Invalid workflow: {0}")]
+    InvalidWorkflow(String),
+
+    #[error("Unauthorized push delivery: {0}")]
+    Unauthorized(String),
+
+    #[error("Invalid provisioning: {0}")]
+    Provisioning(String),
+
+    #[error("I/O error: {0}")]
+    Io(String),
+}
+
+impl From<TaskSinkError<PubSubError>> for PubSubError {
+    fn from(err: TaskSinkError<PubSubError>) -> Self {
+        match err {
+            TaskSinkError::PushError(e) => e,
+            TaskSinkError::CodecError(e) => PubSubError::Codec(e.to_string()),
+        }
+    }
 }
 
 /// Type alias for an PubSub task with context and [`PubSubTaskId`] as the task ID type.
@@ -90,36 +364,283 @@ pub type PubSubTaskId = Uuid;
 /// Task arguments are compressed to this format using the selected [`Codec`]
 pub type PubSubCompact = Vec<u8>;
 
+/// A task Pub/Sub accepted, identified for correlating it with other systems
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishedTask {
+    /// Id Pub/Sub assigned to the task's message
+    pub message_id: String,
+    /// Id of the task, which workers see as its task id
+    pub task_id: PubSubTaskId,
+}
+
 /// Name of the task id attribute in pub/sub
 ///
 /// pub/sub attributes just map string keys to string values,
 /// so we make a constant for the key.
-pub(crate) const PUBSUB_ATTRIBUTE_TASK_ID: &'static str = "task_id";
+pub(crate) const PUBSUB_ATTRIBUTE_TASK_ID: &str = "task_id";
+
+/// Reads the task id attribute of a message
+pub(crate) fn message_task_id(message: &PubsubMessage) -> Option<PubSubTaskId> {
+    message
+        .attributes
+        .get(PUBSUB_ATTRIBUTE_TASK_ID)
+        .and_then(|s| {
+            Uuid::from_str(s)
+                .inspect_err(|e| tracing::error!("Failed to deserialize task id: {e}"))
+                .ok()
+        })
+}
+
+/// Time since `message` was published at `now`, if known
+#[cfg(feature = "consume")]
+fn message_age(message: &PubsubMessage, now: SystemTime) -> Option<Duration> {
+    let published = message.publish_time.as_ref()?;
+    let published = std::time::UNIX_EPOCH
+        + Duration::new(
+            published.seconds.try_into().ok()?,
+            published.nanos.try_into().ok()?,
+        );
+    now.duration_since(published).ok()
+}
+
+/// Builds the task handed to workers for a received message
+pub(crate) fn received_task<M>(
+    args: M,
+    message: &TransportMessage,
+    task_id: Option<PubSubTaskId>,
+    context_hooks: &[ContextHook],
+) -> PubSubTask<M> {
+    message_task(
+        args,
+        &message.message,
+        message.ack_id(),
+        message.delivery_attempt(),
+        task_id,
+        context_hooks,
+    )
+}
+
+/// Builds the task for `message`, acknowledged with `ack_id`
+pub(crate) fn message_task<M>(
+    args: M,
+    message: &PubsubMessage,
+    ack_id: &str,
+    delivery_attempt: Option<usize>,
+    task_id: Option<PubSubTaskId>,
+    context_hooks: &[ContextHook],
+) -> PubSubTask<M> {
+    let attributes = MessageAttributes::from_map(&message.attributes);
+    let mut ctx = attributes.restore_context(PubSubContext::new(ack_id.to_string()));
+    ctx.set_payload_hash(report::payload_hash(&message.data));
+    ctx.set_delivery(message.message_id.clone(), delivery_attempt);
+    ctx.set_ordering_key(&message.ordering_key);
+    ctx.set_message_attributes(message.attributes.clone());
+    apply_hooks(context_hooks, message, &mut ctx);
+    let mut task = attributes.restore_parts(TaskBuilder::new(args).with_ctx(ctx));
+    if let Some(task_id) = task_id {
+        task = task.with_task_id(TaskId::new(task_id))
+    }
+    task.build()
+}
 
 /// Configuration for PubSub backend behavior
 #[derive(Debug, Clone)]
 pub struct PubSubConfig {
     /// Channel buffer size for message processing (default: 100)
+    ///
+    /// This and the flow control limits below can change at runtime, see
+    /// [`reload`].
     pub buffer_size: usize,
     /// Maximum message size in bytes (default: 10MB)
     pub max_message_size: usize,
-    /// Maximum number of outstanding messages
+    /// Maximum number of outstanding messages (default: the subscriber's
+    /// default of 50)
+    ///
+    /// Pub/Sub stops delivering once this many messages are leased to the
+    /// worker and not yet settled. Earlier versions didn't pass this limit to
+    /// the streaming pull, so setting it now throttles delivery.
     pub max_outstanding_messages: Option<i64>,
-    /// Maximum bytes of outstanding messages
+    /// Maximum bytes of outstanding messages (default: the subscriber's
+    /// default of 1GB)
+    ///
+    /// Enforced by the streaming pull like `max_outstanding_messages`.
     pub max_outstanding_bytes: Option<i64>,
+    /// Maximum number of tasks a worker processes concurrently (default: unlimited)
+    pub concurrency_limit: Option<usize>,
+    /// Fail tasks immediately instead of waiting when the worker is at its
+    /// concurrency limit (default: false)
+    ///
+    /// Shed tasks are reported to the worker as errors.
+    pub load_shed: bool,
+    /// Whether the subscription has exactly-once delivery enabled (default: false)
+    ///
+    /// Acknowledgements are then checked and retried on transient failures.
+    /// [`PubSubBackend::ensure_resources`] sets this when it provisions an
+    /// exactly-once subscription.
+    pub exactly_once: bool,
+    /// When messages are acknowledged (default: once the worker takes them)
+    ///
+    /// See [`outcome`] for acknowledging them after their task instead. With
+    /// `republish_retry` as well, failed tasks are republished rather than
+    /// nacked, see [`retry`]. The manual strategy can't be combined with
+    /// `republish_retry`.
+    pub ack_strategy: AckStrategy,
+    /// Maximum number of times the worker attempts a task before giving up
+    /// (default: none, each delivery is attempted once)
+    ///
+    /// This describes retries made by the worker itself, for example with
+    /// apalis' retry layer. Provisioning a dead-letter policy warns when both
+    /// retry layers apply.
+    pub max_attempts: Option<usize>,
+    /// Size the number of buffered messages to the worker's processing rate
+    /// (default: none, up to `buffer_size` messages are buffered)
+    ///
+    /// See [`prefetch`] for how the window is sized.
+    pub adaptive_prefetch: Option<AdaptivePrefetch>,
+    /// Most tasks handed to the worker per second (default: unlimited)
+    pub max_tasks_per_second: Option<f64>,
+    /// Oldest a message may be when received (default: unlimited)
+    ///
+    /// Older messages are acknowledged and dropped without running, for tasks
+    /// that are pointless once stale.
+    pub max_age: Option<Duration>,
+    /// Warn when a task waits longer than this in the local buffer before the
+    /// worker takes it (default: 30s)
+    ///
+    /// Such waits mean the worker itself is too slow, rather than the
+    /// subscription being backed up. They are counted in
+    /// [`PubSubStats::slow_dispatches`](stats::PubSubStats::slow_dispatches).
+    pub slow_dispatch_threshold: Option<Duration>,
+    /// Keep extending the leases of buffered messages, and of running tasks
+    /// when acknowledgement is deferred, within this policy (default: none,
+    /// leases expire after the stream ack deadline)
+    ///
+    /// See [`lease`] for details.
+    pub lease_extension: Option<LeasePolicy>,
+    /// Ack deadline [`PubSubBackend::ensure_resources`] gives the worker
+    /// subscription when creating it (default: none, Pub/Sub's 10 seconds)
+    pub ack_deadline: Option<Duration>,
+    /// Longest a task is expected to run (default: none)
+    ///
+    /// Like `max_attempts`, this describes a timeout applied by the worker
+    /// itself, for example with apalis' timeout layer. Provisioning warns when
+    /// it exceeds the subscription's ack deadline without lease extension.
+    pub task_timeout: Option<Duration>,
+    /// Most payload bytes buffered for the worker and in the sink together
+    /// (default: unlimited)
+    ///
+    /// See [`budget`] for how the limit is enforced.
+    pub max_buffered_bytes: Option<usize>,
+    /// Log a sample of received payloads at debug level (default: none)
+    ///
+    /// See [`sampling`] for how payloads are sampled.
+    pub payload_sampling: Option<PayloadSampling>,
+    /// Most tasks the sink publishes at once (default: 100)
+    ///
+    /// Tasks sent to the sink beyond this wait in a queue of the same size,
+    /// and sending waits once that's full.
+    pub publish_concurrency: usize,
+    /// How published tasks are laid out in messages (default: attributes)
+    ///
+    /// See [`envelope`] for the alternative. Consumers read both formats.
+    pub wire_format: WireFormat,
+    /// Publish failed tasks again for another attempt (default: none)
+    ///
+    /// See [`retry`] for how retries are published.
+    pub republish_retry: Option<RepublishRetry>,
+    /// Delays between retries of acknowledgements, publishes and republished
+    /// tasks (default: exponential from 100ms)
+    ///
+    /// See [`backoff`] for what it applies to.
+    pub backoff: Arc<dyn BackoffStrategy>,
+    /// How often workers check their subscription still exists and is
+    /// attached to its topic (default: 60s)
+    ///
+    /// Workers stop with [`PubSubError::SubscriptionGone`] once it's gone.
+    /// `None` disables the checks, and so does
+    /// [`health_check`](Self::health_check), whose probes check it too.
+    pub subscription_check_interval: Option<Duration>,
+    /// How workers probe their connection to the subscription from their
+    /// heartbeat (default: none)
+    ///
+    /// See [`heartbeat`] for what the probes check.
+    pub health_check: Option<HealthCheck>,
+    /// Hold messages received before their `run_at` until they're due
+    /// (default: none, messages run as soon as they're received)
+    ///
+    /// See [`schedule`] for how messages are held. Tasks pushed with
+    /// [`PubSubBackend::push_scheduled`] are held either way, as this says
+    /// or with the default [`HoldScheduled`].
+    pub hold_scheduled: Option<HoldScheduled>,
+    /// Tasks a worker takes before it stops (default: unlimited)
+    ///
+    /// Once the worker took this many tasks, the backend stops pulling, nacks
+    /// the messages still buffered and ends the worker's stream, so the
+    /// worker completes after its in-flight tasks. Restarting workers this
+    /// way bounds the damage of memory leaks and lets supervisors roll them.
+    pub max_tasks_per_worker: Option<usize>,
+    /// Topic messages that can't be processed are republished to before
+    /// they're dropped (default: none, they're only dropped)
+    ///
+    /// See [`dlq`] for which messages are dead-lettered.
+    pub dead_letter_topic: Option<String>,
+    /// Source of the time for delays, retries, lease extension and message
+    /// age checks (default: the system clock)
+    ///
+    /// See [`clock`] for what it applies to, and for a clock tests can move.
+    pub clock: Arc<dyn Clock>,
 }
 
 impl Default for PubSubConfig {
     fn default() -> Self {
         Self {
             buffer_size: 100,
-            max_message_size: 10 * 1024 * 1024,
+            max_message_size: config::MAX_MESSAGE_SIZE,
             max_outstanding_messages: None,
             max_outstanding_bytes: None,
+            concurrency_limit: None,
+            load_shed: false,
+            exactly_once: false,
+            ack_strategy: AckStrategy::OnReceive,
+            max_attempts: None,
+            adaptive_prefetch: None,
+            max_tasks_per_second: None,
+            max_age: None,
+            slow_dispatch_threshold: Some(Duration::from_secs(30)),
+            lease_extension: None,
+            ack_deadline: None,
+            task_timeout: None,
+            max_buffered_bytes: None,
+            payload_sampling: None,
+            publish_concurrency: 100,
+            wire_format: WireFormat::Attributes,
+            republish_retry: None,
+            backoff: Arc::new(Exponential::default()),
+            subscription_check_interval: Some(Duration::from_secs(60)),
+            health_check: None,
+            hold_scheduled: None,
+            max_tasks_per_worker: None,
+            dead_letter_topic: None,
+            clock: Arc::new(SystemClock),
         }
     }
 }
 
+impl PubSubConfig {
+    /// Builds the [`OverloadLayer`] described by this configuration
+    pub fn overload_layer(&self) -> OverloadLayer {
+        self.overload_layer_with(Arc::new(ConcurrencyControl::new(self.concurrency_limit)))
+    }
+
+    /// Builds the [`OverloadLayer`] with its concurrency limit taken from `concurrency`
+    fn overload_layer_with(&self, concurrency: Arc<ConcurrencyControl>) -> OverloadLayer {
+        Stack::new(
+            ConcurrencyControlLayer::new(concurrency),
+            option_layer(self.load_shed.then_some(LoadShedLayer::new())),
+        )
+    }
+}
+
 /// A Google Cloud Pub/Sub backend for Apalis job processing.
 ///
 /// This backend provides reliable message queue functionality using GCP Pub/Sub,
@@ -128,6 +649,7 @@ impl Default for PubSubConfig {
 /// # Example
 ///
 /// ```no_run
+/// use apalis_core::backend::TaskSink;
 /// use apalis_pubsub::{PubSubBackend, PubSubCompact, PubSubConfig};
 /// use apalis_codec::json::JsonCodec;
 /// use google_cloud_pubsub::client::ClientConfig;
@@ -142,7 +664,7 @@ impl Default for PubSubConfig {
 /// let config = ClientConfig::default().with_auth().await?;
 ///
 /// // Create backend with default configuration
-/// let backend: PubSubBackend<MyJob, JsonCodec<PubSubCompact> =
+/// let mut backend: PubSubBackend<MyJob, JsonCodec<PubSubCompact>> =
 ///     PubSubBackend::new_from_config(
 ///         config,
 ///         "my-topic".to_string(),
@@ -161,7 +683,8 @@ impl Default for PubSubConfig {
 /// With custom configuration:
 ///
 /// ```no_run
-/// # use apalis_pubsub::{PubSubBackend, PubSubCompact PubSubConfig};
+/// # use apalis_core::backend::TaskSink;
+/// # use apalis_pubsub::{PubSubBackend, PubSubCompact, PubSubConfig};
 /// # use apalis_codec::json::JsonCodec;
 /// # use google_cloud_pubsub::client::ClientConfig;
 /// # use serde::{Deserialize, Serialize};
@@ -180,7 +703,7 @@ impl Default for PubSubConfig {
 ///     ..Default::default()
 /// };
 ///
-/// let backend: PubSubBackend<MyJob, JsonCodec<PubSubCompact>> =
+/// let mut backend: PubSubBackend<MyJob, JsonCodec<PubSubCompact>> =
 ///     PubSubBackend::new_with_config(
 ///         config,
 ///         "my-topic".to_string(),
@@ -188,30 +711,53 @@ impl Default for PubSubConfig {
 ///         custom_config,
 ///     ).await?;
 ///
-/// backend.push(MyJob { id: data: "test".into() }).await?;
+/// backend.push(MyJob { data: "test".into() }).await?;
 /// # Ok(())
 /// # }
 /// ```
 ///
+/// # Cloning
+///
+/// Clones share the backend's state, such as its client, configuration,
+/// statistics and shutdown signal, behind one [`Arc`], so cloning is cheap
+/// and every worker polling a clone behaves the same. Each clone publishes
+/// through a sink of its own. Configuring a clone with the `with_*` builders
+/// copies the shared state first, leaving the other clones as they were.
+///
 /// # Shutdown Behavior
 ///
 /// Call `shutdown()` to signal the backend to stop receiving new messages.
 /// In-flight messages will complete processing before the worker terminates.
-#[derive(Clone)]
+/// Messages are acknowledged when the worker picks them up, so messages still
+/// buffered at shutdown are nacked and redelivered to other workers.
 pub struct PubSubBackend<M, Codec> {
-    /// Client must be kept alive as topic/subscription hold references to it
-    #[allow(dead_code)]
-    client: Client,
-    topic: Topic,
-    /// Arc-wrapped subscription for safe sharing across worker threads in poll()
-    subscription: std::sync::Arc<Subscription>,
-    /// Configuration for backend behavior
-    config: PubSubConfig,
+    /// State shared by the clones of the backend
+    inner: Arc<BackendInner<M, Codec>>,
     /// [futures::Sink] that consumes tasks and sends them to pub/sub
     sink: PubSubSink<M, Codec>,
-    /// Cancellation token for graceful shutdown
-    cancel: tokio_util::sync::CancellationToken,
-    _phantom: PhantomData<(M, Codec)>,
+}
+
+impl<M, C> Clone for PubSubBackend<M, C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+impl<M, C> std::ops::Deref for PubSubBackend<M, C> {
+    type Target = BackendInner<M, C>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<M, C> std::ops::DerefMut for PubSubBackend<M, C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        Arc::make_mut(&mut self.inner)
+    }
 }
 
 impl<M, C> PubSubBackend<M, C> {
@@ -251,30 +797,168 @@ impl<M, C> PubSubBackend<M, C> {
         let client = Client::new(config)
             .await
             .map_err(|e| PubSubError::Subscription(e.to_string()))?;
+        Ok(Self::with_client(
+            client,
+            &topic_name,
+            &subscription_name,
+            pubsub_config,
+        ))
+    }
+
+    /// Creates a new PubSubBackend sharing an existing client.
+    ///
+    /// Applications already talking to Pub/Sub can reuse their client, and its
+    /// authentication, instead of creating another one.
+    ///
+    /// # Arguments
+    /// * `client` - The client to share
+    /// * `topic_name` - The name of the topic to publish messages to
+    /// * `subscription_name` - The name of the subscription to receive messages from
+    /// * `pubsub_config` - Custom configuration for backend behavior
+    pub fn with_client(
+        client: Client,
+        topic_name: &str,
+        subscription_name: &str,
+        pubsub_config: PubSubConfig,
+    ) -> Self {
+        let topic = client.topic(topic_name);
+        Self::build(client, Some(topic), subscription_name, pubsub_config)
+    }
+
+    /// Creates a consume-only PubSubBackend, for workers that never publish.
+    ///
+    /// Without a topic, the backend doesn't start a publisher: pushing tasks,
+    /// [`producer`](Self::producer) and provisioning fail, and republished
+    /// retries need a [delay topic](RepublishRetry::delay_topic). Queues are
+    /// named after the subscription.
+    ///
+    /// # Arguments
+    /// * `config` - The client configuration for Google Cloud Pub/Sub
+    /// * `subscription_name` - The name of the subscription to receive messages from
+    /// * `pubsub_config` - Custom configuration for backend behavior
+    pub async fn new_consumer(
+        config: ClientConfig,
+        subscription_name: String,
+        pubsub_config: PubSubConfig,
+    ) -> Result<Self, PubSubError> {
+        let client = Client::new(config)
+            .await
+            .map_err(|e| PubSubError::Subscription(e.to_string()))?;
+        Ok(Self::consumer(client, &subscription_name, pubsub_config))
+    }
 
-        let topic = client.topic(&topic_name);
-        let subscription = client.subscription(&subscription_name);
+    /// Creates a consume-only PubSubBackend sharing an existing client, see
+    /// [`new_consumer`](Self::new_consumer).
+    ///
+    /// # Arguments
+    /// * `client` - The client to share
+    /// * `subscription_name` - The name of the subscription to receive messages from
+    /// * `pubsub_config` - Custom configuration for backend behavior
+    pub fn consumer(client: Client, subscription_name: &str, pubsub_config: PubSubConfig) -> Self {
+        Self::build(client, None, subscription_name, pubsub_config)
+    }
 
-        Ok(Self {
+    fn build(
+        client: Client,
+        topic: Option<Topic>,
+        subscription_name: &str,
+        pubsub_config: PubSubConfig,
+    ) -> Self {
+        let subscription = Arc::new(client.subscription(subscription_name));
+        let concurrency = Arc::new(ConcurrencyControl::new(pubsub_config.concurrency_limit));
+        let budget = pubsub_config
+            .max_buffered_bytes
+            .map(|limit| Arc::new(BufferBudget::new(limit)));
+
+        let runtime = ConfigHandle::new(RuntimeConfig::from(&pubsub_config));
+        let transport: Arc<dyn PubSubTransport> = Arc::new(GcpTransport::new(client.clone()));
+        let in_flight = Arc::new(InFlight::new(Subscriber::new(
+            transport.clone(),
+            subscription.fully_qualified_name(),
+        )));
+
+        let inner = BackendInner {
             client,
-            topic: topic.clone(),
-            subscription: std::sync::Arc::new(subscription),
+            transport,
+            topic,
+            subscription,
             config: pubsub_config,
-            sink: PubSubSink::new(),
             cancel: tokio_util::sync::CancellationToken::new(),
+            result_store: None,
+            stats: Arc::new(PubSubStats::default()),
+            backlog_estimator: None,
+            control: None,
+            concurrency,
+            cancellations: Arc::new(Cancellations::default()),
+            #[cfg(feature = "consume")]
+            heartbeat: None,
+            registry: None,
+            budget,
+            validator: None,
+            context_hooks: Vec::new(),
+            alert_topic: None,
+            reporter: None,
+            auto_create: None,
+            codecs: Codecs::default(),
+            runtime,
+            archiver: None,
+            dead_letter_monitors: Vec::new(),
+            in_flight,
+            ack_policy: None,
+            job_types: Arc::default(),
+            paused_job_types: Arc::default(),
+            #[cfg(feature = "consume")]
+            idle: None,
+            checkpoint_store: None,
+            priority_tiers: priority::PriorityTiers::default(),
+            spawner: spawn::Spawner::default(),
+            pipeline: pipeline::Pipeline::default(),
+            sharding: None,
+            #[cfg(feature = "consume")]
+            breakers: None,
+            #[cfg(feature = "consume")]
+            shared_consumer: None,
             _phantom: PhantomData,
-        })
+        };
+        Self {
+            inner: Arc::new(inner),
+            sink: PubSubSink::new(),
+        }
     }
 
     /// Signals the backend to gracefully shutdown.
     ///
     /// This will stop receiving new messages from the subscription.
     /// In-flight messages will complete processing before the worker terminates.
+    /// Messages received but not yet dispatched to the worker are nacked, so
+    /// other workers get them right away.
     pub fn shutdown(&self) {
         self.cancel.cancel();
     }
+
+    /// Whether the backend only consumes, see [`consumer`](Self::consumer)
+    pub fn is_consume_only(&self) -> bool {
+        self.topic.is_none()
+    }
+
+    /// Name of the backend's queue: its topic, or its subscription for
+    /// consume-only backends
+    #[cfg(feature = "consume")]
+    pub(crate) fn queue_name(&self) -> String {
+        match &self.topic {
+            Some(topic) => topic.id(),
+            None => self.subscription.id(),
+        }
+    }
 }
 
+/// Error of publishing through a consume-only backend
+#[cfg(feature = "publish")]
+pub(crate) fn no_topic() -> PubSubError {
+    PubSubError::Client("The backend is consume-only and has no topic to publish to".to_owned())
+}
+
+#[cfg(feature = "consume")]
 impl<M: Send + 'static, C> Backend for PubSubBackend<M, C>
 where
     C: Codec<M, Compact = PubSubCompact>,
@@ -283,140 +967,591 @@ where
     type Args = M;
     type Error = PubSubError;
     type Beat = futures::stream::BoxStream<'static, Result<(), Self::Error>>;
-    type Layer = PubSubLayer;
+    type Layer = Stack<
+        Either<ReportLayer, Identity>,
+        Stack<
+            Either<AlertLayer, Identity>,
+            Stack<Either<RepublishRetryLayer<C>, Identity>, Stack<OverloadLayer, PubSubLayer>>,
+        >,
+    >;
     type Stream = TaskStream<Task<M, PubSubContext, Self::IdType>, Self::Error>;
     type Context = PubSubContext;
     type IdType = PubSubTaskId;
 
-    fn heartbeat(&self, _worker: &WorkerContext) -> Self::Beat {
-        // Pub/Sub manages connection health internally
-        Box::pin(futures::stream::empty())
+    fn heartbeat(&self, worker: &WorkerContext) -> Self::Beat {
+        let records = match &self.heartbeat {
+            Some(config) => heartbeat::heartbeats(self, config, worker),
+            None => Box::pin(futures::stream::empty()),
+        };
+        let health_checks = match &self.config.health_check {
+            Some(check) => heartbeat::health_checks(
+                self.transport.clone(),
+                self.subscription.fully_qualified_name().to_string(),
+                check,
+            ),
+            None => Box::pin(futures::stream::empty()),
+        };
+        Box::pin(futures::stream::select(records, health_checks))
     }
 
     fn middleware(&self) -> Self::Layer {
-        PubSubLayer
+        let retry = self.config.republish_retry.as_ref().and_then(|retry| {
+            if self.effective_ack_policy().is_none() && self.defers_ack() {
+                tracing::warn!(
+                    "Republished retries can't settle manually acknowledged messages, disabling them"
+                );
+                return None;
+            }
+            let topic = match (&retry.delay_topic, &self.topic) {
+                (Some(delay_topic), _) => self.client.topic(delay_topic),
+                (None, Some(topic)) => topic.clone(),
+                (None, None) => {
+                    tracing::warn!(
+                        "Republished retries need a delay topic on consume-only backends, disabling them"
+                    );
+                    return None;
+                }
+            };
+            Some(RepublishRetryLayer::new(
+                self.transport.clone(),
+                topic.fully_qualified_name().to_string(),
+                retry.max_attempts,
+                self.config.wire_format,
+                self.config.backoff.clone(),
+                self.config.clock.clone(),
+                self.job_types.clone(),
+            )
+            .with_dead_letters(self.dead_letterer())
+            .with_acker(self.acker()))
+        });
+        let failures_retried = retry.is_some();
+        let alert = self.alert_topic.as_ref().map(|topic| {
+            AlertLayer::new(
+                self.transport.clone(),
+                topic,
+                self.config
+                    .republish_retry
+                    .as_ref()
+                    .map(|retry| retry.max_attempts),
+                self.config.backoff.clone(),
+                self.config.clock.clone(),
+                self.job_types.clone(),
+            )
+        });
+        Stack::new(
+            option_layer(self.reporter.clone().map(ReportLayer::new)),
+            Stack::new(
+                option_layer(alert),
+                Stack::new(
+                    option_layer(retry),
+                    Stack::new(
+                        self.config.overload_layer_with(self.concurrency.clone()),
+                        PubSubLayer::new(self.stats.clone(), self.cancellations.clone())
+                            .with_acker(self.acker())
+                            .with_failures_retried(failures_retried)
+                            .with_job_types(self.job_types.clone()),
+                    ),
+                ),
+            ),
+        )
     }
 
-    #[tracing::instrument(skip(self, _worker))]
-    fn poll(self, _worker: &WorkerContext) -> Self::Stream {
-        let subscription = self.subscription.clone();
-        let buffer_size = self.config.buffer_size;
-        let max_message_size = self.config.max_message_size;
+    #[tracing::instrument(skip(self, worker))]
+    fn poll(self, worker: &WorkerContext) -> Self::Stream {
+        if let Some(shared) = self.shared_consumer.clone() {
+            return shared.join(self, worker);
+        }
+        let codecs = self.codecs.clone();
+        let pipeline = self.pipeline.clone();
+        self.receive(worker, move |message| codecs.decode::<C>(message), pipeline)
+    }
+}
+
+#[cfg(feature = "consume")]
+impl<M: Send + 'static, C> PubSubBackend<M, C>
+where
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Streams the tasks of the subscription, with the arguments `decode`
+    /// reads from their message and going through `pipeline`
+    fn receive<T: Send + 'static>(
+        self,
+        worker: &WorkerContext,
+        decode: impl Fn(&PubsubMessage) -> Result<T, BoxDynError> + Send + Sync + 'static,
+        pipeline: pipeline::Pipeline<T>,
+    ) -> TaskStream<PubSubTask<T>, PubSubError> {
+        let runtime = self.runtime.subscribe();
+        let spawner = self.spawner.clone();
         let cancel = self.cancel.clone();
-        let (tx, rx) = tokio::sync::mpsc::channel(buffer_size);
+        let stats = self.stats.clone();
+        let cancellations = self.cancellations.clone();
+        let ack_mode = AckMode {
+            exactly_once: self.config.exactly_once,
+            backoff: self.config.backoff.clone(),
+            clock: self.config.clock.clone(),
+        };
+        // The buffer gate bounds the channel, so its size can change at runtime
+        let (tx, rx) = tokio::sync::mpsc::channel(tokio::sync::Semaphore::MAX_PERMITS);
+        let buffer = Arc::new(ConcurrencyControl::new(Some(
+            self.runtime.get().buffer_size.max(1),
+        )));
+        spawner.spawn(reload::apply_limit(
+            self.runtime.subscribe(),
+            buffer.clone(),
+            |config| Some(config.buffer_size),
+            self.cancel.clone(),
+        ));
+
+        let budget = self.budget.clone();
+        let validator = self.validator.clone();
+        let context_hooks: Arc<[ContextHook]> = self.context_hooks.clone().into();
+        let reporter = self.reporter.clone();
+        let decode = Arc::new(decode);
+        let archiver = self.archiver.clone();
+        let sampler = self
+            .config
+            .payload_sampling
+            .clone()
+            .map(|sampling| Arc::new(PayloadSampler::new(sampling)));
+        let leases = self.config.lease_extension.clone().map(|policy| {
+            let keeper = Arc::new(LeaseKeeper::new(
+                self.subscriber(),
+                policy,
+                self.config.clock.clone(),
+                self.in_flight.clone(),
+            ));
+            spawner.spawn(keeper.clone().run(self.cancel.clone()));
+            keeper
+        });
+
+        let prefetch = self.config.adaptive_prefetch.clone().map(|adaptive| {
+            let gate = adaptive.gate();
+            spawner.spawn(adaptive.run(gate.clone(), self.stats.clone(), self.cancel.clone()));
+            gate
+        });
+
+        spawner.spawn(reload::apply_limit(
+            self.runtime.subscribe(),
+            self.concurrency.clone(),
+            |config| config.concurrency_limit,
+            self.cancel.clone(),
+        ));
+
+        self.start_dead_letter_monitors(&self.cancel);
+        if let Some(idle) = self.idle.clone() {
+            spawner.spawn(idle.run(self.stats.clone(), self.cancel.clone()));
+        }
+
+        if let Some(control) = self.control.clone() {
+            spawner.spawn(control::run_control_loop(
+                control,
+                worker.clone(),
+                self.concurrency.clone(),
+                self.cancellations.clone(),
+                self.paused_job_types.clone(),
+                self.cancel.clone(),
+            ));
+        }
 
         // Spawn task to receive messages from Pub/Sub and send to channel
         let tx_clone = tx.clone();
-        tokio::spawn(async move {
-            let result = subscription
-                .as_ref()
-                .receive(
-                    move |message, _cancel| {
-                        let tx = tx_clone.clone();
-
-                        async move {
-                            let bytes = message.message.data.clone();
-                            let ack_id = message.ack_id().to_string();
-                            let task_id = message
-                                .message
-                                .attributes
-                                .get(PUBSUB_ATTRIBUTE_TASK_ID)
-                                .map(|s| {
-                                    Uuid::from_str(s)
-                                        .inspect_err(|e| {
-                                            tracing::error!("Failed to deserialize task id: {e}")
-                                        })
-                                        .ok()
-                                })
-                                .flatten();
-                            let task_id_str = task_id.map(|id| id.to_string());
-
-                            // Validate message size
-                            if bytes.len() > max_message_size {
-                                tracing::error!(
-                                    size = bytes.len(),
-                                    max = max_message_size,
-                                    "Message exceeds maximum size"
-                                );
-                                if let Err(e) = message.ack().await {
-                                    tracing::error!(error = ?e, "Failed to ack oversized message");
-                                }
-                                return;
-                            }
+        let dispatch_leases = leases.clone();
+        let dispatch_ack_mode = ack_mode.clone();
+        let subscription_reporter = self.reporter.clone();
+        // Tasks pushed with a `not_before` are held even without `hold_scheduled`
+        let hold_run_at = self.config.hold_scheduled.is_some();
+        let hold_scheduled = self.config.hold_scheduled.clone().unwrap_or_default();
+        let hold_cancel = self.cancel.clone();
+        let hold_spawner = self.spawner.clone();
+        let clock = self.config.clock.clone();
+        let in_flight = self.in_flight.clone();
+        let checkpoint_store = self.checkpoint_store.clone();
+        let pipeline = Arc::new(pipeline);
+        let breakers = self.breakers.clone();
+        let paused_job_types = self.paused_job_types.clone();
+        let transport = self.transport.clone();
+        let dead_letters = self.dead_letterer();
+        let on_message = move |mut message: TransportMessage| {
+            let tx = tx_clone.clone();
+            let stats = stats.clone();
+            let cancellations = cancellations.clone();
+            let prefetch = prefetch.clone();
+            let leases = leases.clone();
+            let budget = budget.clone();
+            let sampler = sampler.clone();
+            let validator = validator.clone();
+            let context_hooks = context_hooks.clone();
+            let ack_mode = ack_mode.clone();
+            let reporter = reporter.clone();
+            let decode = decode.clone();
+            let buffer = buffer.clone();
+            let archiver = archiver.clone();
+            let in_flight = in_flight.clone();
+            let checkpoint_store = checkpoint_store.clone();
+            let pipeline = pipeline.clone();
+            let hold_scheduled = hold_scheduled.clone();
+            let hold_cancel = hold_cancel.clone();
+            let hold_spawner = hold_spawner.clone();
+            let clock = clock.clone();
+            let breakers = breakers.clone();
+            let paused_job_types = paused_job_types.clone();
+            let transport = transport.clone();
+            let dead_letters = dead_letters.clone();
+            let (max_message_size, max_age) = {
+                let runtime = runtime.borrow();
+                (runtime.max_message_size, runtime.max_age)
+            };
 
-                            tracing::debug!(task_id_str, "Received message");
+            async move {
+                if let Some(archiver) = &archiver {
+                    archiver.received(&message.message);
+                }
 
-                            // Decode message
-                            let msg: M = match C::decode(&bytes) {
-                                Ok(m) => {
-                                    tracing::trace!("Message decoded successfully");
-                                    m
-                                }
-                                Err(e) => {
-                                    tracing::error!(
-                                        error = ?e,
-                                        task_id_str,
-                                        "Failed to decode message - treating as poison message"
-                                    );
-                                    // Ack poison messages to prevent infinite redelivery
-                                    if let Err(ack_err) = message.ack().await {
-                                        tracing::error!(
-                                            error = ?ack_err,
-                                            "Failed to ack poison message"
-                                        );
-                                    }
-                                    return;
-                                }
-                            };
+                // Unpack envelopes so they read like any other message
+                if let Err(e) = envelope::open(&mut message.message) {
+                    tracing::error!(
+                        error = ?e,
+                        "Failed to open envelope - treating as poison message"
+                    );
+                    report(reporter.as_ref(), || {
+                        ErrorReport::new(FailureKind::Poison, &e)
+                            .with_payload(&message.message.data)
+                            .with_message_id(&message.message.message_id)
+                    });
+                    dlq::reject_poison(dead_letters.as_ref(), &message, &e.to_string(), &ack_mode)
+                        .await;
+                    return;
+                }
 
-                            // Build task with PubSubContext
-                            let mut task =
-                                TaskBuilder::new(msg).with_ctx(PubSubContext::new(ack_id));
+                let bytes = message.message.data.clone();
+                stats.record_received_size(bytes.len());
+                let task_id = message_task_id(&message.message);
+                let task_id_str = task_id.map(|id| id.to_string());
 
-                            if let Some(task_id) = task_id {
-                                task = task.with_task_id(TaskId::new(task_id))
-                            }
+                // Validate message size
+                if bytes.len() > max_message_size {
+                    tracing::error!(
+                        size = bytes.len(),
+                        max = max_message_size,
+                        "Message exceeds maximum size"
+                    );
+                    if let Err(e) = ack::ack_message(&message, &ack_mode).await {
+                        tracing::error!(error = ?e, "Failed to ack oversized message");
+                    }
+                    return;
+                }
 
-                            let task = task.build();
-
-                            // Send task to channel
-                            match tx.send(Ok(Some(task))).await {
-                                Ok(()) => {
-                                    // Ack message now that we've committed to processing it
-                                    if let Err(ack_err) = message.ack().await {
-                                        tracing::error!(error = ?ack_err, "Failed to ack message");
-                                    } else {
-                                        tracing::debug!("Message acknowledged");
-                                    }
-                                }
-                                Err(send_err) => {
-                                    tracing::error!(
-                                        error = ?send_err,
-                                        "Failed to send task to worker"
-                                    );
+                if let Some(age) = message_age(&message.message, clock.now())
+                    .filter(|age| max_age.is_some_and(|max_age| *age > max_age))
+                {
+                    tracing::warn!(task_id_str, ?age, "Dropping stale message");
+                    if let Err(e) = ack::ack_message(&message, &ack_mode).await {
+                        tracing::error!(error = ?e, "Failed to ack stale message");
+                    }
+                    return;
+                }
+
+                tracing::debug!(task_id_str, "Received message");
+                if let Some(sampler) = &sampler {
+                    sampler.sample(&bytes, task_id_str.as_deref());
+                }
+
+                if task_id.is_some_and(|id| cancellations.is_cancelled(&id)) {
+                    tracing::info!(task_id_str, "Dropping cancelled task");
+                    if let Err(e) = ack::ack_message(&message, &ack_mode).await {
+                        tracing::error!(error = ?e, "Failed to ack cancelled task");
+                    }
+                    return;
+                }
+
+                let attributes = &message.message.attributes;
+                let due = parts::read_not_before(attributes).or_else(|| {
+                    hold_run_at
+                        .then(|| parts::read_run_at(attributes))
+                        .flatten()
+                });
+                if let Some(delay) = due.and_then(|due| schedule::until(due, clock.as_ref())) {
+                    hold_scheduled
+                        .hold(message, delay, hold_cancel, &hold_spawner, clock)
+                        .await;
+                    return;
+                }
+
+                // Validate the payload against the producer contract
+                if let Some(Err(reason)) = validator
+                    .as_ref()
+                    .map(|validator| validator.validate(&bytes))
+                {
+                    tracing::error!(
+                        reason,
+                        task_id_str,
+                        "Invalid payload - treating as poison message"
+                    );
+                    report(reporter.as_ref(), || {
+                        ErrorReport::from_display(FailureKind::Poison, &reason)
+                            .with_task_id(task_id)
+                            .with_payload(&bytes)
+                            .with_message_id(&message.message.message_id)
+                    });
+                    dlq::reject_poison(dead_letters.as_ref(), &message, &reason, &ack_mode).await;
+                    return;
+                }
+
+                // Keep paused job types, and those with a tripped breaker,
+                // from being decoded
+                let job_type = jobs::message_job_type::<M>(&message.message);
+                if let Some(breakers) = &breakers {
+                    if breakers.is_quarantined(job_type) {
+                        tracing::debug!(task_id_str, job_type, "Quarantining message");
+                        breakers
+                            .quarantine(transport.as_ref(), &message, &ack_mode)
+                            .await;
+                        return;
+                    }
+                }
+                if paused_job_types.contains(job_type) {
+                    let decision = AckDecision::NackAfter(jobs::PAUSED_REDELIVERY_DELAY);
+                    pipeline::settle(&message, decision, &ack_mode, None).await;
+                    return;
+                }
+
+                // Decode message
+                let decoded = decode(&message.message);
+                if let Some(breakers) = &breakers {
+                    breakers.record(
+                        job_type,
+                        decoded.is_err(),
+                        clock.instant(),
+                        &paused_job_types,
+                    );
+                }
+                let msg = match decoded {
+                    Ok(m) => {
+                        tracing::trace!("Message decoded successfully");
+                        m
+                    }
+                    Err(e) => {
+                        stats.record_decode_failure(job_type);
+                        tracing::error!(
+                            error = ?e,
+                            task_id_str,
+                            "Failed to decode message - treating as poison message"
+                        );
+                        report(reporter.as_ref(), || {
+                            let mut report = ErrorReport::new(FailureKind::Poison, e.as_ref())
+                                .with_task_id(task_id)
+                                .with_payload(&bytes)
+                                .with_message_id(&message.message.message_id);
+                            report.job_type = Some(std::any::type_name::<M>());
+                            report
+                        });
+                        dlq::reject_poison(
+                            dead_letters.as_ref(),
+                            &message,
+                            &e.to_string(),
+                            &ack_mode,
+                        )
+                        .await;
+                        return;
+                    }
+                };
+
+                let mut task = received_task(msg, &message, task_id, &context_hooks);
+                if let Some((store, task_id)) = checkpoint_store.zip(task_id) {
+                    let checkpoint = task.parts.ctx.checkpoint_state().clone();
+                    let checkpoint = checkpoint.load(store, task_id).await;
+                    task.parts.ctx.set_checkpoint(checkpoint);
+                }
+                if !pipeline.is_empty() {
+                    task = match pipeline.run(task).await {
+                        Ok(task) => task,
+                        Err(decision) => {
+                            let dead_letters = dead_letters.as_ref();
+                            pipeline::settle(&message, decision, &ack_mode, dead_letters).await;
+                            return;
+                        }
+                    };
+                }
+
+                let received_at = Instant::now();
+                if let Some(leases) = &leases {
+                    leases.track(message.ack_id());
+                }
+                // Dead-lettering a message settled after its task needs a copy
+                in_flight.track(message.ack_id(), &message.message, dead_letters.is_some());
+
+                // Wait for room in the buffer, memory budget and prefetch window
+                let slot = buffer.acquire().await;
+                let budget = match &budget {
+                    Some(budget) => Some(budget.reserve(bytes.len()).await),
+                    None => None,
+                };
+                let prefetch = match &prefetch {
+                    Some(gate) => Some(gate.acquire().await),
+                    None => None,
+                };
+
+                // Queue the task; it's acknowledged once the worker takes it
+                let received = Received {
+                    task,
+                    message,
+                    slot,
+                    prefetch,
+                    budget,
+                    received_at,
+                };
+                match tx.send(Ok(received)).await {
+                    Ok(()) => stats.record_received(),
+                    Err(SendError(item)) => {
+                        tracing::error!("Failed to send task to worker");
+                        // Let another worker pick the message up right away
+                        if let Ok(received) = item {
+                            if let Some(leases) = &leases {
+                                leases.release(received.message.ack_id());
+                            }
+                            if in_flight.untrack(received.message.ack_id()) {
+                                if let Err(e) = received.message.nack().await {
+                                    tracing::error!(error = ?e, "Failed to nack message");
                                 }
                             }
                         }
-                    },
-                    cancel.clone(),
-                    None,
+                    }
+                }
+            }
+        };
+        let on_message: MessageHandler = Arc::new(move |message| on_message(message).boxed());
+        let transport = self.transport.clone();
+        let subscription_name = self.subscription.fully_qualified_name().to_string();
+        let auto_create = self.auto_create.clone();
+        // Receiving also stops when the subscription goes away
+        let receive_cancel = cancel.child_token();
+        let stop_receiving = receive_cancel.clone();
+        spawner.spawn(watch::watch_worker(worker.clone(), receive_cancel.clone()));
+        // Health checks probe the subscription from the heartbeat instead
+        let check_interval = match self.config.health_check {
+            Some(_) => None,
+            None => self.config.subscription_check_interval,
+        };
+        let watch = check_interval.map(|interval| {
+            let receive_cancel = receive_cancel.clone();
+            let watch = watch::watch_subscription(
+                self.transport.clone(),
+                self.subscription.fully_qualified_name().to_string(),
+                interval,
+                receive_cancel.clone(),
+            );
+            spawner.spawn(async move {
+                let gone = watch.await;
+                receive_cancel.cancel();
+                gone
+            })
+        });
+        let mut runtime_changes = self.runtime.subscribe();
+        let round_spawner = spawner.clone();
+        spawner.spawn(async move {
+            let receive = |round, receive_config| {
+                transport.clone().receive(
+                    subscription_name.clone(),
+                    receive_config,
+                    on_message.clone(),
+                    round,
                 )
-                .await;
+            };
+            let subscription_error = |e: Status| {
+                if is_not_found(&e) {
+                    PubSubError::SubscriptionGone(e.to_string())
+                } else {
+                    PubSubError::Subscription(e.to_string())
+                }
+            };
+            let mut result = Ok(());
+            let mut created = false;
+            while !receive_cancel.is_cancelled() {
+                // Flow control is fixed for a streaming pull, so restart it
+                // when the limits change
+                let round = receive_cancel.child_token();
+                let (flow_control, receive_config) = {
+                    let runtime = runtime_changes.borrow_and_update();
+                    (runtime.flow_control(), runtime.receive_config())
+                };
+                round_spawner.spawn({
+                    let round = round.clone();
+                    let changed =
+                        reload::flow_control_changed(runtime_changes.clone(), flow_control);
+                    async move {
+                        if round.run_until_cancelled(changed).await.is_some() {
+                            tracing::info!("Flow control changed, restarting the streaming pull");
+                            round.cancel();
+                        }
+                    }
+                });
+
+                result =
+                    receive(round.clone(), receive_config).await.map_err(|e| {
+                        match auto_create
+                            .as_ref()
+                            .filter(|_| !created && is_not_found(&e))
+                        {
+                            Some(resources) => Either::Left(resources),
+                            None => Either::Right(subscription_error(e)),
+                        }
+                    });
+                round.cancel();
+
+                match result {
+                    Err(Either::Left(resources)) => {
+                        tracing::info!("Subscription not found, creating resources");
+                        created = true;
+                        if let Err(e) = resources.create().await {
+                            result = Err(Either::Right(e));
+                            break;
+                        }
+                    }
+                    Err(Either::Right(_)) => break,
+                    Ok(()) => {}
+                }
+            }
 
-            if let Err(e) = result {
+            // Receiving stopped, so stop watching and see whether that's why
+            receive_cancel.cancel();
+            if let Some(watch) = watch {
+                if let Ok(Some(gone)) = watch.await {
+                    result = Err(Either::Right(gone));
+                }
+            }
+
+            if let Err(Either::Right(e)) = result {
                 tracing::error!(error = ?e, "Subscription error");
-                let err = PubSubError::Subscription(e.to_string());
-                if let Err(send_err) = tx.send(Err(err)).await {
+                report(subscription_reporter.as_ref(), || {
+                    ErrorReport::new(FailureKind::Subscription, &e)
+                });
+                if let Err(send_err) = tx.send(Err(e)).await {
                     tracing::error!(error = ?send_err, "Failed to send subscription error to worker");
                 }
             }
         });
 
-        // Convert channel receiver to stream
-        ReceiverStream::new(rx).boxed()
+        Dispatcher::new(
+            rx,
+            self.cancel.clone(),
+            dispatch_ack_mode,
+            self.cancellations.clone(),
+            self.stats.clone(),
+            self.runtime.subscribe(),
+            dispatch_leases,
+        )
+        .with_reporter(self.reporter.clone())
+        .with_in_flight(self.in_flight.clone())
+        .with_deferred_ack(self.defers_ack())
+        .with_spawner(self.spawner.clone())
+        .with_max_tasks(self.config.max_tasks_per_worker, stop_receiving)
+        .with_paused_job_types(self.paused_job_types.clone())
+        .into_stream()
     }
 }
 
+#[cfg(feature = "consume")]
 impl<M, Decode> BackendExt for PubSubBackend<M, Decode>
 where
     M: Send + 'static,
@@ -430,12 +1565,20 @@ where
     type CompactStream = TaskStream<PubSubTask<PubSubCompact>, Self::Error>;
 
     fn get_queue(&self) -> Queue {
-        self.topic.id().into()
+        self.queue_name().into()
     }
 
-    fn poll_compact(self, _worker: &WorkerContext) -> Self::CompactStream {
-        // We don't give compacted messages to workers
-        // Just return an empty stream
-        futures::stream::empty().boxed()
+    /// Streams the tasks of the subscription with their raw payload, see
+    /// [`PubSubBackend::poll`](Backend::poll)
+    ///
+    /// Payloads aren't decoded, so the backend's [`pipeline`] doesn't
+    /// apply, and workers each get a streaming pull of their own even with
+    /// [`with_shared_consumer`](PubSubBackend::with_shared_consumer).
+    fn poll_compact(self, worker: &WorkerContext) -> Self::CompactStream {
+        self.receive(
+            worker,
+            |message| Ok(message.data.clone()),
+            pipeline::Pipeline::default(),
+        )
     }
 }
@@ -0,0 +1,426 @@
+//! File-backed backend for offline development
+//!
+//! [`LocalPubSubBackend`] stands in for [`PubSubBackend`](crate::PubSubBackend)
+//! where neither Google Cloud nor the Pub/Sub emulator is reachable. Tasks
+//! pushed to it are written to a local directory, one file per task, and
+//! workers polling it take them back in the order they were pushed. Tasks go
+//! through the same codec and carry the same metadata as on Pub/Sub, so
+//! handlers and their [`PubSubContext`] behave alike.
+//!
+//! It keeps to the simplest delivery semantics:
+//!
+//! - a task is removed from the directory when a worker takes it, before it
+//!   runs, so it's delivered at most once and lost if the worker stops while
+//!   running it,
+//! - tasks scheduled with `run_after` stay in the directory until they're due,
+//! - task files that can't be read are moved to its `rejected` subdirectory,
+//! - tasks whose arguments fail to decode are logged and dropped.
+//!
+//! Several workers, even in different processes, can share a directory: each
+//! task is claimed by exactly one of them.
+//!
+//! Requires the `local` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_core::backend::TaskSink;
+//! # use apalis_pubsub::{local::LocalPubSubBackend, PubSubCompact};
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut backend = LocalPubSubBackend::<u32, JsonCodec<PubSubCompact>>::new("./tasks")?;
+//! backend.push(42).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    fs,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use apalis_core::{
+    backend::{codec::Codec, queue::Queue, Backend, BackendExt, TaskStream},
+    timer::sleep,
+    worker::context::WorkerContext,
+};
+use futures::{Sink, StreamExt};
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use prost::Message;
+use tokio_util::sync::CancellationToken;
+use tower::layer::util::Identity;
+use uuid::Uuid;
+
+use crate::{
+    clock::SystemClock,
+    envelope::{self, WireFormat},
+    message_task, message_task_id, parts, schedule,
+    sink::task_message,
+    utils::PubSubContext,
+    PubSubCompact, PubSubError, PubSubTask, PubSubTaskId,
+};
+
+/// Directory holding tasks waiting for a worker
+const QUEUE_DIR: &str = "queue";
+
+/// Directory tasks are moved to when a worker claims them, just before they're
+/// removed
+const CLAIMED_DIR: &str = "claimed";
+
+/// Directory holding task files that couldn't be read
+const REJECTED_DIR: &str = "rejected";
+
+/// Age after which a claimed task whose worker never removed it is put back
+/// in the queue
+///
+/// Workers remove the tasks they claim right away, so older claims are left
+/// by a process that stopped in between.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Extension of task files
+const TASK_EXTENSION: &str = "pb";
+
+fn io_error(e: std::io::Error) -> PubSubError {
+    PubSubError::Io(e.to_string())
+}
+
+/// Microseconds since the Unix epoch
+fn now_micros() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros()
+}
+
+/// Splits the name of a claimed task file into when it was claimed, in
+/// microseconds since the Unix epoch, and the name of the task file
+fn parse_claim(file_name: &str) -> Option<(u128, &str)> {
+    let (claimed_at, name) = file_name.split_once('-')?;
+    Some((claimed_at.parse().ok()?, name))
+}
+
+/// Backend storing tasks in a local directory, see the [module level documentation](self)
+pub struct LocalPubSubBackend<M, C> {
+    dir: PathBuf,
+    poll_interval: Duration,
+    cancel: CancellationToken,
+    _phantom: PhantomData<fn() -> (M, C)>,
+}
+
+impl<M, C> Clone for LocalPubSubBackend<M, C> {
+    fn clone(&self) -> Self {
+        Self {
+            dir: self.dir.clone(),
+            poll_interval: self.poll_interval,
+            cancel: self.cancel.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<M, C> std::fmt::Debug for LocalPubSubBackend<M, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalPubSubBackend")
+            .field("dir", &self.dir)
+            .field("poll_interval", &self.poll_interval)
+            .finish()
+    }
+}
+
+impl<M, C> LocalPubSubBackend<M, C> {
+    /// Stores tasks in `dir`, creating it if needed
+    ///
+    /// Tasks a worker claimed but never removed, because its process stopped
+    /// in between, are put back in the queue once their claim is older than a
+    /// minute. Tasks already handed to a worker aren't, see the
+    /// [module level documentation](self).
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, PubSubError> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(QUEUE_DIR)).map_err(io_error)?;
+        fs::create_dir_all(dir.join(CLAIMED_DIR)).map_err(io_error)?;
+        fs::create_dir_all(dir.join(REJECTED_DIR)).map_err(io_error)?;
+        let now = now_micros();
+        for entry in fs::read_dir(dir.join(CLAIMED_DIR)).map_err(io_error)? {
+            let entry = entry.map_err(io_error)?;
+            let file_name = entry.file_name();
+            // Claims of other running workers are theirs to remove
+            let Some((claimed_at, name)) = file_name.to_str().and_then(parse_claim) else {
+                continue;
+            };
+            if now.saturating_sub(claimed_at) < CLAIM_TIMEOUT.as_micros() {
+                continue;
+            }
+            tracing::warn!(name, "Putting back a task claimed by a stopped worker");
+            // Another process may have put it back first
+            let _ = fs::rename(entry.path(), dir.join(QUEUE_DIR).join(name));
+        }
+        Ok(Self {
+            dir,
+            poll_interval: Duration::from_millis(100),
+            cancel: CancellationToken::new(),
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Checks for new tasks every `interval` while the queue is empty
+    /// (default: 100ms)
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// The directory tasks are stored in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of tasks waiting for a worker
+    pub fn len(&self) -> Result<usize, PubSubError> {
+        Ok(self.queued()?.len())
+    }
+
+    /// Whether no task is waiting for a worker
+    pub fn is_empty(&self) -> Result<bool, PubSubError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Signals workers polling the backend to stop once their tasks complete
+    pub fn shutdown(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Names of the queued task files, oldest first
+    fn queued(&self) -> Result<Vec<String>, PubSubError> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(self.dir.join(QUEUE_DIR)).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            if path.extension().is_some_and(|ext| ext == TASK_EXTENSION) {
+                if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        // Names start with the time the task was pushed
+        names.sort();
+        Ok(names)
+    }
+
+    /// Moves the task file at `path`, named `name`, to the rejected directory
+    fn reject(&self, path: &Path, name: &str) {
+        if let Err(e) = fs::rename(path, self.dir.join(REJECTED_DIR).join(name)) {
+            tracing::error!(error = ?e, name, "Failed to reject task file");
+        }
+    }
+
+    /// Writes `task` to the queue
+    fn write(&self, task: PubSubTask<PubSubCompact>) -> Result<(), PubSubError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let id = Uuid::new_v4();
+        let mut message = task_message(task, None, WireFormat::Attributes);
+        message.message_id = id.to_string();
+        message.publish_time = Some(prost_types::Timestamp {
+            seconds: now.as_secs() as i64,
+            nanos: now.subsec_nanos() as i32,
+        });
+
+        // Write aside first so workers never see a partial file
+        let name = format!("{:020}-{id}.{TASK_EXTENSION}", now.as_micros());
+        let partial = self.dir.join(QUEUE_DIR).join(format!("{name}.partial"));
+        fs::write(&partial, message.encode_to_vec()).map_err(io_error)?;
+        fs::rename(&partial, self.dir.join(QUEUE_DIR).join(&name)).map_err(io_error)?;
+        tracing::debug!(name, "Task stored");
+        Ok(())
+    }
+
+    /// Claims the oldest due task of the queue, removing it from the directory
+    ///
+    /// Moving the file to the claimed directory first makes sure a single
+    /// worker takes it. Files that can't be read are moved to the rejected
+    /// directory instead.
+    fn take(&self) -> Result<Option<(String, PubsubMessage)>, PubSubError> {
+        for name in self.queued()? {
+            let queued = self.dir.join(QUEUE_DIR).join(&name);
+            let Ok(contents) = fs::read(&queued) else {
+                // Taken by another worker
+                continue;
+            };
+            let mut message = match PubsubMessage::decode(contents.as_slice()) {
+                Ok(message) => message,
+                Err(e) => {
+                    tracing::error!(error = ?e, name, "Invalid task file - rejecting it");
+                    self.reject(&queued, &name);
+                    continue;
+                }
+            };
+            if parts::read_run_at(&message.attributes)
+                .and_then(|run_at| schedule::until(run_at, &SystemClock))
+                .is_some()
+            {
+                continue;
+            }
+
+            let claimed = self
+                .dir
+                .join(CLAIMED_DIR)
+                .join(format!("{:020}-{name}", now_micros()));
+            if fs::rename(&queued, &claimed).is_err() {
+                continue;
+            }
+            if let Err(e) = envelope::open(&mut message) {
+                tracing::error!(error = ?e, name, "Invalid task envelope - rejecting it");
+                self.reject(&claimed, &name);
+                continue;
+            }
+            fs::remove_file(&claimed).map_err(io_error)?;
+            return Ok(Some((name, message)));
+        }
+        Ok(None)
+    }
+}
+
+impl<M, C> LocalPubSubBackend<M, C>
+where
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Takes the oldest due task that decodes
+    fn next_task(&self) -> Result<Option<PubSubTask<M>>, PubSubError> {
+        self.next_with(|message| C::decode(&message.data).map_err(|e| e.to_string()))
+    }
+}
+
+impl<M, C> LocalPubSubBackend<M, C> {
+    /// Takes the oldest due task whose arguments `decode` reads
+    fn next_with<T>(
+        &self,
+        decode: impl Fn(&PubsubMessage) -> Result<T, String>,
+    ) -> Result<Option<PubSubTask<T>>, PubSubError> {
+        while let Some((name, message)) = self.take()? {
+            match decode(&message) {
+                Ok(args) => {
+                    let task_id = message_task_id(&message);
+                    return Ok(Some(message_task(
+                        args,
+                        &message,
+                        &name,
+                        None,
+                        task_id,
+                        &[],
+                    )));
+                }
+                Err(e) => tracing::error!(
+                    error = ?e,
+                    name,
+                    "Failed to decode task - dropping it"
+                ),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Streams the tasks `next` takes, until the backend shuts down
+    fn stream<T: Send + 'static>(
+        self,
+        next: fn(&Self) -> Result<Option<PubSubTask<T>>, PubSubError>,
+    ) -> TaskStream<PubSubTask<T>, PubSubError>
+    where
+        Self: Send + 'static,
+    {
+        futures::stream::unfold(self, move |backend| async move {
+            loop {
+                if backend.cancel.is_cancelled() {
+                    return None;
+                }
+                match next(&backend) {
+                    Ok(Some(task)) => return Some((Ok(Some(task)), backend)),
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::error!(error = ?e, "Failed to read local tasks");
+                        return Some((Err(e), backend));
+                    }
+                }
+                backend
+                    .cancel
+                    .run_until_cancelled(sleep(backend.poll_interval))
+                    .await;
+            }
+        })
+        .boxed()
+    }
+}
+
+impl<M: Send + 'static, C> Backend for LocalPubSubBackend<M, C>
+where
+    C: Codec<M, Compact = PubSubCompact> + 'static,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Args = M;
+    type Error = PubSubError;
+    type Beat = futures::stream::BoxStream<'static, Result<(), Self::Error>>;
+    type Layer = Identity;
+    type Stream = TaskStream<PubSubTask<M>, Self::Error>;
+    type Context = PubSubContext;
+    type IdType = PubSubTaskId;
+
+    fn heartbeat(&self, _worker: &WorkerContext) -> Self::Beat {
+        Box::pin(futures::stream::empty())
+    }
+
+    fn middleware(&self) -> Self::Layer {
+        Identity::new()
+    }
+
+    fn poll(self, _worker: &WorkerContext) -> Self::Stream {
+        self.stream(Self::next_task)
+    }
+}
+
+impl<M: Send + 'static, C> BackendExt for LocalPubSubBackend<M, C>
+where
+    C: Codec<M, Compact = PubSubCompact> + 'static,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Codec = C;
+
+    type Compact = PubSubCompact;
+
+    type CompactStream = TaskStream<PubSubTask<PubSubCompact>, Self::Error>;
+
+    fn get_queue(&self) -> Queue {
+        self.dir.to_string_lossy().as_ref().into()
+    }
+
+    /// Streams tasks with their arguments still encoded, as they were pushed
+    fn poll_compact(self, _worker: &WorkerContext) -> Self::CompactStream {
+        self.stream(|backend| backend.next_with(|message| Ok(message.data.clone())))
+    }
+}
+
+impl<M, C> Sink<PubSubTask<PubSubCompact>> for LocalPubSubBackend<M, C> {
+    type Error = PubSubError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        item: PubSubTask<PubSubCompact>,
+    ) -> Result<(), Self::Error> {
+        // Tasks are written as they come, there's nothing to buffer
+        self.write(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
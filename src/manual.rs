@@ -0,0 +1,285 @@
+//! Consuming tasks without a worker
+//!
+//! Applications with their own event loop don't always want apalis' worker
+//! machinery around a handler. [`PubSubBackend::into_task_stream`] returns the
+//! decoded tasks as a plain [`Stream`](futures::Stream), to drive with
+//! `select!`, a loop or any stream combinator.
+//!
+//! Tasks of the stream are received exactly like a worker's: flow control,
+//! buffering, [lease extension](crate::lease), cancellations and
+//! [paused job types](crate::jobs#pausing-job-types) all apply. Their
+//! messages are however never acknowledged by the backend, as with the
+//! [`AckStrategy::Manual`] strategy: the caller settles each with an
+//! [`AckHandle`], from [`PubSubBackend::ack_handle`], once it's done with it. Messages left unsettled are redelivered when their
+//! ack deadline passes.
+//!
+//! The stream ends when the backend [shuts down](PubSubBackend::shutdown),
+//! nacking the messages still buffered.
+//!
+//! # With apalis workers
+//!
+//! [`AckHandle`] also implements apalis' [`Acknowledge`] trait, so workers
+//! can settle messages through apalis' `AcknowledgeLayer` like with other
+//! backends: with the [`AckStrategy::Manual`] strategy, the worker leaves
+//! messages in flight, and the layer acknowledges those of tasks that
+//! succeed and nacks the others, after the delay of a [`RetryAfter`] error if
+//! any.
+//!
+//! ```no_run
+//! # use apalis::prelude::*;
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_core::{error::BoxDynError, worker::ext::ack::AcknowledgeLayer};
+//! # use apalis_pubsub::{outcome::AckStrategy, PubSubBackend, PubSubCompact, PubSubConfig};
+//! # use google_cloud_pubsub::client::ClientConfig;
+//! # async fn handle(n: u32) -> Result<(), BoxDynError> { Ok(()) }
+//! # async fn example() {
+//! let config = PubSubConfig {
+//!     ack_strategy: AckStrategy::Manual,
+//!     ..Default::default()
+//! };
+//! let backend = PubSubBackend::<u32, JsonCodec<PubSubCompact>>::new_with_config(
+//!     ClientConfig::default(),
+//!     "tasks".to_string(),
+//!     "tasks-sub".to_string(),
+//!     config,
+//! )
+//! .await
+//! .unwrap();
+//! let worker = WorkerBuilder::new("worker")
+//!     .backend(backend.clone())
+//!     .layer(AcknowledgeLayer::new(backend.ack_handle()))
+//!     .build(handle);
+//! # }
+//! ```
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact, PubSubError};
+//! # use futures::StreamExt;
+//! # async fn handle(n: u32) -> Result<(), std::io::Error> { Ok(()) }
+//! # async fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) -> Result<(), PubSubError> {
+//! let acks = backend.ack_handle();
+//! let mut tasks = backend.into_task_stream();
+//! while let Some(task) = tasks.next().await {
+//!     let task = task?;
+//!     match handle(task.args).await {
+//!         Ok(()) => acks.ack(&task.parts.ctx).await?,
+//!         Err(_) => acks.nack(&task.parts.ctx).await?,
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::{sync::Arc, time::Duration};
+
+use apalis_core::{error::BoxDynError, task::Parts, worker::ext::ack::Acknowledge};
+use futures::{future::BoxFuture, FutureExt};
+
+#[cfg(feature = "consume")]
+use crate::outcome::AckStrategy;
+use crate::{
+    ack::{ack_by_id, modify_deadline, AckMode},
+    dlq::{DeadLetterer, DEAD_LETTER_KIND_REJECTED},
+    inflight::InFlight,
+    lease::MAX_ACK_DEADLINE,
+    outcome::{AckDecision, RetryAfter},
+    stats::PubSubStats,
+    transport::Subscriber,
+    utils::PubSubContext,
+    PubSubBackend, PubSubError, PubSubTaskId,
+};
+#[cfg(feature = "consume")]
+use {
+    crate::{PubSubCompact, PubSubTask},
+    apalis_core::{
+        backend::{codec::Codec, Backend},
+        worker::context::WorkerContext,
+    },
+    futures::{stream::BoxStream, StreamExt, TryStreamExt},
+};
+
+/// Why messages settled with [`AckDecision::DeadLetter`] were dead-lettered,
+/// when it isn't known better
+pub(crate) const DEAD_LETTER_REASON: &str = "Dead-lettered on settlement";
+
+/// Acknowledges or nacks the messages of a backend's tasks, see the
+/// [module level documentation](self)
+///
+/// Handles are cheap to clone. Settling a message that was already settled,
+/// by this handle or through the [registry](crate::abort), does nothing.
+#[derive(Clone)]
+pub struct AckHandle {
+    subscriber: Subscriber,
+    mode: AckMode,
+    in_flight: Arc<InFlight>,
+    stats: Arc<PubSubStats>,
+    dead_letters: Option<DeadLetterer>,
+}
+
+impl std::fmt::Debug for AckHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AckHandle")
+            .field("exactly_once", &self.mode.exactly_once)
+            .finish()
+    }
+}
+
+impl AckHandle {
+    /// Acknowledges the message of the task with `ctx`, it's done
+    pub async fn ack(&self, ctx: &PubSubContext) -> Result<(), PubSubError> {
+        self.settle(ctx, AckDecision::Ack).await
+    }
+
+    /// Nacks the message of the task with `ctx`, so Pub/Sub redelivers it
+    /// right away
+    pub async fn nack(&self, ctx: &PubSubContext) -> Result<(), PubSubError> {
+        self.settle(ctx, AckDecision::Nack).await
+    }
+
+    /// Lets Pub/Sub redeliver the message of the task with `ctx` after
+    /// `delay`, at most 10 minutes
+    pub async fn nack_after(
+        &self,
+        ctx: &PubSubContext,
+        delay: Duration,
+    ) -> Result<(), PubSubError> {
+        self.settle(ctx, AckDecision::NackAfter(delay)).await
+    }
+
+    /// Settles the message of the task with `ctx` as `decision` says
+    ///
+    /// [`AckDecision::DeadLetter`] republishes the message to the
+    /// [dead-letter topic](crate::PubSubConfig::dead_letter_topic) first, if
+    /// any, and nacks it instead when that fails, see [`crate::dlq`].
+    pub async fn settle(
+        &self,
+        ctx: &PubSubContext,
+        decision: AckDecision,
+    ) -> Result<(), PubSubError> {
+        self.settle_because(ctx, decision, DEAD_LETTER_REASON).await
+    }
+
+    /// Settles the message of the task with `ctx` as `decision` says, a
+    /// dead-lettered message carrying `reason`
+    pub(crate) async fn settle_because(
+        &self,
+        ctx: &PubSubContext,
+        decision: AckDecision,
+        reason: &str,
+    ) -> Result<(), PubSubError> {
+        let copy = match decision {
+            AckDecision::DeadLetter => self.in_flight.take_message(&ctx.ack_id),
+            _ => None,
+        };
+        if !self.in_flight.untrack(&ctx.ack_id) {
+            // Settled already
+            return Ok(());
+        }
+        let decision = match (decision, &self.dead_letters) {
+            (AckDecision::DeadLetter, Some(dead_letters)) => {
+                let published = match &copy {
+                    Some(message) => dead_letters
+                        .publish(message, DEAD_LETTER_KIND_REJECTED, reason)
+                        .await
+                        .inspect_err(
+                            |e| tracing::error!(error = ?e, "Failed to dead-letter message"),
+                        )
+                        .is_ok(),
+                    None => false,
+                };
+                if published {
+                    decision
+                } else {
+                    tracing::warn!(
+                        ack_id = ctx.ack_id,
+                        "Message wasn't dead-lettered, nacking it"
+                    );
+                    AckDecision::Nack
+                }
+            }
+            _ => decision,
+        };
+        let result = match decision {
+            AckDecision::Ack => ack_by_id(&self.subscriber, &ctx.ack_id, &self.mode).await,
+            AckDecision::DeadLetter => {
+                tracing::warn!(ack_id = ctx.ack_id, "Dead-lettering message");
+                ack_by_id(&self.subscriber, &ctx.ack_id, &self.mode).await
+            }
+            AckDecision::Nack => {
+                modify_deadline(&self.subscriber, &ctx.ack_id, 0, &self.mode).await
+            }
+            AckDecision::NackAfter(delay) => {
+                let seconds = delay.min(MAX_ACK_DEADLINE).as_secs() as i32;
+                modify_deadline(&self.subscriber, &ctx.ack_id, seconds, &self.mode).await
+            }
+        };
+        match &result {
+            Ok(()) if matches!(decision, AckDecision::Ack | AckDecision::DeadLetter) => {
+                self.stats.record_acked();
+                tracing::debug!("Message acknowledged");
+            }
+            Ok(()) => tracing::debug!(?decision, "Message nacked"),
+            Err(PubSubError::AckExpired(_)) => self.stats.record_ack_expired(),
+            Err(_) => {}
+        }
+        result
+    }
+}
+
+impl<Res> Acknowledge<Res, PubSubContext, PubSubTaskId> for AckHandle {
+    type Error = PubSubError;
+    type Future = BoxFuture<'static, Result<(), PubSubError>>;
+
+    fn ack(
+        &mut self,
+        res: &Result<Res, BoxDynError>,
+        parts: &Parts<PubSubContext, PubSubTaskId>,
+    ) -> Self::Future {
+        let decision = match res {
+            Ok(_) => AckDecision::Ack,
+            Err(e) => {
+                RetryAfter::find(e.as_ref()).map_or(AckDecision::Nack, AckDecision::NackAfter)
+            }
+        };
+        let handle = self.clone();
+        let ctx = parts.ctx.clone();
+        async move { handle.settle(&ctx, decision).await }.boxed()
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// A handle settling the messages of the backend's tasks
+    pub fn ack_handle(&self) -> AckHandle {
+        AckHandle {
+            subscriber: self.subscriber(),
+            mode: AckMode {
+                exactly_once: self.config.exactly_once,
+                backoff: self.config.backoff.clone(),
+                clock: self.config.clock.clone(),
+            },
+            in_flight: self.in_flight.clone(),
+            stats: self.stats.clone(),
+            dead_letters: self.dead_letterer(),
+        }
+    }
+}
+
+#[cfg(feature = "consume")]
+impl<M, C> PubSubBackend<M, C>
+where
+    M: Send + 'static,
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Consumes the backend's subscription as a stream of tasks, whose
+    /// messages are settled with an [`AckHandle`], see [`manual`](crate::manual)
+    pub fn into_task_stream(mut self) -> BoxStream<'static, Result<PubSubTask<M>, PubSubError>> {
+        self.config.ack_strategy = AckStrategy::Manual;
+        let worker = WorkerContext::new::<Self>(&self.queue_name());
+        self.poll(&worker)
+            .try_filter_map(|task| futures::future::ready(Ok(task)))
+            .boxed()
+    }
+}
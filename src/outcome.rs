@@ -0,0 +1,288 @@
+//! Acknowledging messages according to the outcome of their task
+//!
+//! By default, messages are acknowledged once the worker takes them, whatever
+//! becomes of their task: delivery is at most once. With
+//! [`PubSubBackend::with_ack_policy`], acknowledgement is instead deferred
+//! until the handler returns, and an [`AckPolicy`] maps its outcome to an
+//! [`AckDecision`]: a task failing with a transient error can be redelivered,
+//! now or after a delay, while one failing with a permanent domain error is
+//! dropped for good.
+//!
+//! For plain at-least-once delivery, setting
+//! [`PubSubConfig::ack_strategy`](crate::PubSubConfig::ack_strategy) to
+//! [`AckStrategy::OnSuccess`] acknowledges the messages of tasks that succeed
+//! and nacks the others, as the [`AckOnSuccess`] policy does. A policy set
+//! with [`PubSubBackend::with_ack_policy`] takes precedence over the strategy.
+//! With [`PubSubConfig::republish_retry`](crate::PubSubConfig::republish_retry)
+//! as well, failed tasks the policy would redeliver are republished for
+//! another attempt instead, and their message acknowledged, see
+//! [`retry`](crate::retry).
+//! With [`AckStrategy::Manual`], messages are left to an [`AckHandle`],
+//! which also settles them as apalis' `AcknowledgeLayer`, see
+//! [`manual`](crate::manual).
+//!
+//! Tasks cancelled or past their deadline while buffered are still
+//! acknowledged and dropped without running.
+//!
+//! Handlers that know when their task is worth retrying, for example from a
+//! rate-limited API's `Retry-After` header, can fail with a [`RetryAfter`]
+//! error, or an error caused by one. Its message is then redelivered after
+//! that delay, whatever the policy would have decided.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{
+//! #     outcome::{AckDecision, AckPolicy},
+//! #     utils::PubSubContext,
+//! #     PubSubBackend, PubSubCompact,
+//! # };
+//! # use std::{error::Error, sync::Arc, time::Duration};
+//! #[derive(Debug, thiserror::Error)]
+//! #[error("invalid order")]
+//! struct InvalidOrder;
+//!
+//! struct OrderPolicy;
+//!
+//! impl AckPolicy for OrderPolicy {
+//!     fn decide(&self, _ctx: &PubSubContext, outcome: Result<(), &(dyn Error + 'static)>) -> AckDecision {
+//!         match outcome {
+//!             Ok(()) => AckDecision::Ack,
+//!             // Retrying won't make the order valid
+//!             Err(e) if e.is::<InvalidOrder>() => AckDecision::DeadLetter,
+//!             Err(_) => AckDecision::NackAfter(Duration::from_secs(30)),
+//!         }
+//!     }
+//! }
+//!
+//! # fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let backend = backend.with_ack_policy(Arc::new(OrderPolicy));
+//! # }
+//! ```
+use std::{any::Any, error::Error, fmt, sync::Arc, time::Duration};
+
+use apalis_core::error::BoxDynError;
+
+use crate::{
+    manual::{AckHandle, DEAD_LETTER_REASON},
+    report::{report, ErrorReport, ErrorReporter, FailureKind},
+    utils::PubSubContext,
+    PubSubBackend, PubSubError,
+};
+
+/// What becomes of a message once its task finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckDecision {
+    /// Acknowledge the message, it's done
+    Ack,
+    /// Nack the message so Pub/Sub redelivers it right away
+    Nack,
+    /// Let Pub/Sub redeliver the message after this delay, at most 10 minutes
+    NackAfter(Duration),
+    /// Give up on the message: it's republished to the
+    /// [dead-letter topic](crate::PubSubConfig::dead_letter_topic), if any,
+    /// and acknowledged so it's never redelivered, see [`crate::dlq`]
+    DeadLetter,
+}
+
+/// When messages are acknowledged, see the [module level documentation](self)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AckStrategy {
+    /// Acknowledge messages once the worker takes them, before their task
+    /// runs: tasks that fail or don't finish are lost
+    #[default]
+    OnReceive,
+    /// Acknowledge messages once their task succeeded, and nack them when it
+    /// fails so Pub/Sub redelivers them
+    OnSuccess,
+    /// Leave messages to be settled with an [`AckHandle`], for example by
+    /// apalis' `AcknowledgeLayer`, see [`manual`](crate::manual)
+    Manual,
+}
+
+/// Handler error asking for the task to be retried after a delay, at most
+/// 10 minutes, see the [module level documentation](self)
+///
+/// Only applies when acknowledgement is deferred, with an
+/// [ack policy](PubSubBackend::with_ack_policy) or an [`AckStrategy`] other
+/// than [`OnReceive`](AckStrategy::OnReceive), otherwise messages are
+/// acknowledged before their task runs.
+///
+/// ```no_run
+/// # use apalis_core::error::BoxDynError;
+/// # use apalis_pubsub::outcome::RetryAfter;
+/// # use std::time::Duration;
+/// async fn sync_account(account: u32) -> Result<(), BoxDynError> {
+///     // The API is rate limited for another 30 seconds
+///     Err(RetryAfter(Duration::from_secs(30)).into())
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Retry after {0:?}")]
+pub struct RetryAfter(pub Duration);
+
+impl RetryAfter {
+    /// The delay asked for by `error` or one of its causes, if any
+    pub(crate) fn find(error: &(dyn Error + 'static)) -> Option<Duration> {
+        let mut error = Some(error);
+        while let Some(current) = error {
+            if let Some(RetryAfter(delay)) = current.downcast_ref() {
+                return Some(*delay);
+            }
+            error = current.source();
+        }
+        None
+    }
+}
+
+/// Maps the outcome of a task to an [`AckDecision`]
+pub trait AckPolicy: Send + Sync {
+    /// Decides what becomes of the message of the task with `ctx`, given the
+    /// handler's `outcome`
+    fn decide(
+        &self,
+        ctx: &PubSubContext,
+        outcome: Result<(), &(dyn Error + 'static)>,
+    ) -> AckDecision;
+}
+
+/// Acknowledges tasks that succeed and nacks tasks that fail
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AckOnSuccess;
+
+impl AckPolicy for AckOnSuccess {
+    fn decide(
+        &self,
+        _: &PubSubContext,
+        outcome: Result<(), &(dyn Error + 'static)>,
+    ) -> AckDecision {
+        match outcome {
+            Ok(()) => AckDecision::Ack,
+            Err(_) => AckDecision::Nack,
+        }
+    }
+}
+
+/// Handler error that isn't an [`Error`], shown to policies by its message
+#[derive(Debug)]
+struct HandlerError(String);
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for HandlerError {}
+
+/// Settles messages once their task finished, according to a policy
+#[derive(Clone)]
+pub(crate) struct Acker {
+    pub(crate) policy: Arc<dyn AckPolicy>,
+    pub(crate) handle: AckHandle,
+    pub(crate) reporter: Option<Arc<dyn ErrorReporter>>,
+}
+
+impl Acker {
+    /// What becomes of the message of the task with `ctx`, given its `outcome`
+    pub(crate) fn decide(
+        &self,
+        ctx: &PubSubContext,
+        outcome: Result<(), &(dyn Error + 'static)>,
+    ) -> AckDecision {
+        match outcome.err().and_then(RetryAfter::find) {
+            Some(delay) => AckDecision::NackAfter(delay),
+            None => self.policy.decide(ctx, outcome),
+        }
+    }
+
+    /// What becomes of the message of the task with `ctx`, given the `error`
+    /// its handler failed with
+    pub(crate) fn decide_error<E: fmt::Display + 'static>(
+        &self,
+        ctx: &PubSubContext,
+        error: &E,
+    ) -> AckDecision {
+        // Handler errors are usually boxed, which keeps their type
+        match (error as &dyn Any).downcast_ref::<BoxDynError>() {
+            Some(boxed) => self.decide(ctx, Err(boxed.as_ref())),
+            None => self.decide(ctx, Err(&HandlerError(error.to_string()))),
+        }
+    }
+
+    /// Acknowledges or nacks the message of the task with `ctx`, as decided
+    pub(crate) async fn settle(&self, ctx: &PubSubContext, decision: AckDecision) {
+        self.settle_because(ctx, decision, DEAD_LETTER_REASON).await
+    }
+
+    /// Settles the message of the task with `ctx` as decided, after its
+    /// handler failed with `error`
+    pub(crate) async fn settle_error(
+        &self,
+        ctx: &PubSubContext,
+        decision: AckDecision,
+        error: &str,
+    ) {
+        self.settle_because(ctx, decision, error).await
+    }
+
+    async fn settle_because(&self, ctx: &PubSubContext, decision: AckDecision, reason: &str) {
+        match self.handle.settle_because(ctx, decision, reason).await {
+            Ok(()) => {}
+            Err(e @ PubSubError::AckExpired(_)) => {
+                tracing::warn!(
+                    error = ?e,
+                    ?decision,
+                    "Ack deadline expired before the message was settled, it will be redelivered"
+                );
+                report(self.reporter.as_ref(), || {
+                    ErrorReport::new(FailureKind::Ack, &e)
+                });
+            }
+            Err(e) => {
+                tracing::error!(error = ?e, ?decision, "Failed to settle message");
+                report(self.reporter.as_ref(), || {
+                    ErrorReport::new(FailureKind::Ack, &e)
+                });
+            }
+        }
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Acknowledges messages once their task finished, as decided by `policy`
+    pub fn with_ack_policy(mut self, policy: Arc<dyn AckPolicy>) -> Self {
+        self.ack_policy = Some(policy);
+        self
+    }
+
+    /// The policy messages are settled with after their task, if they aren't
+    /// acknowledged on receipt
+    #[cfg(feature = "consume")]
+    pub(crate) fn effective_ack_policy(&self) -> Option<Arc<dyn AckPolicy>> {
+        match (&self.ack_policy, self.config.ack_strategy) {
+            (Some(policy), _) => Some(policy.clone()),
+            (None, AckStrategy::OnSuccess) => Some(Arc::new(AckOnSuccess)),
+            (None, AckStrategy::OnReceive | AckStrategy::Manual) => None,
+        }
+    }
+
+    /// Whether messages are acknowledged after their task rather than on
+    /// receipt
+    #[cfg(feature = "consume")]
+    pub(crate) fn defers_ack(&self) -> bool {
+        self.ack_policy.is_some() || self.config.ack_strategy != AckStrategy::OnReceive
+    }
+
+    /// Settles messages with the backend's ack policy, if any
+    #[cfg(feature = "consume")]
+    pub(crate) fn acker(&self) -> Option<Acker> {
+        let policy = self.effective_ack_policy()?;
+        Some(Acker {
+            policy,
+            handle: self.ack_handle(),
+            reporter: self.reporter.clone(),
+        })
+    }
+}
@@ -0,0 +1,351 @@
+//! Task metadata carried in message attributes
+//!
+//! Pub/Sub only transports a payload and string attributes, so the backend
+//! writes the apalis metadata of a task into attributes when publishing it and
+//! restores it when the task is consumed:
+//!
+//! | Attribute        | Task part                                    |
+//! |------------------|----------------------------------------------|
+//! | `task_id`        | task id                                      |
+//! | `attempt`        | attempts made so far, when any               |
+//! | `run_at`         | when the task should run, as a UNIX time     |
+//! | `priority`       | [`PubSubContext::priority`], when not 0      |
+//! | `run_before`     | [`PubSubContext::run_before`], when set      |
+//! | `not_before`     | [`PubSubContext::not_before`], when set      |
+//! | `job_type`       | [`PubSubContext::job_type`], when set        |
+//! | `checkpoint`     | [`PubSubContext::last_checkpoint`], when set |
+//! | `codec`          | codec of the payload, see [`crate::codecs`]  |
+//! | `schema_version` | [`PubSubContext::schema_version`], when set  |
+//! | `traceparent`    | [`PubSubContext::trace_context`], when set   |
+//! | `meta.<key>`     | [`PubSubContext::meta`] under `<key>`        |
+//! | any other        | [`PubSubContext::attributes`]                |
+//!
+//! This lets retries and scheduling metadata survive the trip through Pub/Sub,
+//! like they do in the SQL backends.
+//! Handlers can still read the attributes of the received message as they
+//! were, including those set by other producers or used by subscription
+//! filters, with [`PubSubContext::message_attributes`].
+//!
+//! Both paths go through [`MessageAttributes`], which types these attributes,
+//! the message's ordering key and the attributes apalis doesn't know about.
+//! Applications reading or writing task messages outside of apalis can use it
+//! too, rather than spelling attribute names out.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_core::{backend::TaskSink, task::builder::TaskBuilder};
+//! # use apalis_pubsub::{utils::PubSubContext, PubSubBackend, PubSubCompact};
+//! # async fn example(
+//! #     mut backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>,
+//! # ) -> Result<(), Box<dyn std::error::Error>> {
+//! let ctx = PubSubContext::default()
+//!     .with_priority(10)
+//!     .with_meta("source", "backfill");
+//! backend
+//!     .push_task(TaskBuilder::new(42).with_ctx(ctx).run_in_minutes(5).build())
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashMap;
+
+use apalis_core::task::{attempt::Attempt, builder::TaskBuilder, Parts};
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+
+use crate::{
+    checkpoint::Checkpoint, codecs::PUBSUB_ATTRIBUTE_CODEC, envelope::PUBSUB_ATTRIBUTE_FORMAT,
+    utils::PubSubContext, PubSubTaskId, PUBSUB_ATTRIBUTE_TASK_ID,
+};
+
+/// Name of the attribute holding the attempts made so far
+pub(crate) const PUBSUB_ATTRIBUTE_ATTEMPT: &str = "attempt";
+
+/// Name of the attribute holding when the task should run
+pub(crate) const PUBSUB_ATTRIBUTE_RUN_AT: &str = "run_at";
+
+/// Name of the attribute holding the priority of the task
+pub(crate) const PUBSUB_ATTRIBUTE_PRIORITY: &str = "priority";
+
+/// Name of the attribute holding the deadline of the task
+pub(crate) const PUBSUB_ATTRIBUTE_RUN_BEFORE: &str = "run_before";
+
+/// Name of the attribute holding when the task is scheduled for
+pub(crate) const PUBSUB_ATTRIBUTE_NOT_BEFORE: &str = "not_before";
+
+/// Name of the attribute holding the job type of the task
+pub(crate) const PUBSUB_ATTRIBUTE_JOB_TYPE: &str = "job_type";
+
+/// Name of the attribute holding the last checkpoint of the task
+pub(crate) const PUBSUB_ATTRIBUTE_CHECKPOINT: &str = "checkpoint";
+
+/// Name of the attribute holding the version of the payload's schema
+pub(crate) const PUBSUB_ATTRIBUTE_SCHEMA_VERSION: &str = "schema_version";
+
+/// Name of the attribute holding the W3C trace context of the task
+pub(crate) const PUBSUB_ATTRIBUTE_TRACE_CONTEXT: &str = "traceparent";
+
+/// Prefix of the attributes holding custom metadata of the task
+pub(crate) const PUBSUB_ATTRIBUTE_META_PREFIX: &str = "meta.";
+
+/// Attributes apalis writes on the messages it publishes
+pub(crate) const RESERVED_ATTRIBUTES: &[&str] = &[
+    PUBSUB_ATTRIBUTE_TASK_ID,
+    PUBSUB_ATTRIBUTE_ATTEMPT,
+    PUBSUB_ATTRIBUTE_RUN_AT,
+    PUBSUB_ATTRIBUTE_PRIORITY,
+    PUBSUB_ATTRIBUTE_RUN_BEFORE,
+    PUBSUB_ATTRIBUTE_NOT_BEFORE,
+    PUBSUB_ATTRIBUTE_JOB_TYPE,
+    PUBSUB_ATTRIBUTE_CHECKPOINT,
+    PUBSUB_ATTRIBUTE_CODEC,
+    PUBSUB_ATTRIBUTE_SCHEMA_VERSION,
+    PUBSUB_ATTRIBUTE_TRACE_CONTEXT,
+    PUBSUB_ATTRIBUTE_FORMAT,
+];
+
+/// The attributes of a task message, typed, see the
+/// [module level documentation](self)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageAttributes {
+    /// Id of the task
+    pub task_id: Option<PubSubTaskId>,
+    /// Attempts made so far, when any
+    pub attempt: Option<usize>,
+    /// When the task should run, as a UNIX time
+    pub run_at: Option<u64>,
+    /// Priority of the task, when not 0
+    pub priority: Option<i32>,
+    /// Deadline of the task, as a UNIX time
+    pub run_before: Option<u64>,
+    /// When the task is scheduled for, as a UNIX time
+    pub not_before: Option<u64>,
+    /// Job type of the task
+    pub job_type: Option<String>,
+    /// Last checkpoint of the task
+    pub checkpoint: Option<String>,
+    /// Name of the codec the payload is encoded with, when not the backend's
+    pub content_type: Option<String>,
+    /// Version of the payload's schema
+    pub schema_version: Option<String>,
+    /// W3C `traceparent` of the trace the task belongs to
+    pub trace_context: Option<String>,
+    /// Ordering key of the message, which Pub/Sub carries besides attributes
+    pub ordering_key: Option<String>,
+    /// Custom metadata of the task, see [`PubSubContext::meta`]
+    pub meta: HashMap<String, String>,
+    /// Attributes apalis doesn't know about
+    pub custom: HashMap<String, String>,
+}
+
+impl MessageAttributes {
+    /// Whether apalis reads or writes the attribute `name` itself
+    pub fn is_reserved(name: &str) -> bool {
+        RESERVED_ATTRIBUTES.contains(&name) || name.starts_with(PUBSUB_ATTRIBUTE_META_PREFIX)
+    }
+
+    /// Reads the attributes and ordering key of `message`
+    pub fn from_message(message: &PubsubMessage) -> Self {
+        let mut attributes = Self::from_map(&message.attributes);
+        attributes.ordering_key =
+            (!message.ordering_key.is_empty()).then(|| message.ordering_key.clone());
+        attributes
+    }
+
+    /// Reads `attributes`, logging and ignoring invalid values
+    pub fn from_map(attributes: &HashMap<String, String>) -> Self {
+        let mut typed = Self {
+            task_id: parse_attribute(attributes, PUBSUB_ATTRIBUTE_TASK_ID),
+            attempt: parse_attribute(attributes, PUBSUB_ATTRIBUTE_ATTEMPT),
+            run_at: parse_attribute(attributes, PUBSUB_ATTRIBUTE_RUN_AT),
+            priority: parse_attribute(attributes, PUBSUB_ATTRIBUTE_PRIORITY),
+            run_before: parse_attribute(attributes, PUBSUB_ATTRIBUTE_RUN_BEFORE),
+            not_before: parse_attribute(attributes, PUBSUB_ATTRIBUTE_NOT_BEFORE),
+            job_type: attributes.get(PUBSUB_ATTRIBUTE_JOB_TYPE).cloned(),
+            checkpoint: attributes.get(PUBSUB_ATTRIBUTE_CHECKPOINT).cloned(),
+            content_type: attributes.get(PUBSUB_ATTRIBUTE_CODEC).cloned(),
+            schema_version: attributes.get(PUBSUB_ATTRIBUTE_SCHEMA_VERSION).cloned(),
+            trace_context: attributes.get(PUBSUB_ATTRIBUTE_TRACE_CONTEXT).cloned(),
+            ..Default::default()
+        };
+        for (name, value) in attributes {
+            if let Some(key) = name.strip_prefix(PUBSUB_ATTRIBUTE_META_PREFIX) {
+                typed.meta.insert(key.to_owned(), value.clone());
+            } else if !Self::is_reserved(name) {
+                typed.custom.insert(name.clone(), value.clone());
+            }
+        }
+        typed
+    }
+
+    /// The metadata of the task with `parts`, published with `ordering_key`
+    pub(crate) fn from_parts(
+        parts: &Parts<PubSubContext, PubSubTaskId>,
+        ordering_key: Option<String>,
+    ) -> Self {
+        let ctx = &parts.ctx;
+        Self {
+            task_id: parts.task_id.as_ref().map(|id| *id.inner()),
+            attempt: Some(parts.attempt.current()).filter(|attempt| *attempt > 0),
+            run_at: Some(parts.run_at),
+            priority: Some(ctx.priority()).filter(|priority| *priority != 0),
+            run_before: ctx.run_before(),
+            not_before: ctx.not_before(),
+            job_type: ctx.job_type().map(str::to_owned),
+            checkpoint: ctx.last_checkpoint(),
+            content_type: None,
+            schema_version: ctx.schema_version().map(str::to_owned),
+            trace_context: ctx.trace_context().map(str::to_owned),
+            ordering_key,
+            meta: ctx.meta_entries().clone(),
+            custom: ctx
+                .attributes()
+                .iter()
+                .filter(|(key, _)| {
+                    let reserved = Self::is_reserved(key);
+                    if reserved {
+                        tracing::warn!(key, "Ignoring attribute reserved by the backend");
+                    }
+                    !reserved
+                })
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        }
+    }
+
+    /// The attributes as Pub/Sub carries them, without the ordering key
+    pub fn to_map(&self) -> HashMap<String, String> {
+        let mut attributes = HashMap::new();
+        self.write(&mut attributes);
+        attributes
+    }
+
+    /// Writes the attributes and ordering key into `message`, over the
+    /// attributes it already has
+    pub fn apply(&self, message: &mut PubsubMessage) {
+        self.write(&mut message.attributes);
+        if let Some(ordering_key) = &self.ordering_key {
+            message.ordering_key = ordering_key.clone();
+        }
+    }
+
+    /// Writes the attributes into `attributes`, custom ones first so reserved
+    /// ones take precedence
+    pub(crate) fn write(&self, attributes: &mut HashMap<String, String>) {
+        attributes.extend(self.custom.clone());
+        for (key, value) in &self.meta {
+            attributes.insert(
+                format!("{PUBSUB_ATTRIBUTE_META_PREFIX}{key}"),
+                value.clone(),
+            );
+        }
+        let typed = [
+            (
+                PUBSUB_ATTRIBUTE_TASK_ID,
+                self.task_id.map(|id| id.to_string()),
+            ),
+            (
+                PUBSUB_ATTRIBUTE_ATTEMPT,
+                self.attempt.map(|a| a.to_string()),
+            ),
+            (PUBSUB_ATTRIBUTE_RUN_AT, self.run_at.map(|t| t.to_string())),
+            (
+                PUBSUB_ATTRIBUTE_PRIORITY,
+                self.priority.map(|p| p.to_string()),
+            ),
+            (
+                PUBSUB_ATTRIBUTE_RUN_BEFORE,
+                self.run_before.map(|t| t.to_string()),
+            ),
+            (
+                PUBSUB_ATTRIBUTE_NOT_BEFORE,
+                self.not_before.map(|t| t.to_string()),
+            ),
+            (PUBSUB_ATTRIBUTE_JOB_TYPE, self.job_type.clone()),
+            (PUBSUB_ATTRIBUTE_CHECKPOINT, self.checkpoint.clone()),
+            (PUBSUB_ATTRIBUTE_CODEC, self.content_type.clone()),
+            (PUBSUB_ATTRIBUTE_SCHEMA_VERSION, self.schema_version.clone()),
+            (PUBSUB_ATTRIBUTE_TRACE_CONTEXT, self.trace_context.clone()),
+        ];
+        for (name, value) in typed {
+            if let Some(value) = value {
+                attributes.insert(name.to_owned(), value);
+            }
+        }
+    }
+
+    /// Restores the context metadata of a task
+    pub(crate) fn restore_context(&self, mut ctx: PubSubContext) -> PubSubContext {
+        if let Some(priority) = self.priority {
+            ctx = ctx.with_priority(priority);
+        }
+        if let Some(run_before) = self.run_before {
+            ctx = ctx.with_run_before(run_before);
+        }
+        if let Some(not_before) = self.not_before {
+            ctx = ctx.with_not_before(not_before);
+        }
+        if let Some(job_type) = &self.job_type {
+            ctx = ctx.with_job_type(job_type.clone());
+        }
+        if let Some(checkpoint) = &self.checkpoint {
+            ctx.set_checkpoint(Checkpoint::restored(Some(checkpoint.clone())));
+        }
+        if let Some(schema_version) = &self.schema_version {
+            ctx = ctx.with_schema_version(schema_version.clone());
+        }
+        if let Some(trace_context) = &self.trace_context {
+            ctx = ctx.with_trace_context(trace_context.clone());
+        }
+        for (key, value) in &self.meta {
+            ctx = ctx.with_meta(key.clone(), value.clone());
+        }
+        for (key, value) in &self.custom {
+            ctx = ctx.with_attribute(key.clone(), value.clone());
+        }
+        ctx
+    }
+
+    /// Restores the attempts and schedule of a task
+    pub(crate) fn restore_parts<M>(
+        &self,
+        mut task: TaskBuilder<M, PubSubContext, PubSubTaskId>,
+    ) -> TaskBuilder<M, PubSubContext, PubSubTaskId> {
+        if let Some(attempt) = self.attempt {
+            task = task.with_attempt(Attempt::new_with_value(attempt));
+        }
+        if let Some(run_at) = self.run_at {
+            task = task.run_at_timestamp(run_at);
+        }
+        task
+    }
+}
+
+/// Parses the attribute `name`, logging and ignoring invalid values
+fn parse_attribute<T: std::str::FromStr>(
+    attributes: &HashMap<String, String>,
+    name: &str,
+) -> Option<T>
+where
+    T::Err: std::fmt::Display,
+{
+    attributes.get(name).and_then(|value| {
+        value
+            .parse()
+            .inspect_err(|e| tracing::warn!(attribute = name, value, "Invalid attribute: {e}"))
+            .ok()
+    })
+}
+
+/// When the message with `attributes` should run, as a UNIX time
+#[cfg(any(feature = "consume", feature = "local"))]
+pub(crate) fn read_run_at(attributes: &HashMap<String, String>) -> Option<u64> {
+    parse_attribute(attributes, PUBSUB_ATTRIBUTE_RUN_AT)
+}
+
+/// When the message with `attributes` is scheduled for, as a UNIX time
+#[cfg(feature = "consume")]
+pub(crate) fn read_not_before(attributes: &HashMap<String, String>) -> Option<u64> {
+    parse_attribute(attributes, PUBSUB_ATTRIBUTE_NOT_BEFORE)
+}
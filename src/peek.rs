@@ -0,0 +1,76 @@
+//! Inspecting upcoming messages without consuming them
+//!
+//! [`PubSubBackend::peek`] pulls messages, decodes them and immediately hands
+//! them back to Pub/Sub, so operators and debugging tools can look at upcoming
+//! work while workers keep running.
+//!
+//! Peeked messages count as deliveries: they contribute to a dead-letter
+//! policy's delivery attempts and may be delivered out of order afterwards.
+use apalis_core::backend::codec::Codec;
+use futures::future::join_all;
+
+use crate::{
+    envelope, message_task_id, received_task, transport::TransportMessage, PubSubBackend,
+    PubSubCompact, PubSubError, PubSubTask,
+};
+
+impl<M, C> PubSubBackend<M, C>
+where
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Returns up to `n` upcoming tasks without acknowledging them
+    ///
+    /// Pub/Sub may return fewer messages than available. Messages that fail
+    /// to decode are logged and skipped.
+    pub async fn peek(&self, n: usize) -> Result<Vec<PubSubTask<M>>, PubSubError> {
+        let messages = self
+            .subscription
+            .pull(n.try_into().unwrap_or(i32::MAX), None)
+            .await
+            .map_err(|e| PubSubError::Subscription(e.to_string()))?;
+
+        // Hand the messages back before doing anything else with them
+        let nacks = join_all(messages.iter().map(|message| message.nack())).await;
+        if let Some(Err(e)) = nacks.into_iter().find(Result::is_err) {
+            tracing::warn!(error = ?e, "Failed to nack peeked message");
+        }
+
+        Ok(messages
+            .into_iter()
+            .map(|mut message| {
+                let ack_id = message.ack_id().to_string();
+                TransportMessage::new(
+                    self.transport.clone(),
+                    self.subscription.fully_qualified_name(),
+                    ack_id,
+                    std::mem::take(&mut message.message),
+                    message.delivery_attempt(),
+                )
+            })
+            .filter_map(|mut message| {
+                let decoded = envelope::open(&mut message.message).and_then(|()| {
+                    self.codecs
+                        .decode::<C>(&message.message)
+                        .map_err(|e| PubSubError::Codec(e.to_string()))
+                });
+                match decoded {
+                    Ok(args) => Some(received_task(
+                        args,
+                        &message,
+                        message_task_id(&message.message),
+                        &self.context_hooks,
+                    )),
+                    Err(e) => {
+                        tracing::warn!(
+                            error = ?e,
+                            message_id = message.message.message_id,
+                            "Failed to decode peeked message"
+                        );
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+}
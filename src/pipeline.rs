@@ -0,0 +1,216 @@
+//! Transforming tasks before they reach the worker
+//!
+//! Normalization and enrichment shared by every handler of a backend can run
+//! once, as a [`Pipeline`] of async stages set with
+//! [`PubSubBackend::with_pipeline`]. Stages run in order on every decoded task,
+//! between its receipt and its dispatch to the worker, and may:
+//!
+//! - change its arguments, with [`Pipeline::map`],
+//! - add metadata to its context, with [`Pipeline::enrich`],
+//! - drop it, with [`Pipeline::filter`], or
+//! - do any of these, with [`Pipeline::stage`].
+//!
+//! A stage drops a task by returning an [`AckDecision`], which settles its
+//! message like an [`AckPolicy`](crate::outcome::AckPolicy) would: acknowledged
+//! for good, dead-lettered, or nacked for redelivery, now or after a delay.
+//!
+//! Stages run after [context hooks](crate::extensions), so they can read what
+//! hooks attached to the context.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{pipeline::Pipeline, PubSubBackend, PubSubCompact};
+//! # use std::collections::HashMap;
+//! # fn example(backend: PubSubBackend<String, JsonCodec<PubSubCompact>>) {
+//! let pipeline = Pipeline::<String>::new()
+//!     .filter(|task| !task.args.is_empty())
+//!     .map(|email: String| async move { email.trim().to_lowercase() })
+//!     .enrich(|email: &String| {
+//!         let domain = email.rsplit('@').next().unwrap_or_default().to_string();
+//!         async move { HashMap::from([("domain".to_string(), domain)]) }
+//!     });
+//! let backend = backend.with_pipeline(pipeline);
+//! # }
+//! ```
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use apalis_core::task::Task;
+use futures::{future::BoxFuture, FutureExt};
+
+#[cfg(feature = "consume")]
+use crate::{
+    ack::{ack_message, modify_message_deadline, AckMode},
+    dlq::{self, DeadLetterer, DEAD_LETTER_KIND_REJECTED},
+    lease::MAX_ACK_DEADLINE,
+    transport::TransportMessage,
+};
+use crate::{outcome::AckDecision, PubSubBackend, PubSubTask};
+
+/// What a stage makes of a task: the task to pass on, or how to settle its
+/// dropped message
+pub type StageResult<M> = Result<PubSubTask<M>, AckDecision>;
+
+/// A stage of a [`Pipeline`]
+type Stage<M> = Arc<dyn Fn(PubSubTask<M>) -> BoxFuture<'static, StageResult<M>> + Send + Sync>;
+
+/// Async stages run on tasks before dispatch, see the
+/// [module level documentation](self)
+pub struct Pipeline<M> {
+    stages: Vec<Stage<M>>,
+}
+
+impl<M> Clone for Pipeline<M> {
+    fn clone(&self) -> Self {
+        Self {
+            stages: self.stages.clone(),
+        }
+    }
+}
+
+impl<M> Default for Pipeline<M> {
+    fn default() -> Self {
+        Self { stages: Vec::new() }
+    }
+}
+
+impl<M> std::fmt::Debug for Pipeline<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("stages", &self.stages.len())
+            .finish()
+    }
+}
+
+impl<M: Send + 'static> Pipeline<M> {
+    /// An empty pipeline, passing tasks on unchanged
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `stage` on tasks, after the stages added before it
+    pub fn stage<F, Fut>(mut self, stage: F) -> Self
+    where
+        F: Fn(PubSubTask<M>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = StageResult<M>> + Send + 'static,
+    {
+        self.stages.push(Arc::new(move |task| stage(task).boxed()));
+        self
+    }
+
+    /// Replaces the arguments of tasks with what `map` makes of them
+    pub fn map<F, Fut>(self, map: F) -> Self
+    where
+        F: Fn(M) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = M> + Send + 'static,
+    {
+        let map = Arc::new(map);
+        self.stage(move |task: PubSubTask<M>| {
+            let map = map.clone();
+            async move {
+                let Task { args, parts } = task;
+                Ok(Task {
+                    args: map(args).await,
+                    parts,
+                })
+            }
+        })
+    }
+
+    /// Drops tasks for which `keep` returns false, acknowledging their message
+    pub fn filter<F>(self, keep: F) -> Self
+    where
+        F: Fn(&PubSubTask<M>) -> bool + Send + Sync + 'static,
+    {
+        self.stage(move |task| {
+            let kept = keep(&task);
+            async move {
+                if kept {
+                    Ok(task)
+                } else {
+                    tracing::debug!(task_id = ?task.parts.task_id, "Task filtered out");
+                    Err(AckDecision::Ack)
+                }
+            }
+        })
+    }
+
+    /// Adds the metadata `enrich` derives from the arguments of tasks to their
+    /// context, see [`PubSubContext::meta`](crate::utils::PubSubContext::meta)
+    pub fn enrich<F, Fut>(self, enrich: F) -> Self
+    where
+        F: Fn(&M) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HashMap<String, String>> + Send + 'static,
+    {
+        self.stage(move |mut task: PubSubTask<M>| {
+            let meta = enrich(&task.args);
+            async move {
+                let mut ctx = std::mem::take(&mut task.parts.ctx);
+                for (key, value) in meta.await {
+                    ctx = ctx.with_meta(key, value);
+                }
+                task.parts.ctx = ctx;
+                Ok(task)
+            }
+        })
+    }
+
+    /// Whether tasks pass through unchanged
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Runs the stages on `task`, stopping at the first that drops it
+    #[cfg(feature = "consume")]
+    pub(crate) async fn run(&self, mut task: PubSubTask<M>) -> StageResult<M> {
+        for stage in &self.stages {
+            task = stage(task).await?;
+        }
+        Ok(task)
+    }
+}
+
+/// Settles the message of a task a stage dropped, as `decision` says
+///
+/// Dead-lettered messages are republished with `dead_letters`, if any, see
+/// [`crate::dlq`].
+#[cfg(feature = "consume")]
+pub(crate) async fn settle(
+    message: &TransportMessage,
+    decision: AckDecision,
+    mode: &AckMode,
+    dead_letters: Option<&DeadLetterer>,
+) {
+    let result = match decision {
+        AckDecision::Ack => ack_message(message, mode).await,
+        AckDecision::DeadLetter => {
+            let reason = "Dropped by a pipeline stage";
+            dlq::reject(
+                dead_letters,
+                message,
+                DEAD_LETTER_KIND_REJECTED,
+                reason,
+                mode,
+            )
+            .await;
+            return;
+        }
+        AckDecision::Nack => modify_message_deadline(message, 0, mode).await,
+        AckDecision::NackAfter(delay) => {
+            let seconds = delay.min(MAX_ACK_DEADLINE).as_secs() as i32;
+            modify_message_deadline(message, seconds, mode).await
+        }
+    };
+    if let Err(e) = result {
+        tracing::error!(error = ?e, ?decision, "Failed to settle dropped message");
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Runs `pipeline` on every decoded task before it's dispatched
+    pub fn with_pipeline(mut self, pipeline: Pipeline<M>) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+}
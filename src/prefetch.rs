@@ -0,0 +1,95 @@
+//! Adaptive prefetching
+//!
+//! Every message received ahead of the worker holds a lease: Pub/Sub won't
+//! redeliver it to another worker until the lease expires. A large
+//! [`PubSubConfig::buffer_size`](crate::PubSubConfig::buffer_size) keeps a fast
+//! worker busy, but lets a slow worker hoard messages other workers could
+//! process right away.
+//!
+//! With [`PubSubConfig::adaptive_prefetch`](crate::PubSubConfig::adaptive_prefetch)
+//! the number of messages buffered ahead of the worker follows its processing
+//! rate instead. Once a second the backend measures how many tasks the worker
+//! took, which reflects both handler latency and concurrency, and sizes the
+//! prefetch window to cover [`AdaptivePrefetch::lookahead`] of work at that
+//! rate.
+use std::time::Duration;
+#[cfg(feature = "consume")]
+use {
+    crate::{control::ConcurrencyControl, stats::PubSubStats},
+    apalis_core::timer::sleep,
+    std::sync::Arc,
+    tokio_util::sync::CancellationToken,
+};
+
+/// How often the prefetch window is resized
+#[cfg(feature = "consume")]
+const ADJUST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Weight of the latest measurement in the smoothed processing rate
+#[cfg(feature = "consume")]
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Bounds and target of the adaptive prefetch window
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptivePrefetch {
+    /// Fewest messages buffered ahead of the worker (default: 1)
+    pub min: usize,
+    /// Most messages buffered ahead of the worker (default: 100)
+    ///
+    /// [`PubSubConfig::buffer_size`](crate::PubSubConfig::buffer_size) still
+    /// caps the buffer, so there's no point setting this higher.
+    pub max: usize,
+    /// How much work to keep buffered, at the worker's current rate (default: 2s)
+    pub lookahead: Duration,
+}
+
+impl Default for AdaptivePrefetch {
+    fn default() -> Self {
+        Self {
+            min: 1,
+            max: 100,
+            lookahead: Duration::from_secs(2),
+        }
+    }
+}
+
+#[cfg(feature = "consume")]
+impl AdaptivePrefetch {
+    /// Window covering the lookahead at `rate` tasks per second
+    fn window(&self, rate: f64) -> usize {
+        let window = (rate * self.lookahead.as_secs_f64()).ceil() as usize;
+        window.clamp(self.min.max(1), self.max.max(self.min).max(1))
+    }
+
+    /// Gate limiting buffered messages, starting at the smallest window
+    pub(crate) fn gate(&self) -> Arc<ConcurrencyControl> {
+        Arc::new(ConcurrencyControl::new(Some(self.window(0.0))))
+    }
+
+    /// Resizes `gate` to the worker's processing rate until `cancel` fires
+    pub(crate) async fn run(
+        self,
+        gate: Arc<ConcurrencyControl>,
+        stats: Arc<PubSubStats>,
+        cancel: CancellationToken,
+    ) {
+        let mut rate = 0.0;
+        let mut started = stats.started();
+        while cancel
+            .run_until_cancelled(sleep(ADJUST_INTERVAL))
+            .await
+            .is_some()
+        {
+            let now_started = stats.started();
+            let measured = (now_started - started) as f64 / ADJUST_INTERVAL.as_secs_f64();
+            started = now_started;
+            rate = RATE_SMOOTHING * measured + (1.0 - RATE_SMOOTHING) * rate;
+
+            let window = self.window(rate);
+            if gate.limit() != Some(window) {
+                tracing::debug!(window, rate, "Resized prefetch window");
+                gate.set_limit(window);
+            }
+        }
+    }
+}
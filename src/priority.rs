@@ -0,0 +1,85 @@
+//! Routing tasks to topics by priority
+//!
+//! Pub/Sub delivers messages in no particular order, so priorities are
+//! served by separate topics, each with its own workers. With
+//! [`PubSubBackend::with_priority_tiers`], the backend publishes every task to
+//! the topic of its priority tier, read from [`PubSubContext::priority`], so
+//! producers only set the priority:
+//!
+//! - a task goes to the tier with the highest minimum priority not above its own,
+//! - tasks below every tier go to the backend's topic,
+//! - a [`topic`](crate::jobs::JobTypeConfig::topic) set for the job type of a
+//!   task takes precedence over its tier.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_core::{backend::TaskSink, task::builder::TaskBuilder};
+//! # use apalis_pubsub::{priority::PriorityTiers, utils::PubSubContext, PubSubBackend, PubSubCompact};
+//! # async fn example(
+//! #     backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>,
+//! # ) -> Result<(), Box<dyn std::error::Error>> {
+//! let mut backend = backend.with_priority_tiers(
+//!     PriorityTiers::default()
+//!         .with_tier(10, "tasks-high")
+//!         .with_tier(100, "tasks-urgent"),
+//! );
+//! // Published to `tasks-high`
+//! let ctx = PubSubContext::default().with_priority(20);
+//! backend.push_task(TaskBuilder::new(42).with_ctx(ctx).build()).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`PubSubContext::priority`]: crate::utils::PubSubContext::priority
+use crate::PubSubBackend;
+
+/// Topics tasks are published to by priority, see the
+/// [module level documentation](self)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PriorityTiers {
+    /// Minimum priority and topic of each tier, sorted by minimum priority
+    tiers: Vec<(i32, String)>,
+}
+
+impl PriorityTiers {
+    /// Publishes tasks with a priority of at least `min_priority` to `topic`,
+    /// unless a tier with a higher minimum applies
+    ///
+    /// Adding a tier with the same minimum priority replaces its topic.
+    pub fn with_tier(mut self, min_priority: i32, topic: impl Into<String>) -> Self {
+        let topic = topic.into();
+        match self
+            .tiers
+            .binary_search_by_key(&min_priority, |(min, _)| *min)
+        {
+            Ok(index) => self.tiers[index].1 = topic,
+            Err(index) => self.tiers.insert(index, (min_priority, topic)),
+        }
+        self
+    }
+
+    /// Topic of the tier tasks with `priority` belong to, `None` for the
+    /// backend's topic
+    pub fn topic(&self, priority: i32) -> Option<&str> {
+        self.tiers
+            .iter()
+            .rev()
+            .find(|(min, _)| *min <= priority)
+            .map(|(_, topic)| topic.as_str())
+    }
+
+    /// Whether no tier was added
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Publishes tasks to the topic of their priority tier
+    pub fn with_priority_tiers(mut self, tiers: PriorityTiers) -> Self {
+        self.priority_tiers = tiers;
+        self
+    }
+}
@@ -0,0 +1,227 @@
+//! Publishing tasks without a backend
+//!
+//! Web services that enqueue tasks but never run them don't need a worker's
+//! subscription, buffers and background tasks. A [`PubSubProducer`] only
+//! holds a topic: it encodes tasks with the codec `C` and publishes them the
+//! way a [`PubSubBackend`] does, so workers of a backend consume them as
+//! usual. Each call returns once Pub/Sub accepted the tasks, with their
+//! message and task ids.
+//!
+//! Producers are cheap to clone and can be shared between request handlers.
+//!
+//! Requires the `publish` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{producer::PubSubProducer, PubSubCompact, PubSubError};
+//! # use google_cloud_pubsub::client::{Client, ClientConfig};
+//! # use std::collections::HashMap;
+//! # async fn example() -> Result<(), PubSubError> {
+//! let client = Client::new(ClientConfig::default().with_auth().await.unwrap())
+//!     .await
+//!     .unwrap();
+//! let producer = PubSubProducer::<u32, JsonCodec<PubSubCompact>>::new(client, "my-topic");
+//!
+//! producer.push(42).await?;
+//! producer.push_batch(vec![1, 2, 3]).await?;
+//! producer
+//!     .push_with_attributes(7, HashMap::from([("tenant".to_string(), "acme".to_string())]))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
+
+use apalis_core::{
+    backend::codec::Codec,
+    task::{builder::TaskBuilder, task_id::TaskId},
+};
+use futures::future::try_join_all;
+use google_cloud_pubsub::client::Client;
+use uuid::Uuid;
+
+use crate::{
+    backoff::{BackoffStrategy, Exponential},
+    clock::{Clock, SystemClock},
+    envelope::WireFormat,
+    no_topic,
+    sink::{publish_with_retry, task_message, OrderingKeyFn},
+    transport::{GcpTransport, PubSubTransport},
+    utils::PubSubContext,
+    PubSubBackend, PubSubCompact, PubSubError, PublishedTask,
+};
+
+/// Publishes tasks to a topic, see the [module level documentation](self)
+pub struct PubSubProducer<M, C> {
+    transport: Arc<dyn PubSubTransport>,
+    /// Fully qualified name of the topic
+    topic: String,
+    wire_format: WireFormat,
+    backoff: Arc<dyn BackoffStrategy>,
+    clock: Arc<dyn Clock>,
+    ordering_key: Option<OrderingKeyFn<M>>,
+    _codec: PhantomData<fn() -> C>,
+}
+
+impl<M, C> Clone for PubSubProducer<M, C> {
+    fn clone(&self) -> Self {
+        Self {
+            transport: self.transport.clone(),
+            topic: self.topic.clone(),
+            wire_format: self.wire_format,
+            backoff: self.backoff.clone(),
+            clock: self.clock.clone(),
+            ordering_key: self.ordering_key.clone(),
+            _codec: PhantomData,
+        }
+    }
+}
+
+impl<M, C> std::fmt::Debug for PubSubProducer<M, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PubSubProducer")
+            .field("topic", &self.topic)
+            .field("wire_format", &self.wire_format)
+            .finish()
+    }
+}
+
+impl<M, C> PubSubProducer<M, C> {
+    /// Creates a producer publishing to the topic `topic_name` with `client`
+    pub fn new(client: Client, topic_name: &str) -> Self {
+        Self {
+            topic: client.topic(topic_name).fully_qualified_name().to_string(),
+            transport: Arc::new(GcpTransport::new(client)),
+            wire_format: WireFormat::Attributes,
+            backoff: Arc::new(Exponential::default()),
+            clock: Arc::new(SystemClock),
+            ordering_key: None,
+            _codec: PhantomData,
+        }
+    }
+
+    /// Lays tasks out in messages as `wire_format` says, see
+    /// [`PubSubConfig::wire_format`](crate::PubSubConfig::wire_format)
+    pub fn with_wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = wire_format;
+        self
+    }
+
+    /// Waits `backoff` between retries of transient publish failures
+    pub fn with_backoff(mut self, backoff: Arc<dyn BackoffStrategy>) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Publishes tasks with the ordering key returned by `ordering_key`, see
+    /// [`PubSubBackend::with_ordering_key`]
+    pub fn with_ordering_key(
+        mut self,
+        ordering_key: impl Fn(&M) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.ordering_key = Some(Arc::new(ordering_key));
+        self
+    }
+
+    /// Publishes through `transport` instead of the client's connection
+    pub fn with_transport(mut self, transport: Arc<dyn PubSubTransport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Fully qualified name of the topic tasks are published to
+    pub fn topic_name(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl<M, C> PubSubProducer<M, C>
+where
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: std::error::Error,
+{
+    /// Publishes a task with `args`, returning its message and task ids
+    pub async fn push(&self, args: M) -> Result<PublishedTask, PubSubError> {
+        self.push_with_attributes(args, HashMap::new()).await
+    }
+
+    /// Publishes a task for each of `args`, returning their message and task
+    /// ids in the same order
+    ///
+    /// Tasks are published concurrently, the first failure is returned.
+    pub async fn push_batch(&self, args: Vec<M>) -> Result<Vec<PublishedTask>, PubSubError> {
+        try_join_all(
+            args.into_iter()
+                .map(|args| self.push_with_attributes(args, HashMap::new())),
+        )
+        .await
+    }
+
+    /// Publishes a task with `args` and the message attributes `attributes`,
+    /// returning its message and task ids
+    ///
+    /// Attributes let subscription filters and non-apalis consumers select
+    /// tasks. Those the backend reserves, see
+    /// [`MessageAttributes::is_reserved`](crate::parts::MessageAttributes::is_reserved),
+    /// are ignored.
+    pub async fn push_with_attributes(
+        &self,
+        args: M,
+        attributes: HashMap<String, String>,
+    ) -> Result<PublishedTask, PubSubError> {
+        let ordering_key = self
+            .ordering_key
+            .as_ref()
+            .and_then(|ordering_key| ordering_key(&args));
+        let encoded = C::encode(&args).map_err(|e| PubSubError::Codec(e.to_string()))?;
+        let ctx = attributes
+            .into_iter()
+            .fold(PubSubContext::default(), |ctx, (key, value)| {
+                ctx.with_attribute(key, value)
+            });
+        let task_id = Uuid::new_v4();
+        let task = TaskBuilder::new(encoded)
+            .with_ctx(ctx)
+            .with_task_id(TaskId::new(task_id))
+            .build();
+        let message = task_message(task, ordering_key, self.wire_format);
+
+        let message_id = publish_with_retry(
+            self.transport.as_ref(),
+            &self.topic,
+            message,
+            self.backoff.as_ref(),
+            self.clock.as_ref(),
+        )
+        .await
+        .map_err(|e| PubSubError::Client(e.to_string()))?;
+        tracing::debug!(message_id, topic = self.topic, "Task published");
+        Ok(PublishedTask {
+            message_id,
+            task_id,
+        })
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// A [`PubSubProducer`] publishing to the backend's topic, with its
+    /// transport, wire format and retries
+    ///
+    /// The producer applies the backend's ordering key, but not its job type
+    /// or priority settings. Fails if the backend is
+    /// [consume-only](PubSubBackend::consumer).
+    pub fn producer(&self) -> Result<PubSubProducer<M, C>, PubSubError> {
+        let topic = self.topic_name().ok_or_else(no_topic)?;
+        Ok(PubSubProducer {
+            transport: self.transport.clone(),
+            topic: topic.to_string(),
+            wire_format: self.config.wire_format,
+            backoff: self.config.backoff.clone(),
+            clock: self.config.clock.clone(),
+            ordering_key: self.sink.ordering_key.clone(),
+            _codec: PhantomData,
+        })
+    }
+}
@@ -0,0 +1,443 @@
+//! Declarative provisioning of topics and subscriptions
+//!
+//! [`Provisioning`] describes the Pub/Sub resources a backend depends on: its
+//! topic, its worker subscription and any additional export subscriptions.
+//! [`PubSubBackend::ensure_resources`] creates whatever is missing, so the queue
+//! topology can live next to the worker code instead of in separate tooling.
+//!
+//! Existing resources are left untouched.
+//!
+//! In development environments,
+//! [`PubSubBackend::with_auto_create`] instead creates the resources the first
+//! time a publish or the subscription finds one missing, and tries again.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{provision::*, PubSubBackend, PubSubCompact};
+//! # use std::time::Duration;
+//! # async fn example(
+//! #     mut backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>,
+//! # ) -> Result<(), apalis_pubsub::PubSubError> {
+//! let provisioning = Provisioning::new()
+//!     .with_message_retention(Duration::from_secs(3 * 24 * 60 * 60))
+//!     .retain_acked_messages(true)
+//!     .with_label("team", "billing")
+//!     .with_allowed_regions(["europe-west1", "europe-west4"])
+//!     .with_bigquery_export(BigQueryExport::new(
+//!         "jobs-to-bigquery",
+//!         "my-project.analytics.jobs",
+//!     ));
+//!
+//! backend.ensure_resources(&provisioning).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{sync::Arc, time::Duration};
+
+use google_cloud_gax::grpc::{Code, Status};
+use google_cloud_googleapis::pubsub::v1::{BigQueryConfig, DeadLetterPolicy, ExpirationPolicy};
+use google_cloud_pubsub::{
+    subscription::{Subscription, SubscriptionConfig},
+    topic::{Topic, TopicConfig},
+};
+
+use crate::{
+    admin::{LABEL_MANAGED_BY, LABEL_MANAGED_BY_APALIS},
+    PubSubBackend, PubSubError,
+};
+
+/// A subscription writing every message of the topic to a BigQuery table
+#[derive(Debug, Clone)]
+pub struct BigQueryExport {
+    /// Name of the export subscription
+    pub subscription: String,
+    /// Where and how messages are written
+    pub config: BigQueryConfig,
+}
+
+impl BigQueryExport {
+    /// Exports messages to `table`, given as `{project}.{dataset}.{table}`
+    ///
+    /// Message metadata (message id, publish time and attributes, which
+    /// include the task id) is written alongside the payload.
+    pub fn new(subscription: impl Into<String>, table: impl Into<String>) -> Self {
+        Self {
+            subscription: subscription.into(),
+            config: BigQueryConfig {
+                table: table.into(),
+                write_metadata: true,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Writes payload fields to the table's columns using the topic's schema
+    pub fn use_topic_schema(mut self, use_topic_schema: bool) -> Self {
+        self.config.use_topic_schema = use_topic_schema;
+        self
+    }
+
+    /// Ignores payload fields that have no matching column
+    pub fn drop_unknown_fields(mut self, drop_unknown_fields: bool) -> Self {
+        self.config.drop_unknown_fields = drop_unknown_fields;
+        self
+    }
+}
+
+/// The Pub/Sub resources a backend depends on
+///
+/// See the [module level documentation](self) for more details.
+#[derive(Debug, Clone, Default)]
+pub struct Provisioning {
+    topic: TopicConfig,
+    subscription: SubscriptionConfig,
+    bigquery_exports: Vec<BigQueryExport>,
+    dead_letter: Option<DeadLetter>,
+}
+
+/// Dead-letter settings of the worker subscription
+#[derive(Debug, Clone)]
+struct DeadLetter {
+    topic: String,
+    max_delivery_attempts: i32,
+}
+
+/// Ack deadlines Pub/Sub accepts, in seconds
+pub(crate) const ACK_DEADLINE_RANGE: std::ops::RangeInclusive<u64> = 10..=600;
+
+/// Ack deadline of subscriptions created without one, in seconds
+const DEFAULT_ACK_DEADLINE: u64 = 10;
+
+/// Bounds Pub/Sub accepts for a dead-letter policy's maximum delivery attempts
+const DELIVERY_ATTEMPTS_RANGE: std::ops::RangeInclusive<i32> = 5..=100;
+
+impl Provisioning {
+    /// Creates a provisioning with default topic and subscription settings
+    ///
+    /// Resources are labelled as managed by apalis, see [`admin`](crate::admin).
+    pub fn new() -> Self {
+        Self::default().with_label(LABEL_MANAGED_BY, LABEL_MANAGED_BY_APALIS)
+    }
+
+    /// How long the subscription keeps unacknowledged messages (10 minutes to 7 days)
+    ///
+    /// Also applies to acknowledged messages when
+    /// [`retain_acked_messages`](Self::retain_acked_messages) is set.
+    pub fn with_message_retention(mut self, retention: Duration) -> Self {
+        self.subscription.message_retention_duration = Some(retention);
+        self
+    }
+
+    /// Keeps acknowledged messages in the subscription, so it can be seeked
+    /// back to replay them
+    pub fn retain_acked_messages(mut self, retain: bool) -> Self {
+        self.subscription.retain_acked_messages = retain;
+        self
+    }
+
+    /// How long the topic keeps published messages, regardless of their
+    /// acknowledgement state (10 minutes to 31 days)
+    ///
+    /// Subscriptions can then be seeked to any point within this window.
+    pub fn with_topic_retention(mut self, retention: Duration) -> Self {
+        self.topic.message_retention_duration = Some(retention);
+        self
+    }
+
+    /// Enables exactly-once delivery on the worker subscription
+    ///
+    /// Provisioning a backend with this switches it to response-checked
+    /// acknowledgements, see
+    /// [`PubSubConfig::exactly_once`](crate::PubSubConfig::exactly_once).
+    pub fn enable_exactly_once_delivery(mut self, enable: bool) -> Self {
+        self.subscription.enable_exactly_once_delivery = enable;
+        self
+    }
+
+    /// Enables message ordering on the worker subscription
+    ///
+    /// Ordering only applies to messages published with an ordering key, so
+    /// [`PubSubBackend::ensure_resources`] refuses to provision this for a
+    /// backend without [`PubSubBackend::with_ordering_key`].
+    pub fn enable_message_ordering(mut self, enable: bool) -> Self {
+        self.subscription.enable_message_ordering = enable;
+        self
+    }
+
+    /// Deletes the worker subscription after it has been inactive for `ttl`
+    ///
+    /// `None` keeps the subscription forever. When not set, Pub/Sub's default
+    /// of 31 days applies.
+    pub fn with_expiration(mut self, ttl: Option<Duration>) -> Self {
+        self.subscription.expiration_policy = Some(ExpirationPolicy {
+            ttl: ttl.map(|ttl| prost_types::Duration {
+                seconds: ttl.as_secs() as i64,
+                nanos: ttl.subsec_nanos() as i32,
+            }),
+        });
+        self
+    }
+
+    /// Adds a label to every provisioned topic and subscription
+    ///
+    /// Useful to follow tagging conventions such as `team`, `env` or `cost-center`.
+    pub fn with_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let (key, value) = (key.into(), value.into());
+        self.topic.labels.insert(key.clone(), value.clone());
+        self.subscription.labels.insert(key, value);
+        self
+    }
+
+    /// Restricts the regions where the topic may persist messages
+    ///
+    /// Publishing from other regions is still allowed; messages are stored
+    /// in one of the allowed regions. Use
+    /// [`enforce_in_transit`](Self::enforce_in_transit) to also keep them
+    /// there while in transit.
+    pub fn with_allowed_regions(
+        mut self,
+        regions: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        let policy = self.topic.message_storage_policy.get_or_insert_default();
+        policy.allowed_persistence_regions = regions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Rejects publish and subscribe requests made outside the allowed regions
+    pub fn enforce_in_transit(mut self, enforce: bool) -> Self {
+        let policy = self.topic.message_storage_policy.get_or_insert_default();
+        policy.enforce_in_transit = enforce;
+        self
+    }
+
+    /// Forwards messages to `topic` after `max_delivery_attempts` failed deliveries
+    ///
+    /// The dead-letter topic is created if it doesn't exist. Pub/Sub accepts
+    /// between 5 and 100 delivery attempts. The Pub/Sub service account needs
+    /// permission to publish to the topic and to acknowledge on the subscription.
+    pub fn with_dead_letter_policy(
+        mut self,
+        topic: impl Into<String>,
+        max_delivery_attempts: i32,
+    ) -> Self {
+        self.dead_letter = Some(DeadLetter {
+            topic: topic.into(),
+            max_delivery_attempts,
+        });
+        self
+    }
+
+    /// Adds a BigQuery export subscription on the backend's topic
+    pub fn with_bigquery_export(mut self, export: BigQueryExport) -> Self {
+        self.bigquery_exports.push(export);
+        self
+    }
+}
+
+/// Creates `topic` with `config` if it doesn't exist
+async fn ensure_topic(topic: &Topic, config: &TopicConfig) -> Result<(), PubSubError> {
+    let exists = topic
+        .exists(None)
+        .await
+        .map_err(|e| PubSubError::Client(e.to_string()))?;
+    if !exists && ignore_already_exists(topic.create(Some(config.clone()), None).await)? {
+        tracing::info!(topic = topic.id(), "Created topic");
+    }
+    Ok(())
+}
+
+/// Whether a resource was created, not treating an already existing resource as an error
+///
+/// Several workers starting at once race to create the same resources.
+fn ignore_already_exists(result: Result<(), Status>) -> Result<bool, PubSubError> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(status) if status.code() == Code::AlreadyExists => Ok(false),
+        Err(status) => Err(PubSubError::Client(status.to_string())),
+    }
+}
+
+/// The resources of a provisioning, resolved against a backend
+pub(crate) struct Resources {
+    topic: Topic,
+    topic_config: TopicConfig,
+    dead_letter_topic: Option<Topic>,
+    subscriptions: Vec<(Subscription, SubscriptionConfig)>,
+    /// Serializes creation by tasks hitting missing resources at once
+    creating: tokio::sync::Mutex<()>,
+}
+
+impl Resources {
+    /// Creates whatever resources don't exist
+    pub(crate) async fn create(&self) -> Result<(), PubSubError> {
+        let _creating = self.creating.lock().await;
+        ensure_topic(&self.topic, &self.topic_config).await?;
+        if let Some(topic) = &self.dead_letter_topic {
+            ensure_topic(topic, &self.topic_config).await?;
+        }
+
+        for (subscription, config) in &self.subscriptions {
+            let exists = subscription
+                .exists(None)
+                .await
+                .map_err(|e| PubSubError::Subscription(e.to_string()))?;
+            if exists {
+                continue;
+            }
+
+            let result = subscription
+                .create(self.topic.fully_qualified_name(), config.clone(), None)
+                .await;
+            if ignore_already_exists(result)? {
+                tracing::info!(subscription = subscription.id(), "Created subscription");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a failure means a topic or subscription doesn't exist
+pub(crate) fn is_not_found(status: &Status) -> bool {
+    status.code() == Code::NotFound
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Creates the topic and subscriptions described by `provisioning` if
+    /// they don't exist
+    ///
+    /// If `provisioning` enables exactly-once delivery, the backend's
+    /// acknowledgements are switched to match. Enabling message ordering
+    /// without an ordering key extractor is an error.
+    pub async fn ensure_resources(
+        &mut self,
+        provisioning: &Provisioning,
+    ) -> Result<(), PubSubError> {
+        self.resolve_resources(provisioning)?.create().await
+    }
+
+    /// Creates the resources described by `provisioning` the first time
+    /// publishing or receiving finds one missing, then tries again
+    ///
+    /// Meant for development environments, where provisioning up front with
+    /// [`ensure_resources`](Self::ensure_resources) is a chore. The
+    /// provisioning is checked right away, with the same rules.
+    pub fn with_auto_create(mut self, provisioning: &Provisioning) -> Result<Self, PubSubError> {
+        self.auto_create = Some(Arc::new(self.resolve_resources(provisioning)?));
+        Ok(self)
+    }
+
+    /// Checks `provisioning` against the backend and resolves its resources
+    fn resolve_resources(&mut self, provisioning: &Provisioning) -> Result<Resources, PubSubError> {
+        let Some(topic) = self.topic.clone() else {
+            return Err(PubSubError::Provisioning(
+                "Consume-only backends have no topic to provision".to_string(),
+            ));
+        };
+        if provisioning.subscription.enable_message_ordering && !self.has_ordering_key() {
+            return Err(PubSubError::Provisioning(
+                "Message ordering is enabled but the backend has no ordering key extractor"
+                    .to_string(),
+            ));
+        }
+        if !provisioning.subscription.enable_message_ordering && self.has_ordering_key() {
+            tracing::warn!(
+                "Backend publishes ordering keys but message ordering is not enabled on the subscription"
+            );
+        }
+
+        if provisioning.subscription.enable_exactly_once_delivery {
+            self.config.exactly_once = true;
+        }
+
+        let mut worker_config = provisioning.subscription.clone();
+        self.apply_ack_deadline(&mut worker_config)?;
+        let mut dead_letter_topic = None;
+        if let Some(dead_letter) = &provisioning.dead_letter {
+            self.validate_dead_letter(dead_letter)?;
+
+            let topic = self.client.topic(&dead_letter.topic);
+            worker_config.dead_letter_policy = Some(DeadLetterPolicy {
+                dead_letter_topic: topic.fully_qualified_name().to_string(),
+                max_delivery_attempts: dead_letter.max_delivery_attempts,
+            });
+            dead_letter_topic = Some(topic);
+        }
+
+        let subscriptions = std::iter::once((self.subscription.as_ref().clone(), worker_config))
+            .chain(provisioning.bigquery_exports.iter().map(|export| {
+                let config = SubscriptionConfig {
+                    bigquery_config: Some(export.config.clone()),
+                    labels: provisioning.subscription.labels.clone(),
+                    ..Default::default()
+                };
+                (self.client.subscription(&export.subscription), config)
+            }))
+            .collect();
+
+        Ok(Resources {
+            topic,
+            topic_config: provisioning.topic.clone(),
+            dead_letter_topic,
+            subscriptions,
+            creating: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Sets the configured ack deadline and checks it leaves tasks enough time
+    fn apply_ack_deadline(&self, config: &mut SubscriptionConfig) -> Result<(), PubSubError> {
+        if let Some(deadline) = self.config.ack_deadline {
+            let seconds = deadline.as_secs();
+            if !ACK_DEADLINE_RANGE.contains(&seconds) {
+                return Err(PubSubError::Provisioning(format!(
+                    "Ack deadline must be between {} and {} seconds, got {seconds}",
+                    ACK_DEADLINE_RANGE.start(),
+                    ACK_DEADLINE_RANGE.end()
+                )));
+            }
+            config.ack_deadline_seconds = seconds as i32;
+        }
+
+        let deadline = match config.ack_deadline_seconds {
+            0 => DEFAULT_ACK_DEADLINE,
+            seconds => seconds as u64,
+        };
+        if let Some(timeout) = self.config.task_timeout {
+            if timeout.as_secs() > deadline && self.config.lease_extension.is_none() {
+                tracing::warn!(
+                    task_timeout_secs = timeout.as_secs(),
+                    ack_deadline_secs = deadline,
+                    "Task timeout exceeds the subscription's ack deadline without lease extension; \
+                     long tasks may be redelivered while still running"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the dead-letter policy against Pub/Sub's limits and the
+    /// backend's own retries
+    fn validate_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), PubSubError> {
+        let attempts = dead_letter.max_delivery_attempts;
+        if !DELIVERY_ATTEMPTS_RANGE.contains(&attempts) {
+            return Err(PubSubError::Provisioning(format!(
+                "Dead-letter max delivery attempts must be between {} and {}, got {attempts}",
+                DELIVERY_ATTEMPTS_RANGE.start(),
+                DELIVERY_ATTEMPTS_RANGE.end()
+            )));
+        }
+
+        // Every delivery runs the worker's own retries, so the two multiply
+        if let Some(max_attempts) = self.config.max_attempts.filter(|&n| n > 1) {
+            tracing::warn!(
+                max_attempts,
+                max_delivery_attempts = attempts,
+                total = max_attempts * attempts as usize,
+                "Worker retries and dead-letter delivery attempts both apply; \
+                 a failing task runs up to `total` times before being dead-lettered"
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+//! Publishing the tasks sent to the sink of a backend
+//!
+//! Tasks sent to the [`Sink`] of a [`PubSubBackend`] are prepared right away,
+//! then published by a task of their own, see [`PubSubSink`](crate::sink::PubSubSink).
+//!
+//! [`PubSubBackend::push`] and its variants queue tasks the same way, but hand
+//! the outcome of each back to the caller rather than to the next flush.
+//!
+//! apalis' `TaskSink` needs a [`Backend`](apalis_core::backend::Backend), so
+//! without the `consume` feature tasks are pushed with these methods only.
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::SystemTime,
+};
+
+use apalis_core::{
+    backend::codec::Codec,
+    task::{builder::TaskBuilder, task_id::TaskId},
+};
+use futures::{
+    future::{join_all, poll_fn},
+    task::AtomicWaker,
+    Sink, StreamExt,
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::PollSender;
+use uuid::Uuid;
+
+use crate::{
+    archive::Archiver,
+    backoff::BackoffStrategy,
+    budget::BufferBudget,
+    clock::Clock,
+    codecs::PUBSUB_ATTRIBUTE_CODEC,
+    envelope::WireFormat,
+    no_topic,
+    provision::{is_not_found, Resources},
+    report::{report, ErrorReport, ErrorReporter, FailureKind},
+    schedule,
+    shard::{shard_name, Sharding},
+    sink::{publish_with_retry, task_message},
+    transport::PubSubTransport,
+    utils::PubSubContext,
+    PubSubBackend, PubSubCompact, PubSubError, PubSubTask, PublishedTask, PUBSUB_ATTRIBUTE_TASK_ID,
+};
+
+/// Receives the outcome of a task queued with [`PubSubBackend::push`] and
+/// its variants
+type Reply = oneshot::Sender<Result<PublishedTask, PubSubError>>;
+
+/// Tasks sent through a sink and not yet published
+#[derive(Default)]
+pub(crate) struct Outstanding {
+    count: AtomicUsize,
+    /// First failure not yet reported by the sink
+    error: Mutex<Option<PubSubError>>,
+    /// The sink waiting for the count to drop to zero
+    flushed: AtomicWaker,
+}
+
+impl Outstanding {
+    /// Records that a task was published or failed to be, with `result`
+    fn done(&self, result: Result<(), PubSubError>) {
+        if let Err(e) = result {
+            self.error.lock().unwrap().get_or_insert(e);
+        }
+        self.count.fetch_sub(1, Ordering::AcqRel);
+        self.flushed.wake();
+    }
+
+    /// Takes the first failure since the last one was taken, if any
+    fn take_error(&self) -> Result<(), PubSubError> {
+        self.error.lock().unwrap().take().map_or(Ok(()), Err)
+    }
+
+    /// Whether all tasks were published, or the first failure
+    fn poll_flushed(&self, cx: &mut Context<'_>) -> Poll<Result<(), PubSubError>> {
+        self.flushed.register(cx.waker());
+        self.take_error()?;
+        if self.count.load(Ordering::Acquire) == 0 {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// A task waiting in the publisher's queue
+pub(crate) struct Queued {
+    prepared: PreparedTask,
+    /// Payload bytes held in the backend's budget until it's published
+    bytes: usize,
+    outstanding: Arc<Outstanding>,
+    /// Where the outcome goes instead of the next flush, if anywhere
+    reply: Option<Reply>,
+}
+
+/// Publishes the tasks of a sink, see [`PubSubSink`](crate::sink::PubSubSink)
+struct Publisher {
+    transport: Arc<dyn PubSubTransport>,
+    /// The backend's topic, none for consume-only backends
+    topic: Option<String>,
+    wire_format: WireFormat,
+    backoff: Arc<dyn BackoffStrategy>,
+    clock: Arc<dyn Clock>,
+    auto_create: Option<Arc<Resources>>,
+    archiver: Option<Archiver>,
+    reporter: Option<Arc<dyn ErrorReporter>>,
+    budget: Option<Arc<BufferBudget>>,
+}
+
+impl Publisher {
+    /// Publishes queued tasks, `concurrency` at a time, until every sender
+    /// is dropped
+    async fn run(self, mut queue: mpsc::Receiver<Queued>, concurrency: usize) {
+        let publisher = &self;
+        futures::stream::poll_fn(|cx| queue.poll_recv(cx))
+            .for_each_concurrent(concurrency, |queued| async move {
+                let result = publisher.publish(queued.prepared).await;
+                // Published or dropped, either way the payload is no longer buffered
+                if let Some(budget) = &publisher.budget {
+                    budget.release(queued.bytes);
+                }
+                if let Err(e) = &result {
+                    tracing::error!("Failed to send task to pub/sub backend: {e}");
+                    report(publisher.reporter.as_ref(), || {
+                        ErrorReport::new(FailureKind::Publish, e)
+                    });
+                }
+                match queued.reply {
+                    // The caller handles the failure, flushing the sink shouldn't
+                    Some(reply) => {
+                        queued.outstanding.done(Ok(()));
+                        let _ = reply.send(result);
+                    }
+                    None => queued.outstanding.done(result.map(|_| ())),
+                }
+            })
+            .await;
+    }
+
+    /// Publishes a task, creating the backend's resources when they're
+    /// missing and configured to be
+    async fn publish(&self, mut prepared: PreparedTask) -> Result<PublishedTask, PubSubError> {
+        let (topic, auto_create) = match (&prepared.topic, &self.topic) {
+            (Some(topic), _) => (topic.as_str(), None),
+            (None, Some(topic)) => (topic.as_str(), self.auto_create.as_ref()),
+            (None, None) => return Err(no_topic()),
+        };
+        let task_id = *prepared
+            .task
+            .parts
+            .task_id
+            .get_or_insert_with(|| TaskId::new(Uuid::new_v4()))
+            .inner();
+        let mut message = task_message(prepared.task, prepared.ordering_key, self.wire_format);
+        if let Some(codec_name) = prepared.codec_name {
+            message
+                .attributes
+                .insert(PUBSUB_ATTRIBUTE_CODEC.to_owned(), codec_name);
+        }
+        // Make log message
+        let task_id_log = format!(
+            "\n\tTask ID: {}",
+            message.attributes[PUBSUB_ATTRIBUTE_TASK_ID]
+        );
+
+        if let Some(archiver) = &self.archiver {
+            archiver.published(&message);
+        }
+
+        let retry = auto_create.map(|_| message.clone());
+        let mut result = publish_with_retry(
+            self.transport.as_ref(),
+            topic,
+            message,
+            self.backoff.as_ref(),
+            self.clock.as_ref(),
+        )
+        .await;
+        if let (Err(status), Some(resources), Some(message)) = (&result, auto_create, retry) {
+            if is_not_found(status) {
+                tracing::info!("Topic not found, creating resources");
+                resources.create().await?;
+                result = publish_with_retry(
+                    self.transport.as_ref(),
+                    topic,
+                    message,
+                    self.backoff.as_ref(),
+                    self.clock.as_ref(),
+                )
+                .await;
+            }
+        }
+        result
+            .inspect(|id| tracing::debug!("Message published:\n\tPub/sub id: {id}{task_id_log}"))
+            .map(|message_id| PublishedTask {
+                message_id,
+                task_id,
+            })
+            .map_err(|e| PubSubError::Client(e.to_string()))
+    }
+}
+
+/// A buffered task ready to be published
+struct PreparedTask {
+    task: PubSubTask<PubSubCompact>,
+    ordering_key: Option<String>,
+    /// Codec the arguments are encoded with, when named
+    codec_name: Option<String>,
+    /// Topic of the task's job type, priority tier or shard, if not the
+    /// backend's
+    topic: Option<String>,
+}
+
+impl<M, C> PubSubBackend<M, C>
+where
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: std::fmt::Debug,
+{
+    /// Applies the ordering key, job type overrides, priority tier and shard
+    /// of `task`
+    fn prepare(&self, mut task: PubSubTask<PubSubCompact>) -> PreparedTask {
+        let overrides = self.job_types.get::<M>(&task.parts.ctx);
+        let codec = overrides
+            .and_then(|overrides| overrides.codec.as_ref())
+            .filter(|&codec| Some(codec) != self.codecs.name.as_ref());
+        let shard_key = self.sharding.as_ref().is_some_and(Sharding::has_key);
+        let args = (self.sink.ordering_key.is_some() || codec.is_some() || shard_key)
+            .then(|| {
+                C::decode(&task.args)
+                    .inspect_err(|e| tracing::warn!(error = ?e, "Failed to decode task"))
+                    .ok()
+            })
+            .flatten();
+
+        let ordering_key = self
+            .sink
+            .ordering_key
+            .as_ref()
+            .zip(args.as_ref())
+            .and_then(|(ordering_key, args)| ordering_key(args));
+
+        let mut codec_name = self.codecs.name.clone();
+        if let Some((codec, args)) = codec.zip(args.as_ref()) {
+            let encoded = match self.codecs.encoder(codec) {
+                Some(encode) => encode(args),
+                None => Err(format!("No codec registered for {codec}").into()),
+            };
+            match encoded {
+                Ok(encoded) => {
+                    task.args = encoded;
+                    codec_name = Some(codec.clone());
+                }
+                Err(e) => tracing::warn!(
+                    error = ?e,
+                    codec,
+                    "Failed to encode task for its job type, publishing with the backend's codec"
+                ),
+            }
+        }
+
+        let topic = overrides
+            .and_then(|overrides| overrides.topic.as_deref())
+            .or_else(|| self.priority_tiers.topic(task.parts.ctx.priority()))
+            .map(|topic| self.client.topic(topic).fully_qualified_name().to_string())
+            .or_else(|| {
+                let (sharding, topic) = self.sharding.as_ref().zip(self.topic.as_ref())?;
+                let shard = sharding.shard(args.as_ref(), ordering_key.as_deref());
+                let name = shard_name(&topic.id(), shard);
+                Some(self.client.topic(&name).fully_qualified_name().to_string())
+            });
+
+        PreparedTask {
+            task,
+            ordering_key,
+            codec_name,
+            topic,
+        }
+    }
+
+    /// Hands `item` to the publisher task, the sink must be ready for it
+    fn queue(
+        &mut self,
+        item: PubSubTask<PubSubCompact>,
+        reply: Option<Reply>,
+    ) -> Result<(), PubSubError> {
+        let bytes = item.args.len();
+        self.stats.record_published_size(bytes);
+        if let Some(budget) = &self.budget {
+            budget.add(bytes);
+        }
+        // Ordering keys and job type overrides need the decoded task, so
+        // apply them here rather than on the publisher task
+        let queued = Queued {
+            prepared: self.prepare(item),
+            bytes,
+            outstanding: self.sink.outstanding.clone(),
+            reply,
+        };
+        self.sink.outstanding.count.fetch_add(1, Ordering::AcqRel);
+        let sent = self
+            .sink
+            .publisher
+            .as_mut()
+            .ok_or_else(|| PubSubError::Client("The sink isn't ready".to_string()))
+            .and_then(|publisher| {
+                publisher
+                    .send_item(queued)
+                    .map_err(|_| PubSubError::Client("The publisher task stopped".to_string()))
+            });
+        if sent.is_err() {
+            self.sink.outstanding.count.fetch_sub(1, Ordering::AcqRel);
+            if let Some(budget) = &self.budget {
+                budget.release(bytes);
+            }
+        }
+        sent
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Starts the publisher task of the sink
+    fn start_publisher(&self) -> PollSender<Queued> {
+        let concurrency = self.config.publish_concurrency.max(1);
+        let (tx, rx) = mpsc::channel(concurrency);
+        let publisher = Publisher {
+            transport: self.transport.clone(),
+            topic: self.topic_name().map(str::to_string),
+            wire_format: self.config.wire_format,
+            backoff: self.config.backoff.clone(),
+            clock: self.config.clock.clone(),
+            auto_create: self.auto_create.clone(),
+            archiver: self.archiver.clone(),
+            reporter: self.reporter.clone(),
+            budget: self.budget.clone(),
+        };
+        self.spawner.spawn(publisher.run(rx, concurrency));
+        PollSender::new(tx)
+    }
+}
+
+impl<M, C> Sink<PubSubTask<PubSubCompact>> for PubSubBackend<M, C>
+where
+    M: Unpin,
+    C: Codec<M, Compact = PubSubCompact> + Unpin,
+    C::Error: std::fmt::Debug,
+{
+    type Error = PubSubError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let me = self.get_mut();
+        // A task sent earlier failed to publish, report it before taking more
+        me.sink.outstanding.take_error()?;
+        if me.is_backpressured() {
+            // Over the memory budget, wait for publishes before taking more
+            ready!(me.sink.outstanding.poll_flushed(cx))?;
+        }
+        if me.sink.publisher.is_none() {
+            me.sink.publisher = Some(me.start_publisher());
+        }
+        let publisher = me.sink.publisher.as_mut().expect("publisher started");
+        publisher
+            .poll_reserve(cx)
+            .map_err(|_| PubSubError::Client("The publisher task stopped".to_string()))
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        item: PubSubTask<PubSubCompact>,
+    ) -> Result<(), Self::Error> {
+        self.get_mut().queue(item, None)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.sink.outstanding.poll_flushed(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let me = self.get_mut();
+        ready!(me.sink.outstanding.poll_flushed(cx))?;
+        // The publisher task ends once its queue is dropped
+        me.sink.publisher = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<M, C> PubSubBackend<M, C>
+where
+    M: Unpin,
+    C: Codec<M, Compact = PubSubCompact> + Unpin,
+    C::Error: std::error::Error,
+{
+    /// Publishes a task with `args`, returning its message and task ids once
+    /// Pub/Sub accepted it
+    ///
+    /// This takes precedence over `TaskSink::push`, which remains available
+    /// as `TaskSink::push(&mut backend, args)` when the `consume` feature is
+    /// enabled too.
+    pub async fn push(&mut self, args: M) -> Result<PublishedTask, PubSubError> {
+        self.push_with_ctx(args, PubSubContext::default()).await
+    }
+
+    /// Publishes a task with `args` and the message attributes `attributes`,
+    /// returning its message and task ids once Pub/Sub accepted it
+    ///
+    /// Attributes let subscription filters and non-apalis consumers select
+    /// tasks, see [`PubSubContext::with_attribute`]. Those the backend
+    /// reserves are ignored.
+    pub async fn push_with_attributes(
+        &mut self,
+        args: M,
+        attributes: HashMap<String, String>,
+    ) -> Result<PublishedTask, PubSubError> {
+        let ctx = attributes
+            .into_iter()
+            .fold(PubSubContext::default(), |ctx, (key, value)| {
+                ctx.with_attribute(key, value)
+            });
+        self.push_with_ctx(args, ctx).await
+    }
+
+    /// Publishes a task with `args` that runs once `run_at` has passed,
+    /// returning its message and task ids once Pub/Sub accepted it
+    ///
+    /// Workers receiving the task earlier hold it until it's due, see
+    /// [`schedule`].
+    pub async fn push_scheduled(
+        &mut self,
+        args: M,
+        run_at: SystemTime,
+    ) -> Result<PublishedTask, PubSubError> {
+        let ctx = PubSubContext::default().with_not_before(schedule::not_before(run_at));
+        self.push_with_ctx(args, ctx).await
+    }
+
+    /// Publishes a task for each of `args`, returning the outcome of each in
+    /// the same order
+    ///
+    /// Every task is encoded and queued before waiting for any to be
+    /// published, so a backfill goes out
+    /// [`publish_concurrency`](crate::PubSubConfig::publish_concurrency) tasks
+    /// at a time. Unlike [`TaskSink::push_bulk`](apalis_core::backend::TaskSink::push_bulk),
+    /// a task failing to encode or publish doesn't fail the others.
+    ///
+    /// This takes precedence over `TaskSink::push_all`, which remains
+    /// available as `TaskSink::push_all(&mut backend, tasks)`.
+    pub async fn push_all(
+        &mut self,
+        args: impl IntoIterator<Item = M>,
+    ) -> Vec<Result<PublishedTask, PubSubError>> {
+        let mut outcomes = Vec::new();
+        for args in args {
+            outcomes.push(self.enqueue(args, PubSubContext::default()).await);
+        }
+        join_all(
+            outcomes
+                .into_iter()
+                .map(|outcome| async move { published(outcome?).await }),
+        )
+        .await
+    }
+
+    /// Publishes a task with `args` and `ctx`, waiting for Pub/Sub to accept
+    /// it
+    async fn push_with_ctx(
+        &mut self,
+        args: M,
+        ctx: PubSubContext,
+    ) -> Result<PublishedTask, PubSubError> {
+        published(self.enqueue(args, ctx).await?).await
+    }
+
+    /// Queues a task with `args` and `ctx`, returning where its outcome will
+    /// be sent
+    async fn enqueue(
+        &mut self,
+        args: M,
+        ctx: PubSubContext,
+    ) -> Result<oneshot::Receiver<Result<PublishedTask, PubSubError>>, PubSubError> {
+        let encoded = C::encode(&args).map_err(|e| PubSubError::Codec(e.to_string()))?;
+        let not_before = ctx.not_before();
+        let mut task = TaskBuilder::new(encoded).with_ctx(ctx);
+        if let Some(not_before) = not_before {
+            task = task.run_at_timestamp(not_before);
+        }
+        let task = task.build();
+        let (reply, outcome) = oneshot::channel();
+        poll_fn(|cx| Pin::new(&mut *self).poll_ready(cx)).await?;
+        self.queue(task, Some(reply))?;
+        Ok(outcome)
+    }
+}
+
+/// The outcome of a task queued with `outcome`
+async fn published(
+    outcome: oneshot::Receiver<Result<PublishedTask, PubSubError>>,
+) -> Result<PublishedTask, PubSubError> {
+    outcome.await.unwrap_or_else(|_| {
+        Err(PubSubError::Client(
+            "The publisher task stopped".to_string(),
+        ))
+    })
+}
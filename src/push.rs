@@ -0,0 +1,546 @@
+//! Consuming push subscriptions over HTTP
+//!
+//! Pull workers are awkward on platforms like Cloud Run, where instances only
+//! get CPU while serving requests. [`PushReceiver`] is an alternative [`Backend`]
+//! that serves an HTTP endpoint for a push subscription instead:
+//!
+//! - Each delivery's OIDC token is checked by an optional [`PushVerifier`],
+//!   such as [`GoogleOidcVerifier`].
+//! - The message is decoded into a [`PubSubTask`] and handed to the worker.
+//! - The request is answered once the task completes: `204` acknowledges the
+//!   message, `500` (task failed) and `503` (worker not running) make Pub/Sub
+//!   redeliver it.
+//!
+//! Push deliveries have no ack id, so the [`PubSubContext::ack_id`] of push
+//! tasks holds an id generated for each delivery instead. Pub/Sub may deliver
+//! a message again while a previous delivery is still being handled, and each
+//! request is answered with the outcome of its own task. The Pub/Sub message
+//! id is in [`PubSubContext::message_id`].
+//!
+//! Requires the `push` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis::prelude::*;
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{push::*, PubSubCompact};
+//! # use std::sync::Arc;
+//! async fn handle(job: u32) {}
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let receiver: PushReceiver<u32, JsonCodec<PubSubCompact>> = PushReceiver::new(100)
+//!     .with_verifier(Arc::new(GoogleOidcVerifier::new("https://my-service.run.app")));
+//!
+//! let app = receiver.router();
+//! let worker = WorkerBuilder::new("push-worker")
+//!     .backend(receiver)
+//!     .build(handle);
+//!
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
+//! tokio::spawn(async move { axum::serve(listener, app).await });
+//! worker.run().await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use apalis_core::{
+    backend::{codec::Codec, Backend, TaskStream},
+    task::{builder::TaskBuilder, task_id::TaskId},
+    worker::context::WorkerContext,
+};
+use axum::{
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+use crate::{
+    envelope,
+    extensions::{apply_hooks, ContextHook},
+    parts::MessageAttributes,
+    utils::PubSubContext,
+    PubSubCompact, PubSubError, PubSubTask, PubSubTaskId,
+};
+
+/// Where Google publishes the keys its OIDC tokens are signed with
+const GOOGLE_CERTS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+
+/// Issuers of the OIDC tokens attached to push deliveries
+const GOOGLE_ISSUERS: [&str; 2] = ["accounts.google.com", "https://accounts.google.com"];
+
+/// Least time between two fetches of Google's signing keys
+const MIN_KEYS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Body of a push delivery
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PushRequest {
+    message: PushMessage,
+    subscription: String,
+    /// Present when the subscription has a dead-letter policy
+    #[serde(default)]
+    delivery_attempt: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PushMessage {
+    #[serde(default)]
+    data: String,
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+    message_id: String,
+}
+
+/// Verifies the bearer token attached to push deliveries
+pub trait PushVerifier: Send + Sync {
+    /// Returns an error if the token must be rejected
+    fn verify<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<(), PubSubError>>;
+}
+
+#[derive(Debug, Deserialize)]
+struct OidcClaims {
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+/// [`PushVerifier`] checking Google-signed OIDC tokens
+///
+/// Tokens must be signed by Google, issued for the configured audience and, if
+/// set, for the configured service account. Signing keys are fetched on first
+/// use and refreshed when a token references an unknown key, at most once a
+/// minute, so tokens with made up key ids can't flood Google with requests.
+pub struct GoogleOidcVerifier {
+    audience: String,
+    service_account: Option<String>,
+    certs_url: String,
+    http: reqwest::Client,
+    keys: Mutex<SigningKeys>,
+}
+
+/// Google's signing keys, as last fetched
+#[derive(Default)]
+struct SigningKeys {
+    keys: Option<Arc<JwkSet>>,
+    fetched_at: Option<Instant>,
+}
+
+impl GoogleOidcVerifier {
+    /// Creates a verifier accepting tokens issued for `audience`
+    ///
+    /// The audience is the one configured on the push subscription, which
+    /// defaults to the push endpoint URL.
+    pub fn new(audience: impl Into<String>) -> Self {
+        Self {
+            audience: audience.into(),
+            service_account: None,
+            certs_url: GOOGLE_CERTS_URL.to_string(),
+            http: reqwest::Client::new(),
+            keys: Mutex::default(),
+        }
+    }
+
+    /// Only accepts tokens issued for the given service account email
+    pub fn with_service_account(mut self, email: impl Into<String>) -> Self {
+        self.service_account = Some(email.into());
+        self
+    }
+
+    /// Fetches the signing keys, as a JWK set, from `url` rather than from
+    /// Google, for example to test against keys of your own
+    pub fn with_certs_url(mut self, url: impl Into<String>) -> Self {
+        self.certs_url = url.into();
+        self
+    }
+
+    async fn fetch_keys(&self) -> Result<Arc<JwkSet>, PubSubError> {
+        let keys: JwkSet = self
+            .http
+            .get(&self.certs_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| PubSubError::Client(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| PubSubError::Client(e.to_string()))?;
+
+        let keys = Arc::new(keys);
+        self.keys.lock().unwrap().keys = Some(keys.clone());
+        Ok(keys)
+    }
+
+    async fn key(&self, kid: &str) -> Result<DecodingKey, PubSubError> {
+        let (cached, refresh) = {
+            let mut cache = self.keys.lock().unwrap();
+            let known = cache
+                .keys
+                .as_ref()
+                .is_some_and(|keys| keys.find(kid).is_some());
+            // Google rotates its keys, so refresh when we don't know this one
+            let refresh = !known
+                && cache
+                    .fetched_at
+                    .is_none_or(|at| at.elapsed() >= MIN_KEYS_REFRESH_INTERVAL);
+            if refresh {
+                cache.fetched_at = Some(Instant::now());
+            }
+            (cache.keys.clone(), refresh)
+        };
+        let keys = match cached {
+            _ if refresh => self.fetch_keys().await?,
+            Some(keys) => keys,
+            None => {
+                return Err(PubSubError::Unauthorized(
+                    "Signing keys are unavailable".to_string(),
+                ))
+            }
+        };
+
+        let jwk = keys
+            .find(kid)
+            .ok_or_else(|| PubSubError::Unauthorized(format!("Unknown signing key `{kid}`")))?;
+        DecodingKey::from_jwk(jwk).map_err(|e| PubSubError::Unauthorized(e.to_string()))
+    }
+}
+
+impl PushVerifier for GoogleOidcVerifier {
+    fn verify<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<(), PubSubError>> {
+        async move {
+            let header = jsonwebtoken::decode_header(token)
+                .map_err(|e| PubSubError::Unauthorized(e.to_string()))?;
+            let kid = header
+                .kid
+                .ok_or_else(|| PubSubError::Unauthorized("Token has no key id".to_string()))?;
+            let key = self.key(&kid).await?;
+
+            let mut validation = Validation::new(Algorithm::RS256);
+            validation.set_audience(&[&self.audience]);
+            validation.set_issuer(&GOOGLE_ISSUERS);
+            let claims = jsonwebtoken::decode::<OidcClaims>(token, &key, &validation)
+                .map_err(|e| PubSubError::Unauthorized(e.to_string()))?
+                .claims;
+
+            if let Some(expected) = &self.service_account {
+                if claims.email.as_ref() != Some(expected) || claims.email_verified != Some(true) {
+                    return Err(PubSubError::Unauthorized(format!(
+                        "Token was not issued for `{expected}`"
+                    )));
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+type TaskSender<M> = mpsc::Sender<Result<Option<PubSubTask<M>>, PubSubError>>;
+type TaskReceiver<M> = mpsc::Receiver<Result<Option<PubSubTask<M>>, PubSubError>>;
+
+/// Deliveries waiting for their task to complete, by delivery id
+type PendingDeliveries = Arc<Mutex<HashMap<String, oneshot::Sender<bool>>>>;
+
+/// Removes a delivery from the pending ones when its request is done, or
+/// dropped by the HTTP server before its task completed
+struct PendingGuard {
+    pending: PendingDeliveries,
+    delivery_id: String,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.delivery_id);
+    }
+}
+
+/// A [`Backend`] fed by an HTTP endpoint receiving push deliveries
+///
+/// See the [module level documentation](self) for more details.
+pub struct PushReceiver<M, C> {
+    tx: TaskSender<M>,
+    rx: Arc<Mutex<Option<TaskReceiver<M>>>>,
+    pending: PendingDeliveries,
+    verifier: Option<Arc<dyn PushVerifier>>,
+    context_hooks: Vec<ContextHook>,
+    _codec: PhantomData<fn() -> C>,
+}
+
+impl<M, C> Clone for PushReceiver<M, C> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            rx: self.rx.clone(),
+            pending: self.pending.clone(),
+            verifier: self.verifier.clone(),
+            context_hooks: self.context_hooks.clone(),
+            _codec: PhantomData,
+        }
+    }
+}
+
+impl<M, C> PushReceiver<M, C> {
+    /// Creates a receiver buffering up to `buffer_size` deliveries for the worker
+    ///
+    /// Without a verifier every request is accepted, which is only suitable
+    /// for local development.
+    pub fn new(buffer_size: usize) -> Self {
+        let (tx, rx) = mpsc::channel(buffer_size);
+        Self {
+            tx,
+            rx: Arc::new(Mutex::new(Some(rx))),
+            pending: Arc::default(),
+            verifier: None,
+            context_hooks: Vec::new(),
+            _codec: PhantomData,
+        }
+    }
+
+    /// Sets the verifier checking the token of every delivery
+    pub fn with_verifier(mut self, verifier: Arc<dyn PushVerifier>) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Runs `hook` on every delivery, see [`crate::extensions`]
+    pub fn with_context_hook(
+        mut self,
+        hook: impl Fn(&PubsubMessage, &mut PubSubContext) + Send + Sync + 'static,
+    ) -> Self {
+        self.context_hooks.push(Arc::new(hook));
+        self
+    }
+}
+
+impl<M, C> PushReceiver<M, C>
+where
+    M: Send + 'static,
+    C: Codec<M, Compact = PubSubCompact> + 'static,
+    C::Error: std::fmt::Debug,
+{
+    /// An [`axum`] router accepting push deliveries with `POST /`
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/", post(receive::<M, C>))
+            .with_state(self.clone())
+    }
+}
+
+async fn receive<M, C>(
+    State(receiver): State<PushReceiver<M, C>>,
+    headers: HeaderMap,
+    Json(request): Json<PushRequest>,
+) -> StatusCode
+where
+    M: Send + 'static,
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: std::fmt::Debug,
+{
+    if let Some(verifier) = &receiver.verifier {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let Some(token) = token else {
+            tracing::warn!("Push delivery without bearer token");
+            return StatusCode::UNAUTHORIZED;
+        };
+        if let Err(e) = verifier.verify(token).await {
+            tracing::warn!(error = ?e, "Rejected push delivery");
+            return StatusCode::UNAUTHORIZED;
+        }
+    }
+
+    let PushMessage {
+        data,
+        attributes,
+        message_id,
+    } = request.message;
+    let delivery_attempt = request.delivery_attempt;
+    tracing::debug!(message_id, request.subscription, "Received push delivery");
+
+    let bytes = match STANDARD.decode(data) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!(error = ?e, message_id, "Push delivery data is not valid base64");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    let mut message = PubsubMessage {
+        data: bytes,
+        attributes,
+        message_id: message_id.clone(),
+        ..Default::default()
+    };
+    if let Err(e) = envelope::open(&mut message) {
+        tracing::error!(
+            error = ?e,
+            message_id,
+            "Failed to open envelope - treating as poison message"
+        );
+        return StatusCode::NO_CONTENT;
+    }
+
+    let msg: M = match C::decode(&message.data) {
+        Ok(m) => m,
+        Err(e) => {
+            tracing::error!(
+                error = ?e,
+                message_id,
+                "Failed to decode message - treating as poison message"
+            );
+            // Ack poison messages to prevent infinite redelivery
+            return StatusCode::NO_CONTENT;
+        }
+    };
+
+    // Redeliveries share the message id, so every request gets its own id
+    let delivery_id = Uuid::new_v4().to_string();
+    let attributes = MessageAttributes::from_message(&message);
+    let mut ctx = attributes.restore_context(PubSubContext::new(delivery_id.clone()));
+    ctx.set_delivery(message_id.clone(), delivery_attempt);
+    apply_hooks(&receiver.context_hooks, &message, &mut ctx);
+
+    let mut task = attributes.restore_parts(TaskBuilder::new(msg).with_ctx(ctx));
+    if let Some(task_id) = attributes.task_id {
+        task = task.with_task_id(TaskId::new(task_id));
+    }
+    let task = task.build();
+    if task.parts.ctx.is_expired(SystemTime::now()) {
+        tracing::info!(message_id, "Dropping task past its deadline");
+        return StatusCode::NO_CONTENT;
+    }
+
+    // Registered before sending, as the task may complete right away
+    let (done_tx, done_rx) = oneshot::channel();
+    receiver
+        .pending
+        .lock()
+        .unwrap()
+        .insert(delivery_id.clone(), done_tx);
+    let _guard = PendingGuard {
+        pending: receiver.pending.clone(),
+        delivery_id,
+    };
+
+    if receiver.tx.send(Ok(Some(task))).await.is_err() {
+        tracing::error!(message_id, "Worker is not running");
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    match done_rx.await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::INTERNAL_SERVER_ERROR,
+        // The worker stopped before the task completed
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+impl<M, C> Backend for PushReceiver<M, C>
+where
+    M: Send + 'static,
+{
+    type Args = M;
+    type Error = PubSubError;
+    type Beat = futures::stream::BoxStream<'static, Result<(), Self::Error>>;
+    type Layer = PushLayer;
+    type Stream = TaskStream<PubSubTask<M>, Self::Error>;
+    type Context = PubSubContext;
+    type IdType = PubSubTaskId;
+
+    fn heartbeat(&self, _worker: &WorkerContext) -> Self::Beat {
+        Box::pin(futures::stream::empty())
+    }
+
+    fn middleware(&self) -> Self::Layer {
+        PushLayer {
+            pending: self.pending.clone(),
+        }
+    }
+
+    fn poll(self, _worker: &WorkerContext) -> Self::Stream {
+        match self.rx.lock().unwrap().take() {
+            Some(rx) => ReceiverStream::new(rx).boxed(),
+            None => futures::stream::once(async {
+                Err(PubSubError::Subscription(
+                    "Push receiver is already polled by another worker".to_string(),
+                ))
+            })
+            .boxed(),
+        }
+    }
+}
+
+/// Middleware answering push deliveries once their task completes
+#[derive(Clone)]
+pub struct PushLayer {
+    pending: PendingDeliveries,
+}
+
+impl<S> Layer<S> for PushLayer {
+    type Service = PushService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PushService {
+            inner,
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+/// Service created by [`PushLayer`]
+#[derive(Clone)]
+pub struct PushService<S> {
+    inner: S,
+    pending: PendingDeliveries,
+}
+
+impl<S, M> Service<PubSubTask<M>> for PushService<S>
+where
+    S: Service<PubSubTask<M>>,
+    S::Future: Send + 'static,
+    S::Response: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, task: PubSubTask<M>) -> Self::Future {
+        let delivery_id = task.parts.ctx.ack_id.clone();
+        let pending = self.pending.clone();
+        let future = self.inner.call(task);
+
+        async move {
+            let res = future.await;
+            if let Some(done) = pending.lock().unwrap().remove(&delivery_id) {
+                // The delivery request may have timed out already
+                let _ = done.send(res.is_ok());
+            }
+            res
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,338 @@
+//! Recording received messages and replaying them locally
+//!
+//! Reproducing a production issue usually needs the exact messages that
+//! triggered it. [`PubSubBackend::with_recording`] writes every message the
+//! backend receives, payload and attributes, to a file of newline-delimited
+//! JSON. [`PubSubBackend::with_replay`] later feeds a recording back into the
+//! backend's stream, without touching Pub/Sub at all:
+//!
+//! - messages are handed to the worker in the order they were recorded, once,
+//! - acknowledgements and deadline extensions are accepted and ignored,
+//! - published messages are dropped.
+//!
+//! Recordings are plain text, one [`RecordedMessage`] per line, so they can be
+//! trimmed or edited by hand before being replayed. They're written by a
+//! background task, so a slow disk doesn't hold up the worker: when it falls
+//! too far behind, messages go unrecorded and a warning is logged.
+//!
+//! Requires the `record` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact, PubSubError};
+//! # fn example(
+//! #     production: PubSubBackend<u32, JsonCodec<PubSubCompact>>,
+//! #     local: PubSubBackend<u32, JsonCodec<PubSubCompact>>,
+//! # ) -> Result<(), PubSubError> {
+//! // In production, capture what the workers receive
+//! let production = production.with_recording("messages.ndjson")?;
+//!
+//! // Later, on a laptop, run the same handlers against the capture
+//! let local = local.with_replay("messages.ndjson")?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{future::BoxFuture, FutureExt};
+use google_cloud_gax::grpc::Status;
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use google_cloud_pubsub::subscription::ReceiveConfig;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    transport::{MessageHandler, PubSubTransport, SubscriptionState, TransportMessage},
+    PubSubBackend, PubSubError,
+};
+
+/// Lines waiting to be written before new ones are dropped
+const RECORD_QUEUE_SIZE: usize = 10_000;
+
+/// One line of a recording
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Id Pub/Sub gave the message
+    pub message_id: String,
+    /// Payload, base64 encoded
+    pub data: String,
+    /// Attributes of the message
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// Ordering key of the message, if any
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub ordering_key: String,
+    /// When the message was published, as a UNIX time in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish_time: Option<i64>,
+}
+
+impl From<&PubsubMessage> for RecordedMessage {
+    fn from(message: &PubsubMessage) -> Self {
+        Self {
+            message_id: message.message_id.clone(),
+            data: STANDARD.encode(&message.data),
+            attributes: message.attributes.clone(),
+            ordering_key: message.ordering_key.clone(),
+            publish_time: message.publish_time.map(|time| time.seconds),
+        }
+    }
+}
+
+impl TryFrom<RecordedMessage> for PubsubMessage {
+    type Error = PubSubError;
+
+    fn try_from(recorded: RecordedMessage) -> Result<Self, Self::Error> {
+        Ok(Self {
+            data: STANDARD
+                .decode(&recorded.data)
+                .map_err(|e| PubSubError::Codec(format!("Invalid recorded payload: {e}")))?,
+            attributes: recorded.attributes,
+            message_id: recorded.message_id,
+            publish_time: recorded
+                .publish_time
+                .map(|seconds| prost_types::Timestamp { seconds, nanos: 0 }),
+            ordering_key: recorded.ordering_key,
+        })
+    }
+}
+
+/// Reads the messages of a recording
+pub fn read_recording(path: impl AsRef<Path>) -> Result<Vec<PubsubMessage>, PubSubError> {
+    let file = File::open(path).map_err(|e| PubSubError::Io(e.to_string()))?;
+    let mut messages = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| PubSubError::Io(e.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let recorded: RecordedMessage = serde_json::from_str(&line).map_err(|e| {
+            PubSubError::Codec(format!("Invalid recording line {}: {e}", number + 1))
+        })?;
+        messages.push(recorded.try_into()?);
+    }
+    Ok(messages)
+}
+
+/// Transport writing the messages received through another one to a recording
+pub struct RecordingTransport {
+    inner: Arc<dyn PubSubTransport>,
+    tx: mpsc::Sender<String>,
+    /// The recording and the lines to write to it, until the writer starts
+    /// with the first receive
+    writer: Mutex<Option<(File, mpsc::Receiver<String>)>>,
+}
+
+impl RecordingTransport {
+    /// Records the messages received through `inner`, appending them to the
+    /// file at `path`
+    pub fn new(
+        inner: Arc<dyn PubSubTransport>,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, PubSubError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| PubSubError::Io(e.to_string()))?;
+        let (tx, rx) = mpsc::channel(RECORD_QUEUE_SIZE);
+        Ok(Self {
+            inner,
+            tx,
+            writer: Mutex::new(Some((file, rx))),
+        })
+    }
+}
+
+/// Queues `message` to be appended to the recording as one line
+fn record(tx: &mpsc::Sender<String>, message: &PubsubMessage) {
+    let mut line = serde_json::to_string(&RecordedMessage::from(message))
+        .expect("Recorded messages serialize to JSON");
+    line.push('\n');
+    if tx.try_send(line).is_err() {
+        tracing::warn!(
+            message_id = message.message_id,
+            "Recording is falling behind, message not recorded"
+        );
+    }
+}
+
+/// Appends the lines of `rx` to `file`, flushing whenever none are queued
+async fn write_lines(file: File, mut rx: mpsc::Receiver<String>) {
+    let mut file = BufWriter::new(tokio::fs::File::from_std(file));
+    while let Some(line) = rx.recv().await {
+        let mut written = file.write_all(line.as_bytes()).await;
+        while let Ok(line) = rx.try_recv() {
+            written = written.and(file.write_all(line.as_bytes()).await);
+        }
+        if let Err(e) = written.and(file.flush().await) {
+            tracing::warn!(error = ?e, "Failed to record messages");
+        }
+    }
+}
+
+impl PubSubTransport for RecordingTransport {
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        message: PubsubMessage,
+    ) -> BoxFuture<'a, Result<String, Status>> {
+        self.inner.publish(topic, message)
+    }
+
+    fn receive(
+        self: Arc<Self>,
+        subscription: String,
+        config: ReceiveConfig,
+        handler: MessageHandler,
+        cancel: CancellationToken,
+    ) -> BoxFuture<'static, Result<(), Status>> {
+        if let Some((file, rx)) = self.writer.lock().unwrap().take() {
+            tokio::spawn(write_lines(file, rx));
+        }
+        let tx = self.tx.clone();
+        let handler: MessageHandler = Arc::new(move |message: TransportMessage| {
+            record(&tx, &message.message);
+            handler(message)
+        });
+        self.inner
+            .clone()
+            .receive(subscription, config, handler, cancel)
+    }
+
+    fn acknowledge<'a>(
+        &'a self,
+        subscription: &'a str,
+        ack_ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        self.inner.acknowledge(subscription, ack_ids)
+    }
+
+    fn modify_ack_deadline<'a>(
+        &'a self,
+        subscription: &'a str,
+        ack_ids: Vec<String>,
+        seconds: i32,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        self.inner
+            .modify_ack_deadline(subscription, ack_ids, seconds)
+    }
+
+    fn subscription_state<'a>(
+        &'a self,
+        subscription: &'a str,
+    ) -> BoxFuture<'a, Result<SubscriptionState, Status>> {
+        self.inner.subscription_state(subscription)
+    }
+}
+
+/// Transport serving the messages of a recording, see the
+/// [module level documentation](self)
+pub struct ReplayTransport {
+    /// Messages not handed out yet
+    messages: Mutex<Vec<PubsubMessage>>,
+}
+
+impl ReplayTransport {
+    /// Replays `messages` in order
+    pub fn new(messages: Vec<PubsubMessage>) -> Self {
+        Self {
+            messages: Mutex::new(messages),
+        }
+    }
+
+    /// Replays the recording at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PubSubError> {
+        read_recording(path).map(Self::new)
+    }
+}
+
+impl PubSubTransport for ReplayTransport {
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        _message: PubsubMessage,
+    ) -> BoxFuture<'a, Result<String, Status>> {
+        tracing::debug!(topic, "Replaying, dropping published message");
+        async move { Ok(Uuid::new_v4().to_string()) }.boxed()
+    }
+
+    fn receive(
+        self: Arc<Self>,
+        subscription: String,
+        _config: ReceiveConfig,
+        handler: MessageHandler,
+        cancel: CancellationToken,
+    ) -> BoxFuture<'static, Result<(), Status>> {
+        let messages = std::mem::take(&mut *self.messages.lock().unwrap());
+        let transport: Arc<dyn PubSubTransport> = self;
+        async move {
+            let count = messages.len();
+            for message in messages {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let ack_id = Uuid::new_v4().to_string();
+                handler(TransportMessage::new(
+                    transport.clone(),
+                    subscription.clone(),
+                    ack_id,
+                    message,
+                    None,
+                ))
+                .await;
+            }
+            tracing::info!(count, "Replayed recording");
+            // Like a streaming pull, keep going until cancelled
+            cancel.cancelled().await;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn acknowledge<'a>(
+        &'a self,
+        _subscription: &'a str,
+        _ack_ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        async { Ok(()) }.boxed()
+    }
+
+    fn modify_ack_deadline<'a>(
+        &'a self,
+        _subscription: &'a str,
+        _ack_ids: Vec<String>,
+        _seconds: i32,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        async { Ok(()) }.boxed()
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Records every received message to the file at `path`, appending to it
+    pub fn with_recording(self, path: impl AsRef<Path>) -> Result<Self, PubSubError> {
+        let transport = RecordingTransport::new(self.transport(), path)?;
+        Ok(self.with_transport(Arc::new(transport)))
+    }
+
+    /// Receives the messages of the recording at `path` instead of pulling
+    /// from Pub/Sub
+    pub fn with_replay(self, path: impl AsRef<Path>) -> Result<Self, PubSubError> {
+        let transport = ReplayTransport::open(path)?;
+        Ok(self.with_transport(Arc::new(transport)))
+    }
+}
@@ -0,0 +1,199 @@
+//! Registry of live workers, built from their heartbeats
+//!
+//! A [`WorkerRegistry`] listens on a subscription to the heartbeat topic (see
+//! [`crate::heartbeat`]) and remembers the last [`HeartbeatRecord`] of every
+//! worker. Workers that stop sending heartbeats are forgotten after a timeout,
+//! so [`WorkerRegistry::workers`] lists the workers alive right now and the
+//! queues they serve.
+//!
+//! Give the registry to a backend with [`PubSubBackend::with_worker_registry`]
+//! to have apalis' [`ListWorkers`] report the same workers.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use std::{sync::Arc, time::Duration};
+//! # use apalis_pubsub::registry::WorkerRegistry;
+//! # async fn example(client: google_cloud_pubsub::client::Client) {
+//! let registry = Arc::new(WorkerRegistry::new(
+//!     client.subscription("worker-heartbeats-dashboard"),
+//!     Duration::from_secs(90),
+//! ));
+//! let cancel = tokio_util::sync::CancellationToken::new();
+//! tokio::spawn({
+//!     let registry = registry.clone();
+//!     async move { registry.run(cancel).await }
+//! });
+//!
+//! for worker in registry.workers() {
+//!     println!("{} serves {}", worker.record.worker, worker.record.queue);
+//! }
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use apalis_core::backend::RunningWorker;
+#[cfg(feature = "consume")]
+use apalis_core::backend::{codec::Codec, ListWorkers};
+use google_cloud_pubsub::subscription::Subscription;
+use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "consume")]
+use crate::PubSubCompact;
+use crate::{heartbeat::HeartbeatRecord, PubSubBackend, PubSubError};
+
+/// A worker known to the registry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisteredWorker {
+    /// The worker's latest heartbeat
+    pub record: HeartbeatRecord,
+    /// When that heartbeat was received
+    pub last_heartbeat: SystemTime,
+}
+
+/// Workers alive according to their heartbeats
+pub struct WorkerRegistry {
+    subscription: Subscription,
+    timeout: Duration,
+    /// Latest heartbeat per queue and worker name
+    workers: Mutex<HashMap<(String, String), RegisteredWorker>>,
+}
+
+impl WorkerRegistry {
+    /// Creates a registry reading heartbeats from `subscription`
+    ///
+    /// Workers are considered gone when no heartbeat arrived for `timeout`,
+    /// which should span a few heartbeat intervals. The subscription should
+    /// belong to this registry alone, registries sharing one would each only
+    /// see part of the heartbeats.
+    pub fn new(subscription: Subscription, timeout: Duration) -> Self {
+        Self {
+            subscription,
+            timeout,
+            workers: Mutex::default(),
+        }
+    }
+
+    /// Receives heartbeats until `cancel` fires
+    pub async fn run(self: Arc<Self>, cancel: CancellationToken) -> Result<(), PubSubError> {
+        let registry = self.clone();
+        self.subscription
+            .receive(
+                move |message, _cancel| {
+                    let registry = registry.clone();
+                    async move {
+                        if let Err(e) = message.ack().await {
+                            tracing::warn!(error = ?e, "Failed to ack heartbeat");
+                        }
+                        match HeartbeatRecord::from_message(&message.message) {
+                            Ok(record) => registry.record(record),
+                            Err(e) => tracing::warn!(error = %e, "Ignoring heartbeat"),
+                        }
+                    }
+                },
+                cancel,
+                None,
+            )
+            .await
+            .map_err(|e| PubSubError::Subscription(e.to_string()))
+    }
+
+    /// Remembers `record` as the latest heartbeat of its worker
+    pub fn record(&self, record: HeartbeatRecord) {
+        let key = (record.queue.clone(), record.worker.clone());
+        let worker = RegisteredWorker {
+            record,
+            last_heartbeat: SystemTime::now(),
+        };
+        let mut workers = self.workers.lock().expect("worker registry lock poisoned");
+        if !workers.contains_key(&key) {
+            tracing::info!(queue = key.0, worker = key.1, "Worker joined");
+        }
+        workers.insert(key, worker);
+    }
+
+    /// Workers that sent a heartbeat within the timeout
+    pub fn workers(&self) -> Vec<RegisteredWorker> {
+        let mut workers = self.workers.lock().expect("worker registry lock poisoned");
+        workers.retain(|(queue, worker), registered| {
+            let alive = registered
+                .last_heartbeat
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed < self.timeout);
+            if !alive {
+                tracing::info!(queue, worker, "Worker timed out");
+            }
+            alive
+        });
+        workers.values().cloned().collect()
+    }
+
+    /// Live workers serving `queue`
+    pub fn workers_for(&self, queue: &str) -> Vec<RegisteredWorker> {
+        self.workers()
+            .into_iter()
+            .filter(|worker| worker.record.queue == queue)
+            .collect()
+    }
+}
+
+fn to_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl From<RegisteredWorker> for RunningWorker {
+    fn from(worker: RegisteredWorker) -> Self {
+        RunningWorker {
+            id: worker.record.worker,
+            queue: worker.record.queue,
+            backend: "PubSubBackend".to_string(),
+            started_at: to_secs(worker.record.started_at),
+            last_heartbeat: to_secs(worker.last_heartbeat),
+            layers: String::new(),
+        }
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Reports the workers known to `registry` through [`ListWorkers`]
+    pub fn with_worker_registry(mut self, registry: Arc<WorkerRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    #[cfg(feature = "consume")]
+    fn registered_workers(&self, queue: Option<&str>) -> Vec<RunningWorker> {
+        let Some(registry) = &self.registry else {
+            return Vec::new();
+        };
+        let workers = match queue {
+            Some(queue) => registry.workers_for(queue),
+            None => registry.workers(),
+        };
+        workers.into_iter().map(RunningWorker::from).collect()
+    }
+}
+
+/// Lists the workers known to the [`WorkerRegistry`] given to the backend, or
+/// none without a registry
+#[cfg(feature = "consume")]
+impl<M, C> ListWorkers for PubSubBackend<M, C>
+where
+    M: Send + Sync + 'static,
+    C: Codec<M, Compact = PubSubCompact> + Send + Sync,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    async fn list_workers(&self, queue: &str) -> Result<Vec<RunningWorker>, PubSubError> {
+        Ok(self.registered_workers(Some(queue)))
+    }
+
+    async fn list_all_workers(&self) -> Result<Vec<RunningWorker>, PubSubError> {
+        Ok(self.registered_workers(None))
+    }
+}
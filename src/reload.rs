@@ -0,0 +1,187 @@
+//! Changing tunables of a running backend
+//!
+//! [`PubSubBackend::runtime_config`] returns a [`ConfigHandle`] to the
+//! backend's [`RuntimeConfig`]. Changes made through the handle apply to
+//! running workers right away, without restarting them:
+//!
+//! - the concurrency limit,
+//! - how many messages are buffered for the worker, and the flow control
+//!   limits of the streaming pull, to grow them under backlog or shrink them
+//!   under memory pressure,
+//! - the rate at which tasks are handed to the worker,
+//! - the largest and oldest messages accepted,
+//! - the slow dispatch threshold.
+//!
+//! The handle starts out with the values of the backend's [`PubSubConfig`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact};
+//! # fn example(backend: &PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let config = backend.runtime_config();
+//! // Later, from an admin endpoint or a config watcher
+//! config.update(|config| {
+//!     config.concurrency_limit = Some(16);
+//!     config.max_tasks_per_second = Some(50.0);
+//! });
+//! # }
+//! ```
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+
+use crate::{PubSubBackend, PubSubConfig};
+#[cfg(feature = "consume")]
+use {
+    crate::control::ConcurrencyControl,
+    google_cloud_pubsub::{subscriber::SubscriberConfig, subscription::ReceiveConfig},
+    tokio_util::sync::CancellationToken,
+};
+
+/// Tunables that can change while the backend runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeConfig {
+    /// Maximum number of tasks a worker processes concurrently
+    ///
+    /// A limit can be raised or lowered but not removed: setting `None` on a
+    /// running backend keeps the current limit.
+    pub concurrency_limit: Option<usize>,
+    /// Most messages buffered for the worker, must be at least 1
+    pub buffer_size: usize,
+    /// Maximum number of outstanding messages
+    ///
+    /// Changing the flow control limits restarts the streaming pull.
+    pub max_outstanding_messages: Option<i64>,
+    /// Maximum bytes of outstanding messages
+    pub max_outstanding_bytes: Option<i64>,
+    /// Most tasks handed to the worker per second
+    pub max_tasks_per_second: Option<f64>,
+    /// Maximum message size in bytes
+    pub max_message_size: usize,
+    /// Oldest a message may be when received, older messages are dropped
+    pub max_age: Option<Duration>,
+    /// Warn when a task waits longer than this in the local buffer
+    pub slow_dispatch_threshold: Option<Duration>,
+}
+
+impl From<&PubSubConfig> for RuntimeConfig {
+    fn from(config: &PubSubConfig) -> Self {
+        Self {
+            concurrency_limit: config.concurrency_limit,
+            buffer_size: config.buffer_size,
+            max_outstanding_messages: config.max_outstanding_messages,
+            max_outstanding_bytes: config.max_outstanding_bytes,
+            max_tasks_per_second: config.max_tasks_per_second,
+            max_message_size: config.max_message_size,
+            max_age: config.max_age,
+            slow_dispatch_threshold: config.slow_dispatch_threshold,
+        }
+    }
+}
+
+#[cfg(feature = "consume")]
+impl RuntimeConfig {
+    /// Flow control limits of the streaming pull
+    pub(crate) fn flow_control(&self) -> (Option<i64>, Option<i64>) {
+        (self.max_outstanding_messages, self.max_outstanding_bytes)
+    }
+
+    /// Flow control for the streaming pull, from `max_outstanding_messages`
+    /// and `max_outstanding_bytes`
+    ///
+    /// Limits left unset keep the [`SubscriberConfig`] defaults, so only
+    /// configured limits change how many messages Pub/Sub leases at once.
+    pub(crate) fn receive_config(&self) -> ReceiveConfig {
+        let defaults = SubscriberConfig::default();
+        ReceiveConfig {
+            subscriber_config: Some(SubscriberConfig {
+                max_outstanding_messages: self
+                    .max_outstanding_messages
+                    .unwrap_or(defaults.max_outstanding_messages),
+                max_outstanding_bytes: self
+                    .max_outstanding_bytes
+                    .unwrap_or(defaults.max_outstanding_bytes),
+                ..defaults
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+/// Handle to the [`RuntimeConfig`] of a backend, see the [module level documentation](self)
+#[derive(Debug, Clone)]
+pub struct ConfigHandle {
+    sender: Arc<watch::Sender<RuntimeConfig>>,
+}
+
+impl ConfigHandle {
+    pub(crate) fn new(config: RuntimeConfig) -> Self {
+        Self {
+            sender: Arc::new(watch::Sender::new(config)),
+        }
+    }
+
+    /// The current configuration
+    pub fn get(&self) -> RuntimeConfig {
+        self.sender.borrow().clone()
+    }
+
+    /// Replaces the configuration
+    pub fn set(&self, config: RuntimeConfig) {
+        self.sender.send_replace(config);
+    }
+
+    /// Changes the configuration in place
+    pub fn update(&self, update: impl FnOnce(&mut RuntimeConfig)) {
+        self.sender.send_modify(update);
+    }
+
+    /// Receiver notified of every change
+    pub fn subscribe(&self) -> watch::Receiver<RuntimeConfig> {
+        self.sender.subscribe()
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Handle changing the tunables of the running backend
+    ///
+    /// The handle stays valid after the backend is handed to a worker, and is
+    /// shared by clones of the backend.
+    pub fn runtime_config(&self) -> ConfigHandle {
+        self.runtime.clone()
+    }
+}
+
+/// Applies changes of the limit picked by `limit` to `control` until
+/// `cancel` fires
+#[cfg(feature = "consume")]
+pub(crate) async fn apply_limit(
+    mut config: watch::Receiver<RuntimeConfig>,
+    control: Arc<ConcurrencyControl>,
+    limit: fn(&RuntimeConfig) -> Option<usize>,
+    cancel: CancellationToken,
+) {
+    while let Some(Ok(())) = cancel.run_until_cancelled(config.changed()).await {
+        let limit = limit(&config.borrow_and_update());
+        if let Some(limit) = limit.filter(|&limit| limit > 0 && control.limit() != Some(limit)) {
+            tracing::info!(limit, "Limit changed");
+            control.set_limit(limit);
+        }
+    }
+}
+
+/// Completes once the flow control limits differ from `current`
+#[cfg(feature = "consume")]
+pub(crate) async fn flow_control_changed(
+    mut config: watch::Receiver<RuntimeConfig>,
+    current: (Option<i64>, Option<i64>),
+) {
+    while config.borrow_and_update().flow_control() == current {
+        if config.changed().await.is_err() {
+            // Nothing can change the configuration anymore
+            std::future::pending::<()>().await;
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Reporting failures to error trackers
+//!
+//! [`PubSubBackend::with_error_reporter`] hands every failure the backend sees
+//! to an [`ErrorReporter`], with enough context to group and triage it:
+//!
+//! - handlers returning an error,
+//! - messages dropped as poison because they can't be opened, validated or
+//!   decoded,
+//! - acknowledgements, publishes and the subscription stream failing.
+//!
+//! Reports carry a hash of the payload rather than the payload itself, so
+//! occurrences of the same bad message can be correlated without sending
+//! task data to a third party. Wiring up Sentry, Rollbar or similar is a
+//! matter of implementing [`ErrorReporter`] with their client.
+use std::{
+    any::Any,
+    error::Error,
+    fmt::Display,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use apalis_core::error::BoxDynError;
+use tower::{Layer, Service};
+
+use crate::{PubSubBackend, PubSubTask, PubSubTaskId};
+
+/// Where a reported failure happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// A handler returned an error
+    Handler,
+    /// A received message was dropped as poison
+    Poison,
+    /// Acknowledging a message failed
+    Ack,
+    /// Publishing tasks failed
+    Publish,
+    /// The subscription stream failed
+    Subscription,
+}
+
+/// A failure and its context
+#[derive(Debug, Clone)]
+pub struct ErrorReport {
+    /// Where the failure happened
+    pub kind: FailureKind,
+    /// The error message
+    pub error: String,
+    /// Messages of the errors that caused it, outermost first
+    pub chain: Vec<String>,
+    /// Id of the task involved, if known
+    pub task_id: Option<PubSubTaskId>,
+    /// Rust type name of the task arguments, if known
+    pub job_type: Option<&'static str>,
+    /// Hash of the payload involved, see [`payload_hash`]
+    pub payload_hash: Option<u64>,
+    /// Pub/Sub id of the message involved
+    pub message_id: Option<String>,
+}
+
+impl ErrorReport {
+    /// Reports `error`, including its chain of sources
+    pub fn new(kind: FailureKind, error: &(dyn Error + 'static)) -> Self {
+        let mut report = Self::from_display(kind, error);
+        let mut source = error.source();
+        while let Some(cause) = source {
+            report.chain.push(cause.to_string());
+            source = cause.source();
+        }
+        report
+    }
+
+    /// Reports an error known only by its message
+    pub fn from_display(kind: FailureKind, error: &dyn Display) -> Self {
+        Self {
+            kind,
+            error: error.to_string(),
+            chain: Vec::new(),
+            task_id: None,
+            job_type: None,
+            payload_hash: None,
+            message_id: None,
+        }
+    }
+
+    /// Sets the task involved
+    pub fn with_task_id(mut self, task_id: Option<PubSubTaskId>) -> Self {
+        self.task_id = task_id;
+        self
+    }
+
+    /// Sets the payload involved
+    pub fn with_payload(mut self, payload: &[u8]) -> Self {
+        self.payload_hash = Some(payload_hash(payload));
+        self
+    }
+
+    /// Sets the message involved
+    pub fn with_message_id(mut self, message_id: impl Into<String>) -> Self {
+        self.message_id = Some(message_id.into());
+        self
+    }
+}
+
+/// Hash identifying a payload in reports
+pub fn payload_hash(payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Receives the failures of a backend, see the [module level documentation](self)
+pub trait ErrorReporter: Send + Sync {
+    /// Records a failure
+    fn report(&self, report: ErrorReport);
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Hands every failure the backend sees to `reporter`
+    pub fn with_error_reporter(mut self, reporter: Arc<dyn ErrorReporter>) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+}
+
+/// Reports `report` if a reporter is configured
+pub(crate) fn report(
+    reporter: Option<&Arc<dyn ErrorReporter>>,
+    report: impl FnOnce() -> ErrorReport,
+) {
+    if let Some(reporter) = reporter {
+        reporter.report(report());
+    }
+}
+
+/// Middleware layer reporting handler errors
+#[derive(Clone)]
+pub struct ReportLayer {
+    reporter: Arc<dyn ErrorReporter>,
+}
+
+impl ReportLayer {
+    /// Creates a layer reporting to `reporter`
+    pub fn new(reporter: Arc<dyn ErrorReporter>) -> Self {
+        Self { reporter }
+    }
+}
+
+impl<S> Layer<S> for ReportLayer {
+    type Service = ReportService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ReportService {
+            inner: service,
+            reporter: self.reporter.clone(),
+        }
+    }
+}
+
+/// Service reporting the errors of its inner service
+#[derive(Clone)]
+pub struct ReportService<S> {
+    inner: S,
+    reporter: Arc<dyn ErrorReporter>,
+}
+
+impl<S, M> Service<PubSubTask<M>> for ReportService<S>
+where
+    S: Service<PubSubTask<M>>,
+    S::Future: Send + 'static,
+    S::Response: Send + 'static,
+    S::Error: Display + Send + 'static,
+    M: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = std::pin::Pin<
+        Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: PubSubTask<M>) -> Self::Future {
+        let task_id = req.parts.task_id.map(|id| *id.inner());
+        let payload_hash = req.parts.ctx.payload_hash();
+        let reporter = self.reporter.clone();
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let res = future.await;
+            if let Err(error) = &res {
+                // Handler errors are usually boxed, which keeps their sources
+                let mut report = match (error as &dyn Any).downcast_ref::<BoxDynError>() {
+                    Some(boxed) => ErrorReport::new(FailureKind::Handler, boxed.as_ref()),
+                    None => ErrorReport::from_display(FailureKind::Handler, error),
+                }
+                .with_task_id(task_id);
+                report.job_type = Some(std::any::type_name::<M>());
+                report.payload_hash = payload_hash;
+                reporter.report(report);
+            }
+            res
+        })
+    }
+}
@@ -0,0 +1,167 @@
+//! Publishing handler outputs to a response topic
+//!
+//! Job types implementing [`Responds`] name a topic their handler results are
+//! published to. Adding the [`RespondLayer`] returned by
+//! [`PubSubBackend::respond_layer`] to a worker encodes every successful result
+//! with the backend codec and publishes it, tagged with the id of the task that
+//! produced it. Consumers of the response topic can use a regular
+//! `PubSubBackend<M::Response, C>`, giving a typed produce-consume-respond pipeline.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis::prelude::*;
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_core::error::BoxDynError;
+//! # use apalis_pubsub::{respond::Responds, PubSubBackend, PubSubCompact};
+//! # use serde::{Deserialize, Serialize};
+//! #[derive(Debug, Clone, Serialize, Deserialize)]
+//! struct Resize { width: u32 }
+//!
+//! #[derive(Debug, Clone, Serialize, Deserialize)]
+//! struct Resized { url: String }
+//!
+//! impl Responds for Resize {
+//!     type Response = Resized;
+//!
+//!     fn response_topic() -> String {
+//!         "resize-results".to_string()
+//!     }
+//! }
+//!
+//! async fn resize(job: Resize) -> Result<Resized, BoxDynError> {
+//!     Ok(Resized { url: format!("https://example.com/{}.png", job.width) })
+//! }
+//!
+//! # async fn example(backend: PubSubBackend<Resize, JsonCodec<PubSubCompact>>) {
+//! let worker = WorkerBuilder::new("resizer")
+//!     .backend(backend.clone())
+//!     .layer(backend.respond_layer())
+//!     .build(resize);
+//! # }
+//! ```
+use std::{
+    marker::PhantomData,
+    task::{Context, Poll},
+};
+
+use apalis_core::{backend::codec::Codec, error::BoxDynError};
+use futures::{future::BoxFuture, FutureExt};
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use google_cloud_pubsub::publisher::Publisher;
+use tower::{Layer, Service};
+
+use crate::{PubSubBackend, PubSubCompact, PubSubTask, PUBSUB_ATTRIBUTE_TASK_ID};
+
+/// Job types whose handler output is published to a response topic
+pub trait Responds {
+    /// The type returned by the job's handler
+    type Response;
+
+    /// Name of the topic responses are published to
+    fn response_topic() -> String;
+}
+
+/// Layer that publishes handler responses to the job type's response topic
+///
+/// See the [module level documentation](self) for more details.
+pub struct RespondLayer<M, C> {
+    publisher: Publisher,
+    _marker: PhantomData<fn() -> (M, C)>,
+}
+
+impl<M, C> Clone for RespondLayer<M, C> {
+    fn clone(&self) -> Self {
+        Self {
+            publisher: self.publisher.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<S, M, C> Layer<S> for RespondLayer<M, C> {
+    type Service = RespondService<S, M, C>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RespondService {
+            inner,
+            publisher: self.publisher.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Service created by [`RespondLayer`]
+pub struct RespondService<S, M, C> {
+    inner: S,
+    publisher: Publisher,
+    _marker: PhantomData<fn() -> (M, C)>,
+}
+
+impl<S: Clone, M, C> Clone for RespondService<S, M, C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            publisher: self.publisher.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<S, M, C> Service<PubSubTask<M>> for RespondService<S, M, C>
+where
+    M: Responds,
+    S: Service<PubSubTask<M>, Response = M::Response>,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxDynError>,
+    M::Response: Send + 'static,
+    C: Codec<M::Response, Compact = PubSubCompact>,
+    C::Error: Into<BoxDynError>,
+{
+    type Response = S::Response;
+    type Error = BoxDynError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, task: PubSubTask<M>) -> Self::Future {
+        let task_id = task.parts.task_id.map(|id| id.to_string());
+        let publisher = self.publisher.clone();
+        let future = self.inner.call(task);
+
+        async move {
+            let response = future.await.map_err(Into::into)?;
+
+            let mut message = PubsubMessage {
+                data: C::encode(&response).map_err(Into::into)?,
+                ..Default::default()
+            };
+            if let Some(task_id) = task_id {
+                // Lets consumers correlate the response with the task that produced it
+                message
+                    .attributes
+                    .insert(PUBSUB_ATTRIBUTE_TASK_ID.to_owned(), task_id);
+            }
+
+            let id = publisher.publish(message).await.get().await?;
+            tracing::debug!("Response published:\n\tPub/sub id: {id}");
+
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+impl<M: Responds, C> PubSubBackend<M, C> {
+    /// Creates a [`RespondLayer`] publishing to `M`'s response topic
+    ///
+    /// See the [`respond`](self) module for more details.
+    pub fn respond_layer(&self) -> RespondLayer<M, C> {
+        RespondLayer {
+            publisher: self.client.topic(&M::response_topic()).new_publisher(None),
+            _marker: PhantomData,
+        }
+    }
+}
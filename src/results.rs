@@ -0,0 +1,258 @@
+//! Task result persistence
+//!
+//! Pub/Sub itself forgets a message once it's acknowledged, so there is no way to
+//! ask it whether a task succeeded. This module records handler outcomes in a
+//! pluggable [`ResultStore`], keyed by [`TaskId`]:
+//!
+//! - [`StoreResults`] implements apalis' [`Acknowledge`] trait, so adding it to a
+//!   worker with `ack_with` stores every outcome.
+//! - [`PubSubBackend`] implements [`WaitForCompletion`] on top of the store it was
+//!   configured with through [`PubSubBackend::with_result_store`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis::prelude::*;
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{results::*, PubSubBackend, PubSubCompact};
+//! # use std::sync::Arc;
+//! # async fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! async fn double(job: u32) -> u32 {
+//!     job * 2
+//! }
+//!
+//! let store: Arc<dyn ResultStore> = Arc::new(InMemoryResultStore::default());
+//! let backend = backend.with_result_store(store.clone());
+//!
+//! let worker = WorkerBuilder::new("doubler")
+//!     .backend(backend.clone())
+//!     .ack_with(StoreResults::<u32, JsonCodec<PubSubCompact>>::new(store))
+//!     .build(double);
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+use apalis_core::{
+    backend::{codec::Codec, TaskResult},
+    error::BoxDynError,
+    task::{status::Status, task_id::TaskId, Parts},
+    worker::ext::ack::Acknowledge,
+};
+use futures::{future::BoxFuture, FutureExt};
+#[cfg(feature = "consume")]
+use {
+    apalis_core::{backend::WaitForCompletion, timer::sleep},
+    futures::{stream::BoxStream, StreamExt},
+    std::time::Duration,
+};
+
+use crate::{utils::PubSubContext, PubSubBackend, PubSubCompact, PubSubError, PubSubTaskId};
+
+/// How often [`WaitForCompletion::wait_for`] checks the store for new results
+#[cfg(feature = "consume")]
+const RESULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A stored task outcome, with successful results in their encoded form
+pub type StoredResult = TaskResult<PubSubCompact, PubSubTaskId>;
+
+/// Storage for task outcomes
+pub trait ResultStore: Send + Sync {
+    /// Inserts or replaces the result of a task
+    fn store(&self, result: StoredResult) -> BoxFuture<'_, Result<(), PubSubError>>;
+
+    /// Fetches the result of a task, if it has been stored
+    fn fetch(
+        &self,
+        task_id: &TaskId<PubSubTaskId>,
+    ) -> BoxFuture<'_, Result<Option<StoredResult>, PubSubError>>;
+}
+
+/// A [`ResultStore`] that keeps results in process memory
+#[derive(Debug, Default)]
+pub struct InMemoryResultStore {
+    results: Mutex<HashMap<PubSubTaskId, StoredResult>>,
+}
+
+impl ResultStore for InMemoryResultStore {
+    fn store(&self, result: StoredResult) -> BoxFuture<'_, Result<(), PubSubError>> {
+        self.results
+            .lock()
+            .unwrap()
+            .insert(*result.task_id().inner(), result);
+        futures::future::ready(Ok(())).boxed()
+    }
+
+    fn fetch(
+        &self,
+        task_id: &TaskId<PubSubTaskId>,
+    ) -> BoxFuture<'_, Result<Option<StoredResult>, PubSubError>> {
+        let result = self.results.lock().unwrap().get(task_id.inner()).cloned();
+        futures::future::ready(Ok(result)).boxed()
+    }
+}
+
+/// [`Acknowledge`] implementation that records every outcome in a [`ResultStore`]
+///
+/// Successful responses of type `Res` are encoded with `C` before being stored.
+/// Tasks without a task id can't be looked up later and are skipped.
+pub struct StoreResults<Res, C> {
+    store: Arc<dyn ResultStore>,
+    _codec: PhantomData<fn(&Res) -> C>,
+}
+
+impl<Res, C> StoreResults<Res, C> {
+    /// Creates an acknowledger writing to `store`
+    pub fn new(store: Arc<dyn ResultStore>) -> Self {
+        Self {
+            store,
+            _codec: PhantomData,
+        }
+    }
+}
+
+impl<Res, C> Clone for StoreResults<Res, C> {
+    fn clone(&self) -> Self {
+        Self::new(self.store.clone())
+    }
+}
+
+impl<Res, C> Acknowledge<Res, PubSubContext, PubSubTaskId> for StoreResults<Res, C>
+where
+    C: Codec<Res, Compact = PubSubCompact>,
+    C::Error: std::fmt::Display,
+{
+    type Error = PubSubError;
+    type Future = BoxFuture<'static, Result<(), PubSubError>>;
+
+    fn ack(
+        &mut self,
+        res: &Result<Res, BoxDynError>,
+        parts: &Parts<PubSubContext, PubSubTaskId>,
+    ) -> Self::Future {
+        let Some(task_id) = parts.task_id else {
+            tracing::debug!("Task has no id, not storing its result");
+            return futures::future::ready(Ok(())).boxed();
+        };
+
+        let result = match res {
+            Ok(value) => match C::encode(value) {
+                Ok(encoded) => TaskResult::new(task_id, Status::Done, Ok(encoded)),
+                Err(e) => {
+                    return futures::future::ready(Err(PubSubError::Codec(e.to_string()))).boxed()
+                }
+            },
+            Err(e) => TaskResult::new(task_id, Status::Failed, Err(e.to_string())),
+        };
+
+        let store = self.store.clone();
+        async move { store.store(result).await }.boxed()
+    }
+}
+
+/// Decodes the successful result of a stored outcome with `C`
+#[cfg(feature = "consume")]
+fn decode_result<T, C>(stored: StoredResult) -> Result<TaskResult<T, PubSubTaskId>, PubSubError>
+where
+    C: Codec<T, Compact = PubSubCompact>,
+    C::Error: std::fmt::Display,
+{
+    let TaskResult {
+        task_id,
+        status,
+        result,
+    } = stored;
+    let result = match result {
+        Ok(encoded) => Ok(C::decode(&encoded).map_err(|e| PubSubError::Codec(e.to_string()))?),
+        Err(e) => Err(e),
+    };
+    Ok(TaskResult::new(task_id, status, result))
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Sets the store used by [`WaitForCompletion`] to look up task results
+    ///
+    /// The same store must be given to the worker's [`StoreResults`] acknowledger.
+    pub fn with_result_store(mut self, store: Arc<dyn ResultStore>) -> Self {
+        self.result_store = Some(store);
+        self
+    }
+
+    #[cfg(feature = "consume")]
+    fn result_store(&self) -> Result<Arc<dyn ResultStore>, PubSubError> {
+        self.result_store
+            .clone()
+            .ok_or_else(|| PubSubError::Store("No result store configured".to_string()))
+    }
+}
+
+#[cfg(feature = "consume")]
+impl<M, C, T> WaitForCompletion<T> for PubSubBackend<M, C>
+where
+    Self: apalis_core::backend::Backend<IdType = PubSubTaskId, Error = PubSubError>,
+    C: Codec<T, Compact = PubSubCompact> + Send + Sync + 'static,
+    C::Error: std::fmt::Display,
+    M: Send + Sync,
+    T: Send + 'static,
+{
+    type ResultStream = BoxStream<'static, Result<TaskResult<T, PubSubTaskId>, PubSubError>>;
+
+    fn wait_for(
+        &self,
+        task_ids: impl IntoIterator<Item = TaskId<PubSubTaskId>>,
+    ) -> Self::ResultStream {
+        let store = match self.result_store() {
+            Ok(store) => store,
+            Err(e) => return futures::stream::once(async move { Err(e) }).boxed(),
+        };
+        let pending: Vec<_> = task_ids.into_iter().collect();
+
+        futures::stream::unfold(
+            (store, pending, Vec::new()),
+            |(store, mut pending, mut ready)| async move {
+                loop {
+                    if let Some(result) = ready.pop() {
+                        return Some((result, (store, pending, ready)));
+                    }
+                    if pending.is_empty() {
+                        return None;
+                    }
+
+                    let mut still_pending = Vec::with_capacity(pending.len());
+                    for task_id in pending {
+                        match store.fetch(&task_id).await {
+                            Ok(Some(stored)) => ready.push(decode_result::<T, C>(stored)),
+                            Ok(None) => still_pending.push(task_id),
+                            // Stop waiting for tasks we can't look up
+                            Err(e) => ready.push(Err(e)),
+                        }
+                    }
+                    pending = still_pending;
+
+                    if ready.is_empty() {
+                        sleep(RESULT_POLL_INTERVAL).await;
+                    }
+                }
+            },
+        )
+        .boxed()
+    }
+
+    async fn check_status(
+        &self,
+        task_ids: impl IntoIterator<Item = TaskId<PubSubTaskId>> + Send,
+    ) -> Result<Vec<TaskResult<T, PubSubTaskId>>, PubSubError> {
+        let store = self.result_store()?;
+        let task_ids: Vec<_> = task_ids.into_iter().collect();
+        let mut results = Vec::new();
+        for task_id in task_ids {
+            if let Some(stored) = store.fetch(&task_id).await? {
+                results.push(decode_result::<T, C>(stored)?);
+            }
+        }
+        Ok(results)
+    }
+}
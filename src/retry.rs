@@ -0,0 +1,322 @@
+//! Durable retries by republishing failed tasks
+//!
+//! By default messages are acknowledged once the worker takes them, so a task
+//! whose handler fails isn't redelivered by Pub/Sub. Retrying within the
+//! worker, for example with apalis' retry layer, loses the task if the worker
+//! goes away meanwhile.
+//!
+//! With [`PubSubConfig::republish_retry`](crate::PubSubConfig::republish_retry)
+//! a failed task is instead published again with its attempt count increased,
+//! see [`crate::parts`]. The retry is a regular message, so it survives worker
+//! restarts, shows up in the subscription backlog, and is picked up by any
+//! worker. Retries are scheduled to run after the
+//! [`PubSubConfig::backoff`](crate::PubSubConfig::backoff) delay for their
+//! attempt: they carry a `not_before` attribute, so workers receiving them
+//! earlier hold them until then, see [`crate::schedule`]. Publishing them to
+//! a separate [`RepublishRetry::delay_topic`] lets them be consumed on their
+//! own schedule.
+//!
+//! Tasks failing their last attempt are dropped, or republished to the
+//! [`PubSubConfig::dead_letter_topic`](crate::PubSubConfig::dead_letter_topic)
+//! when there's one, see [`crate::dlq`].
+//!
+//! When acknowledgement is deferred until the task finished, see
+//! [`outcome`](crate::outcome), the layer settles the messages of failed tasks
+//! itself. The original message is acknowledged once its retry is
+//! republished, or once the task is dead-lettered or dropped after its last
+//! attempt, as the retry now stands for it. Redelivering it as well would
+//! retry the task twice, without ever counting the redelivered attempt. It's
+//! settled as the ack policy decides only when republishing fails, and
+//! failures the policy gives up on, acknowledging or dead-lettering them,
+//! aren't republished at all. A [`RetryAfter`](crate::outcome::RetryAfter)
+//! delay replaces the backoff delay of the retry. Messages left to an
+//! [`AckHandle`](crate::manual::AckHandle), with
+//! [`AckStrategy::Manual`](crate::outcome::AckStrategy::Manual), can't be
+//! settled this way, so republished retries are disabled with that strategy.
+//!
+//! The handler's error is still reported to the worker either way.
+use std::{
+    marker::PhantomData,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use apalis_core::{
+    backend::codec::Codec,
+    task::{attempt::Attempt, builder::TaskBuilder},
+};
+use tower::{Layer, Service};
+
+use crate::{
+    backoff::BackoffStrategy,
+    clock::Clock,
+    dlq::{DeadLetterer, DEAD_LETTER_KIND_FAILED},
+    envelope::WireFormat,
+    jobs::JobTypes,
+    outcome::{AckDecision, Acker},
+    schedule,
+    sink::{publish_with_retry, task_message},
+    transport::PubSubTransport,
+    utils::PubSubContext,
+    PubSubCompact, PubSubTask, PubSubTaskId,
+};
+
+/// Republishing of failed tasks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepublishRetry {
+    /// Most times a task is attempted, counting the first (default: 3)
+    pub max_attempts: usize,
+    /// Topic retries are published to (default: none, the backend's topic)
+    pub delay_topic: Option<String>,
+}
+
+impl Default for RepublishRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            delay_topic: None,
+        }
+    }
+}
+
+/// Middleware layer republishing failed tasks, see the [module level documentation](self)
+pub struct RepublishRetryLayer<C> {
+    republisher: Republisher,
+    max_attempts: usize,
+    job_types: Arc<JobTypes>,
+    _codec: PhantomData<fn() -> C>,
+}
+
+impl<C> Clone for RepublishRetryLayer<C> {
+    fn clone(&self) -> Self {
+        Self {
+            republisher: self.republisher.clone(),
+            max_attempts: self.max_attempts,
+            job_types: self.job_types.clone(),
+            _codec: PhantomData,
+        }
+    }
+}
+
+impl<C> RepublishRetryLayer<C> {
+    /// Creates a layer publishing retries to `topic` through `transport`
+    #[cfg(feature = "consume")]
+    pub(crate) fn new(
+        transport: Arc<dyn PubSubTransport>,
+        topic: String,
+        max_attempts: usize,
+        wire_format: WireFormat,
+        backoff: Arc<dyn BackoffStrategy>,
+        clock: Arc<dyn Clock>,
+        job_types: Arc<JobTypes>,
+    ) -> Self {
+        Self {
+            republisher: Republisher {
+                transport,
+                topic,
+                wire_format,
+                backoff,
+                clock,
+                dead_letters: None,
+                acker: None,
+            },
+            max_attempts,
+            job_types,
+            _codec: PhantomData,
+        }
+    }
+
+    /// Republishes tasks failing their last attempt with `dead_letters`
+    #[cfg(feature = "consume")]
+    pub(crate) fn with_dead_letters(mut self, dead_letters: Option<DeadLetterer>) -> Self {
+        self.republisher.dead_letters = dead_letters;
+        self
+    }
+
+    /// Settles the messages of failed tasks with `acker`, when
+    /// acknowledgement is deferred
+    #[cfg(feature = "consume")]
+    pub(crate) fn with_acker(mut self, acker: Option<Acker>) -> Self {
+        self.republisher.acker = acker;
+        self
+    }
+}
+
+/// Publishes failed tasks again, and settles their original message
+#[derive(Clone)]
+struct Republisher {
+    transport: Arc<dyn PubSubTransport>,
+    topic: String,
+    wire_format: WireFormat,
+    backoff: Arc<dyn BackoffStrategy>,
+    clock: Arc<dyn Clock>,
+    dead_letters: Option<DeadLetterer>,
+    acker: Option<Acker>,
+}
+
+impl Republisher {
+    /// Republishes the failed `task` for another attempt, or dead-letters it
+    /// after its last, returning whether the task was taken care of
+    ///
+    /// A retry runs after `delay`, or the backoff delay for its attempt.
+    async fn retry(
+        &self,
+        task: TaskBuilder<PubSubCompact, PubSubContext, PubSubTaskId>,
+        attempts: usize,
+        max_attempts: usize,
+        delay: Option<Duration>,
+        error: &str,
+    ) -> bool {
+        if attempts >= max_attempts {
+            tracing::warn!(attempts, "Task failed on its last attempt");
+            let Some(dead_letters) = &self.dead_letters else {
+                return true;
+            };
+            let message = task_message(task.build(), None, self.wire_format);
+            return match dead_letters
+                .publish(&message, DEAD_LETTER_KIND_FAILED, error)
+                .await
+            {
+                Ok(_) => true,
+                Err(e) => {
+                    tracing::error!(error = ?e, "Failed to dead-letter failed task");
+                    false
+                }
+            };
+        }
+
+        let delay = delay.unwrap_or_else(|| self.backoff.delay(attempts as u32));
+        let mut task = task
+            .with_attempt(Attempt::new_with_value(attempts))
+            .run_after(delay)
+            .build();
+        let ctx = std::mem::take(&mut task.parts.ctx);
+        task.parts.ctx = ctx.with_not_before(schedule::not_before(self.clock.now() + delay));
+        let message = task_message(task, None, self.wire_format);
+        match publish_with_retry(
+            self.transport.as_ref(),
+            &self.topic,
+            message,
+            self.backoff.as_ref(),
+            self.clock.as_ref(),
+        )
+        .await
+        {
+            Ok(id) => {
+                tracing::debug!(attempts, message_id = id, "Republished failed task");
+                true
+            }
+            Err(e) => {
+                tracing::error!(error = ?e, "Failed to republish failed task");
+                false
+            }
+        }
+    }
+}
+
+impl<S, C> Layer<S> for RepublishRetryLayer<C> {
+    type Service = RepublishRetryService<S, C>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        RepublishRetryService {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service republishing tasks its inner service fails
+pub struct RepublishRetryService<S, C> {
+    inner: S,
+    layer: RepublishRetryLayer<C>,
+}
+
+impl<S: Clone, C> Clone for RepublishRetryService<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+impl<S, M, C> Service<PubSubTask<M>> for RepublishRetryService<S, C>
+where
+    S: Service<PubSubTask<M>>,
+    S::Future: Send + 'static,
+    S::Response: Send + 'static,
+    S::Error: std::fmt::Display + Send + 'static,
+    M: Send + 'static,
+    C: Codec<M, Compact = PubSubCompact>,
+    C::Error: std::fmt::Debug,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = std::pin::Pin<
+        Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: PubSubTask<M>) -> Self::Future {
+        let max_attempts = self
+            .layer
+            .job_types
+            .get::<M>(&req.parts.ctx)
+            .and_then(|overrides| overrides.max_attempts)
+            .unwrap_or(self.layer.max_attempts);
+        // The handler takes the arguments, so keep them encoded for a retry
+        let retry = C::encode(&req.args)
+            .inspect_err(
+                |e| tracing::warn!(error = ?e, "Failed to encode task, it won't be retried"),
+            )
+            .ok()
+            .map(|args| {
+                let mut task = TaskBuilder::new(args).with_ctx(req.parts.ctx.clone());
+                if let Some(task_id) = req.parts.task_id {
+                    task = task.with_task_id(task_id);
+                }
+                (task, req.parts.attempt.clone())
+            });
+        let ctx = req.parts.ctx.clone();
+        let republisher = self.layer.republisher.clone();
+
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let res = future.await;
+            let Err(error) = &res else {
+                return res;
+            };
+            // With deferred acknowledgement, what the policy makes of the failure
+            let decision = republisher
+                .acker
+                .as_ref()
+                .map(|acker| acker.decide_error(&ctx, error));
+            let error = error.to_string();
+            let retried = match (retry, decision) {
+                // The policy gave up on the task, don't retry it
+                (_, Some(AckDecision::Ack | AckDecision::DeadLetter)) | (None, _) => false,
+                // The worker counted this attempt when it started the task
+                (Some((task, attempt)), decision) => {
+                    let delay = match decision {
+                        Some(AckDecision::NackAfter(delay)) => Some(delay),
+                        _ => None,
+                    };
+                    republisher
+                        .retry(task, attempt.current(), max_attempts, delay, &error)
+                        .await
+                }
+            };
+            if let (Some(acker), Some(decision)) = (&republisher.acker, decision) {
+                // The republished copy takes over from the original message,
+                // which is only redelivered when republishing failed
+                let decision = if retried { AckDecision::Ack } else { decision };
+                acker.settle_error(&ctx, decision, &error).await;
+            }
+            res
+        })
+    }
+}
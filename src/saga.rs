@@ -0,0 +1,405 @@
+//! Saga coordination on top of [`PubSubBackend`]
+//!
+//! A saga is an ordered list of steps, each with an optional compensation.
+//! Steps are published one at a time as [`SagaMessage`] tasks. When a worker
+//! reports a step as completed the next step is published; when a step fails,
+//! the compensations of every completed step are published in reverse order.
+//!
+//! Saga progress is kept in a pluggable [`SagaStore`], so coordinators running
+//! in different processes can share state.
+//!
+//! The coordinator saves the saga's progress before publishing the message of
+//! its next step, so a worker reporting that step right away always finds it
+//! current. Each step message has a task id derived from the saga, the step
+//! and whether it's an action or a compensation, so publishing it again
+//! carries the same task id. That happens when the report that led to the
+//! step is made again, for example because publishing failed and the report
+//! was retried, or after [`SagaCoordinator::resume`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact, saga::*};
+//! # use apalis_codec::json::JsonCodec;
+//! # use serde::{Deserialize, Serialize};
+//! # use std::sync::Arc;
+//! #[derive(Debug, Clone, Serialize, Deserialize)]
+//! enum Booking {
+//!     ReserveFlight,
+//!     CancelFlight,
+//!     ChargeCard,
+//! }
+//!
+//! # async fn example(
+//! #     backend: PubSubBackend<SagaMessage<Booking>, JsonCodec<PubSubCompact>>,
+//! # ) -> Result<(), apalis_pubsub::PubSubError> {
+//! let mut coordinator = SagaCoordinator::new(backend, Arc::new(InMemorySagaStore::default()));
+//!
+//! coordinator
+//!     .start(vec![
+//!         SagaStep::new(Booking::ReserveFlight).with_compensation(Booking::CancelFlight),
+//!         SagaStep::new(Booking::ChargeCard),
+//!     ])
+//!     .await?;
+//!
+//! // In the worker handling `SagaMessage<Booking>`, report the outcome:
+//! // coordinator.step_completed(&message).await?;
+//! // coordinator.step_failed(&message).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use apalis_core::{
+    backend::TaskSink,
+    task::{builder::TaskBuilder, task_id::TaskId},
+};
+use futures::{future::BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{utils::PubSubContext, PubSubBackend, PubSubError, PubSubTaskId};
+
+/// Unique identifier of a saga
+pub type SagaId = Uuid;
+
+/// A single step of a saga: the task to run and the task that undoes it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SagaStep<M> {
+    /// Task published to perform the step
+    pub action: M,
+    /// Task published to undo the step if a later step fails
+    pub compensation: Option<M>,
+}
+
+impl<M> SagaStep<M> {
+    /// Creates a step without a compensation
+    pub fn new(action: M) -> Self {
+        Self {
+            action,
+            compensation: None,
+        }
+    }
+
+    /// Sets the task that undoes this step
+    pub fn with_compensation(mut self, compensation: M) -> Self {
+        self.compensation = Some(compensation);
+        self
+    }
+}
+
+/// Whether a [`SagaMessage`] performs a step or undoes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SagaMessageKind {
+    /// The message carries the step's action
+    Action,
+    /// The message carries the step's compensation
+    Compensation,
+}
+
+/// The task published for every saga step and compensation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SagaMessage<M> {
+    /// The saga this message belongs to
+    pub saga_id: SagaId,
+    /// Index of the step within the saga
+    pub step: usize,
+    /// Whether this message performs or undoes the step
+    pub kind: SagaMessageKind,
+    /// The user task
+    pub payload: M,
+}
+
+/// Lifecycle of a saga
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SagaStatus {
+    /// Steps are being published in order
+    Running,
+    /// Every step completed
+    Completed,
+    /// A step failed and compensations are being published
+    Compensating,
+    /// Every completed step was compensated
+    Compensated,
+    /// A compensation failed; manual intervention is required
+    Failed,
+}
+
+/// Persisted progress of a saga
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SagaState<M> {
+    /// The saga's identifier
+    pub id: SagaId,
+    /// All steps of the saga
+    pub steps: Vec<SagaStep<M>>,
+    /// Index of the step currently being performed or compensated
+    pub current: usize,
+    /// Current lifecycle status
+    pub status: SagaStatus,
+    /// Step and kind of the report that led to the current step, if any
+    #[serde(default)]
+    pub advanced_by: Option<(usize, SagaMessageKind)>,
+}
+
+impl<M> SagaState<M> {
+    /// Task id of the message performing or undoing `step`
+    pub fn task_id(&self, step: usize, kind: SagaMessageKind) -> PubSubTaskId {
+        let kind = match kind {
+            SagaMessageKind::Action => "action",
+            SagaMessageKind::Compensation => "compensation",
+        };
+        Uuid::new_v5(&self.id, format!("{step}/{kind}").as_bytes())
+    }
+
+    /// Index of the last step before `before` that has a compensation
+    fn previous_compensation(&self, before: usize) -> Option<usize> {
+        (0..before)
+            .rev()
+            .find(|&i| self.steps[i].compensation.is_some())
+    }
+}
+
+/// Storage for saga state
+///
+/// Implement this to keep sagas in a database shared by several coordinators.
+pub trait SagaStore<M>: Send + Sync {
+    /// Loads a saga's state, if it exists
+    fn load(&self, id: SagaId) -> BoxFuture<'_, Result<Option<SagaState<M>>, PubSubError>>;
+
+    /// Inserts or replaces a saga's state
+    fn save(&self, state: SagaState<M>) -> BoxFuture<'_, Result<(), PubSubError>>;
+}
+
+/// A [`SagaStore`] that keeps saga state in process memory
+///
+/// State is lost when the process exits, so this is mostly useful for tests
+/// and single-instance deployments.
+#[derive(Debug)]
+pub struct InMemorySagaStore<M> {
+    states: Mutex<HashMap<SagaId, SagaState<M>>>,
+}
+
+impl<M> Default for InMemorySagaStore<M> {
+    fn default() -> Self {
+        Self {
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<M: Clone + Send> SagaStore<M> for InMemorySagaStore<M> {
+    fn load(&self, id: SagaId) -> BoxFuture<'_, Result<Option<SagaState<M>>, PubSubError>> {
+        let state = self.states.lock().unwrap().get(&id).cloned();
+        futures::future::ready(Ok(state)).boxed()
+    }
+
+    fn save(&self, state: SagaState<M>) -> BoxFuture<'_, Result<(), PubSubError>> {
+        self.states.lock().unwrap().insert(state.id, state);
+        futures::future::ready(Ok(())).boxed()
+    }
+}
+
+/// Drives sagas by publishing their steps and compensations
+pub struct SagaCoordinator<M, C> {
+    backend: PubSubBackend<SagaMessage<M>, C>,
+    store: Arc<dyn SagaStore<M>>,
+}
+
+impl<M, C> Clone for SagaCoordinator<M, C>
+where
+    PubSubBackend<SagaMessage<M>, C>: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            backend: self.backend.clone(),
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl<M, C> SagaCoordinator<M, C>
+where
+    M: Clone,
+    PubSubBackend<SagaMessage<M>, C>: TaskSink<
+        SagaMessage<M>,
+        Error = PubSubError,
+        Context = PubSubContext,
+        IdType = PubSubTaskId,
+    >,
+{
+    /// Creates a coordinator publishing through `backend` and keeping state in `store`
+    pub fn new(backend: PubSubBackend<SagaMessage<M>, C>, store: Arc<dyn SagaStore<M>>) -> Self {
+        Self { backend, store }
+    }
+
+    /// Starts a new saga by publishing its first step
+    ///
+    /// The saga is saved before its first step is published, so when
+    /// publishing fails it can be [resumed](Self::resume) with the id logged.
+    pub async fn start(&mut self, steps: Vec<SagaStep<M>>) -> Result<SagaId, PubSubError> {
+        let status = if steps.is_empty() {
+            SagaStatus::Completed
+        } else {
+            SagaStatus::Running
+        };
+        let state = SagaState {
+            id: Uuid::new_v4(),
+            steps,
+            current: 0,
+            status,
+            advanced_by: None,
+        };
+
+        let id = state.id;
+        self.store.save(state.clone()).await?;
+        self.publish_current(&state)
+            .await
+            .inspect_err(|e| tracing::error!(saga_id = %id, error = ?e, "Failed to start saga"))?;
+        Ok(id)
+    }
+
+    /// Publishes the message of the saga's current step again, under the same
+    /// task id, for example after publishing it failed
+    pub async fn resume(&mut self, id: SagaId) -> Result<SagaStatus, PubSubError> {
+        let state = self.load(id).await?;
+        self.publish_current(&state).await?;
+        Ok(state.status)
+    }
+
+    /// Records that the step carried by `message` completed
+    ///
+    /// Publishes the next step (or compensation) and returns the saga's new status.
+    /// Messages for steps that are not current are ignored, so redelivered
+    /// messages don't advance the saga twice. Reporting the step that led to
+    /// the current one again publishes the current step again, see the
+    /// [module level documentation](self).
+    pub async fn step_completed(
+        &mut self,
+        message: &SagaMessage<M>,
+    ) -> Result<SagaStatus, PubSubError> {
+        let mut state = self.load(message.saga_id).await?;
+        if state.advanced_by == Some((message.step, message.kind)) {
+            self.publish_current(&state).await?;
+            return Ok(state.status);
+        }
+        if message.step != state.current {
+            return Ok(state.status);
+        }
+
+        match (state.status, message.kind) {
+            (SagaStatus::Running, SagaMessageKind::Action) => {
+                let next = state.current + 1;
+                if next < state.steps.len() {
+                    state.current = next;
+                } else {
+                    state.status = SagaStatus::Completed;
+                }
+            }
+            (SagaStatus::Compensating, SagaMessageKind::Compensation) => {
+                compensate_before(&mut state, message.step);
+            }
+            (status, _) => return Ok(status),
+        }
+
+        self.advance(state, message).await
+    }
+
+    /// Records that the step carried by `message` failed
+    ///
+    /// A failed action starts compensating the completed steps in reverse order.
+    /// A failed compensation marks the saga as [`SagaStatus::Failed`].
+    pub async fn step_failed(
+        &mut self,
+        message: &SagaMessage<M>,
+    ) -> Result<SagaStatus, PubSubError> {
+        let mut state = self.load(message.saga_id).await?;
+        if state.advanced_by == Some((message.step, message.kind)) {
+            self.publish_current(&state).await?;
+            return Ok(state.status);
+        }
+        if message.step != state.current {
+            return Ok(state.status);
+        }
+
+        match (state.status, message.kind) {
+            (SagaStatus::Running, SagaMessageKind::Action) => {
+                tracing::warn!(saga_id = %state.id, step = message.step, "Saga step failed, compensating");
+                state.status = SagaStatus::Compensating;
+                compensate_before(&mut state, message.step);
+            }
+            (SagaStatus::Compensating, SagaMessageKind::Compensation) => {
+                tracing::error!(saga_id = %state.id, step = message.step, "Saga compensation failed");
+                state.status = SagaStatus::Failed;
+            }
+            (status, _) => return Ok(status),
+        }
+
+        self.advance(state, message).await
+    }
+
+    /// Fetches the current state of a saga
+    pub async fn state(&self, id: SagaId) -> Result<Option<SagaState<M>>, PubSubError> {
+        self.store.load(id).await
+    }
+
+    async fn load(&self, id: SagaId) -> Result<SagaState<M>, PubSubError> {
+        self.store
+            .load(id)
+            .await?
+            .ok_or_else(|| PubSubError::Store(format!("Unknown saga {id}")))
+    }
+
+    /// Saves the saga as advanced by `message`, then publishes its new
+    /// current step
+    async fn advance(
+        &mut self,
+        mut state: SagaState<M>,
+        message: &SagaMessage<M>,
+    ) -> Result<SagaStatus, PubSubError> {
+        state.advanced_by = Some((message.step, message.kind));
+        self.store.save(state.clone()).await?;
+        self.publish_current(&state).await?;
+        Ok(state.status)
+    }
+
+    /// Publishes the action or compensation of the current step, if the
+    /// saga is still running or compensating
+    async fn publish_current(&mut self, state: &SagaState<M>) -> Result<(), PubSubError> {
+        let step = state.current;
+        let (kind, payload) = match state.status {
+            SagaStatus::Running => (SagaMessageKind::Action, state.steps[step].action.clone()),
+            SagaStatus::Compensating => (
+                SagaMessageKind::Compensation,
+                state.steps[step]
+                    .compensation
+                    .clone()
+                    .expect("compensation steps are only published when present"),
+            ),
+            SagaStatus::Completed | SagaStatus::Compensated | SagaStatus::Failed => return Ok(()),
+        };
+
+        let message = SagaMessage {
+            saga_id: state.id,
+            step,
+            kind,
+            payload,
+        };
+        let task = TaskBuilder::new(message)
+            .with_task_id(TaskId::new(state.task_id(step, kind)))
+            .build();
+        self.backend.push_task(task).await?;
+        Ok(())
+    }
+}
+
+/// Moves the saga to the compensation of the last compensable step before
+/// `step`, or marks it as compensated if there is none
+fn compensate_before<M>(state: &mut SagaState<M>, step: usize) {
+    match state.previous_compensation(step) {
+        Some(previous) => state.current = previous,
+        None => state.status = SagaStatus::Compensated,
+    }
+}
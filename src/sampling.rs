@@ -0,0 +1,87 @@
+//! Sampling of received payloads into debug logs
+//!
+//! When a producer starts sending malformed tasks, seeing a few of the actual
+//! payloads is usually the quickest way to find out what changed. With
+//! [`PubSubConfig::payload_sampling`](crate::PubSubConfig::payload_sampling)
+//! the backend logs received payloads at debug level, before decoding so
+//! undecodable ones are included:
+//!
+//! - at most one payload per [`PayloadSampling::interval`], so busy
+//!   subscriptions don't flood the logs,
+//! - passed through [`PayloadSampling::redact`] first, to mask sensitive fields,
+//! - truncated to [`PayloadSampling::max_len`] characters.
+//!
+//! Payloads are shown as UTF-8, with invalid sequences replaced.
+use std::time::Duration;
+#[cfg(feature = "consume")]
+use std::{sync::Mutex, time::Instant};
+
+/// How received payloads are sampled into debug logs
+#[derive(Debug, Clone)]
+pub struct PayloadSampling {
+    /// Characters of a payload logged at most (default: 512)
+    pub max_len: usize,
+    /// Shortest time between two samples (default: 10s)
+    pub interval: Duration,
+    /// Masks sensitive data in a payload before it's logged (default: none)
+    pub redact: Option<fn(&str) -> String>,
+}
+
+impl Default for PayloadSampling {
+    fn default() -> Self {
+        Self {
+            max_len: 512,
+            interval: Duration::from_secs(10),
+            redact: None,
+        }
+    }
+}
+
+/// Logs received payloads as configured by a [`PayloadSampling`]
+#[cfg(feature = "consume")]
+pub(crate) struct PayloadSampler {
+    sampling: PayloadSampling,
+    last_sample: Mutex<Option<Instant>>,
+}
+
+#[cfg(feature = "consume")]
+impl PayloadSampler {
+    pub(crate) fn new(sampling: PayloadSampling) -> Self {
+        Self {
+            sampling,
+            last_sample: Mutex::new(None),
+        }
+    }
+
+    /// Whether a sample is due, claiming it if so
+    fn claim(&self) -> bool {
+        let mut last_sample = self.last_sample.lock().unwrap();
+        let due = last_sample.is_none_or(|at| at.elapsed() >= self.sampling.interval);
+        if due {
+            *last_sample = Some(Instant::now());
+        }
+        due
+    }
+
+    /// Logs `payload` if debug logging is on and a sample is due
+    pub(crate) fn sample(&self, payload: &[u8], task_id: Option<&str>) {
+        if !tracing::enabled!(tracing::Level::DEBUG) || !self.claim() {
+            return;
+        }
+
+        let text = String::from_utf8_lossy(payload);
+        let text = match self.sampling.redact {
+            Some(redact) => redact(&text),
+            None => text.into_owned(),
+        };
+        let truncated = text.chars().count() > self.sampling.max_len;
+        let sample: String = text.chars().take(self.sampling.max_len).collect();
+        tracing::debug!(
+            task_id,
+            size = payload.len(),
+            truncated,
+            payload = %sample,
+            "Sampled payload"
+        );
+    }
+}
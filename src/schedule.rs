@@ -0,0 +1,125 @@
+//! Holding scheduled messages until they're due
+//!
+//! Pub/Sub delivers messages as soon as they're published, whatever their
+//! `run_at` attribute says. With
+//! [`PubSubConfig::hold_scheduled`](crate::PubSubConfig::hold_scheduled),
+//! messages received before their `run_at` are held back with ack deadline
+//! modifications instead of being handed to the worker:
+//!
+//! - messages due within [`HoldScheduled::max_hold`] are held by the worker,
+//!   which keeps extending their ack deadline and lets the last one lapse
+//!   when they're due, so Pub/Sub redelivers them on time,
+//! - messages due later get the longest ack deadline Pub/Sub accepts, 10
+//!   minutes, and are redelivered then to be checked again.
+//!
+//! Tasks pushed with [`PubSubBackend::push_scheduled`](crate::PubSubBackend::push_scheduled)
+//! carry a `not_before` attribute besides their `run_at`, marking them as
+//! scheduled on purpose. Workers hold them until then whether or not
+//! `hold_scheduled` is set, with the default [`HoldScheduled`] if it isn't.
+//! So do retries republished after a backoff delay, see [`crate::retry`].
+//!
+//! This gives coarse scheduled delivery, to the second at best. Every hold
+//! costs at least one redelivery, which counts towards the delivery attempts
+//! of a dead-letter policy, and held messages count towards the flow control
+//! limits of the streaming pull.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(any(feature = "consume", feature = "local"))]
+use crate::clock::Clock;
+#[cfg(feature = "consume")]
+use {
+    crate::{lease::MAX_ACK_DEADLINE, spawn::Spawner, transport::TransportMessage},
+    std::sync::Arc,
+    tokio_util::sync::CancellationToken,
+};
+
+/// Margin left before an ack deadline when extending it
+#[cfg(feature = "consume")]
+const EXTENSION_MARGIN: Duration = Duration::from_secs(30);
+
+/// How scheduled messages are held, see the [module level documentation](self)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HoldScheduled {
+    /// Messages due within this are held by the worker, later ones are
+    /// redelivered every 10 minutes until they're due (default: 1 hour)
+    pub max_hold: Duration,
+}
+
+impl Default for HoldScheduled {
+    fn default() -> Self {
+        Self {
+            max_hold: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// The UNIX time a task due at `at` doesn't run before, rounded up so it
+/// doesn't run early
+pub(crate) fn not_before(at: SystemTime) -> u64 {
+    let at = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    at.as_secs() + u64::from(at.subsec_nanos() > 0)
+}
+
+/// Time left until `run_at`, a UNIX time, if it's in the future on `clock`
+#[cfg(any(feature = "consume", feature = "local"))]
+pub(crate) fn until(run_at: u64, clock: &dyn Clock) -> Option<Duration> {
+    let now = clock.now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Duration::from_secs(run_at)
+        .checked_sub(now)
+        .filter(|delay| !delay.is_zero())
+}
+
+#[cfg(feature = "consume")]
+impl HoldScheduled {
+    /// Holds `message` for `delay` before Pub/Sub redelivers it
+    pub(crate) async fn hold(
+        &self,
+        message: TransportMessage,
+        delay: Duration,
+        cancel: CancellationToken,
+        spawner: &Spawner,
+        clock: Arc<dyn Clock>,
+    ) {
+        if delay > self.max_hold {
+            tracing::debug!(?delay, "Postponing scheduled message");
+            extend(&message, MAX_ACK_DEADLINE).await;
+            return;
+        }
+
+        tracing::debug!(?delay, "Holding scheduled message");
+        let due = clock.instant() + delay;
+        spawner.spawn(async move {
+            loop {
+                let remaining = due.saturating_duration_since(clock.instant());
+                if remaining <= MAX_ACK_DEADLINE {
+                    // Let the message be redelivered when it's due
+                    extend(&message, remaining).await;
+                    return;
+                }
+                if !extend(&message, MAX_ACK_DEADLINE).await {
+                    return;
+                }
+                let wait = clock.sleep(MAX_ACK_DEADLINE - EXTENSION_MARGIN);
+                if cancel.run_until_cancelled(wait).await.is_none() {
+                    // Another worker picks the message up once its lease lapses
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Sets the ack deadline of `message` to `deadline`, returning whether it
+/// succeeded
+#[cfg(feature = "consume")]
+async fn extend(message: &TransportMessage, deadline: Duration) -> bool {
+    // Round up so the message isn't redelivered early
+    let seconds = deadline.as_secs() + u64::from(deadline.subsec_nanos() > 0);
+    match message.modify_ack_deadline(seconds as i32).await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!(error = ?e, "Failed to extend the ack deadline of a scheduled message");
+            false
+        }
+    }
+}
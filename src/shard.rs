@@ -0,0 +1,169 @@
+//! Spreading tasks across sharded topics
+//!
+//! A single topic, and a single ordering key within it, has a publish
+//! throughput limit. Workloads above it can shard their topic: with
+//! [`PubSubBackend::with_sharding`], the backend publishes each task to one of
+//! `N` sibling topics, named after its topic with a `-0` to `-{N-1}` suffix
+//! (see [`shard_name`]):
+//!
+//! - tasks with a [shard key](Sharding::with_key) go to the shard its hash
+//!   picks, so tasks sharing a key stay on one topic,
+//! - without a shard key, tasks with an
+//!   [ordering key](PubSubBackend::with_ordering_key) are sharded by it, which
+//!   keeps ordered tasks in order,
+//! - other tasks go to the shards in turn.
+//!
+//! A [`topic`](crate::jobs::JobTypeConfig::topic) set for the job type of a
+//! task, or its [priority tier](crate::priority), takes precedence over its
+//! shard.
+//!
+//! Each shard topic has its own subscription, named after the backend's
+//! subscription the same way. [`PubSubBackend::shards`] returns a backend per
+//! shard, consuming its subscription, so one worker can be run for each. The
+//! shard backends share the settings, statistics and shutdown of the backend
+//! they were made from, and can [provision](crate::provision) their own topic
+//! and subscription.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{shard::Sharding, PubSubBackend, PubSubCompact};
+//! # fn example(backend: PubSubBackend<(String, u32), JsonCodec<PubSubCompact>>) {
+//! // Publishes to `tasks-0` to `tasks-7`, by tenant
+//! let backend = backend.with_sharding(
+//!     Sharding::new(8).with_key(|(tenant, _): &(String, u32)| Some(tenant.clone())),
+//! );
+//! // Consume `tasks-sub-0` to `tasks-sub-7`, one worker each
+//! for shard in backend.shards() {
+//!     // WorkerBuilder::new(..).backend(shard)...
+//! #   let _ = shard;
+//! }
+//! # }
+//! ```
+#[cfg(feature = "publish")]
+use std::sync::atomic::Ordering;
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use crate::{inflight::InFlight, sink::OrderingKeyFn, PubSubBackend};
+
+/// Name of shard `shard` of the topic or subscription `name`
+pub fn shard_name(name: &str, shard: usize) -> String {
+    format!("{name}-{shard}")
+}
+
+/// FNV-1a hash of `key`, stable across processes and releases
+#[cfg(feature = "publish")]
+fn stable_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// How tasks are spread across shard topics, see the
+/// [module level documentation](self)
+pub struct Sharding<M> {
+    shards: usize,
+    key: Option<OrderingKeyFn<M>>,
+    /// Shard of the next task without a key
+    next: Arc<AtomicUsize>,
+}
+
+impl<M> Clone for Sharding<M> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards,
+            key: self.key.clone(),
+            next: self.next.clone(),
+        }
+    }
+}
+
+impl<M> std::fmt::Debug for Sharding<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sharding")
+            .field("shards", &self.shards)
+            .field("key", &self.key.is_some())
+            .finish()
+    }
+}
+
+impl<M> Sharding<M> {
+    /// Spreads tasks across `shards` topics, at least 1
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: shards.max(1),
+            key: None,
+            next: Arc::default(),
+        }
+    }
+
+    /// Picks the shard of tasks by the hash of the key `key` returns
+    ///
+    /// Tasks for which it returns `None` are sharded as if it wasn't set.
+    pub fn with_key(mut self, key: impl Fn(&M) -> Option<String> + Send + Sync + 'static) -> Self {
+        self.key = Some(Arc::new(key));
+        self
+    }
+
+    /// Number of shards
+    pub fn shards(&self) -> usize {
+        self.shards
+    }
+
+    /// Whether picking a shard needs the task's arguments
+    #[cfg(feature = "publish")]
+    pub(crate) fn has_key(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Shard of a task with the arguments `args`, when decoded, and the
+    /// ordering key `ordering_key`
+    #[cfg(feature = "publish")]
+    pub(crate) fn shard(&self, args: Option<&M>, ordering_key: Option<&str>) -> usize {
+        let key = self
+            .key
+            .as_ref()
+            .zip(args)
+            .and_then(|(key, args)| key(args));
+        match key.as_deref().or(ordering_key) {
+            Some(key) => (stable_hash(key) % self.shards as u64) as usize,
+            None => self.next.fetch_add(1, Ordering::Relaxed) % self.shards,
+        }
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Publishes tasks to the shards of the backend's topic, see
+    /// [`shard`](crate::shard)
+    pub fn with_sharding(mut self, sharding: Sharding<M>) -> Self {
+        self.sharding = Some(sharding);
+        self
+    }
+
+    /// A backend for each shard, consuming the shard's subscription and
+    /// publishing to the shard's topic
+    ///
+    /// Without sharding, the backend itself is the only shard.
+    pub fn shards(&self) -> Vec<Self> {
+        let Some(sharding) = &self.sharding else {
+            return vec![self.clone()];
+        };
+        (0..sharding.shards)
+            .map(|shard| {
+                let mut backend = self.clone();
+                backend.sharding = None;
+                backend.topic = self
+                    .topic
+                    .as_ref()
+                    .map(|topic| self.client.topic(&shard_name(&topic.id(), shard)));
+                backend.subscription = Arc::new(
+                    self.client
+                        .subscription(&shard_name(&self.subscription.id(), shard)),
+                );
+                backend.in_flight = Arc::new(InFlight::new(backend.subscriber()));
+                backend
+            })
+            .collect()
+    }
+}
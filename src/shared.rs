@@ -0,0 +1,105 @@
+//! Sharing one streaming pull between the workers of a process
+//!
+//! Each worker polling a clone of a backend opens its own streaming pull, with
+//! its own flow control: `N` workers keep up to `N` times
+//! [`max_outstanding_messages`](crate::PubSubConfig::max_outstanding_messages)
+//! messages leased, and a busy worker keeps its buffered messages while an
+//! idle one waits for new ones.
+//!
+//! With [`PubSubBackend::with_shared_consumer`], the workers of the backend
+//! and its clones share a single streaming pull instead. The first worker to
+//! poll starts it, and every worker then takes the next task when it has room
+//! for one, in the order they asked for it. Busy workers leave tasks to idle
+//! ones, and messages are only leased once for the whole process.
+//!
+//! The shared pull runs until the backend [shuts down](PubSubBackend::shutdown)
+//! rather than until a worker stops, and
+//! [`max_tasks_per_worker`](crate::PubSubConfig::max_tasks_per_worker) counts
+//! the tasks of all the workers.
+//!
+//! Requires the `consume` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact};
+//! # fn example(backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>) {
+//! let backend = backend.with_shared_consumer();
+//! for i in 0..4 {
+//!     // WorkerBuilder::new(format!("worker-{i}")).backend(backend.clone())...
+//! #   let _ = (i, backend.clone());
+//! }
+//! # }
+//! ```
+use std::sync::{Arc, OnceLock};
+
+use apalis_core::backend::TaskStream;
+use apalis_core::{
+    backend::{codec::Codec, Backend},
+    worker::context::WorkerContext,
+};
+use futures::stream::Fuse;
+use futures::StreamExt;
+use tokio::sync::Mutex;
+
+use crate::{PubSubBackend, PubSubCompact, PubSubError, PubSubTask};
+
+type SharedStream<M> = Fuse<TaskStream<PubSubTask<M>, PubSubError>>;
+
+/// The streaming pull shared by the workers of a backend, started by the
+/// first one
+pub(crate) struct SharedConsumer<M> {
+    /// Tasks of the shared pull, taken by workers in the order they wait for
+    /// them
+    stream: OnceLock<Arc<Mutex<SharedStream<M>>>>,
+}
+
+impl<M> Default for SharedConsumer<M> {
+    fn default() -> Self {
+        Self {
+            stream: OnceLock::new(),
+        }
+    }
+}
+
+impl<M: Send + 'static> SharedConsumer<M> {
+    /// Tasks of the shared pull for `worker`, starting it with `backend` if
+    /// it isn't running yet
+    pub(crate) fn join<C>(
+        &self,
+        backend: PubSubBackend<M, C>,
+        worker: &WorkerContext,
+    ) -> TaskStream<PubSubTask<M>, PubSubError>
+    where
+        C: Codec<M, Compact = PubSubCompact>,
+        C::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let stream = self
+            .stream
+            .get_or_init(|| {
+                let mut backend = backend;
+                backend.shared_consumer = None;
+                // Outlives the worker starting it, so it isn't tied to it
+                let owner = WorkerContext::new::<PubSubBackend<M, C>>(&backend.queue_name());
+                Arc::new(Mutex::new(backend.poll(&owner).fuse()))
+            })
+            .clone();
+        tracing::debug!(worker = worker.name(), "Worker joined the shared consumer");
+        futures::stream::unfold(stream, |stream| async move {
+            // The lock is fair, so waiting workers take tasks in turn
+            let item = stream.lock().await.next().await?;
+            Some((item, stream))
+        })
+        .boxed()
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Makes the workers of the backend, and of its clones made from now on,
+    /// share one streaming pull, see [`shared`](crate::shared)
+    pub fn with_shared_consumer(mut self) -> Self {
+        self.shared_consumer = Some(Arc::new(SharedConsumer::default()));
+        self
+    }
+}
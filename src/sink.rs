@@ -1,34 +1,52 @@
-use std::{
-    marker::PhantomData,
-    pin::Pin,
-    task::{Context, Poll},
-};
+use std::{marker::PhantomData, sync::Arc};
 
-use futures::{
-    future::{try_join_all, BoxFuture, Shared},
-    FutureExt, Sink,
-};
+use apalis_core::task::task_id::TaskId;
+use google_cloud_gax::grpc::Status;
 use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+#[cfg(feature = "publish")]
+use tokio_util::sync::PollSender;
+use uuid::Uuid;
+
+#[cfg(feature = "publish")]
+use crate::publisher::{Outstanding, Queued};
+use crate::{
+    ack::is_transient,
+    backoff::BackoffStrategy,
+    clock::Clock,
+    envelope::{self, TaskEnvelope, WireFormat},
+    parts::MessageAttributes,
+    transport::PubSubTransport,
+    PubSubBackend, PubSubCompact, PubSubTask,
+};
 
-use crate::{PubSubBackend, PubSubCompact, PubSubError, PubSubTask, PUBSUB_ATTRIBUTE_TASK_ID};
-
-/// The type of the future that the sink polls when attempting to flush data
-type SinkFlushFuture = BoxFuture<'static, Result<(), PubSubError>>;
+/// Derives the ordering key of a message from its task
+pub(crate) type OrderingKeyFn<M> = Arc<dyn Fn(&M) -> Option<String> + Send + Sync>;
 
 /// Message sink for [`PubSubBackend`]
 ///
-/// Consumes messages and sends them to the pub/sub backend
+/// Consumes tasks and hands them to a publisher task, spawned on first use,
+/// which publishes up to [`PubSubConfig::publish_concurrency`](crate::PubSubConfig::publish_concurrency)
+/// of them at once. Sending a task only waits for room in the publisher's
+/// queue, flushing waits for the tasks sent through this sink to be published.
+/// A task failing to publish is reported by the next flush or send.
 pub struct PubSubSink<M, Codec> {
-    buffer: Vec<PubSubTask<PubSubCompact>>,
-    flush_future: Option<Shared<SinkFlushFuture>>,
+    /// Queue of the publisher task, once started
+    #[cfg(feature = "publish")]
+    pub(crate) publisher: Option<PollSender<Queued>>,
+    #[cfg(feature = "publish")]
+    pub(crate) outstanding: Arc<Outstanding>,
+    pub(crate) ordering_key: Option<OrderingKeyFn<M>>,
     _marker: PhantomData<(M, Codec)>,
 }
 
 impl<M, Codec> Clone for PubSubSink<M, Codec> {
     fn clone(&self) -> Self {
         Self {
-            buffer: self.buffer.clone(),
-            flush_future: None,
+            #[cfg(feature = "publish")]
+            publisher: None,
+            #[cfg(feature = "publish")]
+            outstanding: Arc::default(),
+            ordering_key: self.ordering_key.clone(),
             _marker: PhantomData,
         }
     }
@@ -37,126 +55,89 @@ impl<M, Codec> Clone for PubSubSink<M, Codec> {
 impl<M, Codec> PubSubSink<M, Codec> {
     pub fn new() -> Self {
         Self {
-            buffer: Vec::new(),
-            flush_future: None,
+            #[cfg(feature = "publish")]
+            publisher: None,
+            #[cfg(feature = "publish")]
+            outstanding: Arc::default(),
+            ordering_key: None,
             _marker: PhantomData,
         }
     }
 }
 
-impl<M, Codec> Sink<PubSubTask<PubSubCompact>> for PubSubBackend<M, Codec>
-where
-    M: Unpin,
-    Codec: Unpin,
-{
-    type Error = PubSubError;
-
-    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
-    }
-
-    fn start_send(
-        self: Pin<&mut Self>,
-        item: PubSubTask<PubSubCompact>,
-    ) -> Result<(), Self::Error> {
-        self.get_mut().sink.buffer.push(item);
-        Ok(())
-    }
-
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let me = self.get_mut();
-
-        if me.sink.flush_future.is_none() && me.sink.buffer.is_empty() {
-            // No running future, and nothing to flush from the buffer: Nothing to do
-            return Poll::Ready(Ok(()));
+/// Publish attempts made before giving up on a message
+const PUBLISH_ATTEMPTS: u32 = 5;
+
+/// Publishes a message to `topic`, retrying transient failures
+pub(crate) async fn publish_with_retry(
+    transport: &dyn PubSubTransport,
+    topic: &str,
+    message: PubsubMessage,
+    backoff: &dyn BackoffStrategy,
+    clock: &dyn Clock,
+) -> Result<String, Status> {
+    let mut attempt = 1;
+    loop {
+        match transport.publish(topic, message.clone()).await {
+            Err(status) if attempt < PUBLISH_ATTEMPTS && is_transient(&status) => {
+                tracing::debug!(error = ?status, attempt, "Retrying publish");
+                clock.sleep(backoff.delay(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
         }
+    }
+}
 
-        if me.sink.flush_future.is_none() && !me.sink.buffer.is_empty() {
-            // No running flush future, and there's tasks in the buffer to send
-            // Make the future to flush out the buffer and send them to pub/sub
-            let buffer = std::mem::take(&mut me.sink.buffer);
-            let publisher = me.topic.new_publisher(None);
-
-            let fut = async move {
-                let futures = buffer.into_iter().map(|task| {
-                    // Send each task off to the backend
-                    let publisher = publisher.clone();
-                    async move {
-                        let mut message = PubsubMessage {
-                            data: task.args,
-                            ..Default::default()
-                        };
-
-                        let task_id_log = task
-                            .parts
-                            .task_id
-                            .map(|id| {
-                                let id = id.to_string();
-
-                                // Make log message
-                                let log_msg = format!("\n\tTask ID: {}", &id);
-
-                                // Put task in message attributes
-                                message
-                                    .attributes
-                                    .insert(PUBSUB_ATTRIBUTE_TASK_ID.to_owned(), id);
-
-                                log_msg
-                            })
-                            .unwrap_or_default();
-
-                        // Note: this publish function is also buffered, so this whole chain is actually double-buffered
-                        let awaiter = publisher.publish(message).await;
-
-                        // Await the publish result
-                        awaiter
-                            .get()
-                            .await
-                            .inspect(|id| {
-                                tracing::debug!(
-                                    "Message published:\n\tPub/sub id: {id}{task_id_log}"
-                                )
-                            })
-                            .map_err(|e| PubSubError::Client(e.to_string()))
-                    }
-                });
-
-                // Await the sends concurrently
-                // This is, like, the whole point of buffered sending
-                try_join_all(futures).await?;
-
-                Ok::<_, PubSubError>(())
-            };
-
-            me.sink.flush_future = Some(fut.boxed().shared());
+/// Builds the message published for a task
+pub(crate) fn task_message(
+    mut task: PubSubTask<PubSubCompact>,
+    ordering_key: Option<String>,
+    format: WireFormat,
+) -> PubsubMessage {
+    let mut message = PubsubMessage::default();
+
+    // Every message gets a task id so consumers can track its outcome
+    task.parts
+        .task_id
+        .get_or_insert_with(|| TaskId::new(Uuid::new_v4()));
+    // Carry the task's metadata along so consumers can restore it
+    let attributes = MessageAttributes::from_parts(&task.parts, ordering_key);
+
+    match format {
+        WireFormat::Attributes => {
+            attributes.apply(&mut message);
+            message.data = task.args;
         }
-
-        if let Some(fut) = me.sink.flush_future.as_mut() {
-            // Currently flushing tasks to the backend
-            // Poll the future
-            match fut.poll_unpin(cx) {
-                Poll::Ready(Ok(())) => {
-                    // All done!
-                    me.sink.flush_future = None;
-                    Poll::Ready(Ok(()))
-                }
-                Poll::Ready(Err(e)) => {
-                    // Something wen't wrong :(
-                    tracing::error!("Failed to send tasks to pub/sub backend: {e}");
-                    me.sink.flush_future = None;
-                    Poll::Ready(Err(e))
-                }
-                Poll::Pending => {
-                    // Future is still working, so we're still working
-                    Poll::Pending
-                }
+        WireFormat::Envelope => {
+            envelope::seal(&mut message, TaskEnvelope::new(task));
+            // Custom attributes stay outside the envelope for subscription filters
+            for (key, value) in attributes.custom {
+                message.attributes.entry(key).or_insert(value);
             }
-        } else {
-            unreachable!()
+            message.ordering_key = attributes.ordering_key.unwrap_or_default();
         }
     }
+    message
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Publishes tasks with the ordering key returned by `ordering_key`
+    ///
+    /// Messages sharing an ordering key are delivered in publish order when
+    /// the subscription has message ordering enabled, see
+    /// [`Provisioning::enable_message_ordering`](crate::provision::Provisioning::enable_message_ordering).
+    /// Tasks for which `ordering_key` returns `None` are published without one.
+    pub fn with_ordering_key(
+        mut self,
+        ordering_key: impl Fn(&M) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.sink.ordering_key = Some(Arc::new(ordering_key));
+        self
+    }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.poll_flush(cx)
+    /// Whether an ordering key extractor is configured
+    pub fn has_ordering_key(&self) -> bool {
+        self.sink.ordering_key.is_some()
     }
 }
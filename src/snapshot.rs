@@ -0,0 +1,70 @@
+//! Snapshots for rolling back deployments
+//!
+//! A snapshot captures which messages of the subscription are acknowledged.
+//! Taking one right before deploying a new worker release and seeking back to
+//! it if the release misbehaves replays every message the bad release
+//! acknowledged. Messages published after the snapshot are retained too.
+//!
+//! Snapshots expire at the latest 7 days after creation.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact};
+//! # async fn example(
+//! #     backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>,
+//! # ) -> Result<(), apalis_pubsub::PubSubError> {
+//! // In the pre-deploy hook
+//! backend.snapshot_before_deploy("release-42").await?;
+//!
+//! // If release 42 turns out to be broken, after rolling back the code
+//! backend.rollback_to("release-42").await?;
+//! # Ok(())
+//! # }
+//! ```
+use google_cloud_gax::grpc::Code;
+use google_cloud_pubsub::subscription::SeekTo;
+
+use crate::{PubSubBackend, PubSubError};
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Snapshots the subscription's current state under `name`
+    ///
+    /// An existing snapshot with the same name is replaced, so deploy hooks
+    /// can reuse a fixed name.
+    pub async fn snapshot_before_deploy(&self, name: &str) -> Result<(), PubSubError> {
+        match self.subscription.delete_snapshot(name, None).await {
+            Ok(()) => tracing::debug!(snapshot = name, "Replacing existing snapshot"),
+            Err(status) if status.code() == Code::NotFound => {}
+            Err(status) => return Err(PubSubError::Subscription(status.to_string())),
+        }
+
+        self.subscription
+            .create_snapshot(name, Default::default(), None)
+            .await
+            .map_err(|e| PubSubError::Subscription(e.to_string()))?;
+        tracing::info!(snapshot = name, "Created snapshot");
+        Ok(())
+    }
+
+    /// Seeks the subscription back to the snapshot `name`
+    ///
+    /// Messages acknowledged since the snapshot was taken are delivered again.
+    pub async fn rollback_to(&self, name: &str) -> Result<(), PubSubError> {
+        self.subscription
+            .seek(SeekTo::Snapshot(name.to_string()), None)
+            .await
+            .map_err(|e| PubSubError::Subscription(e.to_string()))?;
+        tracing::info!(snapshot = name, "Rolled subscription back to snapshot");
+        Ok(())
+    }
+
+    /// Deletes the snapshot `name`, once the deployment it guarded is known to be good
+    pub async fn delete_snapshot(&self, name: &str) -> Result<(), PubSubError> {
+        self.subscription
+            .delete_snapshot(name, None)
+            .await
+            .map_err(|e| PubSubError::Subscription(e.to_string()))
+    }
+}
@@ -0,0 +1,106 @@
+//! Where the backend runs its background tasks
+//!
+//! The backend runs its receive loop, lease extensions, acknowledgements and
+//! monitors as background tasks. By default they're spawned on the runtime
+//! the backend is polled from, which must be a Tokio runtime.
+//! [`PubSubBackend::with_runtime_handle`] spawns them on a dedicated runtime
+//! instead, so Pub/Sub traffic is kept apart from the application's own work,
+//! and the backend can be polled from threads outside any runtime.
+//!
+//! The backend works on current-thread runtimes as well. Services that run
+//! everything on one thread within a [`LocalSet`](tokio::task::LocalSet), as
+//! some embedded and GUI-adjacent ones do, can keep the backend's tasks on that
+//! thread too with [`PubSubBackend::with_local_spawn`]: they're then spawned
+//! with [`spawn_local`](tokio::task::spawn_local), and the backend must be
+//! polled from within the `LocalSet`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact};
+//! # fn example(
+//! #     backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>,
+//! # ) -> Result<(), Box<dyn std::error::Error>> {
+//! let pubsub_runtime = tokio::runtime::Builder::new_multi_thread()
+//!     .worker_threads(2)
+//!     .enable_all()
+//!     .build()?;
+//! let backend = backend.with_runtime_handle(pubsub_runtime.handle().clone());
+//! # Ok(())
+//! # }
+//! ```
+use std::future::Future;
+
+use tokio::{runtime::Handle, task::JoinHandle};
+
+use crate::PubSubBackend;
+
+/// Spawns background tasks on the configured runtime, or the current one
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Spawner {
+    target: SpawnTarget,
+}
+
+/// Where a [`Spawner`] spawns tasks
+#[derive(Debug, Clone, Default)]
+enum SpawnTarget {
+    /// The runtime the task is spawned from
+    #[default]
+    Current,
+    /// A runtime set by the user
+    Runtime(Handle),
+    /// The `LocalSet` the task is spawned from
+    Local,
+}
+
+impl Spawner {
+    /// Spawns `future` on the configured runtime, or the current one
+    ///
+    /// # Panics
+    ///
+    /// Without a configured runtime, when called outside a Tokio runtime, or
+    /// outside a `LocalSet` in local mode.
+    pub(crate) fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.target {
+            SpawnTarget::Current => tokio::spawn(future),
+            SpawnTarget::Runtime(handle) => handle.spawn(future),
+            SpawnTarget::Local => tokio::task::spawn_local(future),
+        }
+    }
+
+    /// The runtime to spawn on, if there is one, for code that mustn't panic
+    /// such as destructors
+    pub(crate) fn try_handle(&self) -> Option<Handle> {
+        match &self.target {
+            SpawnTarget::Runtime(handle) => Some(handle.clone()),
+            SpawnTarget::Current | SpawnTarget::Local => Handle::try_current().ok(),
+        }
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Runs the backend's background tasks on the runtime of `handle`
+    ///
+    /// Set it before builders starting background work, such as
+    /// [`with_archive`](Self::with_archive), so they use it too.
+    pub fn with_runtime_handle(mut self, handle: Handle) -> Self {
+        self.spawner = Spawner {
+            target: SpawnTarget::Runtime(handle),
+        };
+        self
+    }
+
+    /// Runs the backend's background tasks on the thread of the `LocalSet`
+    /// the backend is polled from, see the [module level documentation](self)
+    pub fn with_local_spawn(mut self) -> Self {
+        self.spawner = Spawner {
+            target: SpawnTarget::Local,
+        };
+        self
+    }
+}
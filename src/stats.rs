@@ -0,0 +1,549 @@
+//! Queue statistics for monitoring UIs
+//!
+//! Pub/Sub doesn't keep per-task state, so the statistics apalis dashboards
+//! display are assembled from two sources:
+//!
+//! - [`PubSubStats`] counters, updated locally as the worker receives and
+//!   processes messages. They only cover the current process.
+//! - An optional [`BacklogEstimator`], typically backed by the Cloud Monitoring
+//!   `pubsub.googleapis.com/subscription/num_undelivered_messages` metric,
+//!   giving the number of messages still waiting in the subscription.
+//!
+//! Handler execution times are also recorded per job type, as
+//! [`LatencyHistogram`]s, so latency objectives can be tracked per task type.
+//! Payload sizes of published and received messages are recorded as
+//! [`SizeHistogram`]s, to notice payloads growing towards the size limits.
+//! Messages that fail to decode are counted per job type, see
+//! [`breaker`](crate::breaker).
+//!
+//! [`PubSubBackend`] implements apalis' [`Metrics`] and [`ListQueues`] traits
+//! on top of all of these.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "consume")]
+use apalis_core::backend::{codec::Codec, ListQueues, Metrics, QueueInfo, StatType, Statistic};
+use futures::future::BoxFuture;
+
+#[cfg(feature = "consume")]
+use crate::PubSubCompact;
+use crate::{PubSubBackend, PubSubError};
+
+/// Estimates how many messages are waiting in a subscription
+///
+/// Implement this with the Cloud Monitoring API (or any other source) to
+/// include the subscription backlog in [`Metrics`].
+pub trait BacklogEstimator: Send + Sync {
+    /// Number of unacknowledged messages in the subscription with the given
+    /// fully qualified name
+    fn undelivered_messages(&self, subscription: &str) -> BoxFuture<'_, Result<u64, PubSubError>>;
+}
+
+/// How long a backlog estimate is reused before asking the [`BacklogEstimator`] again
+const BACKLOG_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// Upper bounds of the [`LatencyHistogram`] buckets, in milliseconds
+pub const LATENCY_BUCKETS_MS: [u64; 13] = [
+    5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000,
+];
+
+/// Distribution of handler execution times
+///
+/// Bucket `i` counts executions up to [`LATENCY_BUCKETS_MS`]`[i]`; one more
+/// bucket at the end counts slower executions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    total_micros: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; LATENCY_BUCKETS_MS.len() + 1],
+            total_micros: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, latency: Duration) {
+        let millis = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| millis <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.total_micros += latency.as_micros() as u64;
+    }
+
+    /// Executions counted per bucket
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Number of executions recorded
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Average execution time
+    pub fn mean(&self) -> Duration {
+        Duration::from_micros(
+            self.total_micros
+                .checked_div(self.count())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Upper bound of the bucket holding the `quantile` (between 0 and 1)
+    ///
+    /// Returns `None` without executions, or when the quantile falls in the
+    /// last, unbounded bucket.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = (quantile.clamp(0.0, 1.0) * count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, &executions) in self.buckets.iter().enumerate() {
+            seen += executions;
+            if seen >= rank {
+                return LATENCY_BUCKETS_MS
+                    .get(bucket)
+                    .map(|&bound| Duration::from_millis(bound));
+            }
+        }
+        None
+    }
+}
+
+/// Upper bounds of the [`SizeHistogram`] buckets, in bytes
+///
+/// The last bound is Pub/Sub's 10MB message size limit.
+pub const SIZE_BUCKETS_BYTES: [u64; 8] = [
+    1 << 10,
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+    4 << 20,
+    10 << 20,
+];
+
+/// Distribution of payload sizes
+///
+/// Bucket `i` counts payloads up to [`SIZE_BUCKETS_BYTES`]`[i]`; one more
+/// bucket at the end counts larger payloads.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    buckets: [u64; SIZE_BUCKETS_BYTES.len() + 1],
+    total_bytes: u64,
+    max_bytes: u64,
+}
+
+impl SizeHistogram {
+    #[cfg(any(feature = "publish", feature = "consume"))]
+    fn record(&mut self, bytes: usize) {
+        let bytes = bytes as u64;
+        let bucket = SIZE_BUCKETS_BYTES
+            .iter()
+            .position(|&bound| bytes <= bound)
+            .unwrap_or(SIZE_BUCKETS_BYTES.len());
+        self.buckets[bucket] += 1;
+        self.total_bytes += bytes;
+        self.max_bytes = self.max_bytes.max(bytes);
+    }
+
+    /// Payloads counted per bucket
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Number of payloads recorded
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Average payload size in bytes
+    pub fn mean(&self) -> u64 {
+        self.total_bytes
+            .checked_div(self.count())
+            .unwrap_or_default()
+    }
+
+    /// Largest payload seen, in bytes
+    pub fn max(&self) -> u64 {
+        self.max_bytes
+    }
+}
+
+/// Counters tracked locally by a backend and its workers
+#[derive(Debug, Default)]
+pub struct PubSubStats {
+    received: AtomicU64,
+    started: AtomicU64,
+    done: AtomicU64,
+    failed: AtomicU64,
+    /// Tasks handed to the worker, with their total and slow waits in the buffer
+    dispatched: AtomicU64,
+    dispatch_wait_micros: AtomicU64,
+    slow_dispatches: AtomicU64,
+    /// Milliseconds since the Unix epoch of the last ack, 0 if none
+    last_ack: AtomicU64,
+    /// Acknowledgements that came after the message's ack deadline
+    ack_expired: AtomicU64,
+    /// Handler execution times per job type
+    latency: Mutex<HashMap<&'static str, LatencyHistogram>>,
+    /// Payload sizes of published and received messages
+    published_sizes: Mutex<SizeHistogram>,
+    received_sizes: Mutex<SizeHistogram>,
+    /// Last backlog estimate and when it was made
+    backlog: Mutex<Option<(Instant, u64)>>,
+    /// Last backlog of each monitored dead-letter subscription, see [`crate::dlq`]
+    dead_letter_backlog: Mutex<HashMap<String, u64>>,
+    /// Messages that failed to decode, per job type
+    decode_failures: Mutex<HashMap<String, u64>>,
+}
+
+impl PubSubStats {
+    /// Messages handed to the worker since startup
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Messages received but not yet picked up by the worker
+    pub fn buffered(&self) -> u64 {
+        self.received()
+            .saturating_sub(self.started.load(Ordering::Relaxed))
+    }
+
+    /// Tasks picked up by the worker since startup
+    pub fn started(&self) -> u64 {
+        self.started.load(Ordering::Relaxed)
+    }
+
+    /// Tasks currently being processed
+    pub fn running(&self) -> u64 {
+        self.started
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.done() + self.failed())
+    }
+
+    /// Tasks that completed successfully since startup
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// Tasks that failed since startup
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Average time tasks waited in the local buffer before the worker took them
+    ///
+    /// Long waits mean the worker can't keep up with what it receives, as
+    /// opposed to a backlog in the subscription.
+    pub fn mean_dispatch_wait(&self) -> Duration {
+        let dispatched = self.dispatched.load(Ordering::Relaxed);
+        let total = self.dispatch_wait_micros.load(Ordering::Relaxed);
+        Duration::from_micros(total.checked_div(dispatched).unwrap_or_default())
+    }
+
+    /// Tasks that waited longer than
+    /// [`PubSubConfig::slow_dispatch_threshold`](crate::PubSubConfig::slow_dispatch_threshold)
+    /// before the worker took them
+    pub fn slow_dispatches(&self) -> u64 {
+        self.slow_dispatches.load(Ordering::Relaxed)
+    }
+
+    /// Acknowledgements rejected because the message's ack deadline had
+    /// passed, see [`PubSubError::AckExpired`](crate::PubSubError::AckExpired)
+    ///
+    /// Each is a message Pub/Sub delivers again although its task ran, so
+    /// this counts tasks processed twice.
+    pub fn ack_expired(&self) -> u64 {
+        self.ack_expired.load(Ordering::Relaxed)
+    }
+
+    /// Handler execution times recorded so far, per job type
+    ///
+    /// Job types are the Rust type names of the task arguments.
+    pub fn latency(&self) -> HashMap<&'static str, LatencyHistogram> {
+        self.latency.lock().unwrap().clone()
+    }
+
+    /// Payload sizes of the tasks pushed through this backend
+    pub fn published_sizes(&self) -> SizeHistogram {
+        self.published_sizes.lock().unwrap().clone()
+    }
+
+    /// Payload sizes of the messages received, including oversized ones
+    pub fn received_sizes(&self) -> SizeHistogram {
+        self.received_sizes.lock().unwrap().clone()
+    }
+
+    /// When the worker last acknowledged a message
+    pub fn last_ack(&self) -> Option<SystemTime> {
+        match self.last_ack.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+
+    /// Last measured backlog of each monitored dead-letter subscription
+    pub fn dead_letter_backlog(&self) -> HashMap<String, u64> {
+        self.dead_letter_backlog.lock().unwrap().clone()
+    }
+
+    /// Messages that failed to decode since startup, per job type
+    ///
+    /// Job types are the `job_type` attribute of the messages, or the Rust
+    /// type name of the task arguments without one.
+    pub fn decode_failures(&self) -> HashMap<String, u64> {
+        self.decode_failures.lock().unwrap().clone()
+    }
+
+    /// The last backlog estimate, if it's recent enough to reuse
+    fn cached_backlog(&self) -> Option<u64> {
+        self.backlog
+            .lock()
+            .unwrap()
+            .filter(|(at, _)| at.elapsed() < BACKLOG_CACHE_TTL)
+            .map(|(_, backlog)| backlog)
+    }
+
+    fn cache_backlog(&self, backlog: u64) {
+        *self.backlog.lock().unwrap() = Some((Instant::now(), backlog));
+    }
+
+    #[cfg(feature = "consume")]
+    pub(crate) fn record_dead_letter_backlog(&self, subscription: &str, backlog: u64) {
+        self.dead_letter_backlog
+            .lock()
+            .unwrap()
+            .insert(subscription.to_string(), backlog);
+    }
+
+    #[cfg(feature = "consume")]
+    pub(crate) fn record_decode_failure(&self, job_type: &str) {
+        *self
+            .decode_failures
+            .lock()
+            .unwrap()
+            .entry(job_type.to_string())
+            .or_default() += 1;
+    }
+
+    #[cfg(feature = "consume")]
+    pub(crate) fn record_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "consume")]
+    pub(crate) fn record_dispatched(&self, wait: Duration, slow: bool) {
+        self.dispatched.fetch_add(1, Ordering::Relaxed);
+        self.dispatch_wait_micros
+            .fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+        if slow {
+            self.slow_dispatches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(feature = "publish")]
+    pub(crate) fn record_published_size(&self, bytes: usize) {
+        self.published_sizes.lock().unwrap().record(bytes);
+    }
+
+    #[cfg(feature = "consume")]
+    pub(crate) fn record_received_size(&self, bytes: usize) {
+        self.received_sizes.lock().unwrap().record(bytes);
+    }
+
+    pub(crate) fn record_latency(&self, job_type: &'static str, latency: Duration) {
+        self.latency
+            .lock()
+            .unwrap()
+            .entry(job_type)
+            .or_default()
+            .record(latency);
+    }
+
+    pub(crate) fn record_acked(&self) {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.last_ack.store(millis as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_ack_expired(&self) {
+        self.ack_expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_started(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_finished(&self, success: bool) {
+        let counter = if success { &self.done } else { &self.failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "consume")]
+fn statistic(title: &str, value: u64, priority: u64) -> Statistic {
+    Statistic {
+        title: title.to_string(),
+        stat_type: StatType::Number,
+        value: value.to_string(),
+        priority: Some(priority),
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Counters tracked by this backend and the workers polling it
+    pub fn stats(&self) -> Arc<PubSubStats> {
+        self.stats.clone()
+    }
+
+    /// Sets the estimator used to report the subscription backlog in [`Metrics`]
+    pub fn with_backlog_estimator(mut self, estimator: Arc<dyn BacklogEstimator>) -> Self {
+        self.backlog_estimator = Some(estimator);
+        self
+    }
+
+    /// Estimated number of tasks waiting to be processed
+    ///
+    /// This is the subscription backlog reported by the [`BacklogEstimator`],
+    /// if one is configured, plus messages buffered locally. Backlog estimates
+    /// are cached for 10 seconds.
+    pub async fn pending_estimate(&self) -> u64 {
+        let backlog = match (&self.backlog_estimator, self.stats.cached_backlog()) {
+            (_, Some(backlog)) => backlog,
+            (Some(estimator), None) => estimator
+                .undelivered_messages(self.subscription.fully_qualified_name())
+                .await
+                .inspect(|&backlog| self.stats.cache_backlog(backlog))
+                .inspect_err(|e| tracing::warn!(error = ?e, "Failed to estimate backlog"))
+                .unwrap_or_default(),
+            (None, None) => 0,
+        };
+        backlog + self.stats.buffered()
+    }
+
+    /// Estimated queue length, see [`pending_estimate`](Self::pending_estimate)
+    pub async fn len(&self) -> u64 {
+        self.pending_estimate().await
+    }
+
+    /// Whether the queue is estimated to be empty
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+}
+
+#[cfg(feature = "consume")]
+impl<M, C> Metrics for PubSubBackend<M, C>
+where
+    M: Send + Sync + 'static,
+    C: Codec<M, Compact = PubSubCompact> + Send + Sync,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    async fn global(&self) -> Result<Vec<Statistic>, PubSubError> {
+        let mut statistics = vec![
+            statistic("Pending", self.pending_estimate().await, 1),
+            statistic("Running", self.stats.running(), 2),
+            statistic("Done", self.stats.done(), 3),
+            statistic("Failed", self.stats.failed(), 4),
+            statistic("Slow dispatches", self.stats.slow_dispatches(), 5),
+            statistic("Expired acks", self.stats.ack_expired(), 5),
+            statistic(
+                "Mean dispatch wait (ms)",
+                self.stats.mean_dispatch_wait().as_millis() as u64,
+                6,
+            ),
+        ];
+
+        let (published, received) = (self.stats.published_sizes(), self.stats.received_sizes());
+        statistics.extend([
+            statistic("Largest published payload (bytes)", published.max(), 7),
+            statistic("Mean published payload (bytes)", published.mean(), 7),
+            statistic("Largest received payload (bytes)", received.max(), 7),
+            statistic("Mean received payload (bytes)", received.mean(), 7),
+        ]);
+
+        let mut dead_letter: Vec<_> = self.stats.dead_letter_backlog().into_iter().collect();
+        dead_letter.sort();
+        for (subscription, backlog) in dead_letter {
+            statistics.push(statistic(
+                &format!("Dead-letter backlog ({subscription})"),
+                backlog,
+                2,
+            ));
+        }
+
+        let mut decode_failures: Vec<_> = self.stats.decode_failures().into_iter().collect();
+        decode_failures.sort();
+        for (job_type, failures) in decode_failures {
+            statistics.push(statistic(
+                &format!("Decode failures ({job_type})"),
+                failures,
+                4,
+            ));
+        }
+
+        let mut latency: Vec<_> = self.stats.latency().into_iter().collect();
+        latency.sort_by_key(|(job_type, _)| *job_type);
+        for (job_type, histogram) in latency {
+            for (label, quantile) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+                // Quantiles past the last bucket are reported as its bound
+                let bound = histogram
+                    .quantile(quantile)
+                    .unwrap_or(Duration::from_millis(
+                        LATENCY_BUCKETS_MS[LATENCY_BUCKETS_MS.len() - 1],
+                    ));
+                statistics.push(statistic(
+                    &format!("{job_type} {label} latency (ms)"),
+                    bound.as_millis() as u64,
+                    8,
+                ));
+            }
+        }
+        Ok(statistics)
+    }
+
+    async fn fetch_by_queue(&self, queue: &str) -> Result<Vec<Statistic>, PubSubError> {
+        // Each backend only knows about its own topic
+        if queue == self.queue_name() {
+            self.global().await
+        } else {
+            Ok(Vec::new())
+        }
+    }
+}
+
+#[cfg(feature = "consume")]
+impl<M, C> ListQueues for PubSubBackend<M, C>
+where
+    M: Send + Sync + 'static,
+    C: Codec<M, Compact = PubSubCompact> + Send + Sync,
+    C::Error: std::error::Error + Send + Sync + 'static,
+{
+    async fn list_queues(&self) -> Result<Vec<QueueInfo>, PubSubError> {
+        Ok(vec![QueueInfo {
+            name: self.queue_name(),
+            stats: self.global().await?,
+            workers: Vec::new(),
+            activity: Vec::new(),
+        }])
+    }
+}
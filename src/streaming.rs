@@ -0,0 +1,191 @@
+//! Stream processing: publishing handler outputs as tasks of another topic
+//!
+//! With the [`OutputLayer`] returned by [`PubSubBackend::output_layer`], a
+//! handler returns any number of outputs, as a `Vec`, an `Option` or any other
+//! [`IntoIterator`]. Each output is encoded with the backend codec and
+//! published to the output topic as a task of its own, so a regular
+//! `PubSubBackend<O, C>` consumes them, turning the worker into a
+//! Pub/Sub-to-Pub/Sub transform.
+//!
+//! Outputs are published before the handler's task completes, and carry the id
+//! of the task they came from in their `source_task_id` metadata, see
+//! [`PubSubContext::meta`](crate::utils::PubSubContext::meta). With an
+//! [`AckPolicy`](crate::outcome::AckPolicy) such as
+//! [`AckOnSuccess`](crate::outcome::AckOnSuccess), the input message is then
+//! only acknowledged once all its outputs were published, and redelivered when
+//! publishing fails.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis::prelude::*;
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_core::error::BoxDynError;
+//! # use apalis_pubsub::{outcome::AckOnSuccess, PubSubBackend, PubSubCompact};
+//! # use std::sync::Arc;
+//! async fn split(line: String) -> Result<Vec<String>, BoxDynError> {
+//!     Ok(line.split_whitespace().map(str::to_string).collect())
+//! }
+//!
+//! # fn example(backend: PubSubBackend<String, JsonCodec<PubSubCompact>>) {
+//! let backend = backend.with_ack_policy(Arc::new(AckOnSuccess));
+//! let worker = WorkerBuilder::new("splitter")
+//!     .backend(backend.clone())
+//!     .layer(backend.output_layer::<String>("words"))
+//!     .build(split);
+//! # }
+//! ```
+use std::{
+    marker::PhantomData,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use apalis_core::{backend::codec::Codec, error::BoxDynError, task::Task};
+use futures::{
+    future::{try_join_all, BoxFuture},
+    FutureExt,
+};
+use tower::{Layer, Service};
+
+use crate::{
+    backoff::BackoffStrategy,
+    clock::Clock,
+    envelope::WireFormat,
+    sink::{publish_with_retry, task_message},
+    transport::PubSubTransport,
+    utils::PubSubContext,
+    PubSubBackend, PubSubCompact, PubSubTask,
+};
+
+/// Metadata key holding the id of the task an output came from
+pub const META_SOURCE_TASK_ID: &str = "source_task_id";
+
+/// Layer publishing the outputs of handlers to an output topic
+///
+/// See the [module level documentation](self) for more details.
+pub struct OutputLayer<O, C> {
+    transport: Arc<dyn PubSubTransport>,
+    topic: String,
+    wire_format: WireFormat,
+    backoff: Arc<dyn BackoffStrategy>,
+    clock: Arc<dyn Clock>,
+    _marker: PhantomData<fn() -> (O, C)>,
+}
+
+impl<O, C> Clone for OutputLayer<O, C> {
+    fn clone(&self) -> Self {
+        Self {
+            transport: self.transport.clone(),
+            topic: self.topic.clone(),
+            wire_format: self.wire_format,
+            backoff: self.backoff.clone(),
+            clock: self.clock.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<S, O, C> Layer<S> for OutputLayer<O, C> {
+    type Service = OutputService<S, O, C>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        OutputService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service created by [`OutputLayer`]
+pub struct OutputService<S, O, C> {
+    inner: S,
+    layer: OutputLayer<O, C>,
+}
+
+impl<S: Clone, O, C> Clone for OutputService<S, O, C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+impl<S, M, O, C> Service<PubSubTask<M>> for OutputService<S, O, C>
+where
+    S: Service<PubSubTask<M>>,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxDynError>,
+    S::Response: IntoIterator<Item = O>,
+    C: Codec<O, Compact = PubSubCompact>,
+    C::Error: Into<BoxDynError>,
+{
+    /// Number of outputs published
+    type Response = usize;
+    type Error = BoxDynError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, task: PubSubTask<M>) -> Self::Future {
+        let source = task.parts.task_id.map(|id| id.to_string());
+        let layer = self.layer.clone();
+        let future = self.inner.call(task);
+
+        async move {
+            let outputs = future.await.map_err(Into::into)?;
+            let messages = outputs
+                .into_iter()
+                .map(|output| {
+                    let mut ctx = PubSubContext::default();
+                    if let Some(source) = &source {
+                        ctx = ctx.with_meta(META_SOURCE_TASK_ID, source);
+                    }
+                    let args = C::encode(&output).map_err(Into::into)?;
+                    let task = Task::builder(args).with_ctx(ctx).build();
+                    Ok(task_message(task, None, layer.wire_format))
+                })
+                .collect::<Result<Vec<_>, BoxDynError>>()?;
+
+            let count = messages.len();
+            try_join_all(messages.into_iter().map(|message| {
+                publish_with_retry(
+                    layer.transport.as_ref(),
+                    &layer.topic,
+                    message,
+                    layer.backoff.as_ref(),
+                    layer.clock.as_ref(),
+                )
+            }))
+            .await?;
+            tracing::debug!(count, topic = layer.topic, "Outputs published");
+            Ok(count)
+        }
+        .boxed()
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Creates an [`OutputLayer`] publishing handler outputs of type `O` to
+    /// the topic `topic_name`
+    ///
+    /// Outputs are encoded with the backend codec and laid out in messages
+    /// as configured by [`PubSubConfig::wire_format`](crate::PubSubConfig::wire_format).
+    pub fn output_layer<O>(&self, topic_name: &str) -> OutputLayer<O, C> {
+        OutputLayer {
+            transport: self.transport.clone(),
+            topic: self
+                .client
+                .topic(topic_name)
+                .fully_qualified_name()
+                .to_string(),
+            wire_format: self.config.wire_format,
+            backoff: self.config.backoff.clone(),
+            clock: self.config.clock.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! The connection between the backend and Pub/Sub
+//!
+//! Every message the backend publishes, receives, acknowledges or extends goes
+//! through a [`PubSubTransport`]. Backends use [`GcpTransport`], built on the
+//! backend's Google Cloud client, unless another one is set with
+//! [`PubSubBackend::with_transport`]: an in-memory transport for tests, one
+//! tuned for the emulator, or one for another broker speaking the same model.
+//!
+//! Topics and subscriptions are always named by their fully qualified name,
+//! `projects/{project}/topics/{topic}` and
+//! `projects/{project}/subscriptions/{subscription}`.
+//!
+//! The subscription watch and health checks ask the transport for the
+//! [`SubscriptionState`] of the subscription. Administrative operations, such
+//! as provisioning, the control topic and worker heartbeats, keep using the
+//! Google Cloud client directly.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_codec::json::JsonCodec;
+//! # use apalis_pubsub::{transport::PubSubTransport, PubSubBackend, PubSubCompact};
+//! # use std::sync::Arc;
+//! # fn example(
+//! #     backend: PubSubBackend<u32, JsonCodec<PubSubCompact>>,
+//! #     in_memory: Arc<dyn PubSubTransport>,
+//! # ) {
+//! let backend = backend.with_transport(in_memory);
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use google_cloud_gax::grpc::Status;
+use google_cloud_googleapis::pubsub::v1::{ModifyAckDeadlineRequest, PubsubMessage};
+#[cfg(feature = "publish")]
+use google_cloud_pubsub::topic::Topic;
+use google_cloud_pubsub::{
+    client::Client, publisher::Publisher, subscriber::ReceivedMessage, subscription::ReceiveConfig,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{inflight::InFlight, provision::is_not_found, PubSubBackend};
+
+/// Called with every message a transport receives
+///
+/// The handler is awaited before the next message of the same stream is
+/// handed to it.
+pub type MessageHandler = Arc<dyn Fn(TransportMessage) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Whether a subscription can still deliver messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionState {
+    /// The subscription exists and is attached to its topic
+    Attached,
+    /// The subscription was detached from its topic
+    Detached,
+    /// The subscription doesn't exist
+    Missing,
+}
+
+/// Publishes, receives and acknowledges messages on behalf of a backend
+pub trait PubSubTransport: Send + Sync + 'static {
+    /// Publishes `message` to `topic`, returning its message id
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        message: PubsubMessage,
+    ) -> BoxFuture<'a, Result<String, Status>>;
+
+    /// Hands the messages of `subscription` to `handler` until `cancel` is
+    /// cancelled or the subscription fails for good
+    ///
+    /// `config` carries the flow control limits of the streaming pull.
+    fn receive(
+        self: Arc<Self>,
+        subscription: String,
+        config: ReceiveConfig,
+        handler: MessageHandler,
+        cancel: CancellationToken,
+    ) -> BoxFuture<'static, Result<(), Status>>;
+
+    /// Acknowledges the messages `ack_ids` of `subscription`
+    fn acknowledge<'a>(
+        &'a self,
+        subscription: &'a str,
+        ack_ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<(), Status>>;
+
+    /// Sets the ack deadline of the messages `ack_ids` of `subscription`, 0
+    /// nacking them
+    fn modify_ack_deadline<'a>(
+        &'a self,
+        subscription: &'a str,
+        ack_ids: Vec<String>,
+        seconds: i32,
+    ) -> BoxFuture<'a, Result<(), Status>>;
+
+    /// The state of `subscription`
+    ///
+    /// Transports without subscriptions of their own keep the default, which
+    /// reports every subscription as attached, so the subscription watch and
+    /// health checks never stop their workers.
+    fn subscription_state<'a>(
+        &'a self,
+        subscription: &'a str,
+    ) -> BoxFuture<'a, Result<SubscriptionState, Status>> {
+        let _ = subscription;
+        futures::future::ready(Ok(SubscriptionState::Attached)).boxed()
+    }
+}
+
+/// A message received through a [`PubSubTransport`]
+pub struct TransportMessage {
+    /// The message as published
+    pub message: PubsubMessage,
+    ack_id: String,
+    delivery_attempt: Option<usize>,
+    subscriber: Subscriber,
+}
+
+impl TransportMessage {
+    /// A message of `subscription` received with `ack_id` through `transport`
+    pub fn new(
+        transport: Arc<dyn PubSubTransport>,
+        subscription: impl Into<String>,
+        ack_id: impl Into<String>,
+        message: PubsubMessage,
+        delivery_attempt: Option<usize>,
+    ) -> Self {
+        Self {
+            message,
+            ack_id: ack_id.into(),
+            delivery_attempt,
+            subscriber: Subscriber::new(transport, subscription),
+        }
+    }
+
+    /// The id acknowledging the message
+    pub fn ack_id(&self) -> &str {
+        &self.ack_id
+    }
+
+    /// How many times the message was delivered, when the subscription has a
+    /// dead-letter policy
+    pub fn delivery_attempt(&self) -> Option<usize> {
+        self.delivery_attempt
+    }
+
+    /// Acknowledges the message
+    pub async fn ack(&self) -> Result<(), Status> {
+        self.subscriber.ack(vec![self.ack_id.clone()]).await
+    }
+
+    /// Nacks the message so it's redelivered right away
+    pub async fn nack(&self) -> Result<(), Status> {
+        self.modify_ack_deadline(0).await
+    }
+
+    /// Sets the ack deadline of the message
+    pub async fn modify_ack_deadline(&self, seconds: i32) -> Result<(), Status> {
+        self.subscriber
+            .modify_ack_deadline(vec![self.ack_id.clone()], seconds)
+            .await
+    }
+}
+
+/// A subscription reached through a transport
+#[derive(Clone)]
+pub(crate) struct Subscriber {
+    transport: Arc<dyn PubSubTransport>,
+    subscription: String,
+}
+
+impl Subscriber {
+    pub(crate) fn new(
+        transport: Arc<dyn PubSubTransport>,
+        subscription: impl Into<String>,
+    ) -> Self {
+        Self {
+            transport,
+            subscription: subscription.into(),
+        }
+    }
+
+    pub(crate) async fn ack(&self, ack_ids: Vec<String>) -> Result<(), Status> {
+        self.transport
+            .acknowledge(&self.subscription, ack_ids)
+            .await
+    }
+
+    pub(crate) async fn modify_ack_deadline(
+        &self,
+        ack_ids: Vec<String>,
+        seconds: i32,
+    ) -> Result<(), Status> {
+        self.transport
+            .modify_ack_deadline(&self.subscription, ack_ids, seconds)
+            .await
+    }
+}
+
+/// Transport over the Google Cloud Pub/Sub client, the default
+pub struct GcpTransport {
+    client: Client,
+    /// Publishers by topic, kept to batch publishes across flushes
+    publishers: Mutex<HashMap<String, Publisher>>,
+}
+
+impl GcpTransport {
+    /// Transport over `client`
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            publishers: Mutex::default(),
+        }
+    }
+
+    fn publisher(&self, topic: &str) -> Publisher {
+        self.publishers
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_insert_with(|| self.client.topic(topic).new_publisher(None))
+            .clone()
+    }
+}
+
+impl PubSubTransport for GcpTransport {
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        message: PubsubMessage,
+    ) -> BoxFuture<'a, Result<String, Status>> {
+        let publisher = self.publisher(topic);
+        async move { publisher.publish(message).await.get().await }.boxed()
+    }
+
+    fn receive(
+        self: Arc<Self>,
+        subscription: String,
+        config: ReceiveConfig,
+        handler: MessageHandler,
+        cancel: CancellationToken,
+    ) -> BoxFuture<'static, Result<(), Status>> {
+        let transport: Arc<dyn PubSubTransport> = self.clone();
+        let name = subscription.clone();
+        let on_message = move |mut received: ReceivedMessage, _cancel| {
+            let ack_id = received.ack_id().to_string();
+            let message = TransportMessage::new(
+                transport.clone(),
+                name.clone(),
+                ack_id,
+                std::mem::take(&mut received.message),
+                received.delivery_attempt(),
+            );
+            handler(message)
+        };
+        async move {
+            self.client
+                .subscription(&subscription)
+                .receive(on_message, cancel, Some(config))
+                .await
+        }
+        .boxed()
+    }
+
+    fn acknowledge<'a>(
+        &'a self,
+        subscription: &'a str,
+        ack_ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        async move { self.client.subscription(subscription).ack(ack_ids).await }.boxed()
+    }
+
+    fn modify_ack_deadline<'a>(
+        &'a self,
+        subscription: &'a str,
+        ack_ids: Vec<String>,
+        seconds: i32,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        let request = ModifyAckDeadlineRequest {
+            subscription: subscription.to_string(),
+            ack_ids,
+            ack_deadline_seconds: seconds,
+        };
+        async move {
+            self.client
+                .subscription(subscription)
+                .get_client()
+                .modify_ack_deadline(request, None)
+                .await
+                .map(|_| ())
+        }
+        .boxed()
+    }
+
+    fn subscription_state<'a>(
+        &'a self,
+        subscription: &'a str,
+    ) -> BoxFuture<'a, Result<SubscriptionState, Status>> {
+        async move {
+            match self.client.subscription(subscription).config(None).await {
+                Ok((_, config)) if config.detached => Ok(SubscriptionState::Detached),
+                Ok(_) => Ok(SubscriptionState::Attached),
+                Err(status) if is_not_found(&status) => Ok(SubscriptionState::Missing),
+                Err(status) => Err(status),
+            }
+        }
+        .boxed()
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Publishes, receives and acknowledges messages through `transport`
+    /// instead of the Google Cloud client
+    ///
+    /// Call before handing the backend to a worker: the in-flight registry is
+    /// reset to use the new transport.
+    pub fn with_transport(mut self, transport: Arc<dyn PubSubTransport>) -> Self {
+        self.transport = transport;
+        self.in_flight = Arc::new(InFlight::new(self.subscriber()));
+        self
+    }
+
+    /// The transport messages go through
+    pub fn transport(&self) -> Arc<dyn PubSubTransport> {
+        self.transport.clone()
+    }
+
+    /// The backend's subscription, reached through its transport
+    pub(crate) fn subscriber(&self) -> Subscriber {
+        Subscriber::new(
+            self.transport.clone(),
+            self.subscription.fully_qualified_name(),
+        )
+    }
+
+    /// Fully qualified name of the backend's topic, none for consume-only
+    /// backends
+    #[cfg(feature = "publish")]
+    pub(crate) fn topic_name(&self) -> Option<&str> {
+        self.topic.as_ref().map(Topic::fully_qualified_name)
+    }
+}
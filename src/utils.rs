@@ -1,3 +1,12 @@
+#[cfg(any(feature = "consume", feature = "push"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{collections::HashMap, convert::Infallible};
+
+use apalis_core::{task::extensions::Extensions, task_fn::FromRequest};
+use tokio_util::sync::CancellationToken;
+
+use crate::{checkpoint::Checkpoint, PubSubError, PubSubTask};
+
 /// Context for a Pub/Sub message containing acknowledgment data.
 ///
 /// # Example
@@ -21,11 +30,276 @@
 pub struct PubSubContext {
     /// The acknowledgment ID for the message
     pub ack_id: String,
+    /// Cancelled when the task is cancelled, see [`crate::cancel`]
+    cancellation: CancellationToken,
+    /// Data attached by context hooks, see [`crate::extensions`]
+    extensions: Extensions,
+    /// Priority of the task, carried in message attributes, see [`crate::parts`]
+    priority: i32,
+    /// Custom metadata of the task, carried in message attributes
+    meta: HashMap<String, String>,
+    /// Message attributes of the task apalis doesn't read, see [`crate::parts`]
+    attributes: HashMap<String, String>,
+    /// Deadline of the task as a UNIX time, carried in message attributes
+    run_before: Option<u64>,
+    /// When the task is scheduled for as a UNIX time, carried in message
+    /// attributes, see [`crate::schedule`]
+    not_before: Option<u64>,
+    /// Job type of the task, carried in message attributes, see [`crate::jobs`]
+    job_type: Option<String>,
+    /// Hash of the message payload, see [`crate::report`]
+    payload_hash: Option<u64>,
+    /// Id of the message the task was received in
+    message_id: Option<String>,
+    /// Times Pub/Sub delivered the message, when counted
+    delivery_attempt: Option<usize>,
+    /// Ordering key of the message the task was received in, when it had one
+    ordering_key: Option<String>,
+    /// Attributes of the message the task was received in
+    message_attributes: HashMap<String, String>,
+    /// Progress of the task, see [`crate::checkpoint`]
+    checkpoint: Checkpoint,
+    /// Version of the payload's schema, carried in message attributes
+    schema_version: Option<String>,
+    /// W3C trace context of the task, carried in message attributes
+    trace_context: Option<String>,
 }
 
 impl PubSubContext {
     /// Creates a new `PubSubContext` instance with the given parameters.
     pub fn new(ack_id: String) -> Self {
-        Self { ack_id }
+        Self {
+            ack_id,
+            cancellation: CancellationToken::new(),
+            extensions: Extensions::new(),
+            priority: 0,
+            meta: HashMap::new(),
+            attributes: HashMap::new(),
+            run_before: None,
+            not_before: None,
+            job_type: None,
+            payload_hash: None,
+            message_id: None,
+            delivery_attempt: None,
+            ordering_key: None,
+            message_attributes: HashMap::new(),
+            checkpoint: Checkpoint::default(),
+            schema_version: None,
+            trace_context: None,
+        }
+    }
+
+    /// Priority of the task (default: 0)
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Sets the priority of the task
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Custom metadata of the task under `key`
+    pub fn meta(&self, key: &str) -> Option<&str> {
+        self.meta.get(key).map(String::as_str)
+    }
+
+    /// Sets custom metadata of the task under `key`
+    pub fn with_meta(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.meta.insert(key.into(), value.into());
+        self
+    }
+
+    /// Message attribute `key` of the task, when set
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes.get(key).map(String::as_str)
+    }
+
+    /// Message attributes of the task apalis doesn't read, such as those set
+    /// for subscription filters
+    pub fn attributes(&self) -> &HashMap<String, String> {
+        &self.attributes
+    }
+
+    /// Publishes the task with the message attribute `key` set to `value`
+    ///
+    /// Unlike [`meta`](Self::meta), attributes aren't prefixed, so
+    /// subscription filters and non-apalis consumers can select tasks by
+    /// them. Attributes apalis reserves, see
+    /// [`MessageAttributes::is_reserved`](crate::parts::MessageAttributes::is_reserved),
+    /// are ignored.
+    pub fn with_attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(key.into(), value.into());
+        self
+    }
+
+    /// When the task stops being worth running, as a UNIX time in seconds
+    pub fn run_before(&self) -> Option<u64> {
+        self.run_before
+    }
+
+    /// Drops the task instead of running it once `timestamp`, a UNIX time in
+    /// seconds, has passed
+    pub fn with_run_before(mut self, timestamp: u64) -> Self {
+        self.run_before = Some(timestamp);
+        self
+    }
+
+    /// When the task is scheduled for, as a UNIX time in seconds
+    pub fn not_before(&self) -> Option<u64> {
+        self.not_before
+    }
+
+    /// Keeps workers from running the task before `timestamp`, a UNIX time in
+    /// seconds, see [`crate::schedule`]
+    pub fn with_not_before(mut self, timestamp: u64) -> Self {
+        self.not_before = Some(timestamp);
+        self
+    }
+
+    /// Job type of the task, when set
+    pub fn job_type(&self) -> Option<&str> {
+        self.job_type.as_deref()
+    }
+
+    /// Sets the job type of the task, whose settings may be overridden
+    pub fn with_job_type(mut self, job_type: impl Into<String>) -> Self {
+        self.job_type = Some(job_type.into());
+        self
+    }
+
+    /// Version of the schema the task's arguments follow, when set
+    pub fn schema_version(&self) -> Option<&str> {
+        self.schema_version.as_deref()
+    }
+
+    /// Records the version of the schema the task's arguments follow, so
+    /// handlers can tell payload revisions apart
+    pub fn with_schema_version(mut self, schema_version: impl Into<String>) -> Self {
+        self.schema_version = Some(schema_version.into());
+        self
+    }
+
+    /// W3C `traceparent` of the trace the task belongs to, when set
+    pub fn trace_context(&self) -> Option<&str> {
+        self.trace_context.as_deref()
+    }
+
+    /// Links the task to the trace with the W3C `traceparent`
+    /// `trace_context`, carried to the worker that runs it
+    pub fn with_trace_context(mut self, trace_context: impl Into<String>) -> Self {
+        self.trace_context = Some(trace_context.into());
+        self
+    }
+
+    /// Whether the deadline of the task has passed at `now`
+    #[cfg(any(feature = "consume", feature = "push"))]
+    pub(crate) fn is_expired(&self, now: SystemTime) -> bool {
+        self.run_before.is_some_and(|deadline| {
+            let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            now >= deadline
+        })
+    }
+
+    /// Id of the message the task was received in, once received
+    pub fn message_id(&self) -> Option<&str> {
+        self.message_id.as_deref()
+    }
+
+    /// Times Pub/Sub delivered the message, counted only when the
+    /// subscription has a dead-letter policy
+    pub fn delivery_attempt(&self) -> Option<usize> {
+        self.delivery_attempt
+    }
+
+    /// Ordering key of the message the task was received in, see
+    /// [`PubSubBackend::with_ordering_key`](crate::PubSubBackend::with_ordering_key)
+    pub fn ordering_key(&self) -> Option<&str> {
+        self.ordering_key.as_deref()
+    }
+
+    /// Every attribute of the message the task was received in, including
+    /// those apalis reads, empty until received
+    ///
+    /// Unlike [`attributes`](Self::attributes), these aren't published again
+    /// with the task, as retries write their own.
+    pub fn message_attributes(&self) -> &HashMap<String, String> {
+        &self.message_attributes
+    }
+
+    pub(crate) fn set_delivery(&mut self, message_id: String, delivery_attempt: Option<usize>) {
+        self.message_id = Some(message_id);
+        self.delivery_attempt = delivery_attempt;
+    }
+
+    pub(crate) fn set_ordering_key(&mut self, ordering_key: &str) {
+        self.ordering_key = (!ordering_key.is_empty()).then(|| ordering_key.to_string());
+    }
+
+    pub(crate) fn set_message_attributes(&mut self, attributes: HashMap<String, String>) {
+        self.message_attributes = attributes;
+    }
+
+    pub(crate) fn meta_entries(&self) -> &HashMap<String, String> {
+        &self.meta
+    }
+
+    pub(crate) fn payload_hash(&self) -> Option<u64> {
+        self.payload_hash
+    }
+
+    pub(crate) fn set_payload_hash(&mut self, payload_hash: u64) {
+        self.payload_hash = Some(payload_hash);
+    }
+
+    /// The extension of type `T`, if one was attached
+    pub fn extension<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions.get()
+    }
+
+    /// Attaches `value`, returning the extension of the same type it replaces
+    pub fn insert_extension<T: Clone + Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.extensions.insert(value)
+    }
+
+    /// Whether the task was cancelled while running
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Completes once the task is cancelled
+    pub async fn cancelled(&self) {
+        self.cancellation.cancelled().await
+    }
+
+    pub(crate) fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Progress recorded by a previous attempt of the task, see [`crate::checkpoint`]
+    pub fn last_checkpoint(&self) -> Option<String> {
+        self.checkpoint.latest()
+    }
+
+    /// Records the progress of the task, so another attempt can resume from it
+    pub async fn checkpoint(&self, checkpoint: impl Into<String>) -> Result<(), PubSubError> {
+        self.checkpoint.save(checkpoint.into()).await
+    }
+
+    pub(crate) fn checkpoint_state(&self) -> &Checkpoint {
+        &self.checkpoint
+    }
+
+    pub(crate) fn set_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.checkpoint = checkpoint;
+    }
+}
+
+impl<M: Sync> FromRequest<PubSubTask<M>> for PubSubContext {
+    type Error = Infallible;
+
+    async fn from_request(task: &PubSubTask<M>) -> Result<Self, Self::Error> {
+        Ok(task.parts.ctx.clone())
     }
 }
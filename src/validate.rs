@@ -0,0 +1,87 @@
+//! Validation of received payloads before dispatch
+//!
+//! A [`PayloadValidator`] checks every received payload before it's decoded
+//! and handed to the worker. Payloads it rejects are treated like undecodable
+//! poison messages: logged and acknowledged so they aren't redelivered. This
+//! catches producers drifting from the agreed contract at the edge, instead
+//! of somewhere inside a handler.
+//!
+//! Validators see the raw bytes the codec is about to decode, not the decoded
+//! task: the message data once an [envelope](crate::envelope) is unpacked,
+//! without its attributes. Topics carrying several
+//! [codecs](crate::codecs) hand validators payloads of every encoding, so a
+//! validator expecting one, like `JsonSchemaValidator`, rejects the others.
+//!
+//! With the `json-schema` feature, [`JsonSchemaValidator`] checks JSON payloads
+//! against a JSON Schema.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # #[cfg(feature = "json-schema")]
+//! # fn example(
+//! #     backend: apalis_pubsub::PubSubBackend<u32, apalis_codec::json::JsonCodec<apalis_pubsub::PubSubCompact>>,
+//! # ) -> Result<(), apalis_pubsub::PubSubError> {
+//! use apalis_pubsub::validate::JsonSchemaValidator;
+//! use std::sync::Arc;
+//!
+//! let schema = serde_json::json!({ "type": "integer", "minimum": 0 });
+//! let backend = backend.with_validator(Arc::new(JsonSchemaValidator::new(&schema)?));
+//! # Ok(())
+//! # }
+//! ```
+use std::sync::Arc;
+
+use crate::PubSubBackend;
+#[cfg(feature = "json-schema")]
+use crate::PubSubError;
+
+/// Checks received payloads before they're decoded
+pub trait PayloadValidator: Send + Sync {
+    /// Returns why `payload` is invalid, if it is
+    ///
+    /// `payload` is the encoded task arguments, as the codec receives them.
+    fn validate(&self, payload: &[u8]) -> Result<(), String>;
+}
+
+/// A [`PayloadValidator`] checking JSON payloads against a JSON Schema
+#[cfg(feature = "json-schema")]
+pub struct JsonSchemaValidator {
+    validator: jsonschema::Validator,
+}
+
+#[cfg(feature = "json-schema")]
+impl JsonSchemaValidator {
+    /// Compiles `schema`, failing if it isn't a valid JSON Schema
+    pub fn new(schema: &serde_json::Value) -> Result<Self, PubSubError> {
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| PubSubError::Codec(format!("Invalid JSON Schema: {e}")))?;
+        Ok(Self { validator })
+    }
+}
+
+#[cfg(feature = "json-schema")]
+impl PayloadValidator for JsonSchemaValidator {
+    fn validate(&self, payload: &[u8]) -> Result<(), String> {
+        let instance: serde_json::Value =
+            serde_json::from_slice(payload).map_err(|e| format!("Payload isn't JSON: {e}"))?;
+        let errors: Vec<_> = self
+            .validator
+            .iter_errors(&instance)
+            .map(|error| format!("{}: {error}", error.instance_path))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+impl<M, C> PubSubBackend<M, C> {
+    /// Rejects received payloads that `validator` finds invalid
+    pub fn with_validator(mut self, validator: Arc<dyn PayloadValidator>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+}
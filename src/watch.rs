@@ -0,0 +1,72 @@
+//! Detection of the worker subscription going away, or the worker stopping
+//!
+//! When the subscription is deleted or detached from its topic while a worker
+//! runs, the streaming pull stops without the receive loop ever returning. The
+//! backend checks the subscription every
+//! [`PubSubConfig::subscription_check_interval`](crate::PubSubConfig::subscription_check_interval),
+//! through its [transport](crate::transport), and, once it's gone, stops
+//! receiving and reports [`PubSubError::SubscriptionGone`] to the worker.
+//!
+//! The backend also stops receiving once the apalis worker polling it is
+//! stopped or shut down, so a stopped worker no longer pulls messages it won't
+//! process.
+use std::{sync::Arc, time::Duration};
+
+use apalis_core::{timer::sleep, worker::context::WorkerContext};
+use google_cloud_gax::grpc::Status;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    transport::{PubSubTransport, SubscriptionState},
+    PubSubError,
+};
+
+/// Checks `subscription` through `transport`, returning why it's gone if it is
+pub(crate) async fn check_subscription(
+    transport: &dyn PubSubTransport,
+    subscription: &str,
+) -> Result<Option<PubSubError>, Status> {
+    let gone = match transport.subscription_state(subscription).await? {
+        SubscriptionState::Attached => return Ok(None),
+        SubscriptionState::Detached => "was detached from its topic",
+        SubscriptionState::Missing => "was deleted",
+    };
+    Ok(Some(PubSubError::SubscriptionGone(format!(
+        "{subscription} {gone}"
+    ))))
+}
+
+/// Checks `subscription` every `interval` until it's gone or `cancel` is
+/// cancelled, returning why it's gone
+pub(crate) async fn watch_subscription(
+    transport: Arc<dyn PubSubTransport>,
+    subscription: String,
+    interval: Duration,
+    cancel: CancellationToken,
+) -> Option<PubSubError> {
+    loop {
+        cancel.run_until_cancelled(sleep(interval)).await?;
+        match check_subscription(transport.as_ref(), &subscription).await {
+            Ok(Some(gone)) => return Some(gone),
+            Ok(None) => {}
+            // Transient failures are retried at the next check
+            Err(status) => tracing::debug!(error = ?status, "Failed to check subscription"),
+        }
+    }
+}
+
+/// How often the state of the worker is checked
+const WORKER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Cancels `receiving` once `worker` is stopped or shutting down
+pub(crate) async fn watch_worker(worker: WorkerContext, receiving: CancellationToken) {
+    let stopped = async {
+        while !worker.is_shutting_down() {
+            sleep(WORKER_CHECK_INTERVAL).await;
+        }
+    };
+    if receiving.run_until_cancelled(stopped).await.is_some() {
+        tracing::info!(worker = worker.name(), "Worker stopped, stopping receiving");
+        receiving.cancel();
+    }
+}
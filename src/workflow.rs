@@ -0,0 +1,412 @@
+//! Workflow orchestration over [`PubSubBackend`]
+//!
+//! A workflow is a directed acyclic graph of named nodes, each carrying a task.
+//! When a run starts, every node without dependencies is published as a
+//! [`WorkflowMessage`]. Workers report completed nodes back to the
+//! [`WorkflowRunner`], which publishes downstream nodes once all of their
+//! upstream nodes have completed. This gives fan-out (several nodes depending
+//! on one) and fan-in (one node depending on several) pipelines.
+//!
+//! Completions are recorded in a pluggable [`WorkflowStore`], atomically, so
+//! runners racing to report the upstream nodes of a fan-in can't lose one of
+//! them. A run is saved before any of its nodes is published, and each node
+//! message has a task id derived from the run and the node, so a node published
+//! again, because its report was retried or two runners saw it become ready,
+//! carries the same task id.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use apalis_pubsub::{PubSubBackend, PubSubCompact, workflow::*};
+//! # use apalis_codec::json::JsonCodec;
+//! # use serde::{Deserialize, Serialize};
+//! # use std::sync::Arc;
+//! #[derive(Debug, Clone, Serialize, Deserialize)]
+//! enum Step {
+//!     Extract(String),
+//!     Transform,
+//!     Load,
+//! }
+//!
+//! # async fn example(
+//! #     backend: PubSubBackend<WorkflowMessage<Step>, JsonCodec<PubSubCompact>>,
+//! # ) -> Result<(), apalis_pubsub::PubSubError> {
+//! let workflow = Workflow::new()
+//!     .node("extract-a", Step::Extract("a".into()), &[])
+//!     .node("extract-b", Step::Extract("b".into()), &[])
+//!     .node("transform", Step::Transform, &["extract-a", "extract-b"])
+//!     .node("load", Step::Load, &["transform"]);
+//!
+//! let mut runner = WorkflowRunner::new(backend, Arc::new(InMemoryWorkflowStore::default()));
+//! runner.start(workflow).await?;
+//!
+//! // In the worker handling `WorkflowMessage<Step>`:
+//! // runner.node_completed(&message).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use apalis_core::{
+    backend::TaskSink,
+    task::{builder::TaskBuilder, task_id::TaskId},
+};
+use futures::{future::BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{utils::PubSubContext, PubSubBackend, PubSubError, PubSubTaskId};
+
+/// Unique identifier of a workflow run
+pub type WorkflowRunId = Uuid;
+
+/// A node of a [`Workflow`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowNode<M> {
+    /// Unique name of the node within the workflow
+    pub name: String,
+    /// Task published when the node becomes ready
+    pub task: M,
+    /// Names of the nodes that must complete before this one is published
+    pub depends_on: Vec<String>,
+}
+
+/// A directed acyclic graph of tasks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Workflow<M> {
+    nodes: Vec<WorkflowNode<M>>,
+}
+
+impl<M> Default for Workflow<M> {
+    fn default() -> Self {
+        Self { nodes: Vec::new() }
+    }
+}
+
+impl<M> Workflow<M> {
+    /// Creates an empty workflow
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a node that is published once every node in `depends_on` completed
+    pub fn node(mut self, name: impl Into<String>, task: M, depends_on: &[&str]) -> Self {
+        self.nodes.push(WorkflowNode {
+            name: name.into(),
+            task,
+            depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+        });
+        self
+    }
+
+    /// The workflow's nodes, in insertion order
+    pub fn nodes(&self) -> &[WorkflowNode<M>] {
+        &self.nodes
+    }
+
+    /// Whether the workflow has a node named `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.nodes.iter().any(|node| node.name == name)
+    }
+
+    /// Checks that node names are unique, dependencies exist and there are no cycles
+    pub fn validate(&self) -> Result<(), PubSubError> {
+        let mut names = HashSet::new();
+        for node in &self.nodes {
+            if !names.insert(node.name.as_str()) {
+                return Err(PubSubError::InvalidWorkflow(format!(
+                    "Duplicate node `{}`",
+                    node.name
+                )));
+            }
+        }
+
+        for node in &self.nodes {
+            if let Some(missing) = node.depends_on.iter().find(|d| !names.contains(d.as_str())) {
+                return Err(PubSubError::InvalidWorkflow(format!(
+                    "Node `{}` depends on unknown node `{missing}`",
+                    node.name
+                )));
+            }
+        }
+
+        // Kahn's algorithm: if we can't complete every node, there's a cycle
+        let mut completed = HashSet::new();
+        while completed.len() < self.nodes.len() {
+            let ready = self.ready(&completed, &completed);
+            if ready.is_empty() {
+                return Err(PubSubError::InvalidWorkflow(
+                    "Workflow contains a dependency cycle".to_string(),
+                ));
+            }
+            completed.extend(ready.into_iter().map(|node| node.name.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Nodes that are not yet published and whose dependencies all completed
+    fn ready(
+        &self,
+        completed: &HashSet<String>,
+        published: &HashSet<String>,
+    ) -> Vec<&WorkflowNode<M>> {
+        self.nodes
+            .iter()
+            .filter(|node| !published.contains(&node.name))
+            .filter(|node| node.depends_on.iter().all(|d| completed.contains(d)))
+            .collect()
+    }
+}
+
+/// The task published for every workflow node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowMessage<M> {
+    /// The run this message belongs to
+    pub run_id: WorkflowRunId,
+    /// Name of the node within the workflow
+    pub node: String,
+    /// The user task
+    pub payload: M,
+}
+
+/// Lifecycle of a workflow run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WorkflowStatus {
+    /// Some nodes have not completed yet
+    Running,
+    /// Every node completed
+    Completed,
+}
+
+/// Persisted progress of a workflow run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowRun<M> {
+    /// The run's identifier
+    pub id: WorkflowRunId,
+    /// The workflow being run
+    pub workflow: Workflow<M>,
+    /// Nodes that have been published
+    pub published: HashSet<String>,
+    /// Nodes that have completed
+    pub completed: HashSet<String>,
+}
+
+impl<M> WorkflowRun<M> {
+    /// Current status of the run
+    pub fn status(&self) -> WorkflowStatus {
+        if self
+            .workflow
+            .nodes
+            .iter()
+            .all(|node| self.completed.contains(&node.name))
+        {
+            WorkflowStatus::Completed
+        } else {
+            WorkflowStatus::Running
+        }
+    }
+
+    /// Task id of the message publishing `node`
+    pub fn task_id(&self, node: &str) -> PubSubTaskId {
+        Uuid::new_v5(&self.id, node.as_bytes())
+    }
+}
+
+/// Storage for workflow runs
+///
+/// Implement this to record completions in a database shared by several runners.
+pub trait WorkflowStore<M>: Send + Sync {
+    /// Loads a run, if it exists
+    fn load(&self, id: WorkflowRunId)
+        -> BoxFuture<'_, Result<Option<WorkflowRun<M>>, PubSubError>>;
+
+    /// Inserts or replaces a run
+    fn save(&self, run: WorkflowRun<M>) -> BoxFuture<'_, Result<(), PubSubError>>;
+
+    /// Adds `node` to the completed nodes of a run and returns the run as
+    /// updated, if it exists
+    ///
+    /// This must be atomic: of two completions recorded concurrently, the
+    /// second must see the first.
+    fn record_completion<'a>(
+        &'a self,
+        id: WorkflowRunId,
+        node: &'a str,
+    ) -> BoxFuture<'a, Result<Option<WorkflowRun<M>>, PubSubError>>;
+
+    /// Adds `nodes` to the published nodes of a run
+    fn record_published<'a>(
+        &'a self,
+        id: WorkflowRunId,
+        nodes: &'a [String],
+    ) -> BoxFuture<'a, Result<(), PubSubError>>;
+}
+
+/// A [`WorkflowStore`] that keeps runs in process memory
+#[derive(Debug)]
+pub struct InMemoryWorkflowStore<M> {
+    runs: Mutex<HashMap<WorkflowRunId, WorkflowRun<M>>>,
+}
+
+impl<M> Default for InMemoryWorkflowStore<M> {
+    fn default() -> Self {
+        Self {
+            runs: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<M: Clone + Send> WorkflowStore<M> for InMemoryWorkflowStore<M> {
+    fn load(
+        &self,
+        id: WorkflowRunId,
+    ) -> BoxFuture<'_, Result<Option<WorkflowRun<M>>, PubSubError>> {
+        let run = self.runs.lock().unwrap().get(&id).cloned();
+        futures::future::ready(Ok(run)).boxed()
+    }
+
+    fn save(&self, run: WorkflowRun<M>) -> BoxFuture<'_, Result<(), PubSubError>> {
+        self.runs.lock().unwrap().insert(run.id, run);
+        futures::future::ready(Ok(())).boxed()
+    }
+
+    fn record_completion<'a>(
+        &'a self,
+        id: WorkflowRunId,
+        node: &'a str,
+    ) -> BoxFuture<'a, Result<Option<WorkflowRun<M>>, PubSubError>> {
+        let run = self.runs.lock().unwrap().get_mut(&id).map(|run| {
+            run.completed.insert(node.to_string());
+            run.clone()
+        });
+        futures::future::ready(Ok(run)).boxed()
+    }
+
+    fn record_published<'a>(
+        &'a self,
+        id: WorkflowRunId,
+        nodes: &'a [String],
+    ) -> BoxFuture<'a, Result<(), PubSubError>> {
+        if let Some(run) = self.runs.lock().unwrap().get_mut(&id) {
+            run.published.extend(nodes.iter().cloned());
+        }
+        futures::future::ready(Ok(())).boxed()
+    }
+}
+
+/// Publishes workflow nodes as their dependencies complete
+pub struct WorkflowRunner<M, C> {
+    backend: PubSubBackend<WorkflowMessage<M>, C>,
+    store: Arc<dyn WorkflowStore<M>>,
+}
+
+impl<M, C> Clone for WorkflowRunner<M, C>
+where
+    PubSubBackend<WorkflowMessage<M>, C>: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            backend: self.backend.clone(),
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl<M, C> WorkflowRunner<M, C>
+where
+    M: Clone,
+    PubSubBackend<WorkflowMessage<M>, C>: TaskSink<
+        WorkflowMessage<M>,
+        Error = PubSubError,
+        Context = PubSubContext,
+        IdType = PubSubTaskId,
+    >,
+{
+    /// Creates a runner publishing through `backend` and recording progress in `store`
+    pub fn new(
+        backend: PubSubBackend<WorkflowMessage<M>, C>,
+        store: Arc<dyn WorkflowStore<M>>,
+    ) -> Self {
+        Self { backend, store }
+    }
+
+    /// Validates `workflow` and starts a run by publishing its root nodes
+    ///
+    /// The run is saved before its root nodes are published.
+    pub async fn start(&mut self, workflow: Workflow<M>) -> Result<WorkflowRunId, PubSubError> {
+        workflow.validate()?;
+
+        let run = WorkflowRun {
+            id: Uuid::new_v4(),
+            workflow,
+            published: HashSet::new(),
+            completed: HashSet::new(),
+        };
+        let id = run.id;
+        self.store.save(run.clone()).await?;
+        self.publish_ready(&run).await?;
+        Ok(id)
+    }
+
+    /// Records that the node carried by `message` completed and publishes
+    /// every node that became ready
+    ///
+    /// Recording the same completion again publishes the nodes it made ready
+    /// that aren't recorded as published yet, under the same task ids. Nodes
+    /// the run's workflow doesn't have are rejected.
+    pub async fn node_completed(
+        &mut self,
+        message: &WorkflowMessage<M>,
+    ) -> Result<WorkflowStatus, PubSubError> {
+        let unknown_run = || PubSubError::Store(format!("Unknown workflow run {}", message.run_id));
+        let run = self
+            .store
+            .load(message.run_id)
+            .await?
+            .ok_or_else(unknown_run)?;
+        if !run.workflow.contains(&message.node) {
+            return Err(PubSubError::InvalidWorkflow(format!(
+                "Workflow run {} has no node `{}`",
+                message.run_id, message.node
+            )));
+        }
+
+        let run = self
+            .store
+            .record_completion(message.run_id, &message.node)
+            .await?
+            .ok_or_else(unknown_run)?;
+        self.publish_ready(&run).await?;
+        Ok(run.status())
+    }
+
+    /// Fetches the current state of a run
+    pub async fn run(&self, id: WorkflowRunId) -> Result<Option<WorkflowRun<M>>, PubSubError> {
+        self.store.load(id).await
+    }
+
+    /// Publishes the nodes of `run` that are ready, then records them as published
+    async fn publish_ready(&mut self, run: &WorkflowRun<M>) -> Result<(), PubSubError> {
+        let mut published = Vec::new();
+        for node in run.workflow.ready(&run.completed, &run.published) {
+            let message = WorkflowMessage {
+                run_id: run.id,
+                node: node.name.clone(),
+                payload: node.task.clone(),
+            };
+            let task = TaskBuilder::new(message)
+                .with_task_id(TaskId::new(run.task_id(&node.name)))
+                .build();
+            self.backend.push_task(task).await?;
+            published.push(node.name.clone());
+        }
+        if !published.is_empty() {
+            self.store.record_published(run.id, &published).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,49 @@
-use apalis_pubsub::{utils::PubSubContext, PubSubConfig};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use apalis_codec::json::JsonCodec;
+use apalis_core::{
+    backend::{codec::Codec, Backend, ListWorkers},
+    error::BoxDynError,
+    task::builder::TaskBuilder,
+    worker::{context::WorkerContext, ext::ack::AcknowledgeLayer},
+};
+use apalis_pubsub::{
+    backoff::{BackoffStrategy, DecorrelatedJitter, Exponential},
+    breaker::{BreakerAction, DecodeBreaker},
+    budget::{BudgetPermit, BufferBudget},
+    clock::{Clock, ManualClock},
+    config::ConfigError,
+    contract,
+    control::{ConcurrencyControl, ConcurrencyControlLayer, ConcurrencyControlService},
+    dlq::{DEAD_LETTER_KIND_ATTRIBUTE, DEAD_LETTER_KIND_REJECTED, DEAD_LETTER_REASON_ATTRIBUTE},
+    google_cloud_pubsub::{client::Client, client::ClientConfig},
+    heartbeat::{HealthCheck, HeartbeatRecord},
+    jobs::PAUSED_REDELIVERY_DELAY,
+    leader::{InMemoryLeaseStore, LeaderElection},
+    lease::LeasePolicy,
+    outcome::{AckDecision, AckPolicy, AckStrategy, RetryAfter},
+    pipeline::Pipeline,
+    registry::{RegisteredWorker, WorkerRegistry},
+    retry::RepublishRetry,
+    transport::{MessageHandler, PubSubTransport, SubscriptionState, TransportMessage},
+    utils::PubSubContext,
+    workflow::Workflow,
+    PubSubBackend, PubSubCompact, PubSubConfig, PubSubTask,
+};
+use futures::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
+use google_cloud_gax::{
+    conn::Environment,
+    grpc::{Code, Status},
+};
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use google_cloud_pubsub::subscription::ReceiveConfig;
+use tokio::sync::{Notify, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tower::{Layer, Service, ServiceExt};
 
 #[test]
 fn test_config_defaults() {
@@ -17,6 +62,26 @@ fn test_config_defaults() {
         config.max_outstanding_bytes, None,
         "Default max outstanding bytes should be None"
     );
+    assert_eq!(
+        config.concurrency_limit, None,
+        "Default concurrency limit should be None"
+    );
+    assert!(
+        !config.load_shed,
+        "Load shedding should be disabled by default"
+    );
+    assert!(
+        !config.exactly_once,
+        "Exactly-once delivery should be disabled by default"
+    );
+    assert_eq!(
+        config.adaptive_prefetch, None,
+        "Adaptive prefetch should be disabled by default"
+    );
+    assert_eq!(
+        config.health_check, None,
+        "Health checks should be disabled by default"
+    );
 }
 
 #[test]
@@ -26,12 +91,60 @@ fn test_config_custom() {
         max_message_size: 5 * 1024 * 1024,
         max_outstanding_messages: Some(1000),
         max_outstanding_bytes: Some(100 * 1024 * 1024),
+        concurrency_limit: Some(8),
+        load_shed: true,
+        ..Default::default()
     };
 
     assert_eq!(config.buffer_size, 200);
     assert_eq!(config.max_message_size, 5 * 1024 * 1024);
     assert_eq!(config.max_outstanding_messages, Some(1000));
     assert_eq!(config.max_outstanding_bytes, Some(100 * 1024 * 1024));
+    assert_eq!(config.concurrency_limit, Some(8));
+    assert!(config.load_shed);
+}
+
+#[test]
+fn test_config_builder() {
+    let config = PubSubConfig::builder()
+        .with_buffer_size(200)
+        .with_concurrency_limit(8)
+        .with_load_shed(true)
+        .build()
+        .unwrap();
+    assert_eq!(config.buffer_size, 200);
+    assert_eq!(config.concurrency_limit, Some(8));
+    assert!(config.load_shed);
+
+    assert_eq!(
+        PubSubConfig::builder().with_buffer_size(0).build().err(),
+        Some(ConfigError::ZeroBufferSize),
+        "Empty buffers should fail"
+    );
+    assert_eq!(
+        PubSubConfig::builder()
+            .with_max_message_size(11 * 1024 * 1024)
+            .build()
+            .err(),
+        Some(ConfigError::InvalidMessageSize(11 * 1024 * 1024)),
+        "Messages over Pub/Sub's limit should fail"
+    );
+    assert!(
+        PubSubConfig::builder()
+            .with_ack_deadline(Duration::from_secs(1))
+            .build()
+            .is_err(),
+        "Ack deadlines Pub/Sub rejects should fail"
+    );
+    assert_eq!(
+        PubSubConfig::builder()
+            .with_ack_strategy(AckStrategy::Manual)
+            .with_republish_retry(RepublishRetry::default())
+            .build()
+            .err(),
+        Some(ConfigError::RetryWithManualAck),
+        "Republished retries can't settle manually acknowledged messages"
+    );
 }
 
 #[test]
@@ -39,3 +152,1602 @@ fn test_pubsub_context_default() {
     let ctx = PubSubContext::default();
     assert_eq!(ctx.ack_id, "", "Default ack_id should be empty string");
 }
+
+#[test]
+fn test_workflow_validation() {
+    let valid = Workflow::new()
+        .node("a", 1, &[])
+        .node("b", 2, &["a"])
+        .node("c", 3, &["a", "b"]);
+    assert!(valid.validate().is_ok());
+
+    let unknown = Workflow::new().node("a", 1, &["missing"]);
+    assert!(
+        unknown.validate().is_err(),
+        "Unknown dependency should fail"
+    );
+
+    let cycle = Workflow::new().node("a", 1, &["b"]).node("b", 2, &["a"]);
+    assert!(cycle.validate().is_err(), "Cycles should fail");
+
+    let duplicate = Workflow::new().node("a", 1, &[]).node("a", 2, &[]);
+    assert!(duplicate.validate().is_err(), "Duplicate names should fail");
+}
+
+#[test]
+fn test_contract_round_trip() {
+    contract::assert_round_trip::<u32, JsonCodec<PubSubCompact>>([0, 1, u32::MAX]);
+
+    let read = contract::assert_compatible::<
+        u32,
+        JsonCodec<PubSubCompact>,
+        u64,
+        JsonCodec<PubSubCompact>,
+    >([7]);
+    assert_eq!(read, vec![7u64]);
+
+    let message = contract::producer_message::<u32, JsonCodec<PubSubCompact>>(&1).unwrap();
+    assert!(
+        contract::consume::<String, JsonCodec<PubSubCompact>>(&message).is_err(),
+        "Incompatible job types should fail"
+    );
+}
+
+#[test]
+fn test_backoff_delays() {
+    let exponential = Exponential::default();
+    assert_eq!(exponential.delay(1), Duration::from_millis(100));
+    assert_eq!(exponential.delay(4), Duration::from_millis(800));
+    assert_eq!(
+        exponential.delay(100),
+        exponential.max,
+        "Delays should be capped"
+    );
+
+    let jitter = DecorrelatedJitter::default();
+    for attempt in 1..20 {
+        let delay = jitter.delay(attempt);
+        assert!(delay >= jitter.base && delay <= jitter.max);
+    }
+}
+
+type TestBackend = PubSubBackend<u32, JsonCodec<PubSubCompact>>;
+
+/// Transport keeping messages in memory, recording what the backend does with
+/// them
+#[derive(Default)]
+struct MemoryTransport {
+    queue: Mutex<VecDeque<(String, PubsubMessage)>>,
+    queued: Notify,
+    published: Mutex<Vec<(String, PubsubMessage)>>,
+    acked: Mutex<Vec<String>>,
+    deadlines: Mutex<Vec<(String, i32)>>,
+    state: Mutex<Option<SubscriptionState>>,
+    /// Code subscription state requests fail with, if any
+    state_error: Mutex<Option<Code>>,
+    /// Code publishes fail with, if any
+    publish_error: Mutex<Option<Code>>,
+    /// Permits publishes wait for before completing, if any
+    publish_permits: Mutex<Option<Arc<Semaphore>>>,
+}
+
+impl MemoryTransport {
+    /// Queues `message` for delivery with `ack_id`
+    fn deliver(&self, ack_id: &str, message: PubsubMessage) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back((ack_id.to_string(), message));
+        self.queued.notify_one();
+    }
+
+    /// The messages published so far, with their topic
+    fn published(&self) -> Vec<(String, PubsubMessage)> {
+        self.published.lock().unwrap().clone()
+    }
+
+    /// Ack ids acknowledged so far
+    fn acked(&self) -> Vec<String> {
+        self.acked.lock().unwrap().clone()
+    }
+
+    /// Ack ids nacked so far, with an ack deadline of 0
+    fn nacked(&self) -> Vec<String> {
+        self.deadlines
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, seconds)| *seconds == 0)
+            .map(|(ack_id, _)| ack_id.clone())
+            .collect()
+    }
+}
+
+impl PubSubTransport for MemoryTransport {
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        message: PubsubMessage,
+    ) -> BoxFuture<'a, Result<String, Status>> {
+        let permits = self.publish_permits.lock().unwrap().clone();
+        async move {
+            if let Some(permits) = permits {
+                permits.acquire().await.unwrap().forget();
+            }
+            if let Some(code) = *self.publish_error.lock().unwrap() {
+                return Err(Status::new(code, "rejected"));
+            }
+            let mut published = self.published.lock().unwrap();
+            published.push((topic.to_string(), message));
+            Ok(format!("message-{}", published.len()))
+        }
+        .boxed()
+    }
+
+    fn receive(
+        self: Arc<Self>,
+        subscription: String,
+        _config: ReceiveConfig,
+        handler: MessageHandler,
+        cancel: CancellationToken,
+    ) -> BoxFuture<'static, Result<(), Status>> {
+        async move {
+            loop {
+                let next = self.queue.lock().unwrap().pop_front();
+                match next {
+                    Some((ack_id, message)) => {
+                        let transport: Arc<dyn PubSubTransport> = self.clone();
+                        let message =
+                            TransportMessage::new(transport, &subscription, ack_id, message, None);
+                        handler(message).await;
+                    }
+                    None => {
+                        if cancel
+                            .run_until_cancelled(self.queued.notified())
+                            .await
+                            .is_none()
+                        {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn acknowledge<'a>(
+        &'a self,
+        _subscription: &'a str,
+        ack_ids: Vec<String>,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        self.acked.lock().unwrap().extend(ack_ids);
+        async { Ok(()) }.boxed()
+    }
+
+    fn modify_ack_deadline<'a>(
+        &'a self,
+        _subscription: &'a str,
+        ack_ids: Vec<String>,
+        seconds: i32,
+    ) -> BoxFuture<'a, Result<(), Status>> {
+        self.deadlines
+            .lock()
+            .unwrap()
+            .extend(ack_ids.into_iter().map(|ack_id| (ack_id, seconds)));
+        async { Ok(()) }.boxed()
+    }
+
+    fn subscription_state<'a>(
+        &'a self,
+        _subscription: &'a str,
+    ) -> BoxFuture<'a, Result<SubscriptionState, Status>> {
+        if let Some(code) = *self.state_error.lock().unwrap() {
+            return async move { Err(Status::new(code, "unreachable")) }.boxed();
+        }
+        let state = self
+            .state
+            .lock()
+            .unwrap()
+            .unwrap_or(SubscriptionState::Attached);
+        async move { Ok(state) }.boxed()
+    }
+}
+
+/// A Google Cloud client pointing at a local listener that never answers, so
+/// nothing reaches Pub/Sub
+async fn offline_client() -> Client {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((connection, _)) = listener.accept().await {
+            connections.push(connection);
+        }
+    });
+    Client::new(ClientConfig {
+        project_id: Some("local-project".to_string()),
+        environment: Environment::Emulator(address.to_string()),
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+}
+
+/// A backend whose messages go through `transport`
+async fn memory_backend<M>(
+    transport: Arc<MemoryTransport>,
+    config: PubSubConfig,
+) -> PubSubBackend<M, JsonCodec<PubSubCompact>> {
+    PubSubBackend::with_client(offline_client().await, "tasks", "tasks-sub", config)
+        .with_transport(transport)
+}
+
+/// A message carrying the task `args`
+fn task_message(args: u32) -> PubsubMessage {
+    PubsubMessage {
+        data: args.to_string().into_bytes(),
+        message_id: format!("id-{args}"),
+        ..Default::default()
+    }
+}
+
+/// Takes the next task of `tasks`, counting its attempt as the worker would
+async fn next_task(tasks: &mut <TestBackend as Backend>::Stream) -> PubSubTask<u32> {
+    let task = tokio::time::timeout(Duration::from_secs(5), tasks.next())
+        .await
+        .expect("No task was received")
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let _ = task.parts.attempt.increment();
+    task
+}
+
+/// Polls `tasks` until `done` holds, for messages settled without reaching
+/// the worker
+async fn poll_until(tasks: &mut <TestBackend as Backend>::Stream, done: impl Fn() -> bool) {
+    let polling = async {
+        while !done() {
+            let _ = tokio::time::timeout(Duration::from_millis(10), tasks.next()).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), polling)
+        .await
+        .expect("Messages weren't settled in time");
+}
+
+/// A message whose payload doesn't decode
+fn undecodable_message(id: u32) -> PubsubMessage {
+    PubsubMessage {
+        data: b"not a number".to_vec(),
+        message_id: format!("bad-{id}"),
+        ..Default::default()
+    }
+}
+
+/// Runs `task` through the backend's middleware, with a handler returning
+/// `result`
+async fn run_task(backend: &TestBackend, task: PubSubTask<u32>, result: Result<(), &'static str>) {
+    let handler =
+        tower::service_fn(
+            move |_: PubSubTask<u32>| async move { result.map_err(BoxDynError::from) },
+        );
+    let mut service = backend.middleware().layer(handler);
+    let _ = service.ready().await.unwrap().call(task).await;
+}
+
+#[tokio::test]
+async fn test_consumer_has_no_producer() {
+    let backend: TestBackend =
+        PubSubBackend::consumer(offline_client().await, "tasks-sub", PubSubConfig::default());
+    assert!(
+        backend.producer().is_err(),
+        "Consume-only backends have no topic to produce to"
+    );
+}
+
+#[tokio::test]
+async fn test_deferred_ack_with_republish_retry() {
+    let transport = Arc::new(MemoryTransport::default());
+    let clock = ManualClock::new();
+    let backend = memory_backend(
+        transport.clone(),
+        PubSubConfig {
+            ack_strategy: AckStrategy::OnSuccess,
+            republish_retry: Some(RepublishRetry::default()),
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        },
+    )
+    .await;
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+
+    transport.deliver("ack-1", task_message(1));
+    for attempt in 1..=3 {
+        let task = next_task(&mut tasks).await;
+        assert_eq!(task.parts.attempt.current(), attempt);
+        run_task(&backend, task, Err("boom")).await;
+
+        let ack_id = format!("ack-{attempt}");
+        assert_eq!(
+            transport.acked().last(),
+            Some(&ack_id),
+            "Failed messages should be acknowledged once their retry is published"
+        );
+        assert!(
+            transport.nacked().is_empty(),
+            "Retried messages shouldn't be redelivered as well"
+        );
+        if attempt < 3 {
+            // Pub/Sub delivers the retry once it's due
+            let (_, retry) = transport.published().pop().unwrap();
+            let not_before: u64 = retry.attributes["not_before"].parse().unwrap();
+            let now = clock.now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            assert!(
+                not_before > now,
+                "Retries should be held until their backoff delay passed"
+            );
+            clock.advance(Duration::from_secs(60));
+            transport.deliver(&format!("ack-{}", attempt + 1), retry);
+        }
+    }
+    assert_eq!(
+        transport.published().len(),
+        2,
+        "Tasks should stop being retried after their last attempt"
+    );
+    assert_eq!(transport.acked().len(), 3);
+    backend.shutdown();
+}
+
+/// A task with `args` as queued through the backend's sink
+fn sink_task(args: u32) -> PubSubTask<PubSubCompact> {
+    TaskBuilder::new(args.to_string().into_bytes())
+        .with_ctx(PubSubContext::default())
+        .build()
+}
+
+#[tokio::test]
+async fn test_sink_flush_waits_for_outstanding_publishes() {
+    let transport = Arc::new(MemoryTransport::default());
+    let permits = Arc::new(Semaphore::new(0));
+    *transport.publish_permits.lock().unwrap() = Some(permits.clone());
+    let mut backend: TestBackend = memory_backend(transport.clone(), PubSubConfig::default()).await;
+
+    backend.feed(sink_task(1)).await.unwrap();
+    backend.feed(sink_task(2)).await.unwrap();
+
+    let flush = backend.flush();
+    tokio::pin!(flush);
+    assert!(
+        futures::poll!(&mut flush).is_pending(),
+        "Flushing should wait for both publishes"
+    );
+    permits.add_permits(1);
+    tokio::task::yield_now().await;
+    assert!(
+        futures::poll!(&mut flush).is_pending(),
+        "Flushing should wait for the last publish"
+    );
+    permits.add_permits(1);
+    tokio::time::timeout(Duration::from_secs(5), flush)
+        .await
+        .expect("Flushing should finish once everything was published")
+        .unwrap();
+    assert_eq!(transport.published().len(), 2);
+}
+
+#[tokio::test]
+async fn test_sink_publish_error_surfaces_on_flush() {
+    let transport = Arc::new(MemoryTransport::default());
+    *transport.publish_error.lock().unwrap() = Some(Code::PermissionDenied);
+    let mut backend: TestBackend = memory_backend(transport.clone(), PubSubConfig::default()).await;
+
+    backend.feed(sink_task(1)).await.unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), backend.flush())
+        .await
+        .expect("Flushing should finish once the publish failed");
+    assert!(
+        result.is_err(),
+        "The failed publish should surface on flush"
+    );
+
+    // The failure is reported once, later tasks publish as usual
+    *transport.publish_error.lock().unwrap() = None;
+    backend.send(sink_task(2)).await.unwrap();
+    assert_eq!(transport.published().len(), 1);
+}
+
+#[tokio::test]
+async fn test_sink_publish_error_surfaces_on_ready() {
+    let transport = Arc::new(MemoryTransport::default());
+    let permits = Arc::new(Semaphore::new(0));
+    *transport.publish_permits.lock().unwrap() = Some(permits.clone());
+    *transport.publish_error.lock().unwrap() = Some(Code::PermissionDenied);
+    let config = PubSubConfig::builder()
+        .with_max_buffered_bytes(1)
+        .build()
+        .unwrap();
+    let mut backend: TestBackend = memory_backend(transport.clone(), config).await;
+
+    // The outstanding payload exhausts the budget, holding back the next task
+    backend.feed(sink_task(1)).await.unwrap();
+    assert!(backend.is_backpressured());
+    let ready = futures::future::poll_fn(|cx| backend.poll_ready_unpin(cx));
+    tokio::pin!(ready);
+    assert!(futures::poll!(&mut ready).is_pending());
+
+    permits.add_permits(1);
+    let result = tokio::time::timeout(Duration::from_secs(5), ready)
+        .await
+        .expect("The sink should be ready once the publish failed");
+    assert!(
+        result.is_err(),
+        "The failed publish should surface on ready"
+    );
+    assert!(!backend.is_backpressured());
+    assert!(transport.published().is_empty());
+}
+
+#[tokio::test]
+async fn test_ack_on_success() {
+    let transport = Arc::new(MemoryTransport::default());
+    let backend = memory_backend(
+        transport.clone(),
+        PubSubConfig {
+            ack_strategy: AckStrategy::OnSuccess,
+            ..Default::default()
+        },
+    )
+    .await;
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+
+    transport.deliver("ack-1", task_message(1));
+    transport.deliver("ack-2", task_message(2));
+    let task = next_task(&mut tasks).await;
+    run_task(&backend, task, Ok(())).await;
+    let task = next_task(&mut tasks).await;
+    run_task(&backend, task, Err("boom")).await;
+
+    assert_eq!(
+        transport.acked(),
+        ["ack-1"],
+        "Only succeeded tasks are acked"
+    );
+    assert_eq!(
+        transport.nacked(),
+        ["ack-2"],
+        "Failed tasks should be nacked exactly once"
+    );
+    assert!(
+        transport.published().is_empty(),
+        "Failed tasks are redelivered, not republished"
+    );
+    backend.shutdown();
+}
+
+#[tokio::test]
+async fn test_manual_ack_through_acknowledge_layer() {
+    let transport = Arc::new(MemoryTransport::default());
+    let backend: TestBackend = memory_backend(
+        transport.clone(),
+        PubSubConfig {
+            ack_strategy: AckStrategy::Manual,
+            ..Default::default()
+        },
+    )
+    .await;
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+    let acks = backend.ack_handle();
+
+    // Failed tasks asking for a delay, or not
+    let results = [Ok(()), Err(Some(Duration::from_secs(30))), Err(None)];
+    let mut settled = Vec::new();
+    for (args, result) in (1..).zip(results) {
+        transport.deliver(&format!("ack-{args}"), task_message(args));
+        let mut task = next_task(&mut tasks).await;
+        task.parts.data.insert(worker.clone());
+        settled.push(task.parts.ctx.clone());
+        let handler = tower::service_fn(move |_: PubSubTask<u32>| async move {
+            result.map_err(|delay| match delay {
+                Some(delay) => BoxDynError::from(RetryAfter(delay)),
+                None => "boom".into(),
+            })
+        });
+        let mut service =
+            AcknowledgeLayer::new(acks.clone()).layer(backend.middleware().layer(handler));
+        let _ = service.ready().await.unwrap().call(task).await;
+    }
+
+    assert_eq!(
+        transport.acked(),
+        ["ack-1"],
+        "Succeeded tasks should be acked"
+    );
+    assert_eq!(
+        *transport.deadlines.lock().unwrap(),
+        [("ack-2".to_string(), 30), ("ack-3".to_string(), 0)],
+        "Failed tasks should be nacked after their RetryAfter delay, if any"
+    );
+
+    // Settling again through the handle does nothing
+    for ctx in &settled {
+        acks.ack(ctx).await.unwrap();
+        acks.nack(ctx).await.unwrap();
+    }
+    assert_eq!(transport.acked().len(), 1);
+    assert_eq!(transport.deadlines.lock().unwrap().len(), 2);
+    assert!(
+        transport.published().is_empty(),
+        "Manually settled messages aren't republished"
+    );
+    backend.shutdown();
+}
+
+#[tokio::test]
+async fn test_saga_republishes_step_with_same_task_id() {
+    use apalis_pubsub::saga::{
+        InMemorySagaStore, SagaCoordinator, SagaMessage, SagaStatus, SagaStep,
+    };
+
+    let transport = Arc::new(MemoryTransport::default());
+    let backend = memory_backend(transport.clone(), PubSubConfig::default()).await;
+    let store = Arc::new(InMemorySagaStore::default());
+    let mut coordinator = SagaCoordinator::new(backend, store);
+
+    let id = coordinator
+        .start(vec![SagaStep::new(1), SagaStep::new(2)])
+        .await
+        .unwrap();
+    let first: SagaMessage<u32> =
+        JsonCodec::<PubSubCompact>::decode(&transport.published()[0].1.data).unwrap();
+    assert_eq!(first.step, 0);
+
+    // A retried report publishes the next step again, under the same task id
+    for _ in 0..2 {
+        let status = coordinator.step_completed(&first).await.unwrap();
+        assert_eq!(status, SagaStatus::Running);
+    }
+    let published = transport.published();
+    assert_eq!(published.len(), 3);
+    let task_id = |message: &PubsubMessage| message.attributes["task_id"].clone();
+    assert_eq!(task_id(&published[1].1), task_id(&published[2].1));
+    assert_ne!(task_id(&published[0].1), task_id(&published[1].1));
+
+    assert_eq!(coordinator.resume(id).await.unwrap(), SagaStatus::Running);
+    assert_eq!(
+        task_id(&transport.published()[3].1),
+        task_id(&published[1].1)
+    );
+}
+
+#[tokio::test]
+async fn test_workflow_fan_in_and_unknown_nodes() {
+    use apalis_pubsub::workflow::{
+        InMemoryWorkflowStore, Workflow, WorkflowMessage, WorkflowRunner, WorkflowStatus,
+    };
+
+    let transport = Arc::new(MemoryTransport::default());
+    let backend = memory_backend(transport.clone(), PubSubConfig::default()).await;
+    let mut runner = WorkflowRunner::new(backend, Arc::new(InMemoryWorkflowStore::default()));
+
+    let workflow = Workflow::new()
+        .node("a", 1, &[])
+        .node("b", 2, &[])
+        .node("join", 3, &["a", "b"]);
+    let run_id = runner.start(workflow).await.unwrap();
+    assert_eq!(transport.published().len(), 2);
+    let message = |node: &str| WorkflowMessage {
+        run_id,
+        node: node.to_string(),
+        payload: 0u32,
+    };
+
+    let unknown = runner.node_completed(&message("c")).await;
+    assert!(matches!(
+        unknown,
+        Err(apalis_pubsub::PubSubError::InvalidWorkflow(_))
+    ));
+
+    // Reporting both upstream nodes together publishes the join once
+    let (mut first, mut second) = (runner.clone(), runner.clone());
+    let (a, b) = (message("a"), message("b"));
+    let (a, b) = tokio::join!(first.node_completed(&a), second.node_completed(&b));
+    assert_eq!(a.unwrap(), WorkflowStatus::Running);
+    assert_eq!(b.unwrap(), WorkflowStatus::Running);
+    let published = transport.published();
+    assert_eq!(published.len(), 3);
+    let join: WorkflowMessage<u32> =
+        JsonCodec::<PubSubCompact>::decode(&published[2].1.data).unwrap();
+    assert_eq!(join.node, "join");
+
+    let status = runner.node_completed(&message("join")).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
+}
+
+#[tokio::test]
+async fn test_health_check_detached_subscription() {
+    let transport = Arc::new(MemoryTransport::default());
+    let config = PubSubConfig {
+        health_check: Some(HealthCheck {
+            interval: Duration::from_millis(10),
+            max_failures: 1,
+        }),
+        ..Default::default()
+    };
+    let backend: TestBackend = memory_backend(transport.clone(), config).await;
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut beats = backend.heartbeat(&worker);
+
+    // The in-memory subscription is healthy until it's detached
+    assert!(beats.next().await.unwrap().is_ok());
+    *transport.state.lock().unwrap() = Some(SubscriptionState::Detached);
+    let beat = beats.next().await.unwrap();
+    assert!(matches!(
+        beat,
+        Err(apalis_pubsub::PubSubError::SubscriptionGone(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_health_check_unreachable_subscription() {
+    let transport = Arc::new(MemoryTransport::default());
+    let config = PubSubConfig {
+        health_check: Some(HealthCheck {
+            interval: Duration::from_millis(10),
+            max_failures: 2,
+        }),
+        ..Default::default()
+    };
+    let backend: TestBackend = memory_backend(transport.clone(), config).await;
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut beats = backend.heartbeat(&worker);
+
+    // Missing permissions don't mean Pub/Sub is unreachable
+    *transport.state_error.lock().unwrap() = Some(Code::PermissionDenied);
+    for _ in 0..3 {
+        assert!(beats.next().await.unwrap().is_ok());
+    }
+
+    *transport.state_error.lock().unwrap() = Some(Code::Unavailable);
+    assert!(
+        beats.next().await.unwrap().is_ok(),
+        "A single failure should be tolerated"
+    );
+    let beat = beats.next().await.unwrap();
+    assert!(matches!(
+        beat,
+        Err(apalis_pubsub::PubSubError::Subscription(_))
+    ));
+}
+
+/// A message carrying the task `args` of the job type `job_type`
+fn job_message(args: u32, job_type: &str) -> PubsubMessage {
+    let mut message = task_message(args);
+    message
+        .attributes
+        .insert("job_type".to_string(), job_type.to_string());
+    message
+}
+
+/// Redelivery deadline of the tasks of paused job types
+fn paused_deadline(ack_id: &str) -> (String, i32) {
+    (ack_id.to_string(), PAUSED_REDELIVERY_DELAY.as_secs() as i32)
+}
+
+#[tokio::test]
+async fn test_paused_job_type_redelivered_until_resumed() {
+    let transport = Arc::new(MemoryTransport::default());
+    let backend: TestBackend = memory_backend(transport.clone(), PubSubConfig::default()).await;
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+
+    // Received while paused
+    backend.pause_job_type("report");
+    transport.deliver("ack-1", job_message(1, "report"));
+    transport.deliver("ack-2", job_message(2, "email"));
+    assert_eq!(
+        next_task(&mut tasks).await.args,
+        2,
+        "Other job types should keep flowing"
+    );
+    assert_eq!(
+        *transport.deadlines.lock().unwrap(),
+        [paused_deadline("ack-1")]
+    );
+
+    // Buffered before the pause, and nacked when dispatched
+    backend.resume_job_type("report");
+    transport.deliver("ack-3", job_message(3, "report"));
+    eventually(|| backend.in_flight().len() == 1).await;
+    backend.pause_job_type("report");
+    poll_until(&mut tasks, || {
+        transport.deadlines.lock().unwrap().len() == 2
+    })
+    .await;
+    assert_eq!(
+        transport.deadlines.lock().unwrap()[1],
+        paused_deadline("ack-3")
+    );
+    assert!(!transport.acked().contains(&"ack-3".to_string()));
+
+    backend.resume_job_type("report");
+    assert!(backend.paused_job_types().is_empty());
+    transport.deliver("ack-4", job_message(4, "report"));
+    assert_eq!(next_task(&mut tasks).await.args, 4);
+    backend.shutdown();
+}
+
+/// Delivers two undecodable messages, tripping a breaker counting at least
+/// two messages, and waits for them to be dropped
+async fn trip_breaker(transport: &MemoryTransport, tasks: &mut <TestBackend as Backend>::Stream) {
+    transport.deliver("bad-1", undecodable_message(1));
+    transport.deliver("bad-2", undecodable_message(2));
+    poll_until(tasks, || transport.acked().len() == 2).await;
+}
+
+#[tokio::test]
+async fn test_decode_breaker_pauses_job_type() {
+    let transport = Arc::new(MemoryTransport::default());
+    let backend: TestBackend = memory_backend(transport.clone(), PubSubConfig::default())
+        .await
+        .with_decode_breaker(DecodeBreaker::new(BreakerAction::Pause).with_min_messages(2));
+    let job_type = std::any::type_name::<u32>();
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+
+    trip_breaker(&transport, &mut tasks).await;
+    assert_eq!(backend.paused_job_types(), [job_type]);
+
+    // Messages of the paused job type are redelivered later, undecoded
+    transport.deliver("ack-3", task_message(3));
+    poll_until(&mut tasks, || {
+        !transport.deadlines.lock().unwrap().is_empty()
+    })
+    .await;
+    assert_eq!(
+        *transport.deadlines.lock().unwrap(),
+        [(
+            "ack-3".to_string(),
+            PAUSED_REDELIVERY_DELAY.as_secs() as i32
+        )]
+    );
+
+    backend.reset_decode_breaker(job_type);
+    assert!(backend.paused_job_types().is_empty());
+    transport.deliver("ack-4", task_message(4));
+    assert_eq!(next_task(&mut tasks).await.args, 4);
+    backend.shutdown();
+}
+
+#[tokio::test]
+async fn test_decode_breaker_quarantines_job_type() {
+    let transport = Arc::new(MemoryTransport::default());
+    let breaker = DecodeBreaker::new(BreakerAction::Quarantine("quarantine".to_string()))
+        .with_min_messages(2);
+    let backend: TestBackend = memory_backend(transport.clone(), PubSubConfig::default())
+        .await
+        .with_decode_breaker(breaker);
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+
+    trip_breaker(&transport, &mut tasks).await;
+    assert!(
+        backend.paused_job_types().is_empty(),
+        "Quarantining doesn't pause the job type"
+    );
+
+    // Messages of the quarantined job type are moved to the quarantine topic
+    transport.deliver("ack-3", task_message(3));
+    poll_until(&mut tasks, || transport.acked().len() == 3).await;
+    let published = transport.published();
+    assert_eq!(published.len(), 1);
+    assert!(published[0].0.ends_with("/topics/quarantine"));
+    assert_eq!(published[0].1.data, task_message(3).data);
+
+    backend.reset_decode_breaker(std::any::type_name::<u32>());
+    transport.deliver("ack-4", task_message(4));
+    assert_eq!(next_task(&mut tasks).await.args, 4);
+    assert_eq!(transport.published().len(), 1);
+    backend.shutdown();
+}
+
+/// Gives up on every failed task
+struct DeadLetterFailures;
+
+impl AckPolicy for DeadLetterFailures {
+    fn decide(
+        &self,
+        _: &PubSubContext,
+        outcome: Result<(), &(dyn std::error::Error + 'static)>,
+    ) -> AckDecision {
+        match outcome {
+            Ok(()) => AckDecision::Ack,
+            Err(_) => AckDecision::DeadLetter,
+        }
+    }
+}
+
+/// A backend dead-lettering to the `dead-letters` topic
+async fn dead_letter_backend(transport: Arc<MemoryTransport>) -> TestBackend {
+    let config = PubSubConfig {
+        dead_letter_topic: Some("dead-letters".to_string()),
+        ..Default::default()
+    };
+    memory_backend(transport, config).await
+}
+
+/// Asserts `transport` published exactly one dead-lettered copy of `message`
+fn assert_dead_lettered(transport: &MemoryTransport, message: PubsubMessage, reason: &str) {
+    let published = transport.published();
+    assert_eq!(
+        published.len(),
+        1,
+        "The message should be dead-lettered once"
+    );
+    let (topic, copy) = &published[0];
+    assert!(topic.ends_with("/topics/dead-letters"));
+    assert_eq!(copy.data, message.data);
+    assert_eq!(
+        copy.attributes[DEAD_LETTER_KIND_ATTRIBUTE],
+        DEAD_LETTER_KIND_REJECTED
+    );
+    assert_eq!(copy.attributes[DEAD_LETTER_REASON_ATTRIBUTE], reason);
+}
+
+#[tokio::test]
+async fn test_ack_policy_dead_letter() {
+    let transport = Arc::new(MemoryTransport::default());
+    let backend = dead_letter_backend(transport.clone())
+        .await
+        .with_ack_policy(Arc::new(DeadLetterFailures));
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+
+    transport.deliver("ack-1", task_message(1));
+    let task = next_task(&mut tasks).await;
+    run_task(&backend, task, Err("invalid")).await;
+
+    assert_dead_lettered(&transport, task_message(1), "invalid");
+    assert_eq!(transport.acked(), ["ack-1"]);
+    assert!(transport.nacked().is_empty());
+    assert!(backend.in_flight().is_empty());
+    backend.shutdown();
+}
+
+#[tokio::test]
+async fn test_pipeline_dead_letter() {
+    let transport = Arc::new(MemoryTransport::default());
+    let pipeline = Pipeline::new().stage(|_| async { Err(AckDecision::DeadLetter) });
+    let backend = dead_letter_backend(transport.clone())
+        .await
+        .with_pipeline(pipeline);
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+
+    transport.deliver("ack-1", task_message(1));
+    let settled = async {
+        while transport.acked().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let dispatched = async {
+        while let Some(task) = tasks.next().await {
+            assert!(task.unwrap().is_none(), "The task should be dropped");
+        }
+    };
+    tokio::select! {
+        _ = tokio::time::timeout(Duration::from_secs(5), settled) => {}
+        _ = dispatched => panic!("The task stream ended"),
+    }
+
+    assert_dead_lettered(&transport, task_message(1), "Dropped by a pipeline stage");
+    assert_eq!(transport.acked(), ["ack-1"]);
+    backend.shutdown();
+}
+
+#[tokio::test]
+async fn test_deferred_lease_lost_while_buffered() {
+    let transport = Arc::new(MemoryTransport::default());
+    let clock = ManualClock::new();
+    let backend: TestBackend = memory_backend(
+        transport.clone(),
+        PubSubConfig {
+            ack_strategy: AckStrategy::OnSuccess,
+            lease_extension: Some(LeasePolicy::default()),
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        },
+    )
+    .await;
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+
+    transport.deliver("ack-1", task_message(1));
+    let received = async {
+        while backend.in_flight().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), received)
+        .await
+        .expect("The message should be buffered");
+
+    // The lease runs out before the worker takes the task
+    clock.advance(Duration::from_secs(60 * 60));
+    let next = tokio::time::timeout(Duration::from_millis(200), tasks.next()).await;
+    assert!(
+        next.is_err(),
+        "Tasks whose lease ran out shouldn't be dispatched"
+    );
+    assert!(
+        backend.in_flight().is_empty(),
+        "Dropped messages shouldn't stay in flight"
+    );
+    backend.shutdown();
+}
+
+/// Waits until `done` holds, for work happening in the background
+async fn eventually(done: impl Fn() -> bool) {
+    let waiting = async {
+        while !done() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), waiting)
+        .await
+        .expect("Condition wasn't met in time");
+}
+
+/// Lease extensions sent so far, as ack ids with their new deadline
+fn extensions(transport: &MemoryTransport) -> Vec<(String, i32)> {
+    transport
+        .deadlines
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, seconds)| *seconds > 0)
+        .cloned()
+        .collect()
+}
+
+/// Advances `clock` by `duration` once the backend's sleeps are all waiting
+/// on it, and waits for them to be waiting again
+async fn advance(clock: &ManualClock, sleeps: usize, duration: Duration) {
+    eventually(|| clock.pending_sleeps() == sleeps).await;
+    clock.advance(duration);
+    eventually(|| clock.pending_sleeps() == sleeps).await;
+}
+
+#[tokio::test]
+async fn test_lease_extended_until_max_total_extension() {
+    let transport = Arc::new(MemoryTransport::default());
+    let clock = ManualClock::new();
+    let policy = LeasePolicy {
+        max_extension: Duration::from_secs(60),
+        max_total_extension: Duration::from_secs(120),
+        extend_running: true,
+    };
+    let backend: TestBackend = memory_backend(
+        transport.clone(),
+        PubSubConfig {
+            lease_extension: Some(policy),
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        },
+    )
+    .await;
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+
+    transport.deliver("ack-1", task_message(1));
+    eventually(|| !backend.in_flight().is_empty()).await;
+    eventually(|| clock.pending_sleeps() > 0).await;
+    let sleeps = clock.pending_sleeps();
+
+    // Extended every half extension while buffered, up to 120s after receipt
+    for _ in 0..4 {
+        advance(&clock, sleeps, Duration::from_secs(30)).await;
+    }
+    let extended = ("ack-1".to_string(), 60);
+    assert_eq!(extensions(&transport), [extended.clone(), extended]);
+
+    // The lease taken at 60s ran out at 120s
+    let next = tokio::time::timeout(Duration::from_millis(200), tasks.next()).await;
+    assert!(
+        next.is_err(),
+        "Tasks whose lease ran out shouldn't be dispatched"
+    );
+    assert!(backend.in_flight().is_empty());
+    assert!(
+        transport.acked().is_empty(),
+        "Messages whose lease ran out are left to their redelivery"
+    );
+    backend.shutdown();
+}
+
+#[tokio::test]
+async fn test_lease_extended_while_running() {
+    let transport = Arc::new(MemoryTransport::default());
+    let clock = ManualClock::new();
+    let backend: TestBackend = memory_backend(
+        transport.clone(),
+        PubSubConfig {
+            ack_strategy: AckStrategy::OnSuccess,
+            lease_extension: Some(LeasePolicy::default()),
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        },
+    )
+    .await;
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+
+    transport.deliver("ack-1", task_message(1));
+    let task = next_task(&mut tasks).await;
+    eventually(|| clock.pending_sleeps() > 0).await;
+    let sleeps = clock.pending_sleeps();
+
+    advance(&clock, sleeps, Duration::from_secs(30)).await;
+    assert_eq!(
+        extensions(&transport),
+        [("ack-1".to_string(), 60)],
+        "Running tasks should keep their lease"
+    );
+
+    run_task(&backend, task, Ok(())).await;
+    assert_eq!(transport.acked(), ["ack-1"]);
+    advance(&clock, sleeps, Duration::from_secs(30)).await;
+    assert_eq!(
+        extensions(&transport).len(),
+        1,
+        "Settled messages shouldn't be extended"
+    );
+    backend.shutdown();
+}
+
+#[cfg(feature = "local")]
+#[tokio::test]
+async fn test_local_poll_compact() {
+    use apalis_core::backend::{BackendExt, TaskSink};
+    use apalis_pubsub::local::LocalPubSubBackend;
+
+    let dir = std::env::temp_dir().join(format!("apalis-pubsub-local-{}", std::process::id()));
+    let mut backend = LocalPubSubBackend::<u32, JsonCodec<PubSubCompact>>::new(&dir).unwrap();
+    backend.push(42).await.unwrap();
+
+    let worker = WorkerContext::new::<LocalPubSubBackend<u32, JsonCodec<PubSubCompact>>>("worker");
+    let mut tasks = backend.clone().poll_compact(&worker);
+    let task = tokio::time::timeout(Duration::from_secs(5), tasks.next())
+        .await
+        .expect("No task was received")
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(task.args, b"42");
+    assert!(backend.is_empty().unwrap());
+
+    backend.shutdown();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(feature = "local")]
+#[tokio::test]
+async fn test_local_rejects_invalid_task_files() {
+    use apalis_core::backend::TaskSink;
+    use apalis_pubsub::local::LocalPubSubBackend;
+
+    type LocalBackend = LocalPubSubBackend<u32, JsonCodec<PubSubCompact>>;
+    let dir = std::env::temp_dir().join(format!("apalis-pubsub-rejected-{}", std::process::id()));
+    let mut backend = LocalBackend::new(&dir).unwrap();
+    std::fs::write(dir.join("queue").join("0-invalid.pb"), b"\xff\xff").unwrap();
+    backend.push(42).await.unwrap();
+
+    let worker = WorkerContext::new::<LocalBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+    let task = tokio::time::timeout(Duration::from_secs(5), tasks.next())
+        .await
+        .expect("No task was received")
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(task.args, 42, "Invalid files shouldn't stop the stream");
+    assert!(backend.is_empty().unwrap());
+    assert!(dir.join("rejected").join("0-invalid.pb").exists());
+
+    backend.shutdown();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Modulus of the public key of `fixtures/push_signing_key.pem`, base64url
+/// encoded
+#[cfg(feature = "push")]
+const PUSH_KEY_MODULUS: &str = concat!(
+    "kCvlgekhYbmJeH55qahVRRzsWTk7dxtxfipt21SLR4W2tv3ln2x24lcVUmISXeKC",
+    "-uVrlavdtGL8ECRYjuJyV0A2BLWG22qHEHnWQbXzjCs9DFFUmKLz6SuCn4_pP5OJ",
+    "mOH5m-qUNalCUESalaQiLhQIIxHfdN8q4i5qq5h1JkOM5SuhFYFAPkrvS0QAYXm5",
+    "zHAp99_CvolOYxG1yJLHFZarYL2HGW0bQhqAA9uXqNzP1blIb5m2EkZYKkvWnRkd",
+    "ruE6Ku0aOVfAREk2xV0YRNLyjlG0gctQlRS5NLianz3Q91FpSwLZJoMVUL9v5s2d",
+    "Xn2ISSgI2d3IM7jffLjEiw",
+);
+
+/// Serves a JWK set holding the test signing key with the id `test-key`,
+/// returning its URL and the number of times it was fetched
+#[cfg(feature = "push")]
+async fn serve_signing_keys() -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let counter = fetches.clone();
+    let keys = serde_json::json!({
+        "keys": [{
+            "kty": "RSA",
+            "alg": "RS256",
+            "use": "sig",
+            "kid": "test-key",
+            "n": PUSH_KEY_MODULUS,
+            "e": "AQAB",
+        }]
+    });
+    let app = axum::Router::new().route(
+        "/certs",
+        axum::routing::get(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let keys = keys.clone();
+            async move { axum::Json(keys) }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (format!("http://{address}/certs"), fetches)
+}
+
+/// A token signed with the test key `kid`, for `audience` and from `issuer`
+#[cfg(feature = "push")]
+fn push_token(kid: &str, audience: &str, issuer: &str) -> String {
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(kid.to_string());
+    let expires = std::time::SystemTime::now() + Duration::from_secs(600);
+    let claims = serde_json::json!({
+        "aud": audience,
+        "iss": issuer,
+        "exp": expires.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        "email": "pusher@project.iam.gserviceaccount.com",
+        "email_verified": true,
+    });
+    let key = EncodingKey::from_rsa_pem(include_bytes!("fixtures/push_signing_key.pem")).unwrap();
+    jsonwebtoken::encode(&header, &claims, &key).unwrap()
+}
+
+#[cfg(feature = "push")]
+#[tokio::test]
+async fn test_push_verifier_audience_and_issuer() {
+    use apalis_pubsub::push::{GoogleOidcVerifier, PushVerifier};
+
+    let (url, _) = serve_signing_keys().await;
+    let verifier = GoogleOidcVerifier::new("https://worker.example.com/push")
+        .with_service_account("pusher@project.iam.gserviceaccount.com")
+        .with_certs_url(url);
+    let issuer = "https://accounts.google.com";
+
+    let valid = push_token("test-key", "https://worker.example.com/push", issuer);
+    assert!(verifier.verify(&valid).await.is_ok());
+
+    let other_audience = push_token("test-key", "https://other.example.com/push", issuer);
+    assert!(
+        verifier.verify(&other_audience).await.is_err(),
+        "Tokens for another audience should be rejected"
+    );
+    let other_issuer = push_token(
+        "test-key",
+        "https://worker.example.com/push",
+        "https://issuer.example.com",
+    );
+    assert!(
+        verifier.verify(&other_issuer).await.is_err(),
+        "Tokens not issued by Google should be rejected"
+    );
+
+    let other_account = GoogleOidcVerifier::new("https://worker.example.com/push")
+        .with_service_account("someone@project.iam.gserviceaccount.com")
+        .with_certs_url(serve_signing_keys().await.0);
+    assert!(
+        other_account.verify(&valid).await.is_err(),
+        "Tokens for another service account should be rejected"
+    );
+}
+
+#[cfg(feature = "push")]
+#[tokio::test]
+async fn test_push_verifier_key_refresh_rate_limit() {
+    use apalis_pubsub::push::{GoogleOidcVerifier, PushVerifier};
+    use std::sync::atomic::Ordering;
+
+    let (url, fetches) = serve_signing_keys().await;
+    let audience = "https://worker.example.com/push";
+    let verifier = GoogleOidcVerifier::new(audience).with_certs_url(url);
+    let issuer = "https://accounts.google.com";
+
+    for _ in 0..3 {
+        let unknown = push_token("unknown-key", audience, issuer);
+        assert!(verifier.verify(&unknown).await.is_err());
+    }
+    assert_eq!(
+        fetches.load(Ordering::SeqCst),
+        1,
+        "Unknown key ids shouldn't refetch the keys more than once a minute"
+    );
+
+    let valid = push_token("test-key", audience, issuer);
+    assert!(verifier.verify(&valid).await.is_ok());
+    assert_eq!(fetches.load(Ordering::SeqCst), 1, "Known keys are cached");
+}
+
+#[cfg(feature = "record")]
+#[tokio::test]
+async fn test_record_then_replay() {
+    let path = std::env::temp_dir().join(format!(
+        "apalis-pubsub-recording-{}.ndjson",
+        std::process::id()
+    ));
+    let transport = Arc::new(MemoryTransport::default());
+    let backend: TestBackend = memory_backend(transport.clone(), PubSubConfig::default())
+        .await
+        .with_recording(&path)
+        .unwrap();
+    let worker = WorkerContext::new::<TestBackend>("worker");
+    let mut tasks = backend.clone().poll(&worker);
+    for args in [1, 2] {
+        transport.deliver(&format!("ack-{args}"), task_message(args));
+        assert_eq!(next_task(&mut tasks).await.args, args);
+    }
+    backend.shutdown();
+
+    // Lines are written in the background
+    let recorded = async {
+        loop {
+            match apalis_pubsub::record::read_recording(&path) {
+                Ok(messages) if messages.len() == 2 => return messages,
+                _ => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    };
+    let recorded = tokio::time::timeout(Duration::from_secs(5), recorded)
+        .await
+        .expect("Messages weren't recorded");
+    assert_eq!(recorded[0].data, task_message(1).data);
+    assert_eq!(recorded[1].message_id, "id-2");
+
+    let replay: TestBackend = memory_backend(Arc::default(), PubSubConfig::default())
+        .await
+        .with_replay(&path)
+        .unwrap();
+    let mut tasks = replay.clone().poll(&worker);
+    assert_eq!(next_task(&mut tasks).await.args, 1);
+    assert_eq!(next_task(&mut tasks).await.args, 2);
+    replay.shutdown();
+    std::fs::remove_file(path).unwrap();
+}
+
+/// Holds a task running until its sender is dropped
+type TaskGate = tokio::sync::oneshot::Receiver<()>;
+
+/// Service running each task until its sender is dropped, under `control`
+fn controlled_service(
+    control: &Arc<ConcurrencyControl>,
+) -> ConcurrencyControlService<
+    impl Service<TaskGate, Response = (), Error = BoxDynError, Future: Send> + Clone,
+> {
+    let handler = tower::service_fn(|done: TaskGate| async move {
+        let _ = done.await;
+        Ok::<_, BoxDynError>(())
+    });
+    ConcurrencyControlLayer::new(control.clone()).layer(handler)
+}
+
+/// A running task, finished by [`finish`]
+type RunningTask = (
+    tokio::sync::oneshot::Sender<()>,
+    tokio::task::JoinHandle<Result<(), BoxDynError>>,
+);
+
+/// Whether `service` could start a task right away
+async fn has_permit<S: Service<TaskGate> + Clone>(service: &S) -> bool {
+    let mut service = service.clone();
+    tokio::time::timeout(Duration::from_millis(50), service.ready())
+        .await
+        .is_ok()
+}
+
+/// Starts a task on `service`, waiting for a permit
+async fn start<S>(service: &S) -> RunningTask
+where
+    S: Service<TaskGate, Response = (), Error = BoxDynError> + Clone,
+    S::Future: Send + 'static,
+{
+    let mut service = service.clone();
+    let (done, rx) = tokio::sync::oneshot::channel();
+    let future = service.ready().await.unwrap().call(rx);
+    (done, tokio::spawn(future))
+}
+
+/// Finishes `task`, releasing its permit
+async fn finish((done, handle): RunningTask) {
+    drop(done);
+    handle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_concurrency_control_lowered_below_in_use() {
+    let control = Arc::new(ConcurrencyControl::new(Some(2)));
+    let service = controlled_service(&control);
+    let first = start(&service).await;
+    let second = start(&service).await;
+
+    control.set_limit(1);
+    assert_eq!(control.limit(), Some(1));
+    assert!(!has_permit(&service).await);
+
+    // The first task to finish gives its permit back to the lowered limit
+    finish(first).await;
+    assert!(
+        !has_permit(&service).await,
+        "The permit should be taken back"
+    );
+    finish(second).await;
+    assert!(has_permit(&service).await);
+
+    let third = start(&service).await;
+    assert!(!has_permit(&service).await, "The new limit should apply");
+    finish(third).await;
+}
+
+#[tokio::test]
+async fn test_concurrency_control_raised_again() {
+    let control = Arc::new(ConcurrencyControl::new(Some(2)));
+    let service = controlled_service(&control);
+    let first = start(&service).await;
+    let second = start(&service).await;
+
+    // Raising after lowering only adds what wasn't owed
+    control.set_limit(1);
+    control.set_limit(3);
+    assert_eq!(control.limit(), Some(3));
+    let third = start(&service).await;
+    assert!(!has_permit(&service).await);
+
+    for task in [first, second, third] {
+        finish(task).await;
+    }
+    let running = [
+        start(&service).await,
+        start(&service).await,
+        start(&service).await,
+    ];
+    assert!(!has_permit(&service).await);
+    for task in running {
+        finish(task).await;
+    }
+}
+
+#[tokio::test]
+async fn test_concurrency_control_lowered_to_zero() {
+    let control = Arc::new(ConcurrencyControl::new(Some(1)));
+    let service = controlled_service(&control);
+    let running = start(&service).await;
+
+    control.set_limit(0);
+    assert_eq!(control.limit(), Some(0));
+    finish(running).await;
+    assert!(
+        !has_permit(&service).await,
+        "No task should start at a limit of 0"
+    );
+
+    control.set_limit(1);
+    assert!(has_permit(&service).await);
+}
+
+/// The permit of `reservation`, if it completes within a short wait
+async fn reserved_in_time(
+    reservation: &mut tokio::task::JoinHandle<BudgetPermit>,
+) -> Option<BudgetPermit> {
+    let joined = tokio::time::timeout(Duration::from_millis(50), reservation).await;
+    joined.ok().map(|permit| permit.unwrap())
+}
+
+#[tokio::test]
+async fn test_buffer_budget_payload_over_limit() {
+    let budget = Arc::new(BufferBudget::new(10));
+    let large = tokio::time::timeout(Duration::from_secs(1), budget.reserve(25))
+        .await
+        .expect("A payload over the budget shouldn't wait while nothing is buffered");
+    assert_eq!(budget.used(), 25);
+    assert!(budget.is_exhausted());
+
+    let waiting = budget.clone();
+    let mut small = tokio::spawn(async move { waiting.reserve(1).await });
+    assert!(reserved_in_time(&mut small).await.is_none());
+    drop(large);
+    let _small = reserved_in_time(&mut small).await.unwrap();
+    assert_eq!(budget.used(), 1);
+}
+
+#[tokio::test]
+async fn test_buffer_budget_released_on_drop() {
+    let budget = Arc::new(BufferBudget::new(10));
+    let first = budget.reserve(4).await;
+    let second = budget.reserve(4).await;
+    assert_eq!(budget.used(), 8);
+    drop(first);
+    assert_eq!(budget.used(), 4);
+    drop(second);
+    assert_eq!(budget.used(), 0);
+    assert!(!budget.is_exhausted());
+}
+
+#[tokio::test]
+async fn test_buffer_budget_waiters_in_order() {
+    let budget = Arc::new(BufferBudget::new(10));
+    let held = budget.reserve(6).await;
+
+    let waiting = budget.clone();
+    let mut first = tokio::spawn(async move { waiting.reserve(6).await });
+    assert!(reserved_in_time(&mut first).await.is_none());
+    // Would fit right away, but waits for the earlier reservation
+    let waiting = budget.clone();
+    let mut second = tokio::spawn(async move { waiting.reserve(4).await });
+    assert!(reserved_in_time(&mut second).await.is_none());
+
+    drop(held);
+    let _first = reserved_in_time(&mut first).await.unwrap();
+    let _second = reserved_in_time(&mut second).await.unwrap();
+    assert_eq!(budget.used(), 10);
+}
+
+/// Elections for the same lease in `store`, one per holder
+fn elections(
+    store: &Arc<InMemoryLeaseStore>,
+    ttl: Duration,
+) -> (Arc<LeaderElection>, Arc<LeaderElection>) {
+    let election =
+        |holder| Arc::new(LeaderElection::new(store.clone(), "scheduler", ttl).with_holder(holder));
+    (election("replica-a"), election("replica-b"))
+}
+
+#[tokio::test]
+async fn test_leader_acquires_lease() {
+    let store = Arc::new(InMemoryLeaseStore::default());
+    let (a, b) = elections(&store, Duration::from_secs(30));
+    assert!(!a.is_leader());
+
+    assert!(a.try_lead().await.unwrap());
+    assert!(a.is_leader());
+    assert!(!b.try_lead().await.unwrap(), "The lease is already held");
+    assert!(!b.is_leader());
+
+    // Renewing keeps the lease
+    assert!(a.try_lead().await.unwrap());
+    assert!(a.is_leader());
+}
+
+#[tokio::test]
+async fn test_leader_lost_on_lease_expiry() {
+    let store = Arc::new(InMemoryLeaseStore::default());
+    let (a, b) = elections(&store, Duration::from_millis(100));
+    assert!(a.try_lead().await.unwrap());
+
+    // The leader stopped renewing, e.g. because it can't reach the store
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(!a.is_leader(), "Leadership should end with the lease");
+    assert!(b.try_lead().await.unwrap(), "An expired lease can be taken");
+    assert!(b.is_leader());
+    assert!(!a.try_lead().await.unwrap());
+    assert!(!a.is_leader());
+}
+
+#[tokio::test]
+async fn test_leader_hands_over_on_shutdown() {
+    let store = Arc::new(InMemoryLeaseStore::default());
+    let (a, b) = elections(&store, Duration::from_secs(30));
+    let cancel = CancellationToken::new();
+    let running = tokio::spawn(a.clone().run(cancel.clone()));
+    eventually(|| a.is_leader()).await;
+    assert!(!b.try_lead().await.unwrap());
+
+    // Released well before it expires, so the next replica takes over at once
+    cancel.cancel();
+    running.await.unwrap();
+    assert!(!a.is_leader());
+    assert!(b.try_lead().await.unwrap());
+    assert!(b.is_leader());
+}
+
+/// A heartbeat of `worker`, serving `queue` with `in_flight` running tasks
+fn heartbeat(worker: &str, queue: &str, in_flight: usize) -> HeartbeatRecord {
+    HeartbeatRecord {
+        worker: worker.to_string(),
+        queue: queue.to_string(),
+        in_flight,
+        started_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        last_ack: None,
+    }
+}
+
+/// A registry reading heartbeats from a subscription nothing is sent to
+async fn worker_registry(timeout: Duration) -> Arc<WorkerRegistry> {
+    let subscription = offline_client().await.subscription("heartbeats-sub");
+    Arc::new(WorkerRegistry::new(subscription, timeout))
+}
+
+/// Names of `workers`, sorted
+fn worker_names(workers: Vec<RegisteredWorker>) -> Vec<String> {
+    let mut names: Vec<_> = workers.into_iter().map(|w| w.record.worker).collect();
+    names.sort();
+    names
+}
+
+#[tokio::test]
+async fn test_registry_register_and_lookup() {
+    let registry = worker_registry(Duration::from_secs(90)).await;
+    assert!(registry.workers().is_empty());
+
+    registry.record(heartbeat("worker-1", "emails", 0));
+    registry.record(heartbeat("worker-2", "emails", 0));
+    registry.record(heartbeat("worker-3", "reports", 0));
+    assert_eq!(
+        worker_names(registry.workers()),
+        ["worker-1", "worker-2", "worker-3"]
+    );
+    assert_eq!(
+        worker_names(registry.workers_for("emails")),
+        ["worker-1", "worker-2"]
+    );
+    assert!(registry.workers_for("invoices").is_empty());
+
+    // The backend reports the same workers through apalis
+    let backend: TestBackend = memory_backend(
+        Arc::new(MemoryTransport::default()),
+        PubSubConfig::default(),
+    )
+    .await
+    .with_worker_registry(registry);
+    let workers = backend.list_workers("reports").await.unwrap();
+    assert_eq!(workers.len(), 1);
+    assert_eq!(workers[0].id, "worker-3");
+    assert_eq!(workers[0].started_at, 1_700_000_000);
+    assert_eq!(backend.list_all_workers().await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn test_registry_duplicate_registration() {
+    let registry = worker_registry(Duration::from_secs(90)).await;
+    registry.record(heartbeat("worker-1", "emails", 0));
+    registry.record(heartbeat("worker-1", "emails", 3));
+
+    // A worker's heartbeats replace each other
+    let workers = registry.workers();
+    assert_eq!(workers.len(), 1);
+    assert_eq!(workers[0].record.in_flight, 3);
+
+    // The same name serving another queue is another worker
+    registry.record(heartbeat("worker-1", "reports", 0));
+    assert_eq!(registry.workers().len(), 2);
+    assert_eq!(registry.workers_for("emails").len(), 1);
+}
+
+#[tokio::test]
+async fn test_registry_forgets_silent_workers() {
+    let registry = worker_registry(Duration::from_millis(200)).await;
+    registry.record(heartbeat("worker-1", "emails", 0));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    registry.record(heartbeat("worker-2", "emails", 0));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(worker_names(registry.workers()), ["worker-2"]);
+}